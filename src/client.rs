@@ -10,17 +10,22 @@ use std::str::FromStr;
 
 use bufstream::BufStream;
 use capnp::serialize;
+use uuid::Uuid;
 use capnp::message::{Allocator, Builder, ReaderOptions};
 
-use messages_capnp::{client_response, command_response};
+use messages_capnp::{client_response, command_response, transaction_status_response,
+                     ReadConsistency, TransactionState};
 use messages;
 use ClientId;
 use LogId;
+use LogIndex;
+use ServerId;
 use TransactionId;
 use Result;
 use RaftError;
 use auth::Auth;
 use Error;
+use session::ProposalId;
 use transaction;
 
 /// The representation of a Client connection to the cluster.
@@ -39,6 +44,9 @@ pub struct Client {
     username: String,
     /// The LogId for the messages for the connection
     lid: LogId,
+    /// The sequence number of the latest proposal. Retries of a proposal reuse its number, so
+    /// that the cluster applies it only once.
+    sequence: u64,
 }
 
 impl Client {
@@ -59,23 +67,38 @@ impl Client {
             password: password,
             username: username,
             lid: lid,
+            sequence: 0,
         }
     }
 
     /// Proposes an entry to be appended to the replicated log. This will only
     /// return once the entry has been durably committed.
     /// Returns `Error` when the entire cluster has an unknown leader. Try proposing again later.
+    /// The entry is applied at most once, even if the request has to be resent.
     pub fn propose(&mut self, session: TransactionId, entry: &[u8]) -> Result<Vec<u8>> {
         scoped_trace!("{:?}: propose", self);
-        let mut message = messages::proposal_request(session, entry, self.lid);
+        self.sequence += 1;
+        let proposal = ProposalId {
+            client: self.id,
+            sequence: self.sequence,
+        };
+        let mut message = messages::proposal_request(session, entry, Some(proposal), self.lid);
         self.send_message(&mut message)
     }
 
     /// Queries an entry from the state machine. This is non-mutating and doesn't go through the
-    /// durable log. Like `.propose()` this will only communicate with the leader of the cluster.
+    /// durable log. The query is only answered once the state machine reflects every entry
+    /// committed before the query was sent.
     pub fn query(&mut self, query: &[u8]) -> Result<Vec<u8>> {
+        self.query_with(query, ReadConsistency::ReadIndex)
+    }
+
+    /// Queries an entry from the state machine with the given consistency. `ReadIndex` behaves
+    /// like `.query()`, `Lease` saves the leader a round trip to its peers while it holds a lease,
+    /// and `Stale` is answered from the state machine of whichever server receives it.
+    pub fn query_with(&mut self, query: &[u8], consistency: ReadConsistency) -> Result<Vec<u8>> {
         scoped_trace!("{:?}: query", self);
-        let mut message = messages::query_request(query, &self.lid);
+        let mut message = messages::query_request(query, consistency, &self.lid);
         self.send_message(&mut message)
     }
 
@@ -93,7 +116,8 @@ impl Client {
     ///
     /// # Arguments
     /// * `session` - The ID of the transaction. The server tries to commit the transaction with
-    /// this ID.
+    /// this ID. The commit fails if the transaction conflicts with a command committed since it
+    /// began.
     pub fn end_transaction(&mut self, session: TransactionId) -> Result<Vec<u8>> {
         let mut message = messages::client_transaction_commit(self.lid, session);
         self.send_message(&mut message)
@@ -102,17 +126,169 @@ impl Client {
     /// Rollbacks the transaction
     ///
     /// # Arguments
-    /// * `session` - The ID of the transaction. The server tries to revert all messages from this
+    /// * `session` - The ID of the transaction. The server drops all messages from this
     /// transaction.
     pub fn rollback_transaction(&mut self, session: TransactionId) -> Result<Vec<u8>> {
         let mut message = messages::client_transaction_rollback(self.lid, session);
         self.send_message(&mut message)
     }
 
+    /// Sets a savepoint within the transaction, to which the transaction can later be rolled
+    /// back without ending it. A savepoint reusing the name of an earlier one hides it.
+    pub fn savepoint(&mut self, session: TransactionId, name: &str) -> Result<()> {
+        let mut message = messages::client_transaction_savepoint(self.lid, session, name);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Rolls the transaction back to the latest savepoint named `name`, dropping the commands
+    /// proposed since and the savepoints set since. The transaction keeps running.
+    pub fn rollback_to(&mut self, session: TransactionId, name: &str) -> Result<()> {
+        let mut message = messages::client_transaction_rollback_to(self.lid, session, name);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Runs a transaction spanning several logs, which commits on either all of them or none.
+    /// `commands` pairs every command with the log it is proposed to. Each log prepares its
+    /// part of the transaction, and this client's log, as coordinator log, then records the
+    /// decision to commit. A log which the decision does not reach learns it from the coordinator
+    /// log.
+    ///
+    /// # Arguments
+    /// * `session` - The ID of the transaction on every log.
+    pub fn multi_log_transaction(&mut self,
+                                 session: TransactionId,
+                                 commands: &[(LogId, &[u8])])
+                                 -> Result<()> {
+        scoped_trace!("{:?}: multi-log transaction {}", self, session);
+        let mut participants: Vec<LogId> = Vec::new();
+        for &(lid, _) in commands {
+            if !participants.contains(&lid) {
+                participants.push(lid);
+            }
+        }
+
+        let mut begun = Vec::new();
+        let mut outcome = Ok(());
+        for &lid in &participants {
+            begun.push(lid);
+            outcome = self.prepare_part(lid, session, commands);
+            if outcome.is_err() {
+                break;
+            }
+        }
+        if outcome.is_ok() {
+            outcome = self.decide(session, true);
+        }
+        // A prepared log may only end its part as the coordinator log decided. If the decision
+        // cannot be established, the logs learn it from the coordinator log later.
+        let commit = match outcome {
+            Ok(()) => Some(true),
+            Err(_) => self.decide(session, false).ok().map(|_| false),
+        };
+        if let Some(commit) = commit {
+            for lid in begun {
+                let mut message = if commit {
+                    messages::client_transaction_commit(lid, session)
+                } else {
+                    messages::client_transaction_rollback(lid, session)
+                };
+                if let Err(error) = self.send_message(&mut message) {
+                    scoped_debug!("unable to end transaction {} on log {:?}: {}",
+                                  session,
+                                  lid,
+                                  error);
+                }
+            }
+        }
+        outcome
+    }
+
+    /// Runs the part of a multi-log transaction on the log `lid`: begins the transaction,
+    /// proposes the commands for the log, and prepares the transaction. The leader of the log
+    /// rolls back the unprepared transactions of a disconnected client, so the part is run
+    /// before moving on to another log.
+    fn prepare_part(&mut self,
+                    lid: LogId,
+                    session: TransactionId,
+                    commands: &[(LogId, &[u8])])
+                    -> Result<()> {
+        try!(self.send_message(&mut messages::client_transaction_begin(lid, session)));
+        for &(_, command) in commands.iter().filter(|&&(log, _)| log == lid) {
+            self.sequence += 1;
+            let proposal = ProposalId {
+                client: self.id,
+                sequence: self.sequence,
+            };
+            let mut message = messages::proposal_request(session, command, Some(proposal), lid);
+            try!(self.send_message(&mut message));
+        }
+        let mut message = messages::client_transaction_prepare(lid, session, self.lid);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Records in this client's log the decision whether to commit a multi-log transaction.
+    /// Fails if a different decision has been recorded already.
+    fn decide(&mut self, session: TransactionId, commit: bool) -> Result<()> {
+        let mut message = messages::client_transaction_decide(self.lid, session, commit);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Creates the log `lid` on every server of the cluster. The cluster has to be configured
+    /// with a metadata log, which records the new log.
+    pub fn create_log(&mut self, lid: LogId) -> Result<()> {
+        scoped_trace!("{:?}: create log {:?}", self, lid);
+        let mut message = messages::create_log_request(self.lid, lid);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Drops the log `lid`, which has to have been created with `create_log()`, on every server
+    /// of the cluster.
+    pub fn drop_log(&mut self, lid: LogId) -> Result<()> {
+        scoped_trace!("{:?}: drop log {:?}", self, lid);
+        let mut message = messages::drop_log_request(self.lid, lid);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Returns how the transaction stands: whether it is running, prepared, committed or rolled
+    /// back, along with its number of commands and the index of the entry which began it. Useful
+    /// when the answer to the request ending the transaction was lost. Returns `None` if the
+    /// transaction never began, or finished too long ago to be remembered.
+    pub fn transaction_status(&mut self,
+                              session: TransactionId)
+                              -> Result<Option<transaction::TransactionStatus>> {
+        scoped_trace!("{:?}: transaction status {}", self, session);
+        let mut message = messages::client_transaction_status(self.lid, session);
+        match try!(self.exchange(&mut message)) {
+            Response::TransactionStatus(status) => Ok(status),
+            Response::Data(_) => panic!("Unexpected message type"),
+        }
+    }
+
+    /// Transfers leadership of the log to the peer `target`. Returns once the target has been
+    /// elected, or with an error if the transfer was rejected or did not complete in time.
+    pub fn transfer_leadership(&mut self, target: ServerId) -> Result<()> {
+        scoped_trace!("{:?}: transfer leadership to {}", self, target);
+        let mut message = messages::leader_transfer_request(target, &self.lid);
+        self.send_message(&mut message).map(|_| ())
+    }
+
+    /// Sends the request to the leader, and returns the data of the response.
     fn send_message<A>(&mut self, message: &mut Builder<A>) -> Result<Vec<u8>>
         where A: Allocator
+    {
+        match try!(self.exchange(message)) {
+            Response::Data(data) => Ok(data),
+            Response::TransactionStatus(_) => panic!("Unexpected message type"),
+        }
+    }
+
+    /// Sends the request to the leader, following redirects, and returns the response.
+    fn exchange<A>(&mut self, message: &mut Builder<A>) -> Result<Response>
+        where A: Allocator
     {
         let mut members = self.cluster.iter().cloned();
+        // The log reported unknown by a server, if any, in case no other server hosts it either.
+        let mut unknown_log = None;
 
         loop {
             // We presume in this loop that most errors are temporary and it may take a redirect
@@ -124,7 +300,9 @@ impl Client {
                     cxn
                 }
                 None => {
-                    let leader = try!(members.next().ok_or(RaftError::LeaderSearchExhausted));
+                    let leader = try!(members.next().ok_or_else(|| {
+                        unknown_log.map_or(RaftError::LeaderSearchExhausted, RaftError::UnknownLog)
+                    }));
                     scoped_debug!("connecting to potential leader {}", leader);
                     // Send the preamble.
                     let preamble = messages::client_connection_preamble(self.id,
@@ -162,13 +340,18 @@ impl Client {
                         Ok(command_response::Which::Success(data)) => {
                             scoped_debug!("received response Success");
                             self.leader_connection = Some(connection);
-                            return data.map(Vec::from)
+                            return data.map(|data| Response::Data(data.to_vec()))
                                 .map_err(|e| e.into()); // Exit the function.
                         }
                         Ok(command_response::Which::UnknownLeader(())) => {
                             scoped_debug!("received response UnknownLeader");
                             () // Keep looping.
                         }
+                        Ok(command_response::Which::UnknownLog(())) => {
+                            // The log may be placed on other servers.
+                            scoped_debug!("received response UnknownLog");
+                            unknown_log = Some(response_log_id(&reader, self.lid));
+                        }
                         Ok(command_response::Which::NotLeader(leader)) => {
                             scoped_debug!("received response NotLeader");
                             let leader_str = try!(leader);
@@ -203,7 +386,7 @@ impl Client {
                         Ok(command_response::Which::Success(data)) => {
                             scoped_debug!("received response Success");
                             self.leader_connection = Some(connection);
-                            return data.map(Vec::from)
+                            return data.map(|data| Response::Data(data.to_vec()))
                                 .map_err(|e| e.into()); // Exit the function.
                         }
                         Ok(command_response::Which::Failure(data)) => {
@@ -214,6 +397,11 @@ impl Client {
                             scoped_debug!("received response UnknownLeader");
                             () // Keep looping.
                         }
+                        Ok(command_response::Which::UnknownLog(())) => {
+                            // The log may be placed on other servers.
+                            scoped_debug!("received response UnknownLog");
+                            unknown_log = Some(response_log_id(&reader, self.lid));
+                        }
                         Ok(command_response::Which::NotLeader(leader)) => {
                             scoped_debug!("received response NotLeader");
                             let leader_str = try!(leader);
@@ -236,12 +424,54 @@ impl Client {
                         Err(_) => continue,
                     }
                 }
+                Ok(client_response::Which::TransactionStatus(Ok(response))) => {
+                    scoped_debug!("received response TransactionStatus");
+                    self.leader_connection = Some(connection);
+                    let status = match try!(response.which()) {
+                        transaction_status_response::Which::Status(status) => {
+                            let state = match try!(status.get_state()) {
+                                TransactionState::Active => transaction::TransactionState::Active,
+                                TransactionState::Prepared => {
+                                    transaction::TransactionState::Prepared
+                                }
+                                TransactionState::Committed => {
+                                    transaction::TransactionState::Committed
+                                }
+                                TransactionState::RolledBack => {
+                                    transaction::TransactionState::RolledBack
+                                }
+                            };
+                            Some(transaction::TransactionStatus {
+                                state: state,
+                                commands: status.get_commands(),
+                                begin_index: LogIndex::from(status.get_begin_index()),
+                            })
+                        }
+                        transaction_status_response::Which::Unknown(()) => None,
+                    };
+                    return Ok(Response::TransactionStatus(status));
+                }
                 _ => panic!("Unexpected message type"), // TODO: return a proper error
             };
         }
     }
 }
 
+/// Returns the log named by the response, or `default` if the response names no valid log.
+fn response_log_id(response: &client_response::Reader, default: LogId) -> LogId {
+    response.get_log_id()
+        .ok()
+        .and_then(|bytes| Uuid::from_bytes(bytes).ok())
+        .map(LogId)
+        .unwrap_or(default)
+}
+
+/// A response of the leader to a client request.
+enum Response {
+    Data(Vec<u8>),
+    TransactionStatus(Option<transaction::TransactionStatus>),
+}
+
 impl fmt::Debug for Client {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         write!(fmt, "Client({})", self.id)
@@ -0,0 +1,249 @@
+//! Tunable parameters of a `Server` and of the logs it serves.
+//!
+//! The defaults suit a cluster on a local network with a few seconds of tolerance for slow
+//! peers. Each log may override the defaults, for example to elect leaders faster on a log which
+//! only spans a data center.
+
+use std::collections::HashMap;
+
+use LogId;
+use RaftError;
+use ServerId;
+
+/// Parameters of the consensus of a single log. Durations are in milliseconds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Lower bound of the randomized election timeout. Also bounds leader leases, the quorum
+    /// check, leadership transfers and the queries forwarded by followers.
+    pub election_timeout_min: u64,
+    /// Upper bound (exclusive) of the randomized election timeout.
+    pub election_timeout_max: u64,
+    /// Interval between heartbeats to an idle follower.
+    pub heartbeat_interval: u64,
+    /// Maximum number of entries sent in a single AppendEntries request.
+    pub max_append_entries: u64,
+    /// Maximum total size of the entries sent in a single AppendEntries request, in bytes. A
+    /// larger entry is sent on its own.
+    pub max_append_entries_bytes: u64,
+    /// Maximum number of unacknowledged AppendEntries requests carrying entries to a follower
+    /// which is known to be consistent with the leader.
+    pub max_inflight_append_entries: u64,
+    /// Number of applied entries retained in the log before it is compacted into a snapshot.
+    pub snapshot_threshold: u64,
+    /// Number of entries a learner may lag behind the leader's log when it is promoted.
+    pub learner_max_lag: u64,
+    /// Time a transaction may go without a request from its client before the leader rolls it
+    /// back.
+    pub transaction_timeout: u64,
+    /// Number of servers replicating a log created at runtime, chosen by `placement::place()`
+    /// when the log is created and recorded in the metadata log. Every server replicates the
+    /// log if `None`, as well as every log the servers start with. A log limited to a number of
+    /// replicas cannot coordinate transactions spanning several logs, since the leaders of the
+    /// other logs resolve in-doubt transactions from their local replica of it.
+    pub replicas: Option<usize>,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            election_timeout_min: 5000,
+            election_timeout_max: 10000,
+            heartbeat_interval: 2000,
+            max_append_entries: 4096,
+            max_append_entries_bytes: 1 << 20,
+            max_inflight_append_entries: 8,
+            snapshot_threshold: 4096,
+            learner_max_lag: 128,
+            transaction_timeout: 60000,
+            replicas: None,
+        }
+    }
+}
+
+impl LogConfig {
+    /// Checks that the parameters are consistent with each other.
+    pub fn validate(&self) -> Result<(), RaftError> {
+        self.check().map_err(|reason| RaftError::InvalidConfig(reason.to_string()))
+    }
+
+    fn check(&self) -> Result<(), &'static str> {
+        if self.election_timeout_min == 0 {
+            return Err("the minimum election timeout must be positive");
+        }
+        if self.election_timeout_max <= self.election_timeout_min {
+            return Err("the maximum election timeout must exceed the minimum");
+        }
+        if self.heartbeat_interval == 0 ||
+           self.heartbeat_interval >= self.election_timeout_min {
+            return Err("the heartbeat interval must be positive and shorter than the minimum \
+                        election timeout");
+        }
+        if self.max_append_entries == 0 {
+            return Err("the maximum number of entries per AppendEntries must be positive");
+        }
+        if self.max_append_entries_bytes == 0 {
+            return Err("the maximum size of an AppendEntries must be positive");
+        }
+        if self.max_inflight_append_entries == 0 {
+            return Err("the maximum number of in-flight AppendEntries must be positive");
+        }
+        if self.snapshot_threshold == 0 {
+            return Err("the snapshot threshold must be positive");
+        }
+        if self.transaction_timeout == 0 {
+            return Err("the transaction timeout must be positive");
+        }
+        if self.replicas == Some(0) {
+            return Err("the number of replicas must be positive");
+        }
+        Ok(())
+    }
+
+    /// Returns how long a leader may serve lease reads after the start of a heartbeat round
+    /// acknowledged by a majority. A tenth of the minimum election timeout is set aside for
+    /// clock drift.
+    pub fn lease_duration(&self) -> u64 {
+        self.election_timeout_min - self.election_timeout_min / 10
+    }
+}
+
+/// Parameters of a `Server`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Maximum number of simultaneous peer and client connections.
+    pub max_connections: usize,
+    /// Initial delay before reconnecting to a peer, in milliseconds. The delay doubles with
+    /// every failed attempt.
+    pub reconnect_backoff_min: u32,
+    /// Maximum delay before reconnecting to a peer, in milliseconds.
+    pub reconnect_backoff_max: u32,
+    /// Parameters of the logs without an override.
+    pub log: LogConfig,
+    /// Parameters of individual logs.
+    pub log_overrides: HashMap<LogId, LogConfig>,
+    /// The log recording the logs created and dropped at runtime. Every server has to start
+    /// with it among its logs. Without a metadata log, the set of logs is fixed.
+    pub metadata_log: Option<LogId>,
+    /// Interval between rounds of leader balancing, in milliseconds. Each round, the server
+    /// transfers the leadership of the logs it leads beyond its share to less loaded replicas.
+    /// Leaders are not balanced if `None`.
+    pub balance_interval: Option<u64>,
+    /// Weights of the servers in leader balancing. A server leads a share of the logs
+    /// proportional to its weight; servers without a weight have a weight of 1, and a server
+    /// with a weight of 0 hands off every leadership.
+    pub leader_weights: HashMap<ServerId, u64>,
+    /// Interval between coalesced heartbeats, in milliseconds. If set, the heartbeats of all
+    /// logs the server leads are sent to each peer in one message per interval, replacing the
+    /// heartbeats of the individual logs; it must then be shorter than the minimum election
+    /// timeout of every log.
+    pub heartbeat_interval: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            max_connections: 129,
+            reconnect_backoff_min: 50,
+            reconnect_backoff_max: 10000,
+            log: LogConfig::default(),
+            log_overrides: HashMap::new(),
+            metadata_log: None,
+            balance_interval: None,
+            leader_weights: HashMap::new(),
+            heartbeat_interval: None,
+        }
+    }
+}
+
+impl Config {
+    /// Returns the parameters of the log `lid`.
+    pub fn log_config(&self, lid: &LogId) -> &LogConfig {
+        self.log_overrides.get(lid).unwrap_or(&self.log)
+    }
+
+    /// Returns the weight of the server `id` in leader balancing.
+    pub fn leader_weight(&self, id: &ServerId) -> u64 {
+        self.leader_weights.get(id).cloned().unwrap_or(1)
+    }
+
+    /// Checks the server parameters and the parameters of every log.
+    pub fn validate(&self) -> Result<(), RaftError> {
+        if self.max_connections == 0 {
+            return Err(RaftError::InvalidConfig("the maximum number of connections must be \
+                                                 positive"
+                .to_string()));
+        }
+        if self.reconnect_backoff_min == 0 ||
+           self.reconnect_backoff_max < self.reconnect_backoff_min {
+            return Err(RaftError::InvalidConfig("the reconnect backoff range must be positive \
+                                                 and not empty"
+                .to_string()));
+        }
+        if self.balance_interval == Some(0) {
+            return Err(RaftError::InvalidConfig("the balance interval must be positive"
+                .to_string()));
+        }
+        try!(self.log.validate());
+        for (lid, config) in &self.log_overrides {
+            try!(config.check()
+                .map_err(|reason| RaftError::InvalidConfig(format!("log {:?}: {}", lid, reason))));
+        }
+        if let Some(interval) = self.heartbeat_interval {
+            let election_timeout_min = self.log_overrides
+                .values()
+                .chain(Some(&self.log))
+                .map(|config| config.election_timeout_min)
+                .min()
+                .unwrap();
+            if interval == 0 || interval >= election_timeout_min {
+                return Err(RaftError::InvalidConfig("the coalesced heartbeat interval must be \
+                                                     positive and shorter than the minimum \
+                                                     election timeout of every log"
+                    .to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use LogId;
+    use config::{Config, LogConfig};
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+
+        config.reconnect_backoff_max = 0;
+        assert!(config.validate().is_err());
+        config.reconnect_backoff_max = 10000;
+
+        // A LAN deployment with fast elections.
+        let lid = LogId(Uuid::new_v4());
+        let lan = LogConfig {
+            election_timeout_min: 150,
+            election_timeout_max: 300,
+            heartbeat_interval: 50,
+            ..LogConfig::default()
+        };
+        config.log_overrides.insert(lid, lan.clone());
+        assert!(config.validate().is_ok());
+        assert_eq!(&lan, config.log_config(&lid));
+        assert_eq!(&config.log, config.log_config(&LogId(Uuid::new_v4())));
+
+        // Heartbeats must be more frequent than elections.
+        config.log_overrides.get_mut(&lid).unwrap().heartbeat_interval = 150;
+        assert!(config.validate().is_err());
+        config.log_overrides.get_mut(&lid).unwrap().heartbeat_interval = 50;
+
+        // Coalesced heartbeats as well, on every log.
+        config.heartbeat_interval = Some(100);
+        assert!(config.validate().is_ok());
+        config.heartbeat_interval = Some(200);
+        assert!(config.validate().is_err());
+    }
+}
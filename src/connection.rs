@@ -13,6 +13,7 @@ use ClientId;
 use Result;
 use ServerId;
 use backoff::Backoff;
+use config::Config;
 use messages;
 use server::{Server, ServerTimeout};
 use state_machine::StateMachine;
@@ -61,24 +62,26 @@ impl Connection {
     ///
     /// Note: the caller must manually set the token field after inserting the
     /// connection into a slab.
-    pub fn unknown(socket: TcpStream) -> Result<Connection> {
+    pub fn unknown(socket: TcpStream, config: &Config) -> Result<Connection> {
         let addr = try!(socket.peer_addr());
         Ok(Connection {
             kind: ConnectionKind::Unknown,
             addr: addr,
             stream: Some(MessageStream::new(socket, ReaderOptions::new())),
-            backoff: Backoff::with_duration_range(50, 10000),
+            backoff: Backoff::with_duration_range(config.reconnect_backoff_min,
+                                                  config.reconnect_backoff_max),
         })
     }
 
     /// Creates a new peer connection.
-    pub fn peer(id: ServerId, addr: SocketAddr) -> Result<Connection> {
+    pub fn peer(id: ServerId, addr: SocketAddr, config: &Config) -> Result<Connection> {
         let stream = try!(TcpStream::connect(&addr));
         Ok(Connection {
             kind: ConnectionKind::Peer(id),
             addr: addr,
             stream: Some(MessageStream::new(stream, ReaderOptions::new())),
-            backoff: Backoff::with_duration_range(50, 10000),
+            backoff: Backoff::with_duration_range(config.reconnect_backoff_min,
+                                                  config.reconnect_backoff_max),
         })
     }
 
@@ -4,42 +4,94 @@
 //!
 //! ```text
 //! Event = AppendEntriesRequest | AppendEntriesResponse
+//!       | PreVoteRequest       | PreVoteResponse
 //!       | RequestVoteRequest   | RequestVoteResponse
-//!       | ElectionTimeout      | HeartbeatTimeout
-//!       | ClientProposal       | ClientQuery
+//!       | InstallSnapshotRequest | InstallSnapshotResponse
+//!       | TimeoutNow           | ReadIndexRequest     | ReadIndexResponse
+//!       | ElectionTimeout      | HeartbeatTimeout     | TransferTimeout
+//!       | CheckQuorumTimeout   | Heartbeat            | HeartbeatResponse
+//!       | ClientProposal       | ClientQuery          | ClientLeaderTransfer
 //! ```
 //!
 //! In response to an event, the `Consensus` may mutate its own state, apply a command to the local
 //! `StateMachine`, or return an event to be sent to one or more remote peers or clients.
+//!
+//! Elections are preceded by a pre-vote round (section 9.6 of the Raft dissertation): a node whose
+//! election timeout fires only increments its term once a majority of the cluster confirms that it
+//! would vote for it. A node returning from a partition therefore can not force a healthy leader
+//! to step down.
+//!
+//! A new leader starts its term by appending a no-op entry. Only entries of the leader's own term
+//! are committed by counting replicas (section 5.4.2 of the Raft paper); the entries of earlier
+//! terms are committed along with them.
+//!
+//! Proposed entries are not sent right away: the `Server` flushes its logs once per turn of the
+//! event loop, so the proposals received in one turn travel in one AppendEntries request. The
+//! leader tracks the progress of each follower. While it probes for the point where the logs
+//! diverge, one request is in flight at a time; once the logs match, requests are pipelined up
+//! to a configured limit.
+//!
+//! Transactions are delimited by entries in the log, so every replica begins, commits and rolls
+//! back a transaction at the same point of the log, and a transaction outlives the leader which
+//! began it. Any number of transactions may run at once: the commands proposed within a
+//! transaction are buffered by every replica until the transaction commits, and then applied
+//! together unless they conflict with a command committed in the meantime (see the `transaction`
+//! module). Proposals outside of transactions are applied as usual. A transaction spanning
+//! several logs is prepared on each of them and decided in a coordinator log; the leader of a log
+//! holding a prepared transaction which is not ended in time asks the `LogManager` to resolve it
+//! from the coordinator log.
+//!
+//! Leadership can be handed to a chosen peer: the leader stops accepting proposals, brings the
+//! peer's log up to date, and sends it a `TimeoutNow` message, upon which the peer starts an
+//! election without waiting for its election timeout.
+//!
+//! Queries are linearizable: the leader records its commit index when a query arrives, confirms
+//! with a round of heartbeats that it is still the leader, and answers the query once the state
+//! machine has applied the recorded index. Followers ask the leader for such a read index.
+//! Clients may instead ask for a lease read, which the leader answers on its own while a majority
+//! has acknowledged one of its heartbeats within the lease duration, or for a stale read from any
+//! server. The heartbeats also serve as a quorum check: a leader which has not heard from a
+//! majority for an election timeout steps down.
+//!
+//! A server leading many logs may coalesce their heartbeats: instead of scheduling a heartbeat
+//! timeout per follower, the `Consensus` hands the `Server` a `Heartbeat` per follower once per
+//! heartbeat interval, and the `Server` bundles the heartbeats for each peer into one message.
+//! A follower treats a `Heartbeat` like an empty AppendEntries request: it checks that its log
+//! holds the latest entry sent to it, and the leader probes a follower which misses entries.
+//!
+//! The members of the cluster are recorded in the log as configuration entries (see the
+//! `membership` module), so membership changes are replicated like any other entry.
 
-use std::{cmp, fmt};
-use std::collections::HashMap;
+use std::{cmp, fmt, mem};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
-use capnp::message::{Builder, Allocator, ReaderOptions, HeapAllocator, Reader};
+use capnp::message::{Builder, HeapAllocator};
 use rand::{self, Rng};
-use capnp::serialize::{self, OwnedSegments};
-use std::io::Cursor;
+use uuid::Uuid;
 
-use {LogId, LogIndex, Term, ServerId, ClientId, messages, TransactionId};
+use {LogId, LogIndex, Term, ServerId, ClientId, messages, TransactionId, RaftError};
 use messages_capnp::{append_entries_request, append_entries_response, client_request,
-                     proposal_request, query_request, message, request_vote_request,
-                     request_vote_response};
-use state::{ConsensusState, LeaderState, CandidateState, FollowerState};
+                     install_snapshot_request, install_snapshot_response, pre_vote_request,
+                     pre_vote_response, proposal_request, query_request, message,
+                     read_index_request, read_index_response, request_vote_request,
+                     request_vote_response, timeout_now, transaction_abort, ReadConsistency};
+use state::{ConsensusState, LeaderState, CandidateState, FollowerState, ProgressMode,
+            ReadRequest, ReadSource};
 use state_machine::StateMachine;
-use transaction::TransactionManager;
+use transaction::{TransactionError, TransactionManager};
 use persistent_log::Log;
+use snapshot::Snapshot;
+use session::{ProposalId, Sessions};
+use entry::Payload;
+use membership::Configuration;
+use config::LogConfig;
 use mio::Timeout as TimeoutHandle;
 
 use std::sync::{Arc, RwLock};
 
-use transaction;
-
-const ELECTION_MIN: u64 = 5000;
-const ELECTION_MAX: u64 = 10000;
-const HEARTBEAT_DURATION: u64 = 2000;
-
 /// Consensus timeout types.
 // TODO Remove LogId, because not neccessary
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -48,20 +100,75 @@ pub enum ConsensusTimeout {
     Election(LogId),
     // A heartbeat timeout. Stable value.
     Heartbeat(ServerId, LogId),
+    // Abandons a leadership transfer which has not completed. Stable value.
+    Transfer(LogId),
+    // Makes the leader step down if it has lost contact with the majority. Stable value.
+    CheckQuorum(LogId),
+    // Rolls back a transaction which has gone without a request for too long. Stable value.
+    Transaction(TransactionId, LogId),
+    // Fails a query forwarded to the leader if the leader has not answered it. Stable value.
+    Read(u64, LogId),
 }
 
 impl ConsensusTimeout {
     /// Returns the timeout period in milliseconds.
-    pub fn duration_ms(&self) -> u64 {
+    pub fn duration_ms(&self, config: &LogConfig) -> u64 {
         match *self {
             ConsensusTimeout::Election(..) => {
-                rand::thread_rng().gen_range::<u64>(ELECTION_MIN, ELECTION_MAX)
+                rand::thread_rng()
+                    .gen_range::<u64>(config.election_timeout_min, config.election_timeout_max)
             }
-            ConsensusTimeout::Heartbeat(..) => HEARTBEAT_DURATION,
+            ConsensusTimeout::Heartbeat(..) => config.heartbeat_interval,
+            ConsensusTimeout::Transfer(..) |
+            ConsensusTimeout::CheckQuorum(..) |
+            ConsensusTimeout::Read(..) => config.election_timeout_min,
+            ConsensusTimeout::Transaction(..) => config.transaction_timeout,
         }
     }
 }
 
+/// The equivalent of an empty AppendEntries request, sent to a follower as part of a
+/// `MultiHeartbeat` message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// The leader's term.
+    pub term: Term,
+    /// Index of the latest entry sent to the follower, which the follower's log has to hold.
+    pub prev_log_index: LogIndex,
+    /// Term of the entry at `prev_log_index`.
+    pub prev_log_term: Term,
+    /// The leader's commit index.
+    pub commit_index: LogIndex,
+    /// The heartbeat round the heartbeat was sent in.
+    pub round: u64,
+}
+
+/// The answer to a `Heartbeat`, sent to the leader as part of a `MultiHeartbeatResponse`
+/// message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HeartbeatResponse {
+    /// The follower's current term.
+    pub term: Term,
+    /// The round of the answered heartbeat.
+    pub round: u64,
+    /// The outcome of the consistency check.
+    pub result: HeartbeatResult,
+}
+
+/// The outcome of applying a `Heartbeat`, as for an AppendEntries request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeartbeatResult {
+    /// The follower's log holds the previous entry. Carries the follower's latest log index.
+    Success(LogIndex),
+    /// The follower's log does not hold the previous entry. Carries the term of the follower's
+    /// entry at the previous index (0 if its log ends before) and the index of the follower's
+    /// first entry of that term, as in an AppendEntries response, and the follower's latest log
+    /// index.
+    InconsistentPrevEntry(Term, LogIndex, LogIndex),
+    /// The heartbeat is of an earlier term than the follower's.
+    StaleTerm,
+}
+
 /// A set of actions for the `Server` to carry out asyncronously in response to applying an event
 /// to a `Consensus` state machine.
 pub struct Actions {
@@ -69,16 +176,12 @@ pub struct Actions {
     pub peer_messages: Vec<(ServerId, Rc<Builder<HeapAllocator>>)>,
     /// Messages to be send to clients.
     pub client_messages: Vec<(ClientId, Rc<Builder<HeapAllocator>>)>,
-    /// Whether to clear existing consensus timeouts.
+    /// The logs whose consensus timeouts to clear, except the timeouts of forwarded reads.
     pub clear_timeouts: Vec<LogId>,
     /// Any new timeouts to create.
     pub timeouts: Vec<ConsensusTimeout>,
     /// Whether to clear outbound peer message queues.
     pub clear_peer_messages: bool,
-    /// Messages which are in queue because there is a transaction active
-    pub transaction_queue: Vec<(LogId, ClientId, Builder<HeapAllocator>)>,
-    /// Messages to be send to all peers in the cluster
-    pub peer_messages_broadcast: Vec<Rc<Builder<HeapAllocator>>>,
 }
 
 impl fmt::Debug for Actions {
@@ -91,19 +194,14 @@ impl fmt::Debug for Actions {
             .iter()
             .map(|client_message| client_message.0)
             .collect();
-        let transaction_messages: Vec<ClientId> = self.transaction_queue
-            .iter()
-            .map(|transaction_message| transaction_message.1)
-            .collect();
         write!(fmt,
                "Actions {{ peer_messages: {:?}, client_messages: {:?}, clear_timeouts: {:?}, \
-                timeouts: {:?}, clear_peer_messages: {}, transaction_queue: {:?} }}",
+                timeouts: {:?}, clear_peer_messages: {} }}",
                peer_messages,
                client_messages,
                self.clear_timeouts,
                self.timeouts,
-               self.clear_peer_messages,
-               transaction_messages)
+               self.clear_peer_messages)
     }
 }
 
@@ -116,8 +214,6 @@ impl Actions {
             clear_timeouts: Vec::with_capacity(1),
             timeouts: vec![],
             clear_peer_messages: false,
-            transaction_queue: vec![],
-            peer_messages_broadcast: vec![],
         }
     }
 }
@@ -127,8 +223,17 @@ impl Actions {
 pub struct Consensus<L, M> {
     /// The ID of this consensus instance.
     id: ServerId,
-    /// The IDs of peers in the consensus group.
+    /// The addresses of the known peers. Only the members of `configuration` take part in the
+    /// consensus.
     pub peers: HashMap<ServerId, SocketAddr>,
+    /// The latest configuration in the log, which is in effect whether or not it is committed.
+    configuration: Configuration,
+    /// Index of the entry holding `configuration`, or of the snapshot covering it. 0 if the
+    /// log does not record a configuration.
+    configuration_index: LogIndex,
+    /// The configuration in effect while the log does not record one: this consensus and its
+    /// initial peers.
+    initial_configuration: Configuration,
 
     /// The persistent log.
     pub log: L,
@@ -139,6 +244,8 @@ pub struct Consensus<L, M> {
     commit_index: LogIndex,
     /// Index of the latest entry applied to the state machine.
     last_applied: LogIndex,
+    /// The latest proposal applied for each client, as of `last_applied`.
+    sessions: Sessions,
 
     /// The current state of the `Consensus` (`Leader`, `Candidate`, or `Follower`).
     pub state: ConsensusState,
@@ -148,11 +255,26 @@ pub struct Consensus<L, M> {
     pub candidate_state: Arc<RwLock<CandidateState>>,
     /// State necessary while a `Follower`. Should not be used otherwise.
     pub follower_state: Arc<RwLock<FollowerState>>,
-    pub transaction: TransactionManager,
+    /// The transactions running as of the last applied entry.
+    pub transactions: TransactionManager,
+    /// The logs created at runtime as of the last applied entry, if this is the metadata log,
+    /// with the replicas they are placed on.
+    pub logs: HashMap<LogId, Option<Vec<ServerId>>>,
     /// The ID of this consensus instance for the log_manager
     lid: LogId,
     /// Currently registered consensus timeouts.
     pub consensus_timeouts: HashMap<ConsensusTimeout, TimeoutHandle>,
+    /// Timing and sizing parameters.
+    config: LogConfig,
+    /// When this consensus last heard from a leader of the current term.
+    leader_contact: Option<Instant>,
+    /// Whether entries were appended since the last `flush()`.
+    unflushed: bool,
+    /// The replica which should lead the log, if the log is placed on a subset of the servers.
+    preferred_leader: Option<ServerId>,
+    /// Whether the server sends the heartbeats of this log bundled with those of other logs,
+    /// instead of this consensus scheduling heartbeat timeouts.
+    coalesced_heartbeats: bool,
 }
 
 impl<L, M> Consensus<L, M>
@@ -164,59 +286,112 @@ impl<L, M> Consensus<L, M>
                lid: LogId,
                peers: HashMap<ServerId, SocketAddr>,
                log: L,
-               state_machine: M)
+               state_machine: M,
+               config: LogConfig)
                -> Consensus<L, M> {
         let leader_state = LeaderState::new(log.latest_log_index().unwrap(),
                                             &peers.keys().cloned().collect());
-        Consensus {
+
+        // Entries covered by the snapshot are committed, and must not be applied again.
+        let mut state_machine = state_machine;
+        let (snapshot_index, sessions, transactions, logs) = match log.snapshot().unwrap() {
+            Some((index, _, data)) => {
+                let snapshot = Snapshot::from_bytes(data).expect("unable to decode snapshot");
+                let (map, entries) = snapshot.state_machine;
+                state_machine.restore_snapshot(map, entries);
+                (index, snapshot.sessions, snapshot.transactions, snapshot.logs)
+            }
+            None => (LogIndex(0), Sessions::new(), TransactionManager::new(), HashMap::new()),
+        };
+
+        let mut members: HashSet<ServerId> = peers.keys().cloned().collect();
+        members.insert(id);
+        let initial_configuration = Configuration::new(members);
+
+        let mut consensus = Consensus {
             id: id,
             peers: peers,
+            configuration: initial_configuration.clone(),
+            configuration_index: LogIndex(0),
+            initial_configuration: initial_configuration,
             log: log,
             state_machine: Arc::new(RwLock::new(state_machine)),
-            commit_index: LogIndex(0),
-            last_applied: LogIndex(0),
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            sessions: sessions,
             state: ConsensusState::Follower,
             leader_state: Arc::new(RwLock::new(leader_state)),
             candidate_state: Arc::new(RwLock::new(CandidateState::new())),
             follower_state: Arc::new(RwLock::new(FollowerState::new())),
-            transaction: TransactionManager::new(),
+            transactions: transactions,
+            logs: logs,
             lid: lid,
             consensus_timeouts: HashMap::new(),
-        }
+            config: config,
+            leader_contact: None,
+            unflushed: false,
+            preferred_leader: None,
+            coalesced_heartbeats: false,
+        };
+        consensus.reload_configuration();
+        consensus
     }
 
-    /// Returns the consenus peers.
-    pub fn peers(&self) -> &HashMap<ServerId, SocketAddr> {
-        &self.peers
+    /// Returns the timing and sizing parameters.
+    pub fn config(&self) -> &LogConfig {
+        &self.config
     }
 
-    /// If a transaction is inactive, method processes client messages
-    pub fn handle_queue(&mut self,
-                        requests_in_queue: &mut Vec<(ClientId, Builder<HeapAllocator>)>,
-                        actions: &mut Actions)
-                        -> Result<(), ()> {
-        if !self.transaction.is_active {
-            for (client, builder) in requests_in_queue.pop() {
-                self.apply_client_message(client,
-                                          &Self::into_reader(&builder)
-                                              .get_root::<client_request::Reader>()
-                                              .unwrap(),
-                                          actions);
-            }
+    /// Sets the servers replicating the log while it does not record a configuration, in place
+    /// of this consensus and its initial peers.
+    pub fn set_initial_members(&mut self, members: HashSet<ServerId>) {
+        self.initial_configuration = Configuration::new(members);
+        self.reload_configuration();
+    }
 
-            Ok(())
-        } else {
-            Ok(())
+    /// Sets the replica which should lead the log. Elections favour it from then on.
+    pub fn set_preferred_leader(&mut self, preferred_leader: Option<ServerId>) {
+        self.preferred_leader = preferred_leader;
+    }
+
+    /// Sets whether the server sends the heartbeats of this log, gathered with
+    /// `coalesced_heartbeats()`. No heartbeat timeouts are scheduled from then on.
+    pub fn set_coalesced_heartbeats(&mut self, coalesced_heartbeats: bool) {
+        self.coalesced_heartbeats = coalesced_heartbeats;
+    }
+
+    /// Returns the period of the timeout in milliseconds. If the log has a preferred leader, its
+    /// election timeout is drawn from the lower half of the range and those of the other
+    /// replicas from the upper half, so that the preferred leader usually wins the election.
+    pub fn timeout_duration_ms(&self, timeout: &ConsensusTimeout) -> u64 {
+        let (min, max) = (self.config.election_timeout_min, self.config.election_timeout_max);
+        let middle = min + (max - min) / 2;
+        match (*timeout, self.preferred_leader) {
+            (ConsensusTimeout::Election(..), Some(preferred)) if middle > min => {
+                if preferred == self.id {
+                    rand::thread_rng().gen_range::<u64>(min, middle)
+                } else {
+                    rand::thread_rng().gen_range::<u64>(middle, max)
+                }
+            }
+            _ => timeout.duration_ms(&self.config),
         }
     }
 
-    /// Applies a peer message to the consensus state machine.
+    /// Returns the consenus peers.
+    pub fn peers(&self) -> &HashMap<ServerId, SocketAddr> {
+        &self.peers
+    }
+
+    /// Applies a peer message to the consensus state machine. Fails if the message is malformed
+    /// or of a kind the consensus does not handle.
     pub fn apply_peer_message(&mut self,
                               from: ServerId,
                               message: &message::Reader,
-                              actions: &mut Actions) {
+                              actions: &mut Actions)
+                              -> ::Result<()> {
         push_log_scope!("{:?}", self);
-        let reader = message.which().unwrap();
+        let reader = try!(message.which());
         match reader {
             message::Which::AppendEntriesRequest(Ok(request)) => {
                 self.append_entries_request(from, request, actions)
@@ -224,100 +399,153 @@ impl<L, M> Consensus<L, M>
             message::Which::AppendEntriesResponse(Ok(response)) => {
                 self.append_entries_response(from, response, actions)
             }
+            message::Which::PreVoteRequest(Ok(request)) => {
+                self.pre_vote_request(from, request, actions)
+            }
+            message::Which::PreVoteResponse(Ok(response)) => {
+                self.pre_vote_response(from, response, actions)
+            }
             message::Which::RequestVoteRequest(Ok(request)) => {
                 self.request_vote_request(from, request, actions)
             }
             message::Which::RequestVoteResponse(Ok(response)) => {
                 self.request_vote_response(from, response, actions)
             }
-            message::Which::TransactionBegin(Ok(response)) => {
-                // TODO do not panic if invalid
-                self.transaction_begin(from,
-                                       TransactionId::from_bytes(response.get_session().unwrap())
-                                           .expect("Invalid TransactionId"),
-                                       actions)
+            message::Which::TimeoutNow(Ok(request)) => self.timeout_now(from, request, actions),
+            message::Which::ReadIndexRequest(Ok(request)) => {
+                self.read_index_request(from, request, actions)
             }
-            message::Which::TransactionCommit(Ok(message)) => {
-                // TODO do not panic if invalid
-                self.transaction_commit(from,
-                                        TransactionId::from_bytes(message.get_session().unwrap())
-                                            .expect("Invalid TransactionId"),
-                                        actions)
+            message::Which::ReadIndexResponse(Ok(response)) => {
+                self.read_index_response(from, response, actions)
             }
-            message::Which::TransactionRollback(Ok(message)) => {
-                scoped_debug!("Rollback");
-
-                // TODO do not panic if invalid
-                self.transaction_rollback(from,
-                                          TransactionId::from_bytes(message.get_session().unwrap())
-                                              .expect("Invalid TransactionId"),
-                                          actions)
-
+            message::Which::InstallSnapshotRequest(Ok(request)) => {
+                self.install_snapshot_request(from, request, actions)
+            }
+            message::Which::InstallSnapshotResponse(Ok(response)) => {
+                self.install_snapshot_response(from, response, actions)
+            }
+            message::Which::TransactionAbort(Ok(request)) => {
+                self.transaction_abort_request(from, request, actions)
+            }
+            message::Which::TransactionBegin(..) |
+            message::Which::TransactionCommit(..) |
+            message::Which::TransactionRollback(..) => {
+                // Transaction boundaries are replicated as log entries.
+                scoped_warn!("ignoring transaction message from peer {}", from);
+            }
+            _ => {
+                let error = format!("cannot handle message from peer {}", from);
+                return Err(::Error::Raft(RaftError::Other(error)));
             }
-            _ => panic!("cannot handle message"),
         }
+        Ok(())
     }
 
-    /// Applies a client message to the consensus state machine.
+    /// Applies a client message to the consensus state machine. Fails if the message is
+    /// malformed or of a kind the consensus does not handle.
     pub fn apply_client_message(&mut self,
                                 from: ClientId,
                                 message: &client_request::Reader,
-                                actions: &mut Actions) {
+                                actions: &mut Actions)
+                                -> ::Result<()> {
 
         push_log_scope!("{:?}", self);
-        let reader = message.which().unwrap();
+        let reader = try!(message.which());
 
         match reader {
             client_request::Which::Proposal(Ok(request)) => {
-                if self.is_leader() {
-                    let session = TransactionId::from_bytes(request.get_session().unwrap())
-                        .expect("Invalid TransactionId");
-
-                    if self.transaction.is_active &&
-                       !self.transaction
-                        .compare(session) {
-
-                        let entry = request.get_entry().unwrap();
-
-                        let message = messages::proposal_request(session, entry, self.lid);
-
-                        actions.transaction_queue.push((self.lid, from, message));
-
-                        self.transaction.count_up();
-                    } else {
-                        self.proposal_request(from, request, actions)
+                self.proposal_request(from, request, actions)
+            }
+            client_request::Which::Query(Ok(query)) => self.query_request(from, query, actions),
+            client_request::Which::TransactionBegin(Ok(request)) => {
+                match parse_session(request.get_session()) {
+                    Some(session) => self.client_transaction_begin(from, session, actions),
+                    None => self.reject_malformed_transaction(from, actions),
+                }
+            }
+            client_request::Which::TransactionCommit(Ok(request)) => {
+                match parse_session(request.get_session()) {
+                    Some(session) => self.client_transaction_commit(from, session, actions),
+                    None => self.reject_malformed_transaction(from, actions),
+                }
+            }
+            client_request::Which::TransactionRollback(Ok(request)) => {
+                match parse_session(request.get_session()) {
+                    Some(session) => self.client_transaction_rollback(from, session, actions),
+                    None => self.reject_malformed_transaction(from, actions),
+                }
+            }
+            client_request::Which::TransactionPrepare(Ok(request)) => {
+                let coordinator = request.get_coordinator()
+                    .ok()
+                    .and_then(|coordinator| Uuid::from_bytes(coordinator).ok())
+                    .map(LogId);
+                match (parse_session(request.get_session()), coordinator) {
+                    (Some(session), Some(coordinator)) => {
+                        self.client_transaction_prepare(from, session, coordinator, actions)
                     }
-
-                } else {
-                    self.proposal_request(from, request, actions)
+                    _ => self.reject_malformed_transaction(from, actions),
                 }
             }
-            client_request::Which::Query(Ok(query)) => {
-                if self.transaction.is_active {
-                    let query = query.get_query().unwrap();
-                    let message = messages::query_request(query, &self.lid);
-
-                    actions.transaction_queue.push((self.lid, from, message));
-                } else {
-                    self.query_request(from, query, actions);
+            client_request::Which::TransactionDecide(Ok(request)) => {
+                match parse_session(request.get_session()) {
+                    Some(session) => {
+                        self.client_transaction_decide(from, session, request.get_commit(), actions)
+                    }
+                    None => self.reject_malformed_transaction(from, actions),
                 }
             }
-            client_request::Which::TransactionBegin(Ok(request)) => {
-                self.client_transaction_begin(from,
-                                              TransactionId::from_bytes(request.get_session()
-                                                      .unwrap())
-                                                  .expect("Transaction invalid"),
-                                              actions);
+            client_request::Which::TransactionStatus(Ok(request)) => {
+                match parse_session(request.get_session()) {
+                    Some(session) => self.client_transaction_status(from, session, actions),
+                    None => self.reject_malformed_transaction(from, actions),
+                }
             }
-            client_request::Which::TransactionCommit(Ok(_)) => {
-                self.client_transaction_commit(from, actions);
+            client_request::Which::TransactionSavepoint(Ok(request)) => {
+                let name = request.get_name().ok().map(|name| name.to_string());
+                match (parse_session(request.get_session()), name) {
+                    (Some(session), Some(name)) => {
+                        self.client_transaction_savepoint(from, session, name, actions)
+                    }
+                    _ => self.reject_malformed_transaction(from, actions),
+                }
             }
-            client_request::Which::TransactionRollback(Ok(_)) => {
-                self.client_transaction_rollback(from, actions);
-
+            client_request::Which::TransactionRollbackTo(Ok(request)) => {
+                let name = request.get_name().ok().map(|name| name.to_string());
+                match (parse_session(request.get_session()), name) {
+                    (Some(session), Some(name)) => {
+                        self.client_transaction_rollback_to(from, session, name, actions)
+                    }
+                    _ => self.reject_malformed_transaction(from, actions),
+                }
+            }
+            client_request::Which::LeaderTransfer(Ok(request)) => {
+                if self.is_leader() {
+                    let target = ServerId::from(request.get_target());
+                    if let Err(error) = self.transfer_leadership(target, Some(from), actions) {
+                        let message = messages::command_response_failure(format!("{}", error)
+                                                                             .as_bytes(),
+                                                                         self.lid);
+                        actions.client_messages.push((from, message));
+                    }
+                } else {
+                    self.redirect_to_leader(from, actions);
+                }
+            }
+            _ => {
+                let error = format!("cannot handle message from client {}", from);
+                return Err(::Error::Raft(RaftError::Other(error)));
             }
-            _ => panic!("cannot handle message"),
         }
+        Ok(())
+    }
+
+    /// Replies to a client whose transaction request is malformed.
+    fn reject_malformed_transaction(&self, from: ClientId, actions: &mut Actions) {
+        scoped_debug!("malformed transaction request from client {}", from);
+        let error = TransactionError::Other("Malformed transaction request".to_string());
+        actions.client_messages
+            .push((from, messages::command_transaction_failure(error, self.lid)));
     }
 
     /// Applies a timeout's actions to the `Consensus`.
@@ -326,20 +554,210 @@ impl<L, M> Consensus<L, M>
         match timeout {
             ConsensusTimeout::Election(..) => self.election_timeout(actions),
             ConsensusTimeout::Heartbeat(peer, ..) => self.heartbeat_timeout(peer, actions),
+            ConsensusTimeout::Transfer(..) => self.transfer_timeout(actions),
+            ConsensusTimeout::CheckQuorum(..) => self.check_quorum(actions),
+            ConsensusTimeout::Transaction(session, ..) => {
+                self.transaction_timeout(session, actions)
+            }
+            ConsensusTimeout::Read(id, ..) => self.read_timeout(id, actions),
+        }
+    }
+
+    /// Starts transferring leadership to `target`. The target is brought up to date, and then
+    /// asked to start an election immediately. If the transfer was requested by a client, the
+    /// client is answered once the transfer completes or is abandoned.
+    pub fn transfer_leadership(&mut self,
+                               target: ServerId,
+                               client: Option<ClientId>,
+                               actions: &mut Actions)
+                               -> Result<(), RaftError> {
+        if !self.is_leader() {
+            return Err(RaftError::LeaderTransferFailed("not the leader".to_string()));
+        }
+        if target == self.id {
+            return Err(RaftError::LeaderTransferFailed("already the leader".to_string()));
         }
+        if !self.voting_peers().contains(&target) {
+            return Err(RaftError::LeaderTransferFailed(format!("unknown peer {}", target)));
+        }
+
+        let mut leader_state = self.leader_state.write().unwrap();
+        if leader_state.transfer.is_some() {
+            return Err(RaftError::LeaderTransferFailed("a transfer is already in progress"
+                .to_string()));
+        }
+        scoped_info!("transferring leadership to peer {}", target);
+        leader_state.transfer = Some((target, client));
+        actions.timeouts.push(ConsensusTimeout::Transfer(self.lid));
+
+        if leader_state.match_index(&target) >= self.latest_log_index() {
+            let message = messages::timeout_now(self.current_term(), &self.lid);
+            actions.peer_messages.push((target, message));
+        } else {
+            self.replicate(target, &mut leader_state, actions);
+        }
+        Ok(())
+    }
+
+    /// Sends a TimeoutNow message to `peer` if leadership is being transferred to it and its log
+    /// has caught up with the leader's.
+    fn send_timeout_now_if_ready(&self, peer: ServerId, actions: &mut Actions) {
+        let leader_state = self.leader_state.read().unwrap();
+        if let Some((target, _)) = leader_state.transfer {
+            if target == peer && leader_state.match_index(&peer) >= self.latest_log_index() {
+                scoped_debug!("peer {} is up to date; sending TimeoutNow", peer);
+                let message = messages::timeout_now(self.current_term(), &self.lid);
+                actions.peer_messages.push((peer, message));
+            }
+        }
+    }
+
+    /// Abandons a leadership transfer which did not complete within an election timeout, and
+    /// resumes accepting proposals.
+    fn transfer_timeout(&mut self, actions: &mut Actions) {
+        if !self.is_leader() {
+            return;
+        }
+        let mut leader_state = self.leader_state.write().unwrap();
+        if let Some((target, client)) = leader_state.transfer.take() {
+            scoped_warn!("leadership transfer to peer {} timed out", target);
+            // The target may still win an election, so the contact with the majority before
+            // the transfer no longer vouches for this leader.
+            leader_state.reset_quorum_contact();
+            if let Some(client) = client {
+                let message = messages::command_response_failure(b"leadership transfer timed out",
+                                                                 self.lid);
+                actions.client_messages.push((client, message));
+            }
+        }
+    }
+
+    /// Applies a TimeoutNow message from the leader by starting an election right away, without
+    /// a pre-vote round.
+    fn timeout_now(&mut self,
+                   from: ServerId,
+                   request: timeout_now::Reader,
+                   actions: &mut Actions) {
+        let leader_term = Term(request.get_term());
+        if leader_term != self.current_term() || self.is_leader() {
+            scoped_debug!("ignoring TimeoutNow from peer {} for term {}", from, leader_term);
+            return;
+        }
+        scoped_info!("TimeoutNow from peer {}: transitioning to Candidate", from);
+        actions.clear_timeouts.push(self.lid);
+        self.transition_to_candidate(actions);
     }
 
-    /// Adds new peer to `peers`
+    /// Adds new peer to `peers`. If this consensus is the leader, and the peer is not part of
+    /// the configuration yet, the peer is added as a learner: it receives the log, but does not
+    /// vote until it is promoted with `promote_learner()`.
     ///
     /// # Arguments
     /// * `peer_id` - The ID of the new peer
     /// * `peer_addr` - The socketaddress of the new peer
-    pub fn add_peer(&mut self, peer_id: ServerId, peer_addr: SocketAddr) {
-        assert_eq!(self.peers.insert(peer_id, peer_addr), None);
+    pub fn add_peer(&mut self,
+                    peer_id: ServerId,
+                    peer_addr: SocketAddr,
+                    actions: &mut Actions)
+                    -> Result<(), RaftError> {
+        self.peers.insert(peer_id, peer_addr);
+        if !self.is_leader() || self.configuration.contains(&peer_id) ||
+           self.configuration.learners.contains(&peer_id) {
+            return Ok(());
+        }
+        scoped_info!("adding peer {} as a learner", peer_id);
+        let mut configuration = self.configuration.clone();
+        configuration.learners.insert(peer_id);
+        self.append_configuration(configuration, actions);
+        Ok(())
+    }
+
+    /// Promotes the learner `peer_id` to a voting member, by starting a membership change. The
+    /// learner's log must be within the configured lag of the leader's log.
+    pub fn promote_learner(&mut self,
+                           peer_id: ServerId,
+                           actions: &mut Actions)
+                           -> Result<(), RaftError> {
+        if !self.configuration.learners.contains(&peer_id) {
+            return Err(RaftError::MembershipChangeFailed(format!("{} is not a learner",
+                                                                 peer_id)));
+        }
+        if self.is_leader() {
+            let match_index = self.leader_state.read().unwrap().match_index(&peer_id);
+            if match_index + self.config.learner_max_lag < self.latest_log_index() {
+                return Err(RaftError::MembershipChangeFailed(format!("learner {} is at index \
+                                                                      {}, too far behind",
+                                                                     peer_id,
+                                                                     match_index)));
+            }
+        }
+        let mut members = self.configuration.members.clone();
+        members.insert(peer_id);
+        self.change_membership(members, actions)
+    }
+
+    /// Starts a membership change removing `peer_id` from the configuration. If the leader
+    /// removes itself, it steps down once the new configuration is committed. Learners are
+    /// removed right away.
+    pub fn remove_peer(&mut self,
+                       peer_id: ServerId,
+                       actions: &mut Actions)
+                       -> Result<(), RaftError> {
+        if self.configuration.learners.contains(&peer_id) {
+            if !self.is_leader() {
+                return Err(RaftError::MembershipChangeFailed("not the leader".to_string()));
+            }
+            let mut configuration = self.configuration.clone();
+            configuration.learners.remove(&peer_id);
+            self.append_configuration(configuration, actions);
+            return Ok(());
+        }
+        if !self.configuration.members.contains(&peer_id) {
+            return Err(RaftError::MembershipChangeFailed(format!("{} is not a member", peer_id)));
+        }
+        let mut members = self.configuration.members.clone();
+        members.remove(&peer_id);
+        self.change_membership(members, actions)
+    }
 
-        let mut lock = self.leader_state.write().unwrap();
+    /// Starts a membership change to `members` by appending the joint configuration to the
+    /// log. The new configuration is appended once the joint configuration is committed.
+    pub fn change_membership(&mut self,
+                             members: HashSet<ServerId>,
+                             actions: &mut Actions)
+                             -> Result<(), RaftError> {
+        if !self.is_leader() {
+            return Err(RaftError::MembershipChangeFailed("not the leader".to_string()));
+        }
+        if members.is_empty() {
+            return Err(RaftError::MembershipChangeFailed("no members left".to_string()));
+        }
+        if self.configuration.is_joint() || self.configuration_index > self.commit_index {
+            return Err(RaftError::MembershipChangeFailed("a membership change is already in \
+                                                          progress"
+                .to_string()));
+        }
+        let configuration = self.configuration.joint(members);
+        scoped_info!("changing membership from {:?} to {:?}",
+                     self.configuration.members,
+                     configuration.members);
+        self.append_configuration(configuration, actions);
+        Ok(())
+    }
 
-        lock.add_peer(peer_id);
+    /// Appends a configuration entry to the log and replicates it. The configuration takes
+    /// effect immediately.
+    fn append_configuration(&mut self, configuration: Configuration, actions: &mut Actions) {
+        let entry = Payload::Configuration(configuration.clone()).to_bytes();
+        let index = self.latest_log_index() + 1;
+        self.set_configuration(index, configuration);
+        self.append_entry(&entry);
+        // New members start out probing from the first entry.
+        self.flush(actions);
+
+        if self.voting_peers().is_empty() {
+            self.advance_commit_index(actions);
+        }
     }
 
     /// Notifies the consensus state machine that a new connection to the peer exists, and
@@ -351,36 +769,34 @@ impl<L, M> Consensus<L, M>
         push_log_scope!("{:?}", self);
 
         self.peers.insert(peer, addr);
+        if !self.replication_peers().contains(&peer) {
+            return;
+        }
 
         match self.state {
             ConsensusState::Leader => {
-                // Send any outstanding entries to the peer, or an empty heartbeat if there are no
-                // outstanding entries.
+                // The requests in flight may have been lost. Probe the peer with any outstanding
+                // entries, or with an empty request if there are no outstanding entries.
                 let mut leader_state = self.leader_state.write().unwrap();
-                let from_index = leader_state.next_index(&peer);
-                let until_index = self.latest_log_index() + 1;
-
-                let prev_log_index = from_index - 1;
-                let prev_log_term = if prev_log_index == LogIndex::from(0) {
-                    Term::from(0)
-                } else {
-                    self.log.entry(prev_log_index).unwrap().0
-                };
-
-                let entries = self.log.entries(from_index, until_index).unwrap();
-                let message = messages::append_entries_request(self.current_term(),
-                                                               prev_log_index,
-                                                               prev_log_term,
-                                                               &entries,
-                                                               self.commit_index,
-                                                               &self.lid);
-
-                leader_state.set_next_index(peer, until_index);
+                leader_state.set_mode(peer, ProgressMode::Probe);
+                self.send_append_entries(peer, &mut leader_state, actions);
+            }
+            ConsensusState::PreCandidate => {
+                // Resend the pre-vote request if a response has not yet been receieved.
+                if self.candidate_state.read().unwrap().peer_voted(peer) ||
+                   !self.voting_peers().contains(&peer) {
+                    return;
+                }
+                let message = messages::pre_vote_request(self.current_term() + 1,
+                                                         self.latest_log_index(),
+                                                         self.latest_log_term(),
+                                                         &self.lid);
                 actions.peer_messages.push((peer, message));
             }
             ConsensusState::Candidate => {
                 // Resend the request vote request if a response has not yet been receieved.
-                if self.candidate_state.read().unwrap().peer_voted(peer) {
+                if self.candidate_state.read().unwrap().peer_voted(peer) ||
+                   !self.voting_peers().contains(&peer) {
                     return;
                 }
                 let current_term = self.current_term();
@@ -409,6 +825,7 @@ impl<L, M> Consensus<L, M>
 
         let leader_term = Term(request.get_term());
         let current_term = self.current_term();
+        let round = request.get_round();
 
         if leader_term < current_term {
             let message = messages::append_entries_response_stale_term(current_term, &self.lid);
@@ -420,85 +837,76 @@ impl<L, M> Consensus<L, M>
             ConsensusState::Follower => {
                 let message = {
                     if current_term < leader_term {
+                        self.fail_reads(actions);
                         self.log.set_current_term(leader_term).unwrap();
                         self.follower_state.write().unwrap().set_leader(from);
                     }
+                    self.leader_contact = Some(Instant::now());
 
                     let leader_prev_log_index = LogIndex(request.get_prev_log_index());
                     let leader_prev_log_term = Term(request.get_prev_log_term());
 
-                    let latest_log_index = self.latest_log_index();
-
-                    if latest_log_index < leader_prev_log_index {
-                        // If the previous entries index was not the same we'd leave a gap! Reply failure.
-                        scoped_debug!("AppendEntriesRequest: inconsistent previous log index: \
-                                      leader: {}, local: {}",
-                                      leader_prev_log_index,
-                                      latest_log_index);
-
+                    let check = self.check_prev_entry(leader_prev_log_index,
+                                                      leader_prev_log_term);
+                    if let Err((conflict_term, first_index)) = check {
                         messages::append_entries_response_inconsistent_prev_entry(
-                            self.current_term(), leader_prev_log_index,&self.lid)
+                            self.current_term(), conflict_term, first_index, round, &self.lid)
                     } else {
-                        let existing_term = if leader_prev_log_index == LogIndex::from(0) {
-                            Term::from(0)
-                        } else {
-                            self.log.entry(leader_prev_log_index).unwrap().0
-                        };
-
-                        if existing_term != leader_prev_log_term {
-                            scoped_debug!("AppendEntriesRequest: inconsistent previous log term: \
-                                          leader term: {}, local term: {}",
-                                          leader_prev_log_term,
-                                          existing_term);
-                            // If an existing entry conflicts with a new one (same index but different terms),
-                            // delete the existing entry and all that follow it
-                            messages::append_entries_response_inconsistent_prev_entry(self.current_term(),
-                                leader_prev_log_index,&self.lid)
-                        } else {
-                            if let Ok(entries) = request.get_entries() {
-                                let num_entries: u32 = entries.len();
-                                let new_latest_log_index = leader_prev_log_index +
-                                                           num_entries as u64;
-
-                                if new_latest_log_index <
-                                   self.follower_state.read().unwrap().min_index {
-                                    // Stale entry; ignore. This guards against overwriting a
-                                    // possibly committed part of the log if messages get
-                                    // rearranged; see ktoso/akka-raft#66.
-                                    return;
-                                }
-                                scoped_debug!("AppendEntriesRequest: {} entries from leader: {}",
-                                              num_entries,
-                                              from);
-
-                                let entries_vec: Vec<(Term, &[u8])> = entries.iter()
-                                    .map(|entry| {
-                                        (Term::from(entry.get_term()),
-                                         entry.get_data().unwrap_or(b""))
-                                    })
-                                    .collect();
-
+                        if let Ok(entries) = request.get_entries() {
+                            let num_entries: u32 = entries.len();
+                            let new_latest_log_index = leader_prev_log_index +
+                                                       num_entries as u64;
+
+                            if new_latest_log_index <
+                               self.follower_state.read().unwrap().min_index {
+                                // Stale entry; ignore. This guards against overwriting a
+                                // possibly committed part of the log if messages get
+                                // rearranged; see ktoso/akka-raft#66.
+                                return;
+                            }
+                            scoped_debug!("AppendEntriesRequest: {} entries from leader: {}",
+                                          num_entries,
+                                          from);
+
+                            let entries_vec: Vec<(Term, &[u8])> = entries.iter()
+                                .map(|entry| {
+                                    (Term::from(entry.get_term()),
+                                     entry.get_data().unwrap_or(b""))
+                                })
+                                .collect();
+
+                            // Skip the entries which are already covered by the snapshot.
+                            let first_index = cmp::max(leader_prev_log_index + 1,
+                                                       self.log.first_log_index().unwrap());
+                            if first_index <= new_latest_log_index ||
+                               first_index == leader_prev_log_index + 1 {
+                                let skip = (first_index - (leader_prev_log_index + 1)) as usize;
                                 self.log
-                                    .append_entries(leader_prev_log_index + 1, &entries_vec)
+                                    .append_entries(first_index, &entries_vec[skip..])
                                     .unwrap();
-                                self.follower_state.write().unwrap().min_index =
-                                    new_latest_log_index;
-                                // We are matching the leader's log up to and including `new_latest_log_index`.
-                                self.commit_index =
-                                    cmp::min(LogIndex::from(request.get_leader_commit()),
-                                             new_latest_log_index);
-                                self.apply_commits();
-
-                            } else {
-                                panic!("AppendEntriesRequest: no entry list")
+                                self.refresh_configuration(first_index);
                             }
+                            self.follower_state.write().unwrap().min_index =
+                                new_latest_log_index;
+                            // We are matching the leader's log up to and including
+                            // `new_latest_log_index`.
+                            self.commit_index =
+                                cmp::max(self.commit_index,
+                                         cmp::min(LogIndex::from(request.get_leader_commit()),
+                                                  new_latest_log_index));
+                            self.apply_commits();
+                            self.serve_reads(actions);
 
-                            messages::append_entries_response_success(self.current_term(),
-                                                                      self.log
-                                                                          .latest_log_index()
-                                                                          .unwrap(),
-                                                                      &self.lid)
+                        } else {
+                            panic!("AppendEntriesRequest: no entry list")
                         }
+
+                        messages::append_entries_response_success(self.current_term(),
+                                                                  self.log
+                                                                      .latest_log_index()
+                                                                      .unwrap(),
+                                                                  round,
+                                                                  &self.lid)
                     }
                 };
 
@@ -506,13 +914,14 @@ impl<L, M> Consensus<L, M>
                 actions.timeouts.push(ConsensusTimeout::Election(self.lid));
                 actions.peer_messages.push((from, message.clone()));
             }
+            ConsensusState::PreCandidate |
             ConsensusState::Candidate => {
                 // recognize the new leader, return to follower state, and apply the entries
                 scoped_info!("received AppendEntriesRequest from Consensus {{ id: {}, term: {} \
                               }} with newer term; transitioning to Follower",
                              from,
                              leader_term);
-                self.transition_to_follower(leader_term, from, actions);
+                self.transition_to_follower(leader_term, from, true, actions);
                 return self.append_entries_request(from, request, actions);
             }
             ConsensusState::Leader => {
@@ -530,12 +939,44 @@ impl<L, M> Consensus<L, M>
                               }} with newer term; transitioning to Follower",
                              from,
                              leader_term);
-                self.transition_to_follower(leader_term, from, actions);
+                self.transition_to_follower(leader_term, from, true, actions);
                 return self.append_entries_request(from, request, actions);
             }
         }
     }
 
+    /// Checks that the log holds the leader's entry at `prev_log_index`, of `prev_log_term`.
+    /// Otherwise returns the term of the local entry at `prev_log_index` and the index of the
+    /// first local entry of that term, so that the leader skips back over the whole conflicting
+    /// term; or term 0 and the index following the local log, if it ends before
+    /// `prev_log_index`.
+    fn check_prev_entry(&self,
+                        prev_log_index: LogIndex,
+                        prev_log_term: Term)
+                        -> Result<(), (Term, LogIndex)> {
+        let latest_log_index = self.latest_log_index();
+        if latest_log_index < prev_log_index {
+            // Appending after the previous entry would leave a gap.
+            scoped_debug!("inconsistent previous log index: leader: {}, local: {}",
+                          prev_log_index,
+                          latest_log_index);
+            return Err((Term(0), latest_log_index + 1));
+        }
+        let existing_term = if prev_log_index < self.snapshot_index() {
+            // Entries covered by the snapshot are committed, so they match.
+            prev_log_term
+        } else {
+            self.log_term(prev_log_index)
+        };
+        if existing_term != prev_log_term {
+            scoped_debug!("inconsistent previous log term: leader term: {}, local term: {}",
+                          prev_log_term,
+                          existing_term);
+            return Err((existing_term, self.first_index_of_term(existing_term, prev_log_index)));
+        }
+        Ok(())
+    }
+
     /// Apply an append entries response to the consensus state machine.
     ///
     /// The provided message may be initialized with a new AppendEntries request to send back to
@@ -546,7 +987,6 @@ impl<L, M> Consensus<L, M>
                                actions: &mut Actions) {
         let local_term = self.current_term();
         let responder_term = Term::from(response.get_term());
-        let local_latest_log_index = self.latest_log_index();
 
         if local_term < responder_term {
             // Responder has a higher term number. Relinquish leader position (if it is held), and
@@ -558,7 +998,7 @@ impl<L, M> Consensus<L, M>
                          transitioning to Follower",
                          from,
                          responder_term);
-            self.transition_to_follower(responder_term, from, actions);
+            self.transition_to_follower(responder_term, from, false, actions);
             return;
         } else if local_term > responder_term {
             scoped_debug!("AppendEntriesResponse from peer {} with a different term: {}",
@@ -567,25 +1007,27 @@ impl<L, M> Consensus<L, M>
             // Responder is responding to an AppendEntries request from a different term. Ignore
             // the response.
             return;
+        } else if !self.is_leader() || !self.replication_peers().contains(&from) {
+            // The leader stepped down, or the responder was removed from the configuration.
+            return;
         }
 
+        // Any answer in the current term shows that the responder still follows this leader.
+        self.leader_state.write().unwrap().ack_round(from, response.get_round());
+        self.update_quorum_contact();
+        self.serve_reads(actions);
+
         match response.which() {
             Ok(append_entries_response::Which::Success(follower_latest_log_index)) => {
                 scoped_trace!("AppendEntriesResponse from peer {}: success", from);
-                scoped_assert!(self.is_leader());
-                let follower_latest_log_index = LogIndex::from(follower_latest_log_index);
-                // scoped_assert!(follower_latest_log_index <= local_latest_log_index);
-                scoped_debug!("Follower_log_index {}", follower_latest_log_index);
-                self.leader_state.write().unwrap().set_match_index(from, follower_latest_log_index);
-                self.advance_commit_index(actions);
+                self.follower_matched(from, LogIndex::from(follower_latest_log_index), actions);
             }
-            Ok(append_entries_response::Which::InconsistentPrevEntry(next_index)) => {
-                scoped_assert!(self.is_leader());
-                scoped_debug!("AppendEntriesResponse from peer {}: \
-                              inconsistent previous entry index: {}",
-                              from,
-                              next_index);
-                self.leader_state.write().unwrap().set_next_index(from, LogIndex::from(next_index));
+            Ok(append_entries_response::Which::InconsistentPrevEntry(conflict)) => {
+                scoped_debug!("AppendEntriesResponse from peer {}: inconsistent previous entry",
+                              from);
+                self.follower_inconsistent(from,
+                                           Term(conflict.get_conflict_term()),
+                                           LogIndex(conflict.get_first_index()));
             }
             Ok(append_entries_response::Which::StaleTerm(..)) => {
                 // The peer is reporting a stale term, but the term number matches the local term.
@@ -609,77 +1051,376 @@ impl<L, M> Consensus<L, M>
             }
         }
 
-        let next_index = self.leader_state.write().unwrap().next_index(&from);
+        self.continue_replication(from, actions);
+    }
+
+    /// Records that the follower's log matches the leader's up to its latest entry, and commits
+    /// the entries now replicated on a majority.
+    fn follower_matched(&mut self,
+                        from: ServerId,
+                        follower_latest_log_index: LogIndex,
+                        actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        // scoped_assert!(follower_latest_log_index <= local_latest_log_index);
+        scoped_debug!("Follower_log_index {}", follower_latest_log_index);
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+            leader_state.set_match_index(from, follower_latest_log_index);
+            if leader_state.mode(&from) == ProgressMode::Replicate {
+                leader_state.release_inflight(from, follower_latest_log_index);
+            } else {
+                // The logs match up to the follower's latest entry; stream the rest.
+                leader_state.set_mode(from, ProgressMode::Replicate);
+            }
+            if leader_state.next_index(&from) <= follower_latest_log_index {
+                leader_state.set_next_index(from, follower_latest_log_index + 1);
+            }
+        }
+        self.advance_commit_index(actions);
+        self.send_timeout_now_if_ready(from, actions);
+    }
+
+    /// Moves the follower's next index back after its log failed the consistency check, given
+    /// the hint returned by the follower, and probes the follower from there.
+    fn follower_inconsistent(&mut self,
+                             from: ServerId,
+                             conflict_term: Term,
+                             first_index: LogIndex) {
+        scoped_assert!(self.is_leader());
+        // If the leader holds entries of the conflicting term, the logs agree up to the last of
+        // them. Otherwise none of the follower's entries of that term match.
+        let next_index = if conflict_term == Term(0) {
+            first_index
+        } else {
+            self.last_index_of_term(conflict_term).map_or(first_index, |index| index + 1)
+        };
+        scoped_debug!("peer {}: conflict term: {}, first index: {}, next index: {}",
+                      from,
+                      conflict_term,
+                      first_index,
+                      next_index);
+        let mut leader_state = self.leader_state.write().unwrap();
+        // Entries up to the match index are known to agree; a reordered response must not move
+        // the next index back behind them.
+        let next_index = cmp::max(next_index, leader_state.match_index(&from) + 1);
+        leader_state.set_next_index(from, next_index);
+        // The requests in flight carry entries the follower can not append.
+        leader_state.set_mode(from, ProgressMode::Probe);
+    }
+
+    /// Sends the follower the entries it is missing, or schedules its next heartbeat if it has
+    /// caught up.
+    fn continue_replication(&mut self, from: ServerId, actions: &mut Actions) {
+        let local_latest_log_index = self.latest_log_index();
+        let mut leader_state = self.leader_state.write().unwrap();
+        let next_index = leader_state.next_index(&from);
         if next_index <= local_latest_log_index {
             // If the peer is behind, send it entries to catch up.
-            scoped_debug!("AppendEntriesResponse: peer {} is missing at least {} entries; \
-                          sending missing entries",
+            scoped_debug!("peer {} is missing at least {} entries; sending missing entries",
                           from,
                           (local_latest_log_index + 1 - next_index.0).0);
-            let prev_log_index = next_index - 1;
-            let prev_log_term = if prev_log_index == LogIndex(0) {
-                Term(0)
-            } else {
-                self.log.entry(prev_log_index).unwrap().0
-            };
+            self.replicate(from, &mut leader_state, actions);
+        } else {
+            // If the peer is caught up, set a heartbeat timeout.
+            scoped_trace!("scheduling heartbeat for peer {}", from);
+            self.schedule_heartbeat(from, actions);
+        }
+    }
 
-            let from_index = next_index;
-            let until_index = local_latest_log_index + 1;
+    /// Sends the peer the entries it is missing, as far as its progress allows: one request at a
+    /// time while probing, up to `max_inflight_append_entries` requests while replicating, and
+    /// nothing while a snapshot is being installed.
+    fn replicate(&self, peer: ServerId, leader_state: &mut LeaderState, actions: &mut Actions) {
+        let latest_log_index = self.latest_log_index();
+        while leader_state.next_index(&peer) <= latest_log_index &&
+              leader_state.can_send(&peer, self.config.max_inflight_append_entries) {
+            self.send_append_entries(peer, leader_state, actions);
+        }
+    }
 
-            let entries = self.log
-                .entries(LogIndex::from(from_index), LogIndex::from(until_index))
-                .unwrap();
+    /// Sends the entries starting at the peer's next index to the peer, as many as fit into one
+    /// request. If those entries have already been compacted, the latest snapshot is sent
+    /// instead.
+    fn send_append_entries(&self,
+                           peer: ServerId,
+                           leader_state: &mut LeaderState,
+                           actions: &mut Actions) {
+        let from_index = leader_state.next_index(&peer);
+
+        if from_index < self.log.first_log_index().unwrap() {
+            let (index, term, data) = self.log
+                .snapshot()
+                .unwrap()
+                .expect("log compacted without a snapshot");
+            scoped_debug!("peer {} is behind the first retained entry; sending snapshot up to {}",
+                          peer,
+                          index);
+            let message = messages::install_snapshot_request(self.current_term(),
+                                                             index,
+                                                             term,
+                                                             data,
+                                                             &self.lid);
+            leader_state.set_next_index(peer, index + 1);
+            leader_state.set_mode(peer, ProgressMode::Snapshot);
+            actions.peer_messages.push((peer, message));
+            return;
+        }
 
-            let message = messages::append_entries_request(local_term,
-                                                           prev_log_index,
-                                                           prev_log_term,
-                                                           &entries,
-                                                           self.commit_index,
-                                                           &self.lid);
+        // Larger gaps are closed over several requests. The first entry is sent even if it
+        // exceeds the byte limit on its own.
+        let max_until_index = cmp::min(self.latest_log_index() + 1,
+                                       from_index + self.config.max_append_entries);
+        let mut entries = Vec::new();
+        let mut bytes = 0;
+        for index in from_index.as_u64()..max_until_index.as_u64() {
+            let (term, data) = self.log.entry(LogIndex(index)).unwrap();
+            bytes += data.len() as u64;
+            if !entries.is_empty() && bytes > self.config.max_append_entries_bytes {
+                break;
+            }
+            entries.push((term, data));
+        }
+        let until_index = from_index + entries.len() as u64;
+        let prev_log_index = from_index - 1;
+        let prev_log_term = self.log_term(prev_log_index);
+
+        let message = messages::append_entries_request(self.current_term(),
+                                                       prev_log_index,
+                                                       prev_log_term,
+                                                       &entries,
+                                                       self.commit_index,
+                                                       leader_state.round(),
+                                                       &self.lid);
+
+        leader_state.set_next_index(peer, until_index);
+        leader_state.record_inflight(peer, until_index - 1);
+        actions.peer_messages.push((peer, message));
+    }
+
+    /// Applies an install snapshot request to the consensus state machine.
+    fn install_snapshot_request(&mut self,
+                                from: ServerId,
+                                request: install_snapshot_request::Reader,
+                                actions: &mut Actions) {
+        let leader_term = Term(request.get_term());
+        let current_term = self.current_term();
+        let snapshot_index = LogIndex(request.get_last_included_index());
+        let snapshot_term = Term(request.get_last_included_term());
+        scoped_debug!("InstallSnapshotRequest from peer {} up to {}", from, snapshot_index);
 
-            self.leader_state.write().unwrap().set_next_index(from, local_latest_log_index + 1);
+        if leader_term < current_term {
+            let message =
+                messages::install_snapshot_response(current_term, self.snapshot_index(), &self.lid);
             actions.peer_messages.push((from, message));
+            return;
+        }
+
+        if self.is_leader() && leader_term == current_term {
+            // The single leader-per-term invariant is broken; there is a bug in the Raft
+            // implementation.
+            panic!("{:?}: peer leader {} with matching term {:?} detected.",
+                   self,
+                   from,
+                   current_term);
+        }
+        if current_term < leader_term || !self.is_follower() {
+            self.transition_to_follower(leader_term, from, true, actions);
         } else {
-            // If the peer is caught up, set a heartbeat timeout.
-            scoped_trace!("AppendEntriesResponse: scheduling heartbeat for peer {}",
-                          from);
-            let timeout = ConsensusTimeout::Heartbeat(from, self.lid);
-            actions.timeouts.push(timeout);
+            self.follower_state.write().unwrap().set_leader(from);
+            actions.clear_timeouts.push(self.lid);
+            actions.timeouts.push(ConsensusTimeout::Election(self.lid));
         }
-    }
+        self.leader_contact = Some(Instant::now());
+
+        // A snapshot which does not extend the committed part of the log carries nothing new.
+        if snapshot_index > self.commit_index {
+            let decoded = request.get_data()
+                .ok()
+                .and_then(|data| Snapshot::from_bytes(data).ok().map(|snapshot| (data, snapshot)));
+            let (data, snapshot) = match decoded {
+                Some(decoded) => decoded,
+                None => {
+                    scoped_warn!("InstallSnapshotRequest from peer {}: malformed snapshot", from);
+                    return;
+                }
+            };
 
-    /// Applies a peer request vote request to the consensus state machine.
-    fn request_vote_request(&mut self,
-                            candidate: ServerId,
-                            request: request_vote_request::Reader,
-                            actions: &mut Actions) {
+            self.log.compact(snapshot_index, snapshot_term, data).unwrap();
+            let (map, entries) = snapshot.state_machine;
+            self.state_machine.write().unwrap().restore_snapshot(map, entries);
+            self.sessions = snapshot.sessions;
+            self.transactions = snapshot.transactions;
+            self.logs = snapshot.logs;
+            self.reload_configuration();
+            self.commit_index = snapshot_index;
+            self.last_applied = snapshot_index;
+
+            let mut follower_state = self.follower_state.write().unwrap();
+            follower_state.min_index = cmp::max(follower_state.min_index, snapshot_index);
+        }
+        self.serve_reads(actions);
 
-        let candidate_term = Term(request.get_term());
-        let candidate_log_term = Term(request.get_last_log_term());
-        let candidate_log_index = LogIndex(request.get_last_log_index());
-        scoped_debug!("RequestVoteRequest from Consensus {{ id: {}, term: {}, latest_log_term: \
-                       {}, latest_log_index: {} }}",
-                      &candidate,
-                      candidate_term,
-                      candidate_log_term,
-                      candidate_log_index);
+        let message =
+            messages::install_snapshot_response(self.current_term(), snapshot_index, &self.lid);
+        actions.peer_messages.push((from, message));
+    }
+
+    /// Applies an install snapshot response to the consensus state machine.
+    fn install_snapshot_response(&mut self,
+                                 from: ServerId,
+                                 response: install_snapshot_response::Reader,
+                                 actions: &mut Actions) {
         let local_term = self.current_term();
+        let responder_term = Term::from(response.get_term());
 
-        let new_local_term = if candidate_term > local_term {
-            scoped_info!("received RequestVoteRequest from Consensus {{ id: {}, term: {} }} \
-                         with newer term; transitioning to Follower",
-                         candidate,
-                         candidate_term);
-            self.transition_to_follower(candidate_term, candidate, actions);
-            candidate_term
+        if local_term < responder_term {
+            scoped_info!("InstallSnapshotResponse from peer {} with newer term: {}; \
+                         transitioning to Follower",
+                         from,
+                         responder_term);
+            self.transition_to_follower(responder_term, from, false, actions);
+            return;
+        } else if local_term > responder_term || !self.is_leader() ||
+                  !self.replication_peers().contains(&from) {
+            // The response belongs to a request from a previous term, or to a removed peer.
+            return;
+        }
+
+        let snapshot_index = LogIndex(response.get_last_included_index());
+        scoped_debug!("InstallSnapshotResponse from peer {}: installed up to {}",
+                      from,
+                      snapshot_index);
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+            if leader_state.match_index(&from) < snapshot_index {
+                leader_state.set_match_index(from, snapshot_index);
+            }
+            if leader_state.next_index(&from) <= snapshot_index {
+                leader_state.set_next_index(from, snapshot_index + 1);
+            }
+            if leader_state.mode(&from) == ProgressMode::Snapshot {
+                leader_state.set_mode(from, ProgressMode::Replicate);
+            }
+        }
+        self.advance_commit_index(actions);
+        self.send_timeout_now_if_ready(from, actions);
+
+        let mut leader_state = self.leader_state.write().unwrap();
+        if leader_state.next_index(&from) <= self.latest_log_index() {
+            self.replicate(from, &mut leader_state, actions);
+        } else {
+            self.schedule_heartbeat(from, actions);
+        }
+    }
+
+    /// Applies a peer pre-vote request to the consensus state machine. The local term and vote
+    /// are left untouched.
+    fn pre_vote_request(&mut self,
+                        candidate: ServerId,
+                        request: pre_vote_request::Reader,
+                        actions: &mut Actions) {
+        let candidate_term = Term(request.get_term());
+        let candidate_log_term = Term(request.get_last_log_term());
+        let candidate_log_index = LogIndex(request.get_last_log_index());
+        scoped_debug!("PreVoteRequest from Consensus {{ id: {}, term: {}, latest_log_term: {}, \
+                       latest_log_index: {} }}",
+                      &candidate,
+                      candidate_term,
+                      candidate_log_term,
+                      candidate_log_index);
+        let local_term = self.current_term();
+
+        let message = if candidate_term <= local_term {
+            messages::pre_vote_response_stale_term(local_term, &self.lid)
+        } else if self.leader_active() {
+            messages::pre_vote_response_leader_active(local_term, &self.lid)
+        } else if !self.is_up_to_date(candidate_log_term, candidate_log_index) {
+            messages::pre_vote_response_inconsistent_log(local_term, &self.lid)
+        } else {
+            messages::pre_vote_response_granted(local_term, &self.lid)
+        };
+        actions.peer_messages.push((candidate, message));
+    }
+
+    /// Applies a pre-vote response to the consensus state machine.
+    fn pre_vote_response(&mut self,
+                         from: ServerId,
+                         response: pre_vote_response::Reader,
+                         actions: &mut Actions) {
+        scoped_debug!("PreVoteResponse from peer {}", from);
+
+        let local_term = self.current_term();
+        let voter_term = Term::from(response.get_term());
+
+        if local_term < voter_term {
+            // The voter is in a newer term, so the election could not be won. Adopt the term
+            // without disturbing the voter.
+            scoped_info!("received PreVoteResponse from Consensus {{ id: {}, term: {} }} with \
+                         newer term; transitioning to Follower",
+                         from,
+                         voter_term);
+            self.transition_to_follower(voter_term, from, false, actions);
+        } else if local_term > voter_term || !self.is_pre_candidate() {
+            // Ignore this message; it came from a previous pre-vote round.
+        } else if let Ok(pre_vote_response::Granted(_)) = response.which() {
+            self.candidate_state.write().unwrap().record_vote(from);
+            if self.has_vote_quorum() {
+                scoped_info!("pre-vote for term {} won; transitioning to Candidate",
+                             local_term + 1);
+                self.transition_to_candidate(actions);
+            }
+        }
+    }
+
+    /// Returns whether a candidate whose latest entry has the given term and index holds a log at
+    /// least as up-to-date as this consensus (§5.4.1): its latest entry is of a later term, or of
+    /// the same term and at least as far along.
+    fn is_up_to_date(&self, candidate_log_term: Term, candidate_log_index: LogIndex) -> bool {
+        let latest_log_term = self.latest_log_term();
+        candidate_log_term > latest_log_term ||
+        (candidate_log_term == latest_log_term && candidate_log_index >= self.latest_log_index())
+    }
+
+    /// Returns whether this consensus is the leader or has heard from the leader within the
+    /// minimum election timeout.
+    fn leader_active(&self) -> bool {
+        self.is_leader() ||
+        self.leader_contact.map_or(false, |contact| {
+            contact.elapsed() < Duration::from_millis(self.config.election_timeout_min)
+        })
+    }
+
+    /// Applies a peer request vote request to the consensus state machine.
+    fn request_vote_request(&mut self,
+                            candidate: ServerId,
+                            request: request_vote_request::Reader,
+                            actions: &mut Actions) {
+
+        let candidate_term = Term(request.get_term());
+        let candidate_log_term = Term(request.get_last_log_term());
+        let candidate_log_index = LogIndex(request.get_last_log_index());
+        scoped_debug!("RequestVoteRequest from Consensus {{ id: {}, term: {}, latest_log_term: \
+                       {}, latest_log_index: {} }}",
+                      &candidate,
+                      candidate_term,
+                      candidate_log_term,
+                      candidate_log_index);
+        let local_term = self.current_term();
+
+        let new_local_term = if candidate_term > local_term {
+            scoped_info!("received RequestVoteRequest from Consensus {{ id: {}, term: {} }} \
+                         with newer term; transitioning to Follower",
+                         candidate,
+                         candidate_term);
+            self.transition_to_follower(candidate_term, candidate, false, actions);
+            candidate_term
         } else {
             local_term
         };
 
         let message = if candidate_term < local_term {
             messages::request_vote_response_stale_term(new_local_term, &self.lid)
-        } else if candidate_log_term < self.latest_log_term() ||
-                                candidate_log_index < self.latest_log_index() {
+        } else if !self.is_up_to_date(candidate_log_term, candidate_log_index) {
             messages::request_vote_response_inconsistent_log(new_local_term, &self.lid)
         } else {
             match self.log.voted_for().unwrap() {
@@ -707,7 +1448,6 @@ impl<L, M> Consensus<L, M>
         let local_term = self.current_term();
         let voter_term = Term::from(response.get_term());
 
-        let majority = self.majority();
         if local_term < voter_term {
             // Responder has a higher term number. The election is compromised; abandon it and
             // revert to follower state with the updated term number. Any further responses we
@@ -719,7 +1459,7 @@ impl<L, M> Consensus<L, M>
                          with newer term; transitioning to Follower",
                          from,
                          voter_term);
-            self.transition_to_follower(voter_term, from, actions);
+            self.transition_to_follower(voter_term, from, false, actions);
         } else if local_term > voter_term {
             // Ignore this message; it came from a previous election cycle.
         } else if self.is_candidate() {
@@ -728,7 +1468,7 @@ impl<L, M> Consensus<L, M>
                 {
                     self.candidate_state.write().unwrap().record_vote(from);
                 }
-                if self.candidate_state.read().unwrap().count_votes() >= majority {
+                if self.has_vote_quorum() {
                     scoped_info!("election for term {} won; transitioning to Leader",
                                  local_term);
                     self.transition_to_leader(actions);
@@ -743,100 +1483,191 @@ impl<L, M> Consensus<L, M>
                             request: proposal_request::Reader,
                             actions: &mut Actions) {
 
-        if self.is_candidate() ||
-           (self.is_follower() && self.follower_state.read().unwrap().leader.is_none()) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if self.leader_state.read().unwrap().transfer.is_some() {
+            // Leadership is being handed over; the client retries once the new leader is known.
             actions.client_messages
                 .push((from, messages::command_response_unknown_leader(self.lid)));
-        } else if self.is_follower() {
-            let message = messages::command_response_not_leader(&self.peers[&self.follower_state
-                                                                    .read()
-                                                                    .unwrap()
-                                                                    .leader
-                                                                    .unwrap()],
-                                                                self.lid);
-            actions.client_messages.push((from, message));
         } else if let Ok(entry) = request.get_entry() {
-            let prev_log_index = self.latest_log_index();
-            let prev_log_term = self.latest_log_term();
-            let term = self.current_term();
-            let log_index = prev_log_index + 1;
-            self.log.append_entries(log_index, &[(term, entry)]).unwrap();
+            let session = request.get_session()
+                .ok()
+                .and_then(|session| TransactionId::from_bytes(session).ok());
+            let entry = match session {
+                Some(session) if self.leader_state
+                    .read()
+                    .unwrap()
+                    .timed_out
+                    .contains_key(&session) => {
+                    let message = messages::command_transaction_failure(TransactionError::TimedOut,
+                                                                        self.lid);
+                    actions.client_messages.push((from, message));
+                    return;
+                }
+                Some(session) if self.leader_state
+                    .read()
+                    .unwrap()
+                    .transactions
+                    .contains_key(&session) => {
+                    // Every request within the transaction defers its timeout.
+                    actions.timeouts.push(ConsensusTimeout::Transaction(session, self.lid));
+                    Payload::TransactionCommand(session, entry.to_vec(), proposal_id(request))
+                }
+                _ => Payload::Command(entry.to_vec(), proposal_id(request)),
+            };
+            let entry = entry.to_bytes();
+            let log_index = self.append_entry(&entry);
             self.leader_state.write().unwrap().proposals.push_back((from, log_index));
-            if self.peers.is_empty() {
+            if self.voting_peers().is_empty() {
                 scoped_debug!("ProposalRequest from client {}: entry {}", from, log_index);
                 self.advance_commit_index(actions);
             } else {
-                scoped_debug!("ProposalRequest from client {}: sending entry {} to peers",
+                scoped_debug!("ProposalRequest from client {}: entry {} is sent to peers with \
+                              the next flush",
                               from,
                               log_index);
-                let message = messages::append_entries_request(term,
-                                                               prev_log_index,
-                                                               prev_log_term,
-                                                               &[(term, entry)],
-                                                               self.commit_index,
-                                                               &self.lid);
-                let mut leader_state = self.leader_state.write().unwrap();
-                for &peer in self.peers.keys() {
-                    if leader_state.next_index(&peer) == log_index {
-                        actions.peer_messages.push((peer, message.clone()));
-                        leader_state.set_next_index(peer, log_index + 1);
-                    }
-                }
             }
         } else {
             panic!("ProposalRequest: no entry given")
         }
     }
 
-    /// Starts new transaction
-    fn transaction_begin(&mut self, _: ServerId, session: TransactionId, _: &mut Actions) {
-        if !self.is_leader() {
-            if !self.transaction.is_active {
-                self.transaction.begin(session,
-                                       self.commit_index,
-                                       self.last_applied,
-                                       Some(self.follower_state.read().unwrap().min_index));
-            } else {
-                scoped_warn!("A transaction is already running");
-            }
-        }
+    /// Appends `entry` to the log in the current term. Returns the index of the entry. The entry
+    /// is sent to the peers by the next `flush()`.
+    fn append_entry(&mut self, entry: &[u8]) -> LogIndex {
+        let log_index = self.latest_log_index() + 1;
+        self.log.append_entries(log_index, &[(self.current_term(), entry)]).unwrap();
+        self.unflushed = true;
+        log_index
     }
 
-    /// Ends the current transaction successfully
-    fn transaction_commit(&mut self, _: ServerId, session: TransactionId, _: &mut Actions) {
-        if self.transaction.is_active {
-            assert_eq!(self.transaction.session.expect("No TransactionSession defined"),
-                       session);
-            self.transaction.end();
-        } else {
-            scoped_warn!("Received TransactionCommit but no transaction is currently running");
+    /// Sends the entries appended since the last flush to the peers. The `Server` flushes its
+    /// logs at the end of every event loop turn, so that the proposals received during a turn
+    /// are batched into a single request per peer.
+    pub fn flush(&mut self, actions: &mut Actions) {
+        let unflushed = mem::replace(&mut self.unflushed, false);
+        if !unflushed || !self.is_leader() {
+            return;
+        }
+        let mut leader_state = self.leader_state.write().unwrap();
+        for peer in self.replication_peers() {
+            self.replicate(peer, &mut leader_state, actions);
         }
-
     }
 
-    /// Ends the current transaction and rolls all messages in this transaction back
-    fn transaction_rollback(&mut self, _: ServerId, _: TransactionId, _: &mut Actions) {
-        if self.transaction.is_active {
-            let (commit_index, last_applied, follower_state_min) = self.transaction
-                .rollback()
-                .unwrap();
-            self.follower_state.write().unwrap().min_index = follower_state_min.unwrap();
-            self.commit_index = commit_index;
-            self.last_applied = last_applied;
+    /// Answers a client request received while not the leader with the address of the known
+    /// leader, if any.
+    fn redirect_to_leader(&self, from: ClientId, actions: &mut Actions) {
+        let leader = if self.is_follower() {
+            self.follower_state.read().unwrap().leader
+        } else {
+            None
+        };
+        let message = match leader {
+            Some(leader) if self.peers.contains_key(&leader) => {
+                messages::command_response_not_leader(&self.peers[&leader], self.lid)
+            }
+            _ => messages::command_response_unknown_leader(self.lid),
+        };
+        actions.client_messages.push((from, message));
+    }
 
-            {
-                let entries_failed = self.log.rollback(commit_index).unwrap();
+    /// Returns the transactions left running by the log: the transactions running as of the last
+    /// applied entry, updated by the transaction entries after it. Each transaction comes with
+    /// its coordinator log if it is prepared.
+    fn find_open_transactions(&self) -> HashMap<TransactionId, Option<LogId>> {
+        let mut transactions: HashMap<TransactionId, Option<LogId>> = self.transactions
+            .sessions()
+            .into_iter()
+            .map(|session| (session, self.transactions.prepared(&session)))
+            .collect();
+        let mut index = self.last_applied + 1;
+        while index <= self.latest_log_index() {
+            let (_, entry) = self.log.entry(index).unwrap();
+            match Payload::from_bytes(entry).expect("unable to decode log entry") {
+                Payload::TransactionBegin(session) => {
+                    transactions.insert(session, None);
+                }
+                Payload::TransactionPrepare(session, coordinator) => {
+                    if let Some(prepared) = transactions.get_mut(&session) {
+                        *prepared = Some(coordinator);
+                    }
+                }
+                Payload::TransactionCommit(session) |
+                Payload::TransactionRollback(session) => {
+                    transactions.remove(&session);
+                }
+                _ => (),
+            }
+            index = index + 1;
+        }
+        transactions
+    }
 
-                for &(_, ref command) in entries_failed.iter().rev() {
-                    self.state_machine.write().unwrap().revert(command.as_slice());
+    /// Appends a transaction entry on behalf of the client, if any, which is answered once the
+    /// entry is committed.
+    fn append_transaction_entry(&mut self,
+                                from: Option<ClientId>,
+                                payload: Payload,
+                                actions: &mut Actions) {
+        if self.leader_state.read().unwrap().transfer.is_some() {
+            if let Some(from) = from {
+                actions.client_messages
+                    .push((from, messages::command_response_unknown_leader(self.lid)));
+            }
+            return;
+        }
+        scoped_debug!("appending transaction entry {:?} for client {:?}", payload, from);
+        let log_index = self.append_entry(&payload.to_bytes());
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+            match payload {
+                Payload::TransactionBegin(session) => {
+                    leader_state.transactions.insert(session, from);
+                    actions.timeouts.push(ConsensusTimeout::Transaction(session, self.lid));
+                }
+                Payload::TransactionPrepare(session, coordinator) => {
+                    leader_state.prepared.insert(session, coordinator);
                 }
+                Payload::TransactionSavepoint(session, _) |
+                Payload::TransactionRollbackTo(session, _) => {
+                    // Every request within the transaction defers its timeout.
+                    actions.timeouts.push(ConsensusTimeout::Transaction(session, self.lid));
+                }
+                Payload::TransactionCommit(session) |
+                Payload::TransactionRollback(session) => {
+                    leader_state.transactions.remove(&session);
+                    leader_state.prepared.remove(&session);
+                }
+                _ => (),
             }
+            if let Some(from) = from {
+                leader_state.proposals.push_back((from, log_index));
+            }
+        }
+        if self.voting_peers().is_empty() {
+            self.advance_commit_index(actions);
+        }
+    }
 
-            self.log.truncate(commit_index).unwrap();
-            self.state_machine.write().unwrap().rollback();
+    /// Answers a request changing or ending the transaction if the transaction is not running.
+    /// Returns whether the request was answered.
+    fn reject_transaction_end(&self,
+                              from: ClientId,
+                              session: TransactionId,
+                              actions: &mut Actions)
+                              -> bool {
+        let mut leader_state = self.leader_state.write().unwrap();
+        let error = if leader_state.timed_out.remove(&session).is_some() {
+            TransactionError::TimedOut
+        } else if !leader_state.transactions.contains_key(&session) {
+            TransactionError::NotActive
         } else {
-            scoped_warn!("Cannot rollback; no transaction running");
-        }
+            return false;
+        };
+        let message = messages::command_transaction_failure(error, self.lid);
+        actions.client_messages.push((from, message));
+        true
     }
 
     /// Client starts new transaction
@@ -844,656 +1675,3197 @@ impl<L, M> Consensus<L, M>
                                 from: ClientId,
                                 session: TransactionId,
                                 actions: &mut Actions) {
-        if self.is_leader() {
-            if !self.transaction.is_active {
-                self.transaction
-                    .begin(session, self.commit_index, self.last_applied, None)
-                    .unwrap();
-                self.transaction.broadcast_begin(self.lid, actions);
-
-                let message = messages::command_transaction_success(&session.as_bytes(), self.lid);
-
-                actions.client_messages.push((from, message));
-            } else {
-                let message =
-                    messages::command_transaction_failure(transaction::TransactionError::NotActive,
-                                                          self.lid);
-
-                actions.client_messages.push((from, message));
-            }
-        } else {
-            let message = messages::command_response_not_leader(&self.peers[&self.follower_state
-                                                                    .read()
-                                                                    .unwrap()
-                                                                    .leader
-                                                                    .unwrap()],
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if self.leader_state.read().unwrap().transactions.contains_key(&session) {
+            let message = messages::command_transaction_failure(TransactionError::AlreadyActive,
                                                                 self.lid);
             actions.client_messages.push((from, message));
+        } else {
+            self.leader_state.write().unwrap().timed_out.remove(&session);
+            self.append_transaction_entry(Some(from), Payload::TransactionBegin(session), actions);
         }
     }
 
     /// Client ends transaction
-    fn client_transaction_commit(&mut self, from: ClientId, actions: &mut Actions) {
-        if self.is_leader() {
-            if self.transaction.is_active {
-                self.transaction.broadcast_end(self.lid, actions);
-                self.transaction.end().unwrap();
-
-                let message =
-                    messages::command_transaction_success(b"Transaction has been stopped",
-                                                          self.lid);
-
-                actions.client_messages.push((from, message));
-            } else {
-                let message =
-                    messages::command_transaction_failure(transaction::TransactionError::AlreadyActive,
-                                                          self.lid);
-                actions.client_messages.push((from, message));
-
-            }
-
-        } else {
-            let message = messages::command_response_not_leader(&self.peers[&self.follower_state
-                                                                    .read()
-                                                                    .unwrap()
-                                                                    .leader
-                                                                    .unwrap()],
-                                                                self.lid);
-            actions.client_messages.push((from, message));
+    fn client_transaction_commit(&mut self,
+                                 from: ClientId,
+                                 session: TransactionId,
+                                 actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if !self.reject_transaction_end(from, session, actions) {
+            self.append_transaction_entry(Some(from), Payload::TransactionCommit(session), actions);
         }
     }
 
     /// Client rollback transaction
-    fn client_transaction_rollback(&mut self, from: ClientId, actions: &mut Actions) {
-        if self.is_leader() {
-            if self.transaction.is_active {
-                self.transaction.broadcast_rollback(self.lid, actions);
-                let (commit_index, last_applied, _) = self.transaction.rollback().unwrap();
-                self.commit_index = commit_index;
-                self.last_applied = last_applied;
-                self.log.rollback(commit_index).expect("Transaction rollback failed");
-
-                let message = messages::command_transaction_success(b"", self.lid);
-
-                let mut leader_state = self.leader_state.write().unwrap();
-                for &peer in self.peers.keys() {
-                    leader_state.set_next_index(peer, commit_index + 1);
-                }
-
-                {
-                    let entries_failed = self.log.rollback(commit_index).unwrap();
+    fn client_transaction_rollback(&mut self,
+                                   from: ClientId,
+                                   session: TransactionId,
+                                   actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if !self.reject_transaction_end(from, session, actions) {
+            self.append_transaction_entry(Some(from),
+                                          Payload::TransactionRollback(session),
+                                          actions);
+        }
+    }
 
-                    for &(_, ref command) in entries_failed.iter().rev() {
-                        self.state_machine.write().unwrap().revert(command.as_slice());
-                    }
-                }
+    /// Client sets a savepoint within the transaction
+    fn client_transaction_savepoint(&mut self,
+                                    from: ClientId,
+                                    session: TransactionId,
+                                    name: String,
+                                    actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if !self.reject_transaction_end(from, session, actions) {
+            self.append_transaction_entry(Some(from),
+                                          Payload::TransactionSavepoint(session, name),
+                                          actions);
+        }
+    }
 
-                self.log.truncate(commit_index).unwrap();
-                self.state_machine.write().unwrap().rollback();
+    /// Client drops the commands of the transaction since a savepoint
+    fn client_transaction_rollback_to(&mut self,
+                                      from: ClientId,
+                                      session: TransactionId,
+                                      name: String,
+                                      actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if !self.reject_transaction_end(from, session, actions) {
+            self.append_transaction_entry(Some(from),
+                                          Payload::TransactionRollbackTo(session, name),
+                                          actions);
+        }
+    }
 
-                actions.client_messages.push((from, message));
-            } else {
-                let message =
-                    messages::command_transaction_failure(transaction::TransactionError::AlreadyActive,
-                                                          self.lid);
-                actions.client_messages.push((from, message));
+    /// Client prepares the transaction to commit as part of a transaction spanning several logs
+    fn client_transaction_prepare(&mut self,
+                                  from: ClientId,
+                                  session: TransactionId,
+                                  coordinator: LogId,
+                                  actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if !self.reject_transaction_end(from, session, actions) {
+            self.append_transaction_entry(Some(from),
+                                          Payload::TransactionPrepare(session, coordinator),
+                                          actions);
+        }
+    }
 
-            }
+    /// Client decides whether to commit a transaction spanning several logs, coordinated by this
+    /// log
+    fn client_transaction_decide(&mut self,
+                                 from: ClientId,
+                                 session: TransactionId,
+                                 commit: bool,
+                                 actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
         } else {
-            let message = messages::command_response_not_leader(&self.peers[&self.follower_state
-                                                                    .read()
-                                                                    .unwrap()
-                                                                    .leader
-                                                                    .unwrap()],
-                                                                self.lid);
-            actions.client_messages.push((from, message));
+            self.append_transaction_entry(Some(from),
+                                          Payload::TransactionDecision(session, commit),
+                                          actions);
         }
     }
 
-
-    /// Applies a client query to the state machine.
-    pub fn query_request(&mut self,
-                         from: ClientId,
-                         request: query_request::Reader,
-                         actions: &mut Actions) {
-
-        if self.is_candidate() ||
-           (self.is_follower() && self.follower_state.read().unwrap().leader.is_none()) {
+    /// Applies a client request to create or drop a log. This consensus must serve the metadata
+    /// log.
+    pub fn log_change_request(&mut self,
+                              from: ClientId,
+                              payload: Payload,
+                              actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
+        } else if self.leader_state.read().unwrap().transfer.is_some() {
             actions.client_messages
                 .push((from, messages::command_response_unknown_leader(self.lid)));
         } else {
-            // TODO: This is probably not exactly safe.
-            let query = request.get_query().unwrap();
-            let result = self.state_machine.read().unwrap().query(query);
-            let message = messages::command_response_success(&result, self.lid);
-            actions.client_messages.push((from, message));
+            scoped_debug!("log change {:?} from client {}", payload, from);
+            let log_index = self.append_entry(&payload.to_bytes());
+            self.leader_state.write().unwrap().proposals.push_back((from, log_index));
+            if self.voting_peers().is_empty() {
+                self.advance_commit_index(actions);
+            }
         }
     }
 
-    fn into_reader<C>(message: &Builder<C>) -> Reader<OwnedSegments>
-        where C: Allocator
-    {
-        let mut buf = Cursor::new(Vec::new());
-
-        serialize::write_message(&mut buf, message).unwrap();
-        buf.set_position(0);
-        serialize::read_message(&mut buf, ReaderOptions::new()).unwrap()
-    }
-
-    /// Triggers a heartbeat timeout for the peer.
-    fn heartbeat_timeout(&mut self, peer: ServerId, actions: &mut Actions) {
-        scoped_assert!(self.is_leader());
-        scoped_debug!("HeartbeatTimeout for peer: {}", peer);
-        let mut message = Builder::new_default();
-        {
-            let mut request = message.init_root::<message::Builder>();
-            request.set_log_id(&self.lid.as_bytes());
-            let mut request = request.init_append_entries_request();
-            request.set_term(self.current_term().as_u64());
-            request.set_prev_log_index(self.latest_log_index().as_u64());
-            request.set_prev_log_term(self.log.latest_log_term().unwrap().as_u64());
-            request.set_leader_commit(self.commit_index.as_u64());
-            request.init_entries(0);
+    /// Answers the proposals and reads still waiting on the log, which is being dropped.
+    pub fn close(&mut self, actions: &mut Actions) {
+        let proposals: Vec<(ClientId, LogIndex)> =
+            self.leader_state.write().unwrap().proposals.drain(..).collect();
+        for (client, _) in proposals {
+            let message = messages::command_response_failure(b"The log was dropped", self.lid);
+            actions.client_messages.push((client, message));
         }
-        let message = Rc::new(message);
-        actions.peer_messages.push((peer, message.clone()));
+        self.fail_reads(actions);
     }
 
-    /// Triggers an election timeout.
-    fn election_timeout(&mut self, actions: &mut Actions) {
-        scoped_assert!(!self.is_leader());
-        if self.peers.is_empty() {
-            // Solitary replica special case; jump straight to Leader state.
-            scoped_info!("ElectionTimeout: transitioning to Leader");
-            scoped_assert!(self.is_follower());
-            scoped_assert!(self.log.voted_for().unwrap().is_none());
-            self.log.inc_current_term().unwrap();
-            self.log.set_voted_for(Some(self.id)).unwrap();
-            let latest_log_index = self.latest_log_index();
-            self.state = ConsensusState::Leader;
-            self.leader_state.write().unwrap().reinitialize(latest_log_index);
+    /// Client asks how the transaction stands. The leader answers like a query, once it has
+    /// confirmed that it still leads, so that a deposed leader does not answer from stale state.
+    fn client_transaction_status(&mut self,
+                                 from: ClientId,
+                                 session: TransactionId,
+                                 actions: &mut Actions) {
+        if !self.is_leader() {
+            self.redirect_to_leader(from, actions);
         } else {
-            scoped_info!("ElectionTimeout: transitioning to Candidate");
-            self.transition_to_candidate(actions);
+            scoped_debug!("TransactionStatus from client {} for transaction {}", from, session);
+            self.start_read(ReadSource::TransactionStatus(from, session), actions);
         }
     }
 
-    /// Transitions this consensus state machine to Leader state.
-    fn transition_to_leader(&mut self, actions: &mut Actions) {
-        scoped_trace!("transitioning to Leader");
-        let current_term = self.current_term();
-        let latest_log_index = self.latest_log_index();
-        let latest_log_term = self.log.latest_log_term().unwrap();
-        self.state = ConsensusState::Leader;
-        self.leader_state.write().unwrap().reinitialize(latest_log_index);
+    /// Applies a request to abort an in-doubt transaction coordinated by this log.
+    fn transaction_abort_request(&mut self,
+                                 from: ServerId,
+                                 request: transaction_abort::Reader,
+                                 actions: &mut Actions) {
+        let session = match parse_session(request.get_session()) {
+            Some(session) => session,
+            None => {
+                scoped_warn!("TransactionAbort from peer {}: malformed transaction", from);
+                return;
+            }
+        };
+        scoped_debug!("TransactionAbort from peer {} for transaction {}", from, session);
+        self.request_abort(session, actions);
+    }
 
-        let message = messages::append_entries_request(current_term,
-                                                       latest_log_index,
-                                                       latest_log_term,
-                                                       &[],
-                                                       self.commit_index,
-                                                       &self.lid);
-        for &peer in self.peers().keys() {
-            actions.peer_messages.push((peer, message.clone()));
+    /// Returns the coordinator log of the transaction if this consensus leads the log and the
+    /// transaction is prepared, and so waits for the decision of the coordinator log.
+    pub fn in_doubt(&self, session: &TransactionId) -> Option<LogId> {
+        if self.is_leader() {
+            self.leader_state.read().unwrap().prepared.get(session).cloned()
+        } else {
+            None
         }
+    }
 
-        // reset transaction
-
-        if self.transaction.is_active {
-            self.transaction.broadcast_rollback(self.lid, actions);
-
-            let (commit_index, last_applied, _) = self.transaction
-                .rollback()
-                .unwrap();
-            self.commit_index = commit_index;
-            self.last_applied = last_applied;
-
-            {
-                let entries_failed = self.log.rollback(commit_index).unwrap();
+    /// Returns the decision whether to commit the transaction, if one has been recorded in this
+    /// log as coordinator log and applied.
+    pub fn decision(&self, session: &TransactionId) -> Option<bool> {
+        self.transactions.decision(session)
+    }
 
-                for &(_, ref command) in entries_failed.iter().rev() {
-                    self.state_machine.write().unwrap().revert(command.as_slice());
-                }
-            }
+    /// Ends the prepared transaction as decided by its coordinator log.
+    pub fn resolve_transaction(&mut self,
+                               session: TransactionId,
+                               commit: bool,
+                               actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        if self.in_doubt(&session).is_none() {
+            return;
         }
+        scoped_info!("resolving in-doubt transaction {}: commit: {}", session, commit);
+        let payload = if commit {
+            Payload::TransactionCommit(session)
+        } else {
+            Payload::TransactionRollback(session)
+        };
+        self.append_transaction_entry(None, payload, actions);
+    }
 
-        actions.clear_timeouts.push(self.lid);
-        actions.clear_peer_messages = true;
+    /// Asks this log, as coordinator log of the transaction, to decide to abort the transaction
+    /// unless it has decided already. A follower forwards the request to its leader.
+    pub fn request_abort(&mut self, session: TransactionId, actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        if self.transactions.decision(&session).is_some() {
+            return;
+        }
+        if self.is_leader() {
+            self.append_transaction_entry(None,
+                                          Payload::TransactionDecision(session, false),
+                                          actions);
+        } else if let Some(leader) = self.follower_state.read().unwrap().leader {
+            actions.peer_messages.push((leader, messages::transaction_abort(session, &self.lid)));
+        }
     }
 
-    /// Transitions the consensus state machine to Candidate state.
-    fn transition_to_candidate(&mut self, actions: &mut Actions) {
-        scoped_trace!("transitioning to Candidate");
-        self.log.inc_current_term().unwrap();
+    /// Rolls back the transaction, which has gone without a request for the transaction timeout.
+    /// Later requests within the transaction fail with `TransactionError::TimedOut`. A prepared
+    /// transaction is left to the decision of its coordinator log.
+    fn transaction_timeout(&mut self, session: TransactionId, actions: &mut Actions) {
+        if !self.is_leader() ||
+           !self.leader_state.read().unwrap().transactions.contains_key(&session) {
+            return;
+        }
+        if self.leader_state.read().unwrap().prepared.contains_key(&session) ||
+           self.leader_state.read().unwrap().transfer.is_some() {
+            // The transaction is handled again later, unless it ends meanwhile.
+            actions.timeouts.push(ConsensusTimeout::Transaction(session, self.lid));
+            return;
+        }
+        scoped_info!("transaction {} timed out; rolling it back", session);
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+            let client = leader_state.transactions[&session];
+            leader_state.timed_out.insert(session, client);
+        }
+        self.append_transaction_entry(None, Payload::TransactionRollback(session), actions);
+    }
+
+    /// Notifies the consensus state machine that the connection to the client has been reset.
+    /// The transactions the client began through this leader are rolled back, since nobody is
+    /// left to end them, unless they are prepared. The client is no longer told about the
+    /// transactions which timed out.
+    pub fn client_connection_reset(&mut self, client: ClientId, actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        if !self.is_leader() {
+            return;
+        }
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+            let forgotten: Vec<TransactionId> = leader_state.timed_out
+                .iter()
+                .filter(|&(_, &owner)| owner == Some(client))
+                .map(|(&session, _)| session)
+                .collect();
+            for session in forgotten {
+                leader_state.timed_out.remove(&session);
+            }
+        }
+        if self.leader_state.read().unwrap().transfer.is_some() {
+            return;
+        }
+        let sessions: Vec<TransactionId> = {
+            let leader_state = self.leader_state.read().unwrap();
+            leader_state.transactions
+                .iter()
+                .filter(|&(session, &owner)| {
+                    owner == Some(client) && !leader_state.prepared.contains_key(session)
+                })
+                .map(|(&session, _)| session)
+                .collect()
+        };
+        for session in sessions {
+            scoped_info!("client {} disconnected; rolling back transaction {}", client, session);
+            self.append_transaction_entry(None, Payload::TransactionRollback(session), actions);
+        }
+    }
+
+    /// Applies a client query to the state machine, once the state machine reflects every entry
+    /// committed before the query was received (the ReadIndex algorithm, section 6.4 of the Raft
+    /// dissertation). The leader confirms its leadership with a round of heartbeats; a follower
+    /// asks the leader for the read index.
+    pub fn query_request(&mut self,
+                         from: ClientId,
+                         request: query_request::Reader,
+                         actions: &mut Actions) {
+        let query = request.get_query().unwrap().to_vec();
+        let consistency = request.get_consistency().unwrap_or(ReadConsistency::ReadIndex);
+        let local = match consistency {
+            ReadConsistency::ReadIndex => false,
+            ReadConsistency::Lease => {
+                self.is_leader() && self.has_lease() &&
+                self.log_term(self.commit_index) == self.current_term()
+            }
+            ReadConsistency::Stale => true,
+        };
+        if local {
+            scoped_debug!("QueryRequest from client {}: answering from the local state machine",
+                          from);
+            let result = self.state_machine.read().unwrap().query(&query);
+            actions.client_messages
+                .push((from, messages::command_response_success(&result, self.lid)));
+            return;
+        }
+        if self.is_leader() {
+            self.start_read(ReadSource::Client(from, query), actions);
+            return;
+        }
+
+        let leader = if self.is_follower() {
+            self.follower_state.read().unwrap().leader
+        } else {
+            None
+        };
+        match leader {
+            Some(leader) => {
+                let id = self.follower_state.write().unwrap().add_read(from, query);
+                scoped_debug!("QueryRequest from client {}: forwarding read {} to leader {}",
+                              from,
+                              id,
+                              leader);
+                let message = messages::read_index_request(self.current_term(), id, &self.lid);
+                actions.peer_messages.push((leader, message));
+                actions.timeouts.push(ConsensusTimeout::Read(id, self.lid));
+            }
+            None => {
+                actions.client_messages
+                    .push((from, messages::command_response_unknown_leader(self.lid)))
+            }
+        }
+    }
+
+    /// Applies a read index request from a follower. The follower is answered once the leadership
+    /// of the follower's term is confirmed.
+    fn read_index_request(&mut self,
+                          from: ServerId,
+                          request: read_index_request::Reader,
+                          actions: &mut Actions) {
+        let term = Term(request.get_term());
+        let id = request.get_id();
+        if self.is_leader() && term == self.current_term() {
+            self.start_read(ReadSource::Peer(from, id), actions);
+        } else {
+            scoped_debug!("ReadIndexRequest from peer {} in term {}: not the leader", from, term);
+            let message =
+                messages::read_index_response_not_leader(self.current_term(), id, &self.lid);
+            actions.peer_messages.push((from, message));
+        }
+    }
+
+    /// Applies a read index response from the leader to the forwarded query it answers.
+    fn read_index_response(&mut self,
+                           from: ServerId,
+                           response: read_index_response::Reader,
+                           actions: &mut Actions) {
+        let id = response.get_id();
+        let term = Term(response.get_term());
+        let index = match response.which() {
+            Ok(read_index_response::Which::Success(index)) => Some(LogIndex(index)),
+            _ => None,
+        };
+        match index {
+            Some(index) if self.is_follower() && term == self.current_term() => {
+                scoped_debug!("ReadIndexResponse from peer {}: read {} at index {}",
+                              from,
+                              id,
+                              index);
+                if let Some(read) = self.follower_state.write().unwrap().reads.get_mut(&id) {
+                    read.2 = Some(index);
+                }
+                self.serve_reads(actions);
+            }
+            _ => {
+                scoped_debug!("ReadIndexResponse from peer {}: read {} failed", from, id);
+                let read = self.follower_state.write().unwrap().reads.remove(&id);
+                if let Some((client, _, _)) = read {
+                    actions.client_messages
+                        .push((client, messages::command_response_unknown_leader(self.lid)));
+                }
+            }
+        }
+    }
+
+    /// Fails the forwarded query if the leader has not returned its read index yet, since the
+    /// request or the response may have been lost.
+    fn read_timeout(&mut self, id: u64, actions: &mut Actions) {
+        let mut follower_state = self.follower_state.write().unwrap();
+        if follower_state.reads.get(&id).map_or(false, |&(_, _, index)| index.is_none()) {
+            scoped_debug!("read {} timed out", id);
+            let (client, _, _) = follower_state.reads.remove(&id).unwrap();
+            actions.client_messages
+                .push((client, messages::command_response_unknown_leader(self.lid)));
+        }
+    }
+
+    /// Queues a read on the leader, and starts a heartbeat round confirming that this consensus
+    /// still leads.
+    fn start_read(&mut self, source: ReadSource, actions: &mut Actions) {
+        let round = {
+            let mut leader_state = self.leader_state.write().unwrap();
+            let round = leader_state.start_round();
+            leader_state.reads.push_back(ReadRequest {
+                source: source,
+                index: self.commit_index,
+                round: round,
+            });
+            round
+        };
+        let message = self.heartbeat(round);
+        for peer in self.voting_peers() {
+            actions.peer_messages.push((peer, message.clone()));
+        }
+        self.serve_reads(actions);
+    }
+
+    /// Answers the reads which are ready.
+    ///
+    /// On the leader, a read is ready once a majority has answered a heartbeat round started
+    /// after the read was received. On a follower, a read is ready once the state machine has
+    /// caught up with the read index returned by the leader.
+    fn serve_reads(&mut self, actions: &mut Actions) {
+        if !self.is_leader() {
+            let mut follower_state = self.follower_state.write().unwrap();
+            let ready: Vec<u64> = follower_state.reads
+                .iter()
+                .filter(|&(_, &(_, _, index))| index.map_or(false, |i| i <= self.last_applied))
+                .map(|(&id, _)| id)
+                .collect();
+            for id in ready {
+                let (client, query, _) = follower_state.reads.remove(&id).unwrap();
+                let result = self.state_machine.read().unwrap().query(&query);
+                actions.client_messages
+                    .push((client, messages::command_response_success(&result, self.lid)));
+            }
+            return;
+        }
+
+        // The leader only knows which entries of earlier terms are committed once an entry of
+        // its own term is committed.
+        if self.log_term(self.commit_index) != self.current_term() {
+            return;
+        }
+        let mut leader_state = self.leader_state.write().unwrap();
+        while let Some(round) = leader_state.reads.front().map(|read| read.round) {
+            let confirmed = self.configuration.is_quorum(|members| {
+                let own = if members.contains(&self.id) { 1 } else { 0 };
+                leader_state.count_round_acks(round, members) + own
+            });
+            // Later reads belong to later rounds. The commit index may have moved past the read
+            // index, which only makes the read more recent.
+            if !confirmed || self.last_applied < self.commit_index {
+                break;
+            }
+            let read = leader_state.reads.pop_front().unwrap();
+            scoped_trace!("read at index {} confirmed in round {}", read.index, round);
+            match read.source {
+                ReadSource::Client(client, query) => {
+                    let result = self.state_machine.read().unwrap().query(&query);
+                    actions.client_messages
+                        .push((client, messages::command_response_success(&result, self.lid)));
+                }
+                ReadSource::Peer(peer, id) => {
+                    let message = messages::read_index_response_success(self.current_term(),
+                                                                        id,
+                                                                        self.commit_index,
+                                                                        &self.lid);
+                    actions.peer_messages.push((peer, message));
+                }
+                ReadSource::TransactionStatus(client, session) => {
+                    let status = self.transactions.status(&session);
+                    scoped_debug!("transaction {} status: {:?}", session, status);
+                    actions.client_messages
+                        .push((client, messages::transaction_status_response(status, self.lid)));
+                }
+            }
+        }
+    }
+
+    /// Answers the queued reads with an unknown leader error, once this consensus can no longer
+    /// serve them.
+    fn fail_reads(&mut self, actions: &mut Actions) {
+        let term = self.current_term();
+        for read in self.leader_state.write().unwrap().reads.drain(..) {
+            match read.source {
+                ReadSource::Client(client, _) |
+                ReadSource::TransactionStatus(client, _) => {
+                    actions.client_messages
+                        .push((client, messages::command_response_unknown_leader(self.lid)));
+                }
+                ReadSource::Peer(peer, id) => {
+                    let message = messages::read_index_response_not_leader(term, id, &self.lid);
+                    actions.peer_messages.push((peer, message));
+                }
+            }
+        }
+        for (_, (client, _, _)) in self.follower_state.write().unwrap().reads.drain() {
+            actions.client_messages
+                .push((client, messages::command_response_unknown_leader(self.lid)));
+        }
+    }
+
+    /// Triggers a heartbeat timeout for the peer.
+    fn heartbeat_timeout(&mut self, peer: ServerId, actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        if !self.replication_peers().contains(&peer) {
+            // The peer has been removed from the configuration.
+            return;
+        }
+        scoped_debug!("HeartbeatTimeout for peer: {}", peer);
+        // Every heartbeat starts a round, so that the acknowledgements renew the lease.
+        let round = self.leader_state.write().unwrap().start_round();
+        actions.peer_messages.push((peer, self.heartbeat(round)));
+    }
+
+    /// Schedules the next heartbeat to an idle peer, unless the server sends the heartbeats.
+    fn schedule_heartbeat(&self, peer: ServerId, actions: &mut Actions) {
+        if !self.coalesced_heartbeats {
+            actions.timeouts.push(ConsensusTimeout::Heartbeat(peer, self.lid));
+        }
+    }
+
+    /// Starts a heartbeat round and returns a heartbeat for each peer the log is replicated to,
+    /// for the server to bundle with the heartbeats of other logs. Returns nothing unless this
+    /// consensus leads.
+    pub fn coalesced_heartbeats(&mut self) -> Vec<(ServerId, Heartbeat)> {
+        if !self.is_leader() {
+            return Vec::new();
+        }
+        let term = self.current_term();
+        let first_log_index = self.log.first_log_index().unwrap();
+        let mut leader_state = self.leader_state.write().unwrap();
+        let round = leader_state.start_round();
+        self.replication_peers()
+            .into_iter()
+            .map(|peer| {
+                // The follower has to hold every entry sent to it, so that entries lost with a
+                // reset connection are found missing. Entries not sent yet are not checked.
+                let next_index = leader_state.next_index(&peer);
+                let prev_log_index = if next_index < first_log_index {
+                    self.latest_log_index()
+                } else {
+                    cmp::min(next_index - 1, self.latest_log_index())
+                };
+                let heartbeat = Heartbeat {
+                    term: term,
+                    prev_log_index: prev_log_index,
+                    prev_log_term: self.log_term(prev_log_index),
+                    commit_index: self.commit_index,
+                    round: round,
+                };
+                (peer, heartbeat)
+            })
+            .collect()
+    }
+
+    /// Applies a heartbeat from the leader of the log, like an AppendEntries request without
+    /// entries, and returns the answer.
+    pub fn heartbeat_request(&mut self,
+                             from: ServerId,
+                             heartbeat: Heartbeat,
+                             actions: &mut Actions)
+                             -> HeartbeatResponse {
+        push_log_scope!("{:?}", self);
+        let current_term = self.current_term();
+        let result = if heartbeat.term < current_term {
+            scoped_debug!("Heartbeat from peer {}: stale term {}", from, heartbeat.term);
+            HeartbeatResult::StaleTerm
+        } else {
+            match self.state {
+                ConsensusState::Follower => {
+                    if current_term < heartbeat.term {
+                        self.fail_reads(actions);
+                        self.log.set_current_term(heartbeat.term).unwrap();
+                        self.follower_state.write().unwrap().set_leader(from);
+                    }
+                    self.leader_contact = Some(Instant::now());
+                    actions.clear_timeouts.push(self.lid);
+                    actions.timeouts.push(ConsensusTimeout::Election(self.lid));
+                    match self.check_prev_entry(heartbeat.prev_log_index, heartbeat.prev_log_term) {
+                        Err((conflict_term, first_index)) => {
+                            HeartbeatResult::InconsistentPrevEntry(conflict_term,
+                                                                   first_index,
+                                                                   self.latest_log_index())
+                        }
+                        Ok(()) => {
+                            // The log matches the leader's up to the previous entry.
+                            let commit_index = cmp::min(heartbeat.commit_index,
+                                                        heartbeat.prev_log_index);
+                            if commit_index > self.commit_index {
+                                self.commit_index = commit_index;
+                                self.apply_commits();
+                            }
+                            self.serve_reads(actions);
+                            HeartbeatResult::Success(self.latest_log_index())
+                        }
+                    }
+                }
+                ConsensusState::Leader if heartbeat.term == current_term => {
+                    panic!("{:?}: peer leader {} with matching term {:?} detected.",
+                           self,
+                           from,
+                           current_term);
+                }
+                _ => {
+                    scoped_info!("received Heartbeat from Consensus {{ id: {}, term: {} }} with \
+                                  newer term; transitioning to Follower",
+                                 from,
+                                 heartbeat.term);
+                    self.transition_to_follower(heartbeat.term, from, true, actions);
+                    return self.heartbeat_request(from, heartbeat, actions);
+                }
+            }
+        };
+        HeartbeatResponse {
+            term: self.current_term(),
+            round: heartbeat.round,
+            result: result,
+        }
+    }
+
+    /// Applies a follower's answer to a heartbeat like an AppendEntries response: the answer
+    /// acknowledges the heartbeat round, and a follower missing entries is probed and sent them.
+    /// Entries lost with a reset connection are thereby sent again.
+    pub fn heartbeat_response(&mut self,
+                              from: ServerId,
+                              response: HeartbeatResponse,
+                              actions: &mut Actions) {
+        push_log_scope!("{:?}", self);
+        if self.current_term() < response.term {
+            scoped_info!("HeartbeatResponse from peer {} with newer term: {}; transitioning to \
+                          Follower",
+                         from,
+                         response.term);
+            self.transition_to_follower(response.term, from, false, actions);
+            return;
+        }
+        if self.current_term() > response.term || !self.is_leader() ||
+           !self.replication_peers().contains(&from) {
+            return;
+        }
+        self.leader_state.write().unwrap().ack_round(from, response.round);
+        self.update_quorum_contact();
+        self.serve_reads(actions);
+
+        if self.leader_state.read().unwrap().mode(&from) == ProgressMode::Snapshot {
+            // The follower catches up by installing the snapshot in flight.
+            return;
+        }
+        match response.result {
+            HeartbeatResult::Success(follower_latest_log_index) => {
+                self.follower_matched(from, follower_latest_log_index, actions);
+            }
+            HeartbeatResult::InconsistentPrevEntry(conflict_term, first_index, latest) => {
+                scoped_debug!("HeartbeatResponse from peer {}: missing entries after {}",
+                              from,
+                              latest);
+                self.follower_inconsistent(from, conflict_term, first_index);
+            }
+            HeartbeatResult::StaleTerm => return,
+        }
+        self.continue_replication(from, actions);
+    }
+
+    /// Returns an empty AppendEntries request for the current term, sent in `round`.
+    fn heartbeat(&self, round: u64) -> Rc<Builder<HeapAllocator>> {
+        messages::append_entries_request(self.current_term(),
+                                         self.latest_log_index(),
+                                         self.latest_log_term(),
+                                         &[],
+                                         self.commit_index,
+                                         round,
+                                         &self.lid)
+    }
+
+    /// Triggers an election timeout.
+    fn election_timeout(&mut self, actions: &mut Actions) {
+        scoped_assert!(!self.is_leader());
+        if !self.configuration.contains(&self.id) {
+            // A server which is not a member of the configuration does not campaign.
+            scoped_info!("ElectionTimeout: not a member of the configuration");
+            return;
+        }
+        if self.voting_peers().is_empty() {
+            // Solitary replica special case; jump straight to Leader state.
+            scoped_info!("ElectionTimeout: transitioning to Leader");
+            scoped_assert!(self.is_follower());
+            scoped_assert!(self.log.voted_for().unwrap().is_none());
+            self.log.inc_current_term().unwrap();
+            self.log.set_voted_for(Some(self.id)).unwrap();
+            let latest_log_index = self.latest_log_index();
+            self.state = ConsensusState::Leader;
+            {
+                let mut leader_state = self.leader_state.write().unwrap();
+                leader_state.set_peers(&self.replication_peers());
+                leader_state.reinitialize(latest_log_index);
+            }
+            self.append_noop(actions);
+            actions.timeouts.push(ConsensusTimeout::CheckQuorum(self.lid));
+        } else {
+            scoped_info!("ElectionTimeout: transitioning to PreCandidate");
+            self.transition_to_pre_candidate(actions);
+        }
+    }
+
+    /// Transitions this consensus state machine to Leader state.
+    fn transition_to_leader(&mut self, actions: &mut Actions) {
+        scoped_trace!("transitioning to Leader");
+
+        let latest_log_index = self.latest_log_index();
+        // The transactions left open by the previous leader go on.
+        let open_transactions = self.find_open_transactions();
+        self.state = ConsensusState::Leader;
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+            leader_state.set_peers(&self.replication_peers());
+            leader_state.reinitialize(latest_log_index);
+            // The clients which began them are not known.
+            leader_state.transactions =
+                open_transactions.keys().map(|&session| (session, None)).collect();
+            leader_state.prepared = open_transactions.iter()
+                .filter_map(|(&session, &coordinator)| coordinator.map(|log| (session, log)))
+                .collect();
+        }
+
+        actions.clear_timeouts.push(self.lid);
+        actions.timeouts.push(ConsensusTimeout::CheckQuorum(self.lid));
+        for &session in open_transactions.keys() {
+            actions.timeouts.push(ConsensusTimeout::Transaction(session, self.lid));
+        }
+        actions.clear_peer_messages = true;
+
+        // The no-op doubles as the first heartbeat of the term.
+        self.append_noop(actions);
+    }
+
+    /// Appends a no-op entry in the current term and sends it to the peers. A leader only counts
+    /// replicas of entries from its own term (§5.4.2), so the entries left uncommitted by earlier
+    /// leaders are committed along with the no-op.
+    fn append_noop(&mut self, actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        let entry = Payload::Noop.to_bytes();
+        let index = self.append_entry(&entry);
+        scoped_debug!("appended no-op entry {} for term {}", index, self.current_term());
+        self.flush(actions);
+        if self.voting_peers().is_empty() {
+            self.advance_commit_index(actions);
+        }
+    }
+
+    /// Transitions the consensus state machine to PreCandidate state, polling the peers for
+    /// whether they would vote for this consensus in the next term.
+    fn transition_to_pre_candidate(&mut self, actions: &mut Actions) {
+        scoped_trace!("transitioning to PreCandidate");
+        self.fail_reads(actions);
+        self.state = ConsensusState::PreCandidate;
+        self.leader_contact = None;
+        let mut candidate_state = self.candidate_state.write().unwrap();
+        candidate_state.clear();
+        candidate_state.record_vote(self.id);
+
+        let message = messages::pre_vote_request(self.current_term() + 1,
+                                                 self.latest_log_index(),
+                                                 self.log.latest_log_term().unwrap(),
+                                                 &self.lid);
+
+        for peer in self.voting_peers() {
+            actions.peer_messages.push((peer, message.clone()));
+        }
+        actions.timeouts.push(ConsensusTimeout::Election(self.lid));
+        actions.clear_peer_messages = true;
+    }
+
+    /// Transitions the consensus state machine to Candidate state.
+    fn transition_to_candidate(&mut self, actions: &mut Actions) {
+        scoped_trace!("transitioning to Candidate");
+        self.log.inc_current_term().unwrap();
         self.log.set_voted_for(Some(self.id)).unwrap();
         self.state = ConsensusState::Candidate;
         let mut candidate_state = self.candidate_state.write().unwrap();
         candidate_state.clear();
         candidate_state.record_vote(self.id);
 
-        let message = messages::request_vote_request(self.current_term(),
-                                                     self.latest_log_index(),
-                                                     self.log.latest_log_term().unwrap(),
-                                                     &self.lid);
+        let message = messages::request_vote_request(self.current_term(),
+                                                     self.latest_log_index(),
+                                                     self.log.latest_log_term().unwrap(),
+                                                     &self.lid);
+
+        for peer in self.voting_peers() {
+            actions.peer_messages.push((peer, message.clone()));
+        }
+        actions.timeouts.push(ConsensusTimeout::Election(self.lid));
+        actions.clear_peer_messages = true;
+    }
+
+    /// Advances the commit index and applies committed entries to the state machine.
+    fn advance_commit_index(&mut self, actions: &mut Actions) {
+        scoped_assert!(self.is_leader());
+        {
+            let leader_state = self.leader_state.read().unwrap();
+            // Only entries of the current term are committed by counting replicas; committing
+            // one commits every entry before it (§5.4.2). Terms never decrease along the log, so
+            // the search stops at the first entry of an earlier term.
+            let current_term = self.current_term();
+            let mut index = self.latest_log_index();
+            while index > self.commit_index && self.log_term(index) == current_term {
+                if self.is_replicated(&leader_state, index) {
+                    self.commit_index = index;
+                    scoped_debug!("commit index advanced to {}", self.commit_index);
+                    break;
+                }
+                index = index - 1;
+            }
+        }
+
+        let results = self.apply_commits();
+        {
+            let mut leader_state = self.leader_state.write().unwrap();
+
+            // TODO: Figure out failure condition here.
+            while let Some(&(client, index)) = leader_state.proposals.get(0) {
+                if index <= self.commit_index {
+                    scoped_trace!("responding to client {} for entry {}", client, index);
+                    // We know that there will be an index here since it was commited
+                    // and the index is less than that which has been commited.
+                    let message = match results.get(&index) {
+                        Some(&Ok(ref result)) => {
+                            messages::command_response_success(result, self.lid)
+                        }
+                        Some(&Err(ref error)) => {
+                            messages::command_transaction_failure(error.clone(), self.lid)
+                        }
+                        None => {
+                            messages::command_response_failure(b"superseded by a later proposal",
+                                                               self.lid)
+                        }
+                    };
+                    actions.client_messages.push((client, message));
+                    leader_state.proposals.pop_front();
+                } else {
+                    break;
+                }
+            }
+        }
+        self.serve_reads(actions);
+
+        // Move a membership change on once its configuration entry is committed.
+        if self.configuration_index <= self.commit_index {
+            if self.configuration.is_joint() {
+                let configuration = self.configuration.finish();
+                scoped_info!("joint configuration committed; appending configuration {:?}",
+                             configuration.members);
+                self.append_configuration(configuration, actions);
+            } else if !self.configuration.members.contains(&self.id) {
+                scoped_info!("removed from the configuration; stepping down");
+                self.step_down(actions);
+            }
+        }
+    }
+
+    /// Applies all committed but unapplied log entries to the state machine.  Returns the set of
+    /// return values from the commits applied, or the reason a transaction entry failed.
+    fn apply_commits(&mut self) -> HashMap<LogIndex, Result<Vec<u8>, TransactionError>> {
+        let mut results = HashMap::new();
+        while self.last_applied < self.commit_index {
+            let index = self.last_applied + 1;
+            let payload = match self.log.entry(index) {
+                Ok((_, entry)) => Payload::from_bytes(entry).expect("unable to decode log entry"),
+                Err(_) => break,
+            };
+
+            match payload {
+                Payload::Command(command, proposal) => {
+                    match proposal {
+                        Some(ref proposal) if self.sessions.is_applied(proposal) => {
+                            // A retried proposal is answered with the result of its first
+                            // application, if that is still known.
+                            scoped_debug!("skipping duplicate proposal {:?}", proposal);
+                            if let Some(result) = self.sessions.result(proposal) {
+                                results.insert(index, Ok(result.to_vec()));
+                            }
+                        }
+                        _ if !command.is_empty() => {
+                            let keys = self.state_machine.read().unwrap().keys(&command);
+                            self.transactions.record_write(index, keys);
+                            let result = self.state_machine.write().unwrap().apply(&command);
+                            if let Some(proposal) = proposal {
+                                self.sessions.record(proposal, result.clone());
+                            }
+                            results.insert(index, Ok(result));
+                        }
+                        _ => (),
+                    }
+                }
+                Payload::TransactionCommand(session, command, proposal) => {
+                    match proposal {
+                        Some(ref proposal) if self.sessions.is_applied(proposal) => {
+                            scoped_debug!("skipping duplicate proposal {:?}", proposal);
+                            if self.sessions.result(proposal).is_some() {
+                                results.insert(index, Ok(Vec::new()));
+                            }
+                        }
+                        _ => {
+                            let keys = self.state_machine.read().unwrap().keys(&command);
+                            let result = self.transactions.buffer(session, command, keys);
+                            if let Err(ref error) = result {
+                                scoped_warn!("unable to buffer command of transaction {}: {}",
+                                             session,
+                                             error);
+                            }
+                            // A command which was not buffered fails again when retried.
+                            if let (Some(proposal), &Ok(())) = (proposal, &result) {
+                                self.sessions.record(proposal, Vec::new());
+                            }
+                            results.insert(index, result.map(|_| Vec::new()));
+                        }
+                    }
+                }
+                Payload::TransactionBegin(session) => {
+                    let result = self.transactions
+                        .begin(session, index)
+                        .map(|_| session.as_bytes().to_vec());
+                    if let Err(ref error) = result {
+                        scoped_warn!("unable to begin transaction {}: {}", session, error);
+                    }
+                    results.insert(index, result);
+                }
+                Payload::TransactionCommit(session) => {
+                    let result = self.transactions.commit(session, index).map(|commands| {
+                        let mut state_machine = self.state_machine.write().unwrap();
+                        for command in commands {
+                            state_machine.apply(&command);
+                        }
+                        b"Transaction has been stopped".to_vec()
+                    });
+                    if let Err(ref error) = result {
+                        scoped_debug!("unable to commit transaction {}: {}", session, error);
+                    }
+                    results.insert(index, result);
+                }
+                Payload::TransactionRollback(session) => {
+                    let result = self.transactions.rollback(session).map(|_| Vec::new());
+                    if let Err(ref error) = result {
+                        scoped_warn!("unable to roll back transaction {}: {}", session, error);
+                    }
+                    results.insert(index, result);
+                }
+                Payload::TransactionPrepare(session, coordinator) => {
+                    let result = self.transactions
+                        .prepare(session, coordinator, index)
+                        .map(|_| Vec::new());
+                    if let Err(ref error) = result {
+                        scoped_debug!("unable to prepare transaction {}: {}", session, error);
+                        // The transaction has ended.
+                        let mut leader_state = self.leader_state.write().unwrap();
+                        leader_state.transactions.remove(&session);
+                        leader_state.prepared.remove(&session);
+                    }
+                    results.insert(index, result);
+                }
+                Payload::TransactionDecision(session, commit) => {
+                    let result = match self.transactions.decide(session, commit) {
+                        decision if decision == commit => Ok(Vec::new()),
+                        true => {
+                            Err(TransactionError::Other("The transaction was committed"
+                                .to_string()))
+                        }
+                        false => Err(TransactionError::Aborted),
+                    };
+                    results.insert(index, result);
+                }
+                Payload::TransactionSavepoint(session, name) => {
+                    let result = self.transactions.savepoint(session, name).map(|_| Vec::new());
+                    if let Err(ref error) = result {
+                        scoped_debug!("unable to set savepoint in transaction {}: {}",
+                                      session,
+                                      error);
+                    }
+                    results.insert(index, result);
+                }
+                Payload::TransactionRollbackTo(session, name) => {
+                    let result = self.transactions.rollback_to(session, &name).map(|_| Vec::new());
+                    if let Err(ref error) = result {
+                        scoped_debug!("unable to roll back transaction {} to savepoint {}: {}",
+                                      session,
+                                      name,
+                                      error);
+                    }
+                    results.insert(index, result);
+                }
+                Payload::CreateLog(lid, replicas) => {
+                    let result = if !self.logs.contains_key(&lid) {
+                        scoped_info!("log {:?} created on replicas {:?}", lid, replicas);
+                        self.logs.insert(lid, replicas);
+                        Ok(Vec::new())
+                    } else {
+                        Err(TransactionError::Other(format!("The log {:?} exists already", lid)))
+                    };
+                    results.insert(index, result);
+                }
+                Payload::DropLog(lid) => {
+                    let result = if self.logs.remove(&lid).is_some() {
+                        scoped_info!("log {:?} dropped", lid);
+                        Ok(Vec::new())
+                    } else {
+                        Err(TransactionError::Other(format!("The log {:?} was not created at \
+                                                             runtime",
+                                                            lid)))
+                    };
+                    results.insert(index, result);
+                }
+                // Configuration entries took effect when they were appended.
+                Payload::Configuration(..) |
+                Payload::Noop => (),
+            }
+            self.last_applied = index;
+        }
+        self.compact_log();
+        results
+    }
+
+    /// Snapshots the state machine and discards the applied prefix of the log, once the log holds
+    /// at least `config.snapshot_threshold` applied entries.
+    fn compact_log(&mut self) {
+        let first_index = self.log.first_log_index().unwrap();
+        if self.last_applied < first_index ||
+           self.last_applied - first_index + 1 < self.config.snapshot_threshold {
+            return;
+        }
+
+        scoped_info!("compacting log up to index {}", self.last_applied);
+        let term = self.log_term(self.last_applied);
+        let configuration = if self.configuration_index <= self.last_applied {
+            self.configuration.clone()
+        } else {
+            self.find_configuration(self.last_applied).1
+        };
+        let snapshot = Snapshot {
+            state_machine: self.state_machine.read().unwrap().snapshot(),
+            configuration: configuration,
+            sessions: self.sessions.clone(),
+            transactions: self.transactions.clone(),
+            logs: self.logs.clone(),
+        };
+        self.log.compact(self.last_applied, term, &snapshot.to_bytes()).unwrap();
+    }
+
+    /// Returns the members of the active configurations, which may be elected leader.
+    pub fn voters(&self) -> HashSet<ServerId> {
+        self.configuration.voters()
+    }
+
+    /// Returns the members of the active configurations other than this consensus.
+    fn voting_peers(&self) -> HashSet<ServerId> {
+        let mut peers = self.configuration.voters();
+        peers.remove(&self.id);
+        peers
+    }
+
+    /// Returns the peers the log is replicated to: the voting peers and the learners.
+    fn replication_peers(&self) -> HashSet<ServerId> {
+        let mut peers = self.voting_peers();
+        peers.extend(self.configuration.learners.iter().cloned());
+        peers.remove(&self.id);
+        peers
+    }
+
+    /// Returns whether the candidate has been granted the votes of a majority of each active
+    /// configuration.
+    fn has_vote_quorum(&self) -> bool {
+        let candidate_state = self.candidate_state.read().unwrap();
+        self.configuration.is_quorum(|members| candidate_state.count_votes(members))
+    }
+
+    /// Returns whether the entry at `index` is stored by a majority of each active
+    /// configuration.
+    fn is_replicated(&self, leader_state: &LeaderState, index: LogIndex) -> bool {
+        self.configuration.is_quorum(|members| {
+            let own = if members.contains(&self.id) { 1 } else { 0 };
+            leader_state.count_match_indexes(index, members) + own
+        })
+    }
+
+    /// Makes `configuration`, stored at `index`, the active configuration.
+    fn set_configuration(&mut self, index: LogIndex, configuration: Configuration) {
+        scoped_debug!("configuration at index {}: {:?}", index, configuration);
+        self.configuration = configuration;
+        self.configuration_index = index;
+        if self.is_leader() {
+            let peers = self.replication_peers();
+            self.leader_state.write().unwrap().set_peers(&peers);
+        }
+    }
+
+    /// Updates the active configuration after the entries starting at `from` were replaced.
+    fn refresh_configuration(&mut self, from: LogIndex) {
+        if self.configuration_index >= from {
+            // The entry holding the configuration is gone.
+            self.reload_configuration();
+            return;
+        }
+        let mut index = cmp::max(from, self.log.first_log_index().unwrap());
+        while index <= self.latest_log_index() {
+            let (_, entry) = self.log.entry(index).unwrap();
+            if let Payload::Configuration(configuration) = Payload::from_bytes(entry)
+                .expect("unable to decode log entry") {
+                self.set_configuration(index, configuration);
+            }
+            index = index + 1;
+        }
+    }
+
+    /// Replaces the active configuration with the latest configuration in the log.
+    fn reload_configuration(&mut self) {
+        let latest_log_index = self.latest_log_index();
+        let (index, configuration) = self.find_configuration(latest_log_index);
+        if configuration != self.configuration || index != self.configuration_index {
+            self.set_configuration(index, configuration);
+        }
+    }
+
+    /// Returns the configuration in effect at `index`, and the index it is stored at.
+    fn find_configuration(&self, index: LogIndex) -> (LogIndex, Configuration) {
+        let first_log_index = self.log.first_log_index().unwrap();
+        let mut index = index;
+        while index >= first_log_index && index > LogIndex(0) {
+            let (_, entry) = self.log.entry(index).unwrap();
+            if let Payload::Configuration(configuration) = Payload::from_bytes(entry)
+                .expect("unable to decode log entry") {
+                return (index, configuration);
+            }
+            index = index - 1;
+        }
+        match self.log.snapshot().unwrap() {
+            Some((snapshot_index, _, data)) => {
+                let snapshot = Snapshot::from_bytes(data).expect("unable to decode snapshot");
+                (snapshot_index, snapshot.configuration)
+            }
+            None => (LogIndex(0), self.initial_configuration.clone()),
+        }
+    }
+
+    /// Records when this leader last heard from a majority: the start of the latest heartbeat
+    /// round acknowledged by one.
+    fn update_quorum_contact(&mut self) {
+        let mut leader_state = self.leader_state.write().unwrap();
+        let confirmed = leader_state.unconfirmed_rounds().into_iter().find(|&round| {
+            self.configuration.is_quorum(|members| {
+                let own = if members.contains(&self.id) { 1 } else { 0 };
+                leader_state.count_round_acks(round, members) + own
+            })
+        });
+        if let Some(round) = confirmed {
+            leader_state.confirm_round(round);
+        }
+    }
+
+    /// Returns whether the leader holds a lease, during which no other leader can be elected.
+    /// There is no lease while leadership is being transferred, since the target campaigns
+    /// without waiting for an election timeout.
+    fn has_lease(&self) -> bool {
+        let leader_state = self.leader_state.read().unwrap();
+        leader_state.transfer.is_none() &&
+        (self.voting_peers().is_empty() ||
+         leader_state.quorum_contact().map_or(false, |contact| {
+            contact.elapsed() < Duration::from_millis(self.config.lease_duration())
+        }))
+    }
+
+    /// Steps down if no majority has acknowledged a heartbeat round started within the last
+    /// election timeout (check-quorum, section 6.2 of the Raft dissertation). Otherwise the
+    /// check is repeated after the next election timeout.
+    fn check_quorum(&mut self, actions: &mut Actions) {
+        if !self.is_leader() {
+            return;
+        }
+        self.update_quorum_contact();
+        let contact = self.leader_state.read().unwrap().quorum_contact();
+        let timeout = Duration::from_millis(self.config.election_timeout_min);
+        if self.voting_peers().is_empty() ||
+           contact.map_or(false, |contact| contact.elapsed() < timeout) {
+            actions.timeouts.push(ConsensusTimeout::CheckQuorum(self.lid));
+        } else {
+            scoped_info!("CheckQuorumTimeout: lost contact with the majority; stepping down");
+            self.step_down(actions);
+        }
+    }
+
+    /// Gives up leadership without knowing of a new leader. Pending reads and leadership
+    /// transfers fail.
+    fn step_down(&mut self, actions: &mut Actions) {
+        self.fail_reads(actions);
+        let transfer = self.leader_state.write().unwrap().transfer.take();
+        if let Some((_, Some(client))) = transfer {
+            let message = messages::command_response_failure(b"leadership transfer failed",
+                                                             self.lid);
+            actions.client_messages.push((client, message));
+        }
+        self.state = ConsensusState::Follower;
+        self.follower_state.write().unwrap().leader = None;
+        actions.clear_timeouts.push(self.lid);
+        actions.timeouts.push(ConsensusTimeout::Election(self.lid));
+    }
+
+    /// Transitions the consensus state machine to Follower state with the provided term, upon a
+    /// message from the peer `from`. The `voted_for` field will be reset. The peer replaces the
+    /// last known leader if it leads the term, and the last known leader is cleared otherwise.
+    fn transition_to_follower(&mut self,
+                              term: Term,
+                              from: ServerId,
+                              from_leader: bool,
+                              actions: &mut Actions) {
+        scoped_trace!("transitioning to Follower");
+        self.fail_reads(actions);
+        if self.is_leader() {
+            let transfer = self.leader_state.write().unwrap().transfer.take();
+            if let Some((target, Some(client))) = transfer {
+                let message = if target == from {
+                    messages::command_response_success(b"", self.lid)
+                } else {
+                    messages::command_response_failure(b"leadership transfer failed", self.lid)
+                };
+                actions.client_messages.push((client, message));
+            }
+        }
+        self.log.set_current_term(term).unwrap();
+        self.state = ConsensusState::Follower;
+        if from_leader {
+            self.follower_state.write().unwrap().set_leader(from);
+        } else {
+            self.follower_state.write().unwrap().leader = None;
+        }
+        actions.clear_timeouts.push(self.lid);
+        actions.clear_peer_messages = true;
+        actions.timeouts.push(ConsensusTimeout::Election(self.lid));
+    }
+
+    /// Returns whether the consensus state machine is currently a Leader.
+    pub fn is_leader(&self) -> bool {
+        self.state == ConsensusState::Leader
+    }
+
+    /// Returns whether the consensus state machine is currently a Follower.
+    fn is_follower(&self) -> bool {
+        self.state == ConsensusState::Follower
+    }
+
+    /// Returns whether the consensus state machine is currently a Candidate.
+    fn is_candidate(&self) -> bool {
+        self.state == ConsensusState::Candidate
+    }
+
+    /// Returns whether the consensus state machine is currently a PreCandidate.
+    fn is_pre_candidate(&self) -> bool {
+        self.state == ConsensusState::PreCandidate
+    }
+
+    /// Returns the current term.
+    fn current_term(&self) -> Term {
+        self.log.current_term().unwrap()
+    }
+
+    /// Returns the term of the latest applied log entry.
+    fn latest_log_term(&self) -> Term {
+        self.log.latest_log_term().unwrap()
+    }
+
+    /// Returns the index of the latest applied log entry.
+    fn latest_log_index(&self) -> LogIndex {
+        self.log.latest_log_index().unwrap()
+    }
+
+    /// Returns the index of the last entry covered by the latest snapshot (0 if the log has not
+    /// been compacted).
+    fn snapshot_index(&self) -> LogIndex {
+        self.log.first_log_index().unwrap() - 1
+    }
+
+    /// Returns the term of the entry at `index`, which may also be the last entry covered by the
+    /// latest snapshot.
+    fn log_term(&self, index: LogIndex) -> Term {
+        if index == LogIndex(0) {
+            return Term(0);
+        }
+        match self.log.snapshot().unwrap() {
+            Some((snapshot_index, term, _)) if snapshot_index == index => term,
+            _ => self.log.entry(index).unwrap().0,
+        }
+    }
+
+    /// Returns the index of the first entry of `term` in the log, searching backwards from the
+    /// entry at `index`, which must be of `term`. The search stops at the first retained entry.
+    fn first_index_of_term(&self, term: Term, index: LogIndex) -> LogIndex {
+        let first_log_index = self.log.first_log_index().unwrap();
+        let mut index = index;
+        while index > first_log_index && self.log_term(index - 1) == term {
+            index = index - 1;
+        }
+        index
+    }
+
+    /// Returns the index of the last entry of `term` in the log, if the log holds one. Entries
+    /// which have been compacted are not considered.
+    fn last_index_of_term(&self, term: Term) -> Option<LogIndex> {
+        let first_log_index = self.log.first_log_index().unwrap();
+        let mut index = self.latest_log_index();
+        while index >= first_log_index && index > LogIndex(0) {
+            let entry_term = self.log_term(index);
+            if entry_term <= term {
+                return if entry_term == term { Some(index) } else { None };
+            }
+            index = index - 1;
+        }
+        None
+    }
+
+}
+
+/// Returns the transaction named by a client request, unless the request is malformed.
+fn parse_session(session: ::capnp::Result<&[u8]>) -> Option<TransactionId> {
+    session.ok().and_then(|session| TransactionId::from_bytes(session).ok())
+}
+
+/// Returns the client session proposal carried by the request, if any. A proposal naming a
+/// malformed client is treated as a proposal outside of any session.
+fn proposal_id(request: proposal_request::Reader) -> Option<ProposalId> {
+    match request.get_client() {
+        Ok(client) if !client.is_empty() => {
+            ClientId::from_bytes(client).ok().map(|client| {
+                ProposalId {
+                    client: client,
+                    sequence: request.get_sequence(),
+                }
+            })
+        }
+        _ => None,
+    }
+}
+
+impl<L, M> fmt::Debug for Consensus<L, M>
+    where L: Log,
+          M: StateMachine
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.state {
+            ConsensusState::Follower => {
+                write!(fmt,
+                       "Follower {{ lid: {}, term: {}, index: {} }}",
+                       self.lid,
+                       self.current_term(),
+                       self.latest_log_index())
+            }
+            ConsensusState::PreCandidate => {
+                write!(fmt,
+                       "PreCandidate {{ lid: {}, term: {}, index: {} }}",
+                       self.lid,
+                       self.current_term(),
+                       self.latest_log_index())
+            }
+            ConsensusState::Candidate => {
+                write!(fmt,
+                       "Candidate {{ lid: {}, term: {}, index: {} }}",
+                       self.lid,
+                       self.current_term(),
+                       self.latest_log_index())
+            }
+            ConsensusState::Leader => {
+                write!(fmt,
+                       "Leader {{ lid: {}, term: {}, index: {} }}",
+                       self.lid,
+                       self.current_term(),
+                       self.latest_log_index())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate env_logger;
+    extern crate test;
+
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::io::Cursor;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    use capnp::serialize::{self, OwnedSegments};
+    use capnp::message::{Allocator, Builder, HeapAllocator, Reader, ReaderOptions};
+    use messages_capnp::{client_request, client_response, command_response, message,
+                         pre_vote_response, transaction_status_response, ReadConsistency,
+                         TransactionState};
+    use ClientId;
+    use LogIndex;
+    use ServerId;
+    use LogId;
+    use Term;
+    use TransactionId;
+    use messages;
+    use consensus::{Actions, Consensus, ConsensusTimeout, Heartbeat, HeartbeatResponse,
+                    HeartbeatResult};
+    use config::LogConfig;
+    use entry::Payload;
+    use membership::Configuration;
+    use session::ProposalId;
+    use state::ConsensusState;
+    use state_machine::NullStateMachine;
+    use persistent_log::{MemLog, Log};
+    use uuid::Uuid;
+    use std::cell::RefCell;
+
+    type TestPeer = Consensus<MemLog, NullStateMachine>;
+
+    lazy_static!{
+        static ref lid: LogId = LogId(Uuid::new_v4());
+    }
+
+    fn new_cluster(size: u64) -> HashMap<ServerId, TestPeer> {
+        let ids: HashMap<ServerId, SocketAddr> = (0..size)
+            .map(Into::into)
+            .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id)).unwrap()))
+            .collect();
+        ids.iter()
+            .map(|(&id, _)| {
+                let mut peers = ids.clone();
+                peers.remove(&id);
+                let store = MemLog::new();
+                (id,
+                 Consensus::new(id, *lid, peers, store, NullStateMachine, LogConfig::default()))
+            })
+            .collect()
+    }
+
+    fn into_reader<A>(message: &Builder<A>) -> Reader<OwnedSegments>
+        where A: Allocator
+    {
+        let mut buf = Cursor::new(Vec::new());
+
+        serialize::write_message(&mut buf, message).unwrap();
+        buf.set_position(0);
+        serialize::read_message(&mut buf, ReaderOptions::new()).unwrap()
+    }
+
+    /// Applies the actions to the consensus peers (and recursively applies any resulting
+    /// actions), and returns any client messages. Messages to peers which are not in `peers` are
+    /// dropped.
+    fn apply_actions(from: ServerId,
+                     mut actions: Actions,
+                     peers: &mut HashMap<ServerId, TestPeer>)
+                     -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
+        let mut queue: VecDeque<(ServerId, ServerId, Rc<Builder<HeapAllocator>>)> = VecDeque::new();
+
+        for (to, message) in actions.peer_messages.iter().cloned() {
+            queue.push_back((from, to, message));
+        }
+        actions.peer_messages.clear();
+
+        while let Some((from, to, message)) = queue.pop_front() {
+            let mut reader = into_reader(&*message);
+            let message_reader = reader.get_root::<message::Reader>().unwrap();
+            match peers.get_mut(&to) {
+                Some(peer) => peer.apply_peer_message(from, &message_reader, &mut actions).unwrap(),
+                None => continue,
+            }
+            let inner_from = to;
+            for (inner_to, message) in actions.peer_messages.iter().cloned() {
+                queue.push_back((inner_from, inner_to, message));
+            }
+            actions.peer_messages.clear();
+        }
+
+        let Actions { client_messages, .. } = actions;
+        client_messages
+    }
+
+    /// Returns the log entry holding the client command `value`.
+    fn command(value: &[u8]) -> Vec<u8> {
+        Payload::Command(value.to_vec(), None).to_bytes()
+    }
+
+    /// Elect `leader` as the leader of a cluster with the provided followers.
+    /// The leader and the followers must be in the same term.
+    fn elect_leader(leader: ServerId, peers: &mut HashMap<ServerId, TestPeer>) {
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        let client_messages = apply_actions(leader, actions, peers);
+        assert!(client_messages.is_empty());
+        assert!(peers[&leader].is_leader());
+    }
+
+    /// Tests that a consensus state machine with no peers will transitition immediately to the
+    /// Leader state upon the first election timeout.
+    #[test]
+    fn test_solitary_consensus_transition_to_leader() {
+        setup_test!("test_solitary_consensus_transition_to_leader");
+        let (_, mut peer) = new_cluster(1).into_iter().next().unwrap();
+        assert!(peer.is_follower());
+
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(peer.is_leader());
+        assert!(actions.peer_messages.is_empty());
+        assert!(actions.client_messages.is_empty());
+        assert_eq!(vec![ConsensusTimeout::CheckQuorum(*lid)], actions.timeouts);
+
+        // The no-op entry of the new term is committed right away.
+        assert_eq!(LogIndex(1), peer.commit_index);
+        assert_eq!(LogIndex(1), peer.last_applied);
+    }
+
+    /// A simple election test over multiple group sizes.
+    #[test]
+    fn test_election() {
+        setup_test!("test_election");
+
+        for group_size in 1..10 {
+            let mut peers = new_cluster(group_size);
+            let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+            let leader = &peer_ids[0];
+            elect_leader(leader.clone(), &mut peers);
+            assert!(peers[leader].is_leader());
+            for follower in peer_ids.iter().skip(1) {
+                assert!(peers[follower].is_follower());
+            }
+        }
+    }
+
+    /// Tests the Raft heartbeating mechanism. The leader receives a heartbeat
+    /// timeout, and in response sends an AppendEntries message to the follower.
+    /// The follower in turn resets its election timout, and replies to the
+    /// leader.
+    #[test]
+    fn test_heartbeat() {
+        setup_test!("test_heartbeat");
+        let mut peers = new_cluster(2);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader_id = &peer_ids[0];
+        let follower_id = &peer_ids[1];
+        elect_leader(leader_id.clone(), &mut peers);
+
+        // Leader pings with a heartbeat timeout.
+        let leader_append_entries = {
+            let mut actions = Actions::new();
+            let leader = peers.get_mut(&leader_id).unwrap();
+            leader.heartbeat_timeout(follower_id.clone(), &mut actions);
+
+            let peer_message = actions.peer_messages.iter().next().unwrap();
+            assert_eq!(peer_message.0, follower_id.clone());
+            peer_message.1.clone()
+        };
+        let mut reader = into_reader(&*leader_append_entries);
+        let message_reader = reader.get_root::<message::Reader>().unwrap();
+
+        // Follower responds.
+        let follower_response = {
+            let mut actions = Actions::new();
+            let follower = peers.get_mut(&follower_id).unwrap();
+            follower.apply_peer_message(leader_id.clone(), &message_reader, &mut actions).unwrap();
+
+            let election_timeout = actions.timeouts.iter().next().unwrap();
+            assert_eq!(election_timeout, &ConsensusTimeout::Election(*lid));
+
+            let peer_message = actions.peer_messages.iter().next().unwrap();
+            assert_eq!(peer_message.0, leader_id.clone());
+            peer_message.1.clone()
+        };
+        let mut reader = into_reader(&*follower_response);
+        let message_reader = reader.get_root::<message::Reader>().unwrap();
+
+        // Leader applies and sends back a heartbeat to establish leadership.
+        let leader = peers.get_mut(&leader_id).unwrap();
+        let mut actions = Actions::new();
+        leader.apply_peer_message(follower_id.clone(), &message_reader, &mut actions).unwrap();
+        let heartbeat_timeout = actions.timeouts.iter().next().unwrap();
+        assert_eq!(heartbeat_timeout,
+                   &ConsensusTimeout::Heartbeat(follower_id.clone(), *lid));
+    }
+
+    /// Emulates a slow heartbeat message in a two-node cluster.
+    ///
+    /// The initial leader (Consensus 0) sends a heartbeat, but before it is received by the follower
+    /// (Consensus 1), Consensus 1's election timeout fires. Consensus 1 transitions to
+    /// pre-candidate state and sends a PreVote to Consensus 0, which is rejected since Consensus 0
+    /// is still the leader. Neither term changes, so the delayed heartbeat from Consensus 0 returns
+    /// Consensus 1 to follower state.
+    #[test]
+    fn test_slow_heartbeat() {
+        setup_test!("test_heartbeat");
+        let mut peers = new_cluster(2);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let peer_0 = &peer_ids[0];
+        let peer_1 = &peer_ids[1];
+        elect_leader(peer_0.clone(), &mut peers);
+
+        let mut peer_0_actions = Actions::new();
+        peers.get_mut(peer_0)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Heartbeat(*peer_1, *lid),
+                           &mut peer_0_actions);
+        assert!(peers[peer_0].is_leader());
+
+        let mut peer_1_actions = Actions::new();
+        peers.get_mut(peer_1)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut peer_1_actions);
+        assert!(peers[peer_1].is_pre_candidate());
+
+        // Apply pre-candidate messages.
+        assert!(apply_actions(*peer_1, peer_1_actions, &mut peers).is_empty());
+        assert!(peers[peer_0].is_leader());
+        assert!(peers[peer_1].is_pre_candidate());
+        assert_eq!(Term(1), peers[peer_1].current_term());
+
+        // Apply delayed heartbeat.
+        assert!(apply_actions(*peer_0, peer_0_actions, &mut peers).is_empty());
+        assert!(peers[peer_0].is_leader());
+        assert!(peers[peer_1].is_follower());
+    }
+
+    /// Tests that with coalesced heartbeats, the leader hands out a heartbeat for each follower
+    /// instead of scheduling heartbeat timeouts, and that followers apply the heartbeats like
+    /// empty AppendEntries requests.
+    #[test]
+    fn test_coalesced_heartbeat() {
+        setup_test!("test_coalesced_heartbeat");
+        let mut peers = new_cluster(3);
+        for peer in peers.values_mut() {
+            peer.set_coalesced_heartbeats(true);
+        }
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+        let term = peers[&leader].current_term();
+        assert_eq!(LogIndex(1), peers[&leader].commit_index);
+
+        let heartbeats = peers.get_mut(&leader).unwrap().coalesced_heartbeats();
+        assert_eq!(2, heartbeats.len());
+        let round = heartbeats[0].1.round;
+        for (follower, heartbeat) in heartbeats {
+            let expected = Heartbeat {
+                term: term,
+                prev_log_index: LogIndex(1),
+                prev_log_term: term,
+                commit_index: LogIndex(1),
+                round: round,
+            };
+            assert_eq!(expected, heartbeat);
+
+            // The follower learns the commit index, and resets its election timeout.
+            let mut actions = Actions::new();
+            let response = peers.get_mut(&follower)
+                .unwrap()
+                .heartbeat_request(leader, heartbeat, &mut actions);
+            assert_eq!(HeartbeatResponse {
+                           term: term,
+                           round: round,
+                           result: HeartbeatResult::Success(LogIndex(1)),
+                       },
+                       response);
+            assert_eq!(LogIndex(1), peers[&follower].commit_index);
+            assert_eq!(vec![ConsensusTimeout::Election(*lid)], actions.timeouts);
+
+            let mut actions = Actions::new();
+            peers.get_mut(&leader).unwrap().heartbeat_response(follower, response, &mut actions);
+            assert!(peers[&leader].is_leader());
+        }
+        let voters = peers[&leader].voters();
+        assert_eq!(2,
+                   peers[&leader].leader_state.read().unwrap().count_round_acks(round, &voters));
+
+        // A caught up follower gets no heartbeat timeout.
+        let response = messages::append_entries_response_success(term, LogIndex(1), round, &*lid);
+        let mut reader = into_reader(&*response);
+        let message_reader = reader.get_root::<message::Reader>().unwrap();
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_peer_message(ServerId(1), &message_reader, &mut actions).unwrap();
+        assert!(actions.timeouts.is_empty());
+
+        // A heartbeat of an earlier term is answered with the current term, which makes the
+        // stale leader step down.
+        let stale = Heartbeat {
+            term: Term(0),
+            prev_log_index: LogIndex(0),
+            prev_log_term: Term(0),
+            commit_index: LogIndex(0),
+            round: 1,
+        };
+        let mut actions = Actions::new();
+        let response = peers.get_mut(&ServerId(1))
+            .unwrap()
+            .heartbeat_request(ServerId(2), stale, &mut actions);
+        assert_eq!(term, response.term);
+        assert_eq!(HeartbeatResult::StaleTerm, response.result);
+        assert!(actions.timeouts.is_empty());
+
+        let newer = HeartbeatResponse {
+            term: Term(term.as_u64() + 1),
+            round: round,
+            result: HeartbeatResult::StaleTerm,
+        };
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().heartbeat_response(ServerId(1), newer, &mut actions);
+        assert!(peers[&leader].is_follower());
+    }
+
+    /// Tests that with coalesced heartbeats, entries lost on the way to a follower are found
+    /// missing by the next heartbeat and sent again.
+    #[test]
+    fn test_coalesced_heartbeat_lost_append_entries() {
+        setup_test!("test_coalesced_heartbeat_lost_append_entries");
+        let mut peers = new_cluster(3);
+        for peer in peers.values_mut() {
+            peer.set_coalesced_heartbeats(true);
+        }
+        let leader = ServerId(0);
+        let follower = ServerId(1);
+        elect_leader(leader, &mut peers);
+
+        // The AppendEntries request carrying a proposal to the follower is lost.
+        let proposal = messages::proposal_request(TransactionId::new(), b"foo", None, *lid);
+        let reader = into_reader(&proposal);
+        let message_reader = reader.get_root::<client_request::Reader>().unwrap();
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.apply_client_message(ClientId::new(), &message_reader, &mut actions).unwrap();
+            peer.flush(&mut actions);
+        }
+        actions.peer_messages.retain(|&(to, _)| to != follower);
+        let client_messages = apply_actions(leader, actions, &mut peers);
+        assert!(is_proposal_success(&client_messages[0].1));
+        let latest_log_index = peers[&leader].latest_log_index();
+        assert!(peers[&follower].latest_log_index() < latest_log_index);
+
+        // The heartbeat finds the entry missing.
+        let heartbeat = peers.get_mut(&leader)
+            .unwrap()
+            .coalesced_heartbeats()
+            .into_iter()
+            .find(|&(peer, _)| peer == follower)
+            .unwrap()
+            .1;
+        let mut actions = Actions::new();
+        let response = peers.get_mut(&follower)
+            .unwrap()
+            .heartbeat_request(leader, heartbeat, &mut actions);
+        match response.result {
+            HeartbeatResult::InconsistentPrevEntry(..) => (),
+            result => panic!("unexpected heartbeat result: {:?}", result),
+        }
+
+        // The leader probes the follower and sends the entry again.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().heartbeat_response(follower, response, &mut actions);
+        assert!(!actions.peer_messages.is_empty());
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        assert_eq!(latest_log_index, peers[&follower].latest_log_index());
+        assert_eq!(peers[&leader].commit_index, peers[&follower].commit_index);
+    }
+
+    /// Tests that a follower which lost contact with a healthy leader can not disrupt it: its
+    /// pre-vote is rejected by the leader and by followers which recently heard from the leader,
+    /// and its term is not incremented.
+    #[test]
+    fn test_pre_vote_rejected_while_leader_active() {
+        setup_test!("test_pre_vote_rejected_while_leader_active");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let partitioned = peer_ids[2];
+        elect_leader(leader, &mut peers);
+
+        // Repeated election timeouts do not increment the partitioned follower's term.
+        for _ in 0..3 {
+            let mut actions = Actions::new();
+            peers.get_mut(&partitioned)
+                .unwrap()
+                .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+            assert!(apply_actions(partitioned, actions, &mut peers).is_empty());
+            assert!(peers[&partitioned].is_pre_candidate());
+            assert_eq!(Term(1), peers[&partitioned].current_term());
+        }
+        assert!(peers[&leader].is_leader());
+        assert_eq!(Term(1), peers[&leader].current_term());
+
+        // A follower which has not heard from a leader grants the pre-vote, and the election
+        // proceeds in the next term.
+        for peer in peers.values_mut() {
+            peer.leader_contact = None;
+            peer.state = ConsensusState::Follower;
+        }
+        let mut actions = Actions::new();
+        peers.get_mut(&partitioned)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(apply_actions(partitioned, actions, &mut peers).is_empty());
+        assert!(peers[&partitioned].is_leader());
+        assert_eq!(Term(2), peers[&partitioned].current_term());
+    }
+
+    /// Tests that a pre-vote is granted to a candidate whose log is at least as up-to-date, even if
+    /// it is shorter, and that a pre-candidate learning of a newer term from a voter does not
+    /// take the voter for the leader.
+    #[test]
+    fn test_pre_vote_up_to_date() {
+        setup_test!("test_pre_vote_up_to_date");
+        let mut peers = new_cluster(3);
+        let (voter, candidate, old_leader) = (ServerId(0), ServerId(1), ServerId(2));
+        let noop = Payload::Noop.to_bytes();
+        {
+            let peer = peers.get_mut(&voter).unwrap();
+            peer.log.append_entries(LogIndex(1), &[(Term(1), &noop[..]), (Term(1), &noop[..])])
+                .unwrap();
+            peer.log.set_current_term(Term(2)).unwrap();
+        }
+        let grants = |last_log_index: u64, last_log_term: u64, voter: &mut TestPeer| {
+            let request = messages::pre_vote_request(Term(3),
+                                                     LogIndex(last_log_index),
+                                                     Term(last_log_term),
+                                                     &*lid);
+            let mut actions = Actions::new();
+            let reader = into_reader(&*request);
+            voter.apply_peer_message(candidate,
+                                     &reader.get_root::<message::Reader>().unwrap(),
+                                     &mut actions).unwrap();
+            let response = into_reader(&*actions.peer_messages[0].1);
+            match response.get_root::<message::Reader>().unwrap().which().unwrap() {
+                message::Which::PreVoteResponse(Ok(response)) => {
+                    match response.which() {
+                        Ok(pre_vote_response::Granted(_)) => true,
+                        _ => false,
+                    }
+                }
+                _ => panic!("unexpected message"),
+            }
+        };
+        assert!(grants(1, 2, peers.get_mut(&voter).unwrap()));
+        assert!(!grants(1, 1, peers.get_mut(&voter).unwrap()));
+        assert!(grants(2, 1, peers.get_mut(&voter).unwrap()));
+
+        let peer = peers.get_mut(&candidate).unwrap();
+        peer.follower_state.write().unwrap().set_leader(old_leader);
+        let mut actions = Actions::new();
+        peer.apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(peer.is_pre_candidate());
+        let response = messages::pre_vote_response_stale_term(Term(2), &*lid);
+        let reader = into_reader(&*response);
+        peer.apply_peer_message(voter,
+                                &reader.get_root::<message::Reader>().unwrap(),
+                                &mut actions).unwrap();
+        assert!(peer.is_follower());
+        assert_eq!(Term(2), peer.current_term());
+        assert_eq!(None, peer.follower_state.read().unwrap().leader);
+    }
+
+    /// Tests that a client proposal is correctly replicated to peers, and the client is notified
+    /// of the success.
+    #[test]
+    fn test_proposal() {
+        setup_test!("test_proposal");
+        // Test various sizes.
+        for i in 1..7 {
+            scoped_debug!("testing size {} cluster", i);
+            let mut peers = new_cluster(i);
+            let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+            let leader = peer_ids[0];
+            elect_leader(leader, &mut peers);
+
+            let value: &[u8] = b"foo";
+            let proposal =
+                messages::proposal_request(TransactionId::new(), value, None, *lid);
+            let reader = into_reader(&proposal);
+            let message_reader = reader.get_root::<client_request::Reader>()
+                .unwrap();
+            let mut actions = Actions::new();
+
+            let client = ClientId::new();
+
+            {
+                let leader = peers.get_mut(&leader).unwrap();
+                leader.apply_client_message(client, &message_reader, &mut actions).unwrap();
+                leader.flush(&mut actions);
+            }
+
+            let client_messages = apply_actions(leader, actions, &mut peers);
+            assert_eq!(1, client_messages.len());
+            for peer in peers.values() {
+                assert_eq!((Term(1), &command(value)[..]), peer.log.entry(LogIndex(2)).unwrap());
+            }
+        }
+    }
+
+    /// Sets up a five server cluster in the situation of Figure 8 (b) of the Raft paper: all
+    /// servers hold the committed entry 1, the leader of term 2 (server 0) replicated entry 2 to
+    /// server 1 only, and the leader of term 3 (server 4) appended a different entry 2 before
+    /// crashing. Every server is in term 3, without a leader.
+    fn figure_8_cluster() -> HashMap<ServerId, TestPeer> {
+        let mut peers = new_cluster(5);
+        let noop = Payload::Noop.to_bytes();
+        let (term_2, term_3) = (command(b"term 2"), command(b"term 3"));
+        for (&id, peer) in peers.iter_mut() {
+            let mut entries = vec![(Term(1), &noop[..])];
+            match id {
+                ServerId(0) | ServerId(1) => entries.push((Term(2), &term_2[..])),
+                ServerId(4) => entries.push((Term(3), &term_3[..])),
+                _ => (),
+            }
+            peer.log.append_entries(LogIndex(1), &entries).unwrap();
+            peer.log.set_current_term(Term(3)).unwrap();
+            peer.commit_index = LogIndex(1);
+            peer.last_applied = LogIndex(1);
+        }
+        peers
+    }
+
+    /// Figure 8 (c) and (d): server 0 becomes the leader of term 4 and replicates entry 2 to a
+    /// majority. The entry is from an earlier term, so it is not committed, and server 4 may still
+    /// be elected and overwrite it.
+    #[test]
+    fn test_figure_8_uncommitted() {
+        setup_test!("test_figure_8_uncommitted");
+        let mut peers = figure_8_cluster();
+        {
+            // Server 0 is elected in term 4, and learns that servers 1 and 2 store entry 2 before
+            // any of them acknowledged its no-op.
+            let leader = peers.get_mut(&ServerId(0)).unwrap();
+            leader.log.set_current_term(Term(4)).unwrap();
+            leader.state = ConsensusState::Leader;
+            let mut leader_state = leader.leader_state.write().unwrap();
+            leader_state.set_peers(&leader.replication_peers());
+            leader_state.reinitialize(LogIndex(2));
+            leader_state.set_match_index(ServerId(1), LogIndex(2));
+            leader_state.set_match_index(ServerId(2), LogIndex(2));
+        }
+        let mut crashed = peers.remove(&ServerId(0)).unwrap();
+        crashed.advance_commit_index(&mut Actions::new());
+        assert_eq!(LogIndex(1), crashed.commit_index);
+        // Server 0 crashes.
+
+        // Server 2 received entry 2 from server 0, and every server learned of term 4.
+        let term_2 = command(b"term 2");
+        peers.get_mut(&ServerId(2))
+            .unwrap()
+            .log
+            .append_entries(LogIndex(2), &[(Term(2), &term_2[..])])
+            .unwrap();
+        for peer in peers.values_mut() {
+            peer.log.set_current_term(Term(4)).unwrap();
+        }
+
+        // Server 4 is elected with the votes of servers 1 to 3, and replaces entry 2 everywhere.
+        elect_leader(ServerId(4), &mut peers);
+        assert_eq!(Term(5), peers[&ServerId(4)].current_term());
+        for peer in peers.values() {
+            assert_eq!((Term(3), &command(b"term 3")[..]), peer.log.entry(LogIndex(2)).unwrap());
+            assert_eq!(LogIndex(3), peer.log.latest_log_index().unwrap());
+        }
+        assert_eq!(LogIndex(3), peers[&ServerId(4)].commit_index);
+    }
+
+    /// Figure 8 (e): server 0 becomes the leader of term 4, and replicates its no-op entry to a
+    /// majority. Committing the no-op commits entry 2, and server 4 can no longer be elected.
+    #[test]
+    fn test_figure_8_committed() {
+        setup_test!("test_figure_8_committed");
+        let mut peers = figure_8_cluster();
+        let server_4 = peers.remove(&ServerId(4)).unwrap();
+        let server_3 = peers.remove(&ServerId(3)).unwrap();
+        elect_leader(ServerId(0), &mut peers);
+        assert_eq!(Term(4), peers[&ServerId(0)].current_term());
+        assert_eq!(LogIndex(3), peers[&ServerId(0)].commit_index);
+        for peer in peers.values() {
+            assert_eq!((Term(2), &command(b"term 2")[..]), peer.log.entry(LogIndex(2)).unwrap());
+            assert_eq!((Term(4), &Payload::Noop.to_bytes()[..]),
+                       peer.log.entry(LogIndex(3)).unwrap());
+        }
+
+        // Server 4's log is not up to date with the majority; its election fails.
+        peers.insert(ServerId(3), server_3);
+        peers.insert(ServerId(4), server_4);
+        for peer in peers.values_mut() {
+            peer.leader_contact = None;
+        }
+        let mut actions = Actions::new();
+        peers.get_mut(&ServerId(4))
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(apply_actions(ServerId(4), actions, &mut peers).is_empty());
+        assert!(!peers[&ServerId(4)].is_leader());
+        assert!(peers[&ServerId(0)].is_leader());
+        assert_eq!((Term(2), &command(b"term 2")[..]),
+                   peers[&ServerId(1)].log.entry(LogIndex(2)).unwrap());
+    }
+
+    /// Tests that the leader compacts its log once the snapshot threshold is reached, and brings a
+    /// follower which missed the compacted entries up to date with an InstallSnapshot request. A
+    /// follower rejects a snapshot which does not decode.
+    #[test]
+    fn test_install_snapshot() {
+        setup_test!("test_install_snapshot");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let lagging = peer_ids[2];
+        for peer in peers.values_mut() {
+            peer.config.snapshot_threshold = 3;
+        }
+        elect_leader(leader, &mut peers);
+
+        // Partition the lagging follower while the entries are committed and compacted.
+        let mut lagging_peer = peers.remove(&lagging).unwrap();
+        let value: &[u8] = b"foo";
+        for _ in 0..4 {
+            assert_eq!(1, propose(leader, value, &mut peers).len());
+        }
+        assert_eq!(LogIndex(4), peers[&leader].log.first_log_index().unwrap());
+        assert_eq!(LogIndex(3), peers[&leader].log.snapshot().unwrap().unwrap().0);
+
+        // A snapshot which does not decode is rejected, and leaves the follower untouched.
+        let term = peers[&leader].current_term();
+        let request = messages::install_snapshot_request(term, LogIndex(3), Term(1), b"foo", &*lid);
+        let reader = into_reader(&*request);
+        let commit_index = lagging_peer.commit_index;
+        let mut actions = Actions::new();
+        lagging_peer.apply_peer_message(leader,
+                                        &reader.get_root::<message::Reader>().unwrap(),
+                                        &mut actions)
+            .unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert!(lagging_peer.log.snapshot().unwrap().is_none());
+        assert_eq!(commit_index, lagging_peer.commit_index);
+
+        // Heal the partition; the follower receives the snapshot followed by the remaining entries.
+        peers.insert(lagging, lagging_peer);
+        let addr = peers[&leader].peers[&lagging];
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(lagging, addr, &mut actions);
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+
+        let follower = &peers[&lagging];
+        assert_eq!(Some((LogIndex(3), Term(1))),
+                   follower.log.snapshot().unwrap().map(|(index, term, _)| (index, term)));
+        assert_eq!(LogIndex(5), follower.latest_log_index());
+        assert_eq!(LogIndex(5), follower.commit_index);
+        assert_eq!((Term(1), &command(value)[..]), follower.log.entry(LogIndex(5)).unwrap());
+
+        // A restarted consensus resumes from the snapshot.
+        let log = peers[&leader].log.clone();
+        let restarted = Consensus::new(leader,
+                                       *lid,
+                                       HashMap::new(),
+                                       log,
+                                       NullStateMachine,
+                                       LogConfig::default());
+        assert_eq!(LogIndex(3), restarted.commit_index);
+        assert_eq!(LogIndex(3), restarted.last_applied);
+    }
 
-        for &peer in self.peers().keys() {
-            actions.peer_messages.push((peer, message.clone()));
+    /// Tests that a follower which missed more entries than fit in one AppendEntries request is
+    /// caught up over several requests.
+    #[test]
+    fn test_max_append_entries() {
+        setup_test!("test_max_append_entries");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let lagging = peer_ids[2];
+        peers.get_mut(&leader).unwrap().config.max_append_entries = 2;
+        elect_leader(leader, &mut peers);
+
+        let lagging_peer = peers.remove(&lagging).unwrap();
+        for _ in 0..5 {
+            assert_eq!(1, propose(leader, b"foo", &mut peers).len());
         }
-        actions.timeouts.push(ConsensusTimeout::Election(self.lid));
-        actions.clear_peer_messages = true;
+        peers.insert(lagging, lagging_peer);
+
+        let requests = reconnect(leader, lagging, &mut peers);
+        assert!(requests.len() > 3);
+        assert!(requests.iter().all(|&entries| entries <= 2));
+        assert_eq!(LogIndex(6), peers[&lagging].latest_log_index());
     }
 
-    /// Advances the commit index and applies committed entries to the state machine.
-    fn advance_commit_index(&mut self, actions: &mut Actions) {
-        scoped_assert!(self.is_leader());
-        let majority = self.majority();
-        {
-            let leader_state = self.leader_state.read().unwrap();
-            // TODO: Figure out failure condition here.
-            while self.commit_index < self.log.latest_log_index().unwrap() {
-                if leader_state.count_match_indexes(self.commit_index + 1) >= majority {
-                    self.commit_index = self.commit_index + 1;
-                    scoped_debug!("commit index advanced to {}", self.commit_index);
-                } else {
-                    break; // If there isn't a majority now, there won't be one later.
+    /// Returns the number of entries in each AppendEntries request among `messages`.
+    fn append_entries_counts(messages: &[(ServerId, Rc<Builder<HeapAllocator>>)])
+                             -> Vec<(ServerId, u32)> {
+        messages.iter()
+            .filter_map(|&(to, ref message)| {
+                let reader = into_reader(&**message);
+                let message = reader.get_root::<message::Reader>().unwrap();
+                match message.which().unwrap() {
+                    message::Which::AppendEntriesRequest(Ok(request)) => {
+                        Some((to, request.get_entries().unwrap().len()))
+                    }
+                    _ => None,
                 }
+            })
+            .collect()
+    }
+
+    /// Tests that the proposals received before a flush are sent in a single request per peer,
+    /// and that no more than `max_inflight_append_entries` requests are unacknowledged.
+    #[test]
+    fn test_batched_pipelined_append_entries() {
+        setup_test!("test_batched_pipelined_append_entries");
+        let mut peers = new_cluster(3);
+        let (leader, follower) = (ServerId(0), ServerId(1));
+        elect_leader(leader, &mut peers);
+
+        let propose_unflushed = |peers: &mut HashMap<ServerId, TestPeer>, count: usize| {
+            let mut actions = Actions::new();
+            for _ in 0..count {
+                let proposal =
+                    messages::proposal_request(TransactionId::new(), b"foo", None, *lid);
+                let reader = into_reader(&proposal);
+                let message_reader = reader.get_root::<client_request::Reader>().unwrap();
+                peers.get_mut(&leader)
+                    .unwrap()
+                    .apply_client_message(ClientId::new(), &message_reader, &mut actions).unwrap();
             }
-        }
+            assert!(actions.peer_messages.is_empty());
+            peers.get_mut(&leader).unwrap().flush(&mut actions);
+            actions
+        };
 
-        let results = self.apply_commits();
-        let mut leader_state = self.leader_state.write().unwrap();
+        // Three proposals go out together.
+        let actions = propose_unflushed(&mut peers, 3);
+        let counts = append_entries_counts(&actions.peer_messages);
+        assert_eq!(2, counts.len());
+        assert!(counts.iter().all(|&(_, entries)| entries == 3));
+        assert_eq!(3, apply_actions(leader, actions, &mut peers).len());
+        assert_eq!(LogIndex(4), peers[&leader].commit_index);
 
-        // TODO: Figure out failure condition here.
-        while let Some(&(client, index)) = leader_state.proposals.get(0) {
-            if index <= self.commit_index {
-                scoped_trace!("responding to client {} for entry {}", client, index);
-                // We know that there will be an index here since it was commited
-                // and the index is less than that which has been commited.
-                let ref result = results[&index];
-                let message = messages::command_response_success(result.as_slice(), self.lid);
-                actions.client_messages.push((client, message));
-                leader_state.proposals.pop_front();
-            } else {
-                break;
+        // With one entry per request, at most two requests are in flight to each follower.
+        {
+            let leader = peers.get_mut(&leader).unwrap();
+            leader.config.max_append_entries = 1;
+            leader.config.max_inflight_append_entries = 2;
+        }
+        let actions = propose_unflushed(&mut peers, 3);
+        let counts = append_entries_counts(&actions.peer_messages);
+        assert_eq!(2, counts.iter().filter(|&&(to, _)| to == follower).count());
+        assert!(counts.iter().all(|&(_, entries)| entries == 1));
+
+        // The acknowledgements release the remaining entry.
+        assert_eq!(3, apply_actions(leader, actions, &mut peers).len());
+        for peer in peers.values() {
+            assert_eq!(LogIndex(7), peer.latest_log_index());
+        }
+        assert_eq!(LogIndex(7), peers[&leader].commit_index);
+    }
+
+    /// Resets the connection from `leader` to `follower`, and exchanges messages between the two
+    /// until neither has anything left to send. Returns the number of entries in each
+    /// AppendEntries request sent to the follower.
+    fn reconnect(leader: ServerId,
+                 follower: ServerId,
+                 peers: &mut HashMap<ServerId, TestPeer>)
+                 -> Vec<u32> {
+        let addr = peers[&leader].peers[&follower];
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(follower, addr, &mut actions);
+        let mut requests = Vec::new();
+        while !actions.peer_messages.is_empty() {
+            let (to, message) = actions.peer_messages.remove(0);
+            let from = if to == follower { leader } else { follower };
+            let reader = into_reader(&*message);
+            let message = reader.get_root::<message::Reader>().unwrap();
+            if let message::Which::AppendEntriesRequest(Ok(request)) = message.which().unwrap() {
+                requests.push(request.get_entries().unwrap().len());
             }
+            peers.get_mut(&to).unwrap().apply_peer_message(from, &message, &mut actions).unwrap();
         }
+        requests
     }
 
-    /// Applies all committed but unapplied log entries to the state machine.  Returns the set of
-    /// return values from the commits applied.
-    fn apply_commits(&mut self) -> HashMap<LogIndex, Vec<u8>> {
-        let mut results = HashMap::new();
-        while self.last_applied < self.commit_index {
-            // Unwrap justified here since we know there is an entry here.
-            let (_, entry) = match self.log.entry(self.last_applied + 1) {
-                Ok(e) => e,
-                Err(_) => break,
-            };
+    /// Appends `count` commands of `term` to the log of `peer`.
+    fn append_commands(peer: &mut TestPeer, term: Term, count: u64) {
+        let entry = command(b"foo");
+        let entries: Vec<(Term, &[u8])> = (0..count).map(|_| (term, &entry[..])).collect();
+        let from = peer.latest_log_index() + 1;
+        peer.log.append_entries(from, &entries).unwrap();
+    }
+
+    /// Tests that a follower whose log ends thousands of entries before the leader's is sent the
+    /// missing entries after a single rejected request.
+    #[test]
+    fn test_backtrack_short_log() {
+        setup_test!("test_backtrack_short_log");
+        let mut peers = new_cluster(3);
+        let (leader, lagging) = (ServerId(0), ServerId(2));
+        for (&id, peer) in peers.iter_mut() {
+            let count = if id == lagging { 10 } else { 3000 };
+            append_commands(peer, Term(1), count);
+            peer.log.set_current_term(Term(1)).unwrap();
+        }
+        let lagging_peer = peers.remove(&lagging).unwrap();
+        elect_leader(leader, &mut peers);
+        peers.insert(lagging, lagging_peer);
+
+        assert_eq!(vec![0, 2991], reconnect(leader, lagging, &mut peers));
+        assert_eq!(LogIndex(3001), peers[&lagging].latest_log_index());
+        let leader_state = peers[&leader].leader_state.read().unwrap();
+        assert_eq!(LogIndex(3001), leader_state.match_index(&lagging));
+    }
 
-            if !entry.is_empty() {
-                let result = self.state_machine.write().unwrap().apply(entry);
-                results.insert(self.last_applied + 1, result);
+    /// Tests that a follower holding thousands of entries which conflict with the leader's log is
+    /// brought in line skipping a whole term per round trip: back to the end of the term both logs
+    /// share, and past the entries of a term only the follower holds.
+    #[test]
+    fn test_backtrack_conflicting_log() {
+        setup_test!("test_backtrack_conflicting_log");
+        let mut peers = new_cluster(3);
+        let (leader, diverged) = (ServerId(0), ServerId(2));
+        for (&id, peer) in peers.iter_mut() {
+            append_commands(peer, Term(1), 10);
+            if id == diverged {
+                // Entries of term 2 which the leader of term 2 did not replicate, followed by the
+                // entries of term 3 which the rest of the cluster never saw.
+                append_commands(peer, Term(2), 2000);
+                append_commands(peer, Term(3), 500);
+            } else {
+                append_commands(peer, Term(2), 1000);
+                append_commands(peer, Term(4), 2000);
             }
-            self.last_applied = self.last_applied + 1;
+            peer.log.set_current_term(Term(4)).unwrap();
         }
-        results
+        let diverged_peer = peers.remove(&diverged).unwrap();
+        elect_leader(leader, &mut peers);
+        peers.insert(diverged, diverged_peer);
+
+        // The follower rejects the first request since its log is shorter, the second since its
+        // entry 2510 is of term 3, which the leader does not hold, and the third since its entry
+        // 2010 is of term 2, which the leader holds up to entry 1010. The fourth request is
+        // accepted.
+        assert_eq!(vec![0, 501, 1001, 2001], reconnect(leader, diverged, &mut peers));
+        let follower = &peers[&diverged];
+        assert_eq!(LogIndex(3011), follower.latest_log_index());
+        assert_eq!(Term(2), follower.log.entry(LogIndex(1010)).unwrap().0);
+        assert_eq!(Term(4), follower.log.entry(LogIndex(1011)).unwrap().0);
+        assert_eq!(Term(5), follower.log.entry(LogIndex(3011)).unwrap().0);
     }
 
-    /// Transitions the consensus state machine to Follower state with the provided term. The
-    /// `voted_for` field will be reset. The provided leader hint will replace the last known
-    /// leader.
-    fn transition_to_follower(&mut self, term: Term, leader: ServerId, actions: &mut Actions) {
-        scoped_trace!("transitioning to Follower");
-        self.log.set_current_term(term).unwrap();
-        self.state = ConsensusState::Follower;
-        self.follower_state.write().unwrap().set_leader(leader);
-        actions.clear_timeouts.push(self.lid);
-        actions.clear_peer_messages = true;
-        actions.timeouts.push(ConsensusTimeout::Election(self.lid));
+    /// Proposes `value` to `leader`, and returns the resulting client messages.
+    fn propose(leader: ServerId,
+               value: &[u8],
+               peers: &mut HashMap<ServerId, TestPeer>)
+               -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
+        propose_in_session(leader, value, None, peers)
     }
 
-    /// Returns whether the consensus state machine is currently a Leader.
-    pub fn is_leader(&self) -> bool {
-        self.state == ConsensusState::Leader
+    /// Proposes `value` to `leader` as `proposal`, and returns the resulting client messages.
+    fn propose_in_session(leader: ServerId,
+                          value: &[u8],
+                          proposal: Option<ProposalId>,
+                          peers: &mut HashMap<ServerId, TestPeer>)
+                          -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
+        let proposal = messages::proposal_request(TransactionId::new(), value, proposal, *lid);
+        client_request(leader, &proposal, peers)
     }
 
-    /// Returns whether the consensus state machine is currently a Follower.
-    fn is_follower(&self) -> bool {
-        self.state == ConsensusState::Follower
+    /// Sends the client request `message` to `peer`, and returns the resulting client messages.
+    fn client_request(peer: ServerId,
+                      message: &Builder<HeapAllocator>,
+                      peers: &mut HashMap<ServerId, TestPeer>)
+                      -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
+        let reader = into_reader(message);
+        let message_reader = reader.get_root::<client_request::Reader>().unwrap();
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&peer).unwrap();
+            peer.apply_client_message(ClientId::new(), &message_reader, &mut actions).unwrap();
+            peer.flush(&mut actions);
+        }
+        apply_actions(peer, actions, peers)
     }
 
-    /// Returns whether the consensus state machine is currently a Candidate.
-    fn is_candidate(&self) -> bool {
-        self.state == ConsensusState::Candidate
+    /// Returns whether the client response is a successful proposal response.
+    fn is_proposal_success(message: &Builder<HeapAllocator>) -> bool {
+        proposal_result(message).is_some()
     }
 
-    /// Returns the current term.
-    fn current_term(&self) -> Term {
-        self.log.current_term().unwrap()
+    /// Returns the result carried by a successful proposal response.
+    fn proposal_result(message: &Builder<HeapAllocator>) -> Option<Vec<u8>> {
+        let reader = into_reader(message);
+        let response = reader.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::Proposal(Ok(response)) => {
+                match response.which().unwrap() {
+                    command_response::Which::Success(Ok(result)) => Some(result.to_vec()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
     }
 
-    /// Returns the term of the latest applied log entry.
-    fn latest_log_term(&self) -> Term {
-        self.log.latest_log_term().unwrap()
+    /// Returns the reason carried by a failed transaction response.
+    fn transaction_failure(message: &Builder<HeapAllocator>) -> Option<Vec<u8>> {
+        let reader = into_reader(message);
+        let response = reader.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::Transaction(Ok(response)) => {
+                match response.which().unwrap() {
+                    command_response::Which::Failure(Ok(reason)) => Some(reason.to_vec()),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
     }
 
-    /// Returns the index of the latest applied log entry.
-    fn latest_log_index(&self) -> LogIndex {
-        self.log.latest_log_index().unwrap()
+    /// Returns the state, the number of commands and the begin index carried by a transaction
+    /// status response which knows the transaction.
+    fn transaction_status(message: &Builder<HeapAllocator>)
+                          -> Option<(TransactionState, u64, u64)> {
+        let reader = into_reader(message);
+        let response = reader.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::TransactionStatus(Ok(response)) => {
+                match response.which().unwrap() {
+                    transaction_status_response::Which::Status(status) => {
+                        Some((status.get_state().unwrap(),
+                              status.get_commands(),
+                              status.get_begin_index()))
+                    }
+                    transaction_status_response::Which::Unknown(()) => None,
+                }
+            }
+            _ => panic!("unexpected response"),
+        }
     }
 
-    /// Get the cluster quorum majority size.
-    fn majority(&self) -> usize {
-        let peers = self.peers.len();
-        let cluster_members = peers.checked_add(1)
-            .expect(&format!("unable to support {} cluster members", peers));
-        (cluster_members >> 1) + 1
+    /// Tests that a retried proposal is answered with the result of its first application
+    /// instead of being applied again.
+    #[test]
+    fn test_duplicate_proposal() {
+        setup_test!("test_duplicate_proposal");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let first = ProposalId {
+            client: ClientId::new(),
+            sequence: 1,
+        };
+        let responses = propose_in_session(leader, b"foo", Some(first), &mut peers);
+        assert_eq!(Some(vec![]), proposal_result(&responses[0].1));
+        // Every replica records the result. Replace it, to tell a recorded result from a fresh
+        // one.
+        for peer in peers.values_mut() {
+            assert_eq!(Some(&b""[..]), peer.sessions.result(&first));
+            peer.sessions.record(first, b"bar".to_vec());
+        }
+
+        let responses = propose_in_session(leader, b"foo", Some(first), &mut peers);
+        assert_eq!(Some(b"bar".to_vec()), proposal_result(&responses[0].1));
+        for peer in peers.values() {
+            assert_eq!(Some(&b"bar"[..]), peer.sessions.result(&first));
+        }
+
+        // Once the client has moved on, the result of its first proposal is gone.
+        let second = ProposalId { sequence: 2, ..first };
+        let responses = propose_in_session(leader, b"foo", Some(second), &mut peers);
+        assert!(is_proposal_success(&responses[0].1));
+        let responses = propose_in_session(leader, b"foo", Some(first), &mut peers);
+        assert_eq!(1, responses.len());
+        assert!(!is_proposal_success(&responses[0].1));
     }
-}
 
-impl<L, M> fmt::Debug for Consensus<L, M>
-    where L: Log,
-          M: StateMachine
-{
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        match self.state {
-            ConsensusState::Follower => {
-                write!(fmt,
-                       "Follower {{ lid: {}, term: {}, index: {} }}",
-                       self.lid,
-                       self.current_term(),
-                       self.latest_log_index())
-            }
-            ConsensusState::Candidate => {
-                write!(fmt,
-                       "Candidate {{ lid: {}, term: {}, index: {} }}",
-                       self.lid,
-                       self.current_term(),
-                       self.latest_log_index())
+    /// Tests that a retried transaction command which could not be buffered fails again instead
+    /// of being answered as a duplicate.
+    #[test]
+    fn test_duplicate_failed_transaction_command() {
+        setup_test!("test_duplicate_failed_transaction_command");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        // The transaction is not running, so the command is not buffered.
+        let proposal = ProposalId {
+            client: ClientId::new(),
+            sequence: 1,
+        };
+        let payload = Payload::TransactionCommand(TransactionId::new(),
+                                                  b"foo".to_vec(),
+                                                  Some(proposal));
+        for _ in 0..2 {
+            let mut actions = Actions::new();
+            {
+                let peer = peers.get_mut(&leader).unwrap();
+                let log_index = peer.append_entry(&payload.to_bytes());
+                let client = proposal.client;
+                peer.leader_state.write().unwrap().proposals.push_back((client, log_index));
+                peer.flush(&mut actions);
             }
-            ConsensusState::Leader => {
-                write!(fmt,
-                       "Leader {{ lid: {}, term: {}, index: {} }}",
-                       self.lid,
-                       self.current_term(),
-                       self.latest_log_index())
+            let responses = apply_actions(leader, actions, &mut peers);
+            assert_eq!(1, responses.len());
+            assert!(!is_proposal_success(&responses[0].1));
+        }
+        for peer in peers.values() {
+            assert!(!peer.sessions.is_applied(&proposal));
+        }
+    }
+
+    /// Tests that transactions run alongside each other and alongside proposals outside of any
+    /// transaction, and that a transaction conflicting with a later command fails to commit.
+    #[test]
+    fn test_transaction() {
+        setup_test!("test_transaction");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let (first, second) = (TransactionId::new(), TransactionId::new());
+        for &session in &[first, second] {
+            let begin = messages::client_transaction_begin(*lid, session);
+            let responses = client_request(leader, &begin, &mut peers);
+            assert_eq!(Some(session.as_bytes().to_vec()), proposal_result(&responses[0].1));
+        }
+        let begin = messages::client_transaction_begin(*lid, first);
+        assert!(!is_proposal_success(&client_request(leader, &begin, &mut peers)[0].1));
+        for peer in peers.values() {
+            assert!(peer.transactions.is_active(&first));
+            assert!(peer.transactions.is_active(&second));
+        }
+
+        // The command of the transaction is buffered, while a proposal outside of any
+        // transaction is applied right away.
+        let proposal = messages::proposal_request(first, b"foo", None, *lid);
+        assert!(is_proposal_success(&client_request(leader, &proposal, &mut peers)[0].1));
+        let proposal = messages::proposal_request(TransactionId::new(), b"bar", None, *lid);
+        assert!(is_proposal_success(&client_request(leader, &proposal, &mut peers)[0].1));
+        let foo = Payload::TransactionCommand(first, b"foo".to_vec(), None).to_bytes();
+        for peer in peers.values() {
+            assert_eq!((Term(1), &foo[..]), peer.log.entry(LogIndex(4)).unwrap());
+            assert_eq!((Term(1), &command(b"bar")[..]), peer.log.entry(LogIndex(5)).unwrap());
+        }
+
+        // NullStateMachine commands modify the whole state, so the first transaction conflicts
+        // with the proposal applied after it began. The second transaction has no commands.
+        let commit = messages::client_transaction_commit(*lid, first);
+        assert!(!is_proposal_success(&client_request(leader, &commit, &mut peers)[0].1));
+        let commit = messages::client_transaction_commit(*lid, second);
+        assert!(is_proposal_success(&client_request(leader, &commit, &mut peers)[0].1));
+        for peer in peers.values() {
+            assert!(peer.transactions.sessions().is_empty());
+            assert_eq!(LogIndex(7), peer.last_applied);
+        }
+    }
+
+    /// Tests that the leader reports how a transaction stands once it has confirmed that it still
+    /// leads, and that followers redirect the request to the leader.
+    #[test]
+    fn test_transaction_status() {
+        setup_test!("test_transaction_status");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let session = TransactionId::new();
+        let status = messages::client_transaction_status(*lid, session);
+        assert!(transaction_status(&client_request(leader, &status, &mut peers)[0].1).is_none());
+
+        let begin = messages::client_transaction_begin(*lid, session);
+        client_request(leader, &begin, &mut peers);
+        for &command in &[b"foo", b"bar"] {
+            let proposal = messages::proposal_request(session, command, None, *lid);
+            client_request(leader, &proposal, &mut peers);
+        }
+        let response = transaction_status(&client_request(leader, &status, &mut peers)[0].1);
+        assert!(response == Some((TransactionState::Active, 2, 2)));
+        let redirect = client_request(ServerId(1), &status, &mut peers);
+        assert!(!is_proposal_success(&redirect[0].1));
+
+        let commit = messages::client_transaction_commit(*lid, session);
+        assert!(is_proposal_success(&client_request(leader, &commit, &mut peers)[0].1));
+        let response = transaction_status(&client_request(leader, &status, &mut peers)[0].1);
+        assert!(response == Some((TransactionState::Committed, 2, 2)));
+
+        // A leader cut off from its followers may have been deposed, and does not answer.
+        peers.remove(&ServerId(1));
+        peers.remove(&ServerId(2));
+        assert!(client_request(leader, &status, &mut peers).is_empty());
+    }
+
+    /// Tests that rolling a transaction back to a savepoint drops only the commands proposed
+    /// since, on every peer.
+    #[test]
+    fn test_transaction_savepoint() {
+        setup_test!("test_transaction_savepoint");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let session = TransactionId::new();
+        let begin = messages::client_transaction_begin(*lid, session);
+        client_request(leader, &begin, &mut peers);
+        let proposal = messages::proposal_request(session, b"foo", None, *lid);
+        client_request(leader, &proposal, &mut peers);
+        let savepoint = messages::client_transaction_savepoint(*lid, session, "step");
+        assert!(is_proposal_success(&client_request(leader, &savepoint, &mut peers)[0].1));
+        let proposal = messages::proposal_request(session, b"bar", None, *lid);
+        client_request(leader, &proposal, &mut peers);
+        for peer in peers.values() {
+            assert_eq!(2, peer.transactions.status(&session).unwrap().commands);
+        }
+
+        let rollback = messages::client_transaction_rollback_to(*lid, session, "step");
+        assert!(is_proposal_success(&client_request(leader, &rollback, &mut peers)[0].1));
+        let rollback = messages::client_transaction_rollback_to(*lid, session, "other");
+        let responses = client_request(leader, &rollback, &mut peers);
+        assert!(transaction_failure(&responses[0].1).is_some());
+        for peer in peers.values() {
+            assert!(peer.transactions.is_active(&session));
+            assert_eq!(1, peer.transactions.status(&session).unwrap().commands);
+        }
+    }
+
+    /// Tests that the logs created and dropped through the metadata log are recorded on every
+    /// peer, and that a log can only be dropped once created.
+    #[test]
+    fn test_log_change() {
+        setup_test!("test_log_change");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let created = LogId(Uuid::new_v4());
+        let replicas = Some(vec![ServerId(1), ServerId(2)]);
+        let changes = vec![(Payload::CreateLog(created, replicas.clone()), true),
+                           (Payload::DropLog(LogId(Uuid::new_v4())), false),
+                           (Payload::CreateLog(created, None), false)];
+        for (payload, success) in changes {
+            let mut actions = Actions::new();
+            {
+                let peer = peers.get_mut(&leader).unwrap();
+                peer.log_change_request(ClientId::new(), payload, &mut actions);
+                peer.flush(&mut actions);
             }
+            let responses = apply_actions(leader, actions, &mut peers);
+            assert_eq!(success, is_proposal_success(&responses[0].1));
+        }
+        for peer in peers.values() {
+            assert_eq!(vec![(created, replicas.clone())],
+                       peer.logs.clone().into_iter().collect::<Vec<_>>());
+        }
+
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.log_change_request(ClientId::new(), Payload::DropLog(created), &mut actions);
+            peer.flush(&mut actions);
+        }
+        apply_actions(leader, actions, &mut peers);
+        for peer in peers.values() {
+            assert!(peer.logs.is_empty());
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate env_logger;
-    extern crate test;
+    /// Tests that the election timeouts of the preferred leader of a log run out before those of
+    /// the other replicas.
+    #[test]
+    fn test_preferred_leader_timeout() {
+        setup_test!("test_preferred_leader_timeout");
+        let mut peers = new_cluster(3);
+        let config = LogConfig::default();
+        let middle = (config.election_timeout_min + config.election_timeout_max) / 2;
+        for peer in peers.values_mut() {
+            peer.set_preferred_leader(Some(ServerId(1)));
+        }
+        let timeout = ConsensusTimeout::Election(*lid);
+        for _ in 0..100 {
+            assert!(peers[&ServerId(1)].timeout_duration_ms(&timeout) < middle);
+            assert!(peers[&ServerId(0)].timeout_duration_ms(&timeout) >= middle);
+            assert!(peers[&ServerId(2)].timeout_duration_ms(&timeout) >= middle);
+        }
+        assert_eq!(config.heartbeat_interval,
+                   peers[&ServerId(0)]
+                       .timeout_duration_ms(&ConsensusTimeout::Heartbeat(ServerId(1), *lid)));
+    }
 
-    use std::collections::{HashMap, VecDeque};
-    use std::io::Cursor;
-    use std::net::SocketAddr;
-    use std::rc::Rc;
-    use std::str::FromStr;
+    /// Tests that a transaction survives the failure of the leader, and that rolling it back
+    /// keeps its entries in the log.
+    #[test]
+    fn test_transaction_failover() {
+        setup_test!("test_transaction_failover");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
 
-    use capnp::serialize::{self, OwnedSegments};
-    use capnp::message::{Allocator, Builder, HeapAllocator, Reader, ReaderOptions};
-    use messages_capnp::{client_request, message};
-    use ClientId;
-    use LogIndex;
-    use ServerId;
-    use LogId;
-    use Term;
-    use TransactionId;
-    use messages;
-    use consensus::{Actions, Consensus, ConsensusTimeout};
-    use state_machine::NullStateMachine;
-    use persistent_log::{MemLog, Log};
-    use uuid::Uuid;
-    use std::cell::RefCell;
+        let session = TransactionId::new();
+        client_request(leader, &messages::client_transaction_begin(*lid, session), &mut peers);
+        let proposal = messages::proposal_request(session, b"foo", None, *lid);
+        assert!(is_proposal_success(&client_request(leader, &proposal, &mut peers)[0].1));
 
-    type TestPeer = Consensus<MemLog, NullStateMachine>;
+        for peer in peers.values_mut() {
+            peer.leader_contact = None;
+        }
+        let new_leader = ServerId(1);
+        elect_leader(new_leader, &mut peers);
+        let leader_state = peers[&new_leader].leader_state.clone();
+        assert!(leader_state.read().unwrap().transactions.contains_key(&session));
+        for peer in peers.values() {
+            assert!(peer.transactions.is_active(&session));
+        }
 
-    lazy_static!{
-        static ref lid: LogId = LogId(Uuid::new_v4());
+        // The new leader begins other transactions while the old one runs.
+        let other = TransactionId::new();
+        let begin = messages::client_transaction_begin(*lid, other);
+        assert!(is_proposal_success(&client_request(new_leader, &begin, &mut peers)[0].1));
+
+        let rollback = messages::client_transaction_rollback(*lid, session);
+        assert!(is_proposal_success(&client_request(new_leader, &rollback, &mut peers)[0].1));
+        let foo = Payload::TransactionCommand(session, b"foo".to_vec(), None).to_bytes();
+        for peer in peers.values() {
+            assert!(!peer.transactions.is_active(&session));
+            assert!(peer.transactions.is_active(&other));
+            assert_eq!(LogIndex(6), peer.latest_log_index());
+            assert_eq!(LogIndex(6), peer.last_applied);
+            assert_eq!((Term(1), &foo[..]), peer.log.entry(LogIndex(3)).unwrap());
+        }
     }
 
-    fn new_cluster(size: u64) -> HashMap<ServerId, TestPeer> {
-        let ids: HashMap<ServerId, SocketAddr> = (0..size)
-            .map(Into::into)
-            .map(|id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id)).unwrap()))
-            .collect();
-        ids.iter()
-            .map(|(&id, _)| {
-                let mut peers = ids.clone();
-                peers.remove(&id);
-                let store = MemLog::new();
-                (id, Consensus::new(id, *lid, peers, store, NullStateMachine))
-            })
-            .collect()
+    /// Tests that the leader rolls back a transaction which times out, or whose client
+    /// disconnects, and that the client learns about the timeout.
+    #[test]
+    fn test_transaction_timeout() {
+        setup_test!("test_transaction_timeout");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let session = TransactionId::new();
+        client_request(leader, &messages::client_transaction_begin(*lid, session), &mut peers);
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.apply_timeout(ConsensusTimeout::Transaction(session, *lid), &mut actions);
+            peer.flush(&mut actions);
+        }
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        for peer in peers.values() {
+            assert!(!peer.transactions.is_active(&session));
+        }
+
+        let timed_out = Some(b"The transaction timed out and was rolled back".to_vec());
+        let proposal = messages::proposal_request(session, b"foo", None, *lid);
+        let responses = client_request(leader, &proposal, &mut peers);
+        assert_eq!(timed_out, transaction_failure(&responses[0].1));
+        let commit = messages::client_transaction_commit(*lid, session);
+        let responses = client_request(leader, &commit, &mut peers);
+        assert_eq!(timed_out, transaction_failure(&responses[0].1));
+        assert!(peers[&leader].leader_state.read().unwrap().timed_out.is_empty());
+
+        // Only the transactions of a disconnected client are rolled back.
+        let client = ClientId::new();
+        let (owned, other, expired) =
+            (TransactionId::new(), TransactionId::new(), TransactionId::new());
+        let mut actions = Actions::new();
+        for session in &[owned, expired] {
+            let begin = messages::client_transaction_begin(*lid, *session);
+            let reader = into_reader(&begin);
+            peers.get_mut(&leader)
+                .unwrap()
+                .apply_client_message(client,
+                                      &reader.get_root::<client_request::Reader>().unwrap(),
+                                      &mut actions).unwrap();
+        }
+        client_request(leader, &messages::client_transaction_begin(*lid, other), &mut peers);
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Transaction(expired, *lid), &mut actions);
+        assert!(peers[&leader].leader_state.read().unwrap().timed_out.contains_key(&expired));
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.client_connection_reset(client, &mut actions);
+            peer.flush(&mut actions);
+        }
+        apply_actions(leader, actions, &mut peers);
+        for peer in peers.values() {
+            assert!(!peer.transactions.is_active(&owned));
+            assert!(!peer.transactions.is_active(&expired));
+            assert!(peer.transactions.is_active(&other));
+        }
+        // The disconnected client is no longer told about its timed out transaction.
+        assert!(peers[&leader].leader_state.read().unwrap().timed_out.is_empty());
+    }
+
+    /// Tests that malformed transaction requests are answered with a failure, and that
+    /// malformed abort requests from peers are dropped.
+    #[test]
+    fn test_malformed_transaction_request() {
+        setup_test!("test_malformed_transaction_request");
+        let mut peers = new_cluster(1);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
+
+        let mut begin = Builder::new_default();
+        begin.init_root::<client_request::Builder>().init_transaction_begin().set_session(b"foo");
+        let mut prepare = Builder::new_default();
+        {
+            let mut request =
+                prepare.init_root::<client_request::Builder>().init_transaction_prepare();
+            request.set_session(&TransactionId::new().as_bytes());
+            request.set_coordinator(b"bar");
+        }
+        for request in &[begin, prepare] {
+            let mut actions = Actions::new();
+            let reader = into_reader(request);
+            peers.get_mut(&leader)
+                .unwrap()
+                .apply_client_message(ClientId::new(),
+                                      &reader.get_root::<client_request::Reader>().unwrap(),
+                                      &mut actions).unwrap();
+            assert_eq!(1, actions.client_messages.len());
+            assert!(transaction_failure(&actions.client_messages[0].1).is_some());
+        }
+
+        // A malformed abort request from a peer is dropped.
+        let mut abort = Builder::new_default();
+        {
+            let mut request = abort.init_root::<message::Builder>();
+            request.set_log_id(&lid.as_bytes());
+            request.init_transaction_abort().set_session(b"foo");
+        }
+        let reader = into_reader(&abort);
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_peer_message(ServerId(1),
+                                &reader.get_root::<message::Reader>().unwrap(),
+                                &mut actions).unwrap();
+        assert!(actions.peer_messages.is_empty());
+        assert!(actions.client_messages.is_empty());
     }
 
-    fn into_reader<A>(message: &Builder<A>) -> Reader<OwnedSegments>
-        where A: Allocator
-    {
-        let mut buf = Cursor::new(Vec::new());
+    /// Tests that a prepared transaction outlives its timeout and the disconnection of its
+    /// client, and ends as decided in its coordinator log.
+    #[test]
+    fn test_prepared_transaction() {
+        setup_test!("test_prepared_transaction");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
 
-        serialize::write_message(&mut buf, message).unwrap();
-        buf.set_position(0);
-        serialize::read_message(&mut buf, ReaderOptions::new()).unwrap()
-    }
+        // The log coordinates its own transaction.
+        let client = ClientId::new();
+        let session = TransactionId::new();
+        let requests = [messages::client_transaction_begin(*lid, session),
+                        messages::proposal_request(session, b"foo", None, *lid),
+                        messages::client_transaction_prepare(*lid, session, *lid)];
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            for request in &requests {
+                let reader = into_reader(request);
+                peer.apply_client_message(client,
+                                          &reader.get_root::<client_request::Reader>().unwrap(),
+                                          &mut actions).unwrap();
+            }
+            peer.flush(&mut actions);
+        }
+        let responses = apply_actions(leader, actions, &mut peers);
+        assert_eq!(3, responses.len());
+        assert!(responses.iter().all(|&(_, ref response)| is_proposal_success(response)));
 
-    /// Applies the actions to the consensus peers (and recursively applies any resulting
-    /// actions), and returns any client messages.
-    fn apply_actions(from: ServerId,
-                     mut actions: Actions,
-                     peers: &mut HashMap<ServerId, TestPeer>)
-                     -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
-        let mut queue: VecDeque<(ServerId, ServerId, Rc<Builder<HeapAllocator>>)> = VecDeque::new();
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.apply_timeout(ConsensusTimeout::Transaction(session, *lid), &mut actions);
+            peer.client_connection_reset(client, &mut actions);
+            peer.flush(&mut actions);
+        }
+        apply_actions(leader, actions, &mut peers);
+        for peer in peers.values() {
+            assert_eq!(Some(*lid), peer.transactions.prepared(&session));
+        }
+        assert_eq!(Some(*lid), peers[&leader].in_doubt(&session));
 
-        for (to, message) in actions.peer_messages.iter().cloned() {
-            queue.push_back((from, to, message));
+        // A follower forwards the request to abort to the leader.
+        let follower = ServerId(1);
+        let mut actions = Actions::new();
+        peers.get_mut(&follower).unwrap().request_abort(session, &mut actions);
+        apply_actions(follower, actions, &mut peers);
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().flush(&mut actions);
+        apply_actions(leader, actions, &mut peers);
+        for peer in peers.values() {
+            assert_eq!(Some(false), peer.decision(&session));
         }
-        actions.peer_messages.clear();
 
-        while let Some((from, to, message)) = queue.pop_front() {
-            let mut reader = into_reader(&*message);
-            let message_reader = reader.get_root::<message::Reader>().unwrap();
-            peers.get_mut(&to)
-                .unwrap()
-                .apply_peer_message(from, &message_reader, &mut actions);
-            let inner_from = to;
-            for (inner_to, message) in actions.peer_messages.iter().cloned() {
-                queue.push_back((inner_from, inner_to, message));
-            }
-            actions.peer_messages.clear();
+        let mut actions = Actions::new();
+        {
+            let peer = peers.get_mut(&leader).unwrap();
+            peer.resolve_transaction(session, false, &mut actions);
+            peer.flush(&mut actions);
+        }
+        apply_actions(leader, actions, &mut peers);
+        for peer in peers.values() {
+            assert!(!peer.transactions.is_active(&session));
         }
 
-        let Actions { client_messages, .. } = actions;
-        client_messages
+        // The first decision stands.
+        let decide = messages::client_transaction_decide(*lid, session, true);
+        let responses = client_request(leader, &decide, &mut peers);
+        assert_eq!(Some(b"The transaction was aborted".to_vec()),
+                   transaction_failure(&responses[0].1));
     }
 
-    /// Elect `leader` as the leader of a cluster with the provided followers.
-    /// The leader and the followers must be in the same term.
-    fn elect_leader(leader: ServerId, peers: &mut HashMap<ServerId, TestPeer>) {
+    /// Tests that leadership is handed to a lagging peer once it has caught up, and that the
+    /// requesting client is notified when the target has been elected.
+    #[test]
+    fn test_leader_transfer() {
+        setup_test!("test_leader_transfer");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let target = peer_ids[2];
+        elect_leader(leader, &mut peers);
+
+        // Partition the target; it misses an entry and the start of the transfer.
+        let target_peer = peers.remove(&target).unwrap();
+        assert_eq!(1, propose(leader, b"foo", &mut peers).len());
+
+        let client = ClientId::new();
         let mut actions = Actions::new();
         peers.get_mut(&leader)
             .unwrap()
-            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
-        let client_messages = apply_actions(leader, actions, peers);
-        assert!(client_messages.is_empty());
-        assert!(peers[&leader].is_leader());
+            .transfer_leadership(target, Some(client), &mut actions)
+            .unwrap();
+        assert!(actions.timeouts.contains(&ConsensusTimeout::Transfer(*lid)));
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+
+        // Proposals are rejected, and no second transfer may be started.
+        let client_messages = propose(leader, b"bar", &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert!(!is_proposal_success(&client_messages[0].1));
+        assert_eq!(LogIndex(2), peers[&leader].latest_log_index());
+        let mut actions = Actions::new();
+        assert!(peers.get_mut(&leader)
+            .unwrap()
+            .transfer_leadership(peer_ids[1], None, &mut actions)
+            .is_err());
+
+        // Heal the partition; the target catches up and is elected.
+        peers.insert(target, target_peer);
+        let addr = peers[&leader].peers[&target];
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(target, addr, &mut actions);
+        let client_messages = apply_actions(leader, actions, &mut peers);
+
+        assert!(peers[&target].is_leader());
+        assert_eq!(Term(2), peers[&target].current_term());
+        assert!(peers[&leader].is_follower());
+        assert_eq!(1, client_messages.len());
+        assert_eq!(client, client_messages[0].0);
+        assert!(is_proposal_success(&client_messages[0].1));
     }
 
-    /// Tests the majority function.
+    /// Tests that a leadership transfer which does not complete is abandoned when the transfer
+    /// timeout fires, after which proposals are accepted again.
     #[test]
-    fn test_majority() {
-        let (_, peer) = new_cluster(1).into_iter().next().unwrap();
-        assert_eq!(1, peer.majority());
+    fn test_leader_transfer_timeout() {
+        setup_test!("test_leader_transfer_timeout");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let target = peer_ids[2];
+        elect_leader(leader, &mut peers);
+        peers.remove(&target);
+
+        let client = ClientId::new();
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .transfer_leadership(target, Some(client), &mut actions)
+            .unwrap();
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
 
-        let (_, peer) = new_cluster(2).into_iter().next().unwrap();
-        assert_eq!(2, peer.majority());
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Transfer(*lid), &mut actions);
+        assert!(peers[&leader].is_leader());
+        assert_eq!(1, actions.client_messages.len());
+        assert_eq!(client, actions.client_messages[0].0);
+        assert!(!is_proposal_success(&actions.client_messages[0].1));
 
-        let (_, peer) = new_cluster(3).into_iter().next().unwrap();
-        assert_eq!(2, peer.majority());
+        let client_messages = propose(leader, b"foo", &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert!(is_proposal_success(&client_messages[0].1));
+    }
 
-        let (_, peer) = new_cluster(4).into_iter().next().unwrap();
-        assert_eq!(3, peer.majority());
+    /// Returns the set of the given server IDs.
+    fn members(ids: &[u64]) -> HashSet<ServerId> {
+        ids.iter().map(|&id| ServerId(id)).collect()
     }
 
-    /// Tests that a consensus state machine with no peers will transitition immediately to the
-    /// Leader state upon the first election timeout.
-    #[test]
-    fn test_solitary_consensus_transition_to_leader() {
-        setup_test!("test_solitary_consensus_transition_to_leader");
-        let (_, mut peer) = new_cluster(1).into_iter().next().unwrap();
-        assert!(peer.is_follower());
+    /// Adds a new consensus with the ID `new_peer` to the cluster, and registers it with
+    /// `leader`, which adds it as a learner.
+    fn add_learner(leader: ServerId, new_peer: ServerId, peers: &mut HashMap<ServerId, TestPeer>) {
+        let addr = SocketAddr::from_str(&format!("127.0.0.1:{}", new_peer)).unwrap();
+        let mut all_peers = peers[&leader].peers.clone();
+        all_peers.insert(leader,
+                         SocketAddr::from_str(&format!("127.0.0.1:{}", leader)).unwrap());
+        let consensus = Consensus::new(new_peer,
+                                       *lid,
+                                       all_peers,
+                                       MemLog::new(),
+                                       NullStateMachine,
+                                       LogConfig::default());
+        peers.insert(new_peer, consensus);
 
         let mut actions = Actions::new();
-        peer.apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
-        assert!(peer.is_leader());
-        assert!(actions.peer_messages.is_empty());
-        assert!(actions.client_messages.is_empty());
-        assert!(actions.timeouts.is_empty());
+        peers.get_mut(&leader).unwrap().add_peer(new_peer, addr, &mut actions).unwrap();
+        assert!(apply_actions(leader, actions, peers).is_empty());
     }
 
-    /// A simple election test over multiple group sizes.
+    /// Tests that a peer is added as a learner, promoted through the joint configuration, and
+    /// that entries are then replicated to it.
     #[test]
-    fn test_election() {
-        setup_test!("test_election");
+    fn test_add_peer() {
+        setup_test!("test_add_peer");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        let new_peer = ServerId(3);
+        elect_leader(leader, &mut peers);
+        add_learner(leader, new_peer, &mut peers);
+        for peer in peers.values() {
+            assert_eq!(members(&[3]), peer.configuration.learners);
+        }
 
-        for group_size in 1..10 {
-            let mut peers = new_cluster(group_size);
-            let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
-            let leader = &peer_ids[0];
-            elect_leader(leader.clone(), &mut peers);
-            assert!(peers[leader].is_leader());
-            for follower in peer_ids.iter().skip(1) {
-                assert!(peers[follower].is_follower());
-            }
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().promote_learner(new_peer, &mut actions).unwrap();
+        assert_eq!(Configuration::new(members(&[0, 1, 2])).joint(members(&[0, 1, 2, 3])),
+                   peers[&leader].configuration);
+
+        // Only one membership change may be in progress.
+        assert!(peers.get_mut(&leader)
+            .unwrap()
+            .remove_peer(ServerId(1), &mut Actions::new())
+            .is_err());
+
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        for peer in peers.values() {
+            assert_eq!(Configuration::new(members(&[0, 1, 2, 3])), peer.configuration);
         }
+        assert_eq!(LogIndex(4), peers[&leader].commit_index);
+
+        assert_eq!(1, propose(leader, b"foo", &mut peers).len());
+        assert_eq!((Term(1), &command(b"foo")[..]),
+                   peers[&new_peer].log.entry(LogIndex(5)).unwrap());
     }
 
-    /// Tests the Raft heartbeating mechanism. The leader receives a heartbeat
-    /// timeout, and in response sends an AppendEntries message to the follower.
-    /// The follower in turn resets its election timout, and replies to the
-    /// leader.
+    /// Tests that a learner receives entries without counting toward the commit quorum or
+    /// campaigning, and that it is only promoted once it has caught up.
     #[test]
-    fn test_heartbeat() {
-        setup_test!("test_heartbeat");
+    fn test_learner() {
+        setup_test!("test_learner");
         let mut peers = new_cluster(2);
-        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
-        let leader_id = &peer_ids[0];
-        let follower_id = &peer_ids[1];
-        elect_leader(leader_id.clone(), &mut peers);
-
-        // Leader pings with a heartbeat timeout.
-        let leader_append_entries = {
-            let mut actions = Actions::new();
-            let leader = peers.get_mut(&leader_id).unwrap();
-            leader.heartbeat_timeout(follower_id.clone(), &mut actions);
+        let leader = ServerId(0);
+        let follower = ServerId(1);
+        let learner = ServerId(2);
+        elect_leader(leader, &mut peers);
+        add_learner(leader, learner, &mut peers);
 
-            let peer_message = actions.peer_messages.iter().next().unwrap();
-            assert_eq!(peer_message.0, follower_id.clone());
-            peer_message.1.clone()
-        };
-        let mut reader = into_reader(&*leader_append_entries);
-        let message_reader = reader.get_root::<message::Reader>().unwrap();
+        // The learner's acknowledgement does not commit an entry without the follower.
+        let follower_peer = peers.remove(&follower).unwrap();
+        assert!(propose(leader, b"foo", &mut peers).is_empty());
+        assert_eq!((Term(1), &command(b"foo")[..]),
+                   peers[&learner].log.entry(LogIndex(3)).unwrap());
+        assert_eq!(LogIndex(2), peers[&leader].commit_index);
 
-        // Follower responds.
-        let follower_response = {
-            let mut actions = Actions::new();
-            let follower = peers.get_mut(&follower_id).unwrap();
-            follower.apply_peer_message(leader_id.clone(), &message_reader, &mut actions);
+        peers.insert(follower, follower_peer);
+        let addr = peers[&leader].peers[&follower];
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().peer_connection_reset(follower, addr, &mut actions);
+        assert_eq!(1, apply_actions(leader, actions, &mut peers).len());
 
-            let election_timeout = actions.timeouts.iter().next().unwrap();
-            assert_eq!(election_timeout, &ConsensusTimeout::Election(*lid));
+        // The learner does not campaign.
+        let mut actions = Actions::new();
+        peers.get_mut(&learner)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(peers[&learner].is_follower());
+        assert!(actions.peer_messages.is_empty());
 
-            let peer_message = actions.peer_messages.iter().next().unwrap();
-            assert_eq!(peer_message.0, leader_id.clone());
-            peer_message.1.clone()
-        };
-        let mut reader = into_reader(&*follower_response);
-        let message_reader = reader.get_root::<message::Reader>().unwrap();
+        // A lagging learner is not promoted.
+        let learner_peer = peers.remove(&learner).unwrap();
+        assert_eq!(1, propose(leader, b"bar", &mut peers).len());
+        peers.get_mut(&leader).unwrap().config.learner_max_lag = 0;
+        assert!(peers.get_mut(&leader)
+            .unwrap()
+            .promote_learner(learner, &mut Actions::new())
+            .is_err());
 
-        // Leader applies and sends back a heartbeat to establish leadership.
-        let leader = peers.get_mut(&leader_id).unwrap();
+        peers.insert(learner, learner_peer);
+        let addr = peers[&leader].peers[&learner];
         let mut actions = Actions::new();
-        leader.apply_peer_message(follower_id.clone(), &message_reader, &mut actions);
-        let heartbeat_timeout = actions.timeouts.iter().next().unwrap();
-        assert_eq!(heartbeat_timeout,
-                   &ConsensusTimeout::Heartbeat(follower_id.clone(), *lid));
+        peers.get_mut(&leader).unwrap().peer_connection_reset(learner, addr, &mut actions);
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().promote_learner(learner, &mut actions).unwrap();
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+        assert_eq!(Configuration::new(members(&[0, 1, 2])), peers[&leader].configuration);
     }
 
-    /// Emulates a slow heartbeat message in a two-node cluster.
-    ///
-    /// The initial leader (Consensus 0) sends a heartbeat, but before it is received by the follower
-    /// (Consensus 1), Consensus 1's election timeout fires. Consensus 1 transitions to candidate state
-    /// and attempts to send a RequestVote to Consensus 0. When the partition is fixed, the
-    /// RequestVote should prompt Consensus 0 to step down. Consensus 1 should send a stale term
-    /// message in response to the heartbeat from Consensus 0.
+    /// Tests that a leader which removes itself steps down once the new configuration is
+    /// committed, and that the remaining members elect a leader among themselves.
     #[test]
-    fn test_slow_heartbeat() {
-        setup_test!("test_heartbeat");
-        let mut peers = new_cluster(2);
-        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
-        let peer_0 = &peer_ids[0];
-        let peer_1 = &peer_ids[1];
-        elect_leader(peer_0.clone(), &mut peers);
+    fn test_remove_leader() {
+        setup_test!("test_remove_leader");
+        let mut peers = new_cluster(3);
+        let leader = ServerId(0);
+        elect_leader(leader, &mut peers);
 
-        let mut peer_0_actions = Actions::new();
-        peers.get_mut(peer_0)
+        let mut actions = Actions::new();
+        peers.get_mut(&leader).unwrap().remove_peer(leader, &mut actions).unwrap();
+        assert!(peers[&leader].is_leader());
+        assert!(apply_actions(leader, actions, &mut peers).is_empty());
+
+        assert!(peers[&leader].is_follower());
+        for peer in peers.values() {
+            assert_eq!(Configuration::new(members(&[1, 2])), peer.configuration);
+        }
+
+        // The removed server does not campaign.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
             .unwrap()
-            .apply_timeout(ConsensusTimeout::Heartbeat(*peer_1, *lid),
-                           &mut peer_0_actions);
-        assert!(peers[peer_0].is_leader());
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(peers[&leader].is_follower());
+        assert!(actions.peer_messages.is_empty());
 
-        let mut peer_1_actions = Actions::new();
-        peers.get_mut(peer_1)
+        for peer in peers.values_mut() {
+            peer.leader_contact = None;
+        }
+        let new_leader = ServerId(1);
+        let mut actions = Actions::new();
+        peers.get_mut(&new_leader)
             .unwrap()
-            .apply_timeout(ConsensusTimeout::Election(*lid), &mut peer_1_actions);
-        assert!(peers[peer_1].is_candidate());
+            .apply_timeout(ConsensusTimeout::Election(*lid), &mut actions);
+        assert!(apply_actions(new_leader, actions, &mut peers).is_empty());
+        assert!(peers[&new_leader].is_leader());
 
-        // Apply candidate messages.
-        assert!(apply_actions(*peer_1, peer_1_actions, &mut peers).is_empty());
-        assert!(peers[peer_0].is_follower());
-        assert!(peers[peer_1].is_leader());
+        // Entries are committed without the removed server.
+        let latest_log_index = peers[&leader].latest_log_index();
+        assert_eq!(1, propose(new_leader, b"foo", &mut peers).len());
+        assert_eq!(latest_log_index, peers[&leader].latest_log_index());
+    }
 
-        // Apply stale heartbeat.
-        assert!(apply_actions(*peer_0, peer_0_actions, &mut peers).is_empty());
-        assert!(peers[peer_0].is_follower());
-        assert!(peers[peer_1].is_leader());
+    /// Sends the query `value` to `peer`, and returns the resulting client messages.
+    fn query(peer: ServerId,
+             value: &[u8],
+             consistency: ReadConsistency,
+             peers: &mut HashMap<ServerId, TestPeer>)
+             -> Vec<(ClientId, Rc<Builder<HeapAllocator>>)> {
+        let reader = into_reader(&messages::query_request(value, consistency, &*lid));
+        let message_reader = reader.get_root::<client_request::Reader>().unwrap();
+        let mut actions = Actions::new();
+        peers.get_mut(&peer)
+            .unwrap()
+            .apply_client_message(ClientId::new(), &message_reader, &mut actions).unwrap();
+        apply_actions(peer, actions, peers)
     }
 
-    /// Tests that a client proposal is correctly replicated to peers, and the client is notified
-    /// of the success.
+    /// Tests that queries are answered by the leader and by followers once the leader has
+    /// confirmed its leadership, and that a leader cut off from the majority does not answer.
     #[test]
-    fn test_proposal() {
-        setup_test!("test_proposal");
-        // Test various sizes.
-        for i in 1..7 {
-            scoped_debug!("testing size {} cluster", i);
-            let mut peers = new_cluster(i);
-            let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
-            let leader = peer_ids[0];
-            elect_leader(leader, &mut peers);
+    fn test_read_index() {
+        setup_test!("test_read_index");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        let follower = peer_ids[1];
+        elect_leader(leader, &mut peers);
 
-            let value: &[u8] = b"foo";
-            let reader =
-                into_reader(&messages::proposal_request(TransactionId::new(), value, *lid));
-            let message_reader = reader.get_root::<client_request::Reader>()
-                .unwrap();
-            let mut actions = Actions::new();
+        // The no-op entry committed on election lets the leader answer right away.
+        let client_messages = query(leader, b"foo", ReadConsistency::ReadIndex, &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert!(is_proposal_success(&client_messages[0].1));
+
+        // A follower forwards the query to the leader.
+        let client_messages = query(follower, b"foo", ReadConsistency::ReadIndex, &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert!(is_proposal_success(&client_messages[0].1));
+        assert!(peers[&follower].follower_state.read().unwrap().reads.is_empty());
+
+        // A forwarded query whose request is lost fails once its timeout fires.
+        let reader = into_reader(&messages::query_request(b"foo",
+                                                          ReadConsistency::ReadIndex,
+                                                          &*lid));
+        let mut actions = Actions::new();
+        peers.get_mut(&follower)
+            .unwrap()
+            .apply_client_message(ClientId::new(),
+                                  &reader.get_root::<client_request::Reader>().unwrap(),
+                                  &mut actions).unwrap();
+        assert_eq!(1, actions.peer_messages.len());
+        let timeout = actions.timeouts[0];
+        let mut actions = Actions::new();
+        peers.get_mut(&follower).unwrap().apply_timeout(timeout, &mut actions);
+        assert_eq!(1, actions.client_messages.len());
+        assert!(!is_proposal_success(&actions.client_messages[0].1));
+        assert!(peers[&follower].follower_state.read().unwrap().reads.is_empty());
 
-            let client = ClientId::new();
+        // A partitioned leader can not confirm its leadership.
+        let mut followers: HashMap<ServerId, TestPeer> = peer_ids[1..]
+            .iter()
+            .map(|id| (*id, peers.remove(id).unwrap()))
+            .collect();
+        assert!(query(leader, b"foo", ReadConsistency::ReadIndex, &mut peers).is_empty());
+        assert_eq!(1, peers[&leader].leader_state.read().unwrap().reads.len());
+
+        // Once a new leader is elected, the old leader fails the query.
+        let new_leader = peer_ids[1];
+        followers.get_mut(&new_leader).unwrap().leader_contact = None;
+        followers.get_mut(&peer_ids[2]).unwrap().leader_contact = None;
+        elect_leader(new_leader, &mut followers);
+        peers.extend(followers);
+        let mut actions = Actions::new();
+        peers.get_mut(&new_leader).unwrap().heartbeat_timeout(leader, &mut actions);
+        let client_messages = apply_actions(new_leader, actions, &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert!(!is_proposal_success(&client_messages[0].1));
+        assert!(peers[&leader].is_follower());
+    }
 
-            peers.get_mut(&leader)
-                .unwrap()
-                .apply_client_message(client, &message_reader, &mut actions);
+    /// Tests that a leader holding a lease answers queries without contacting its peers, and that
+    /// stale queries are answered by any server.
+    #[test]
+    fn test_lease_read() {
+        setup_test!("test_lease_read");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+        assert_eq!(1, propose(leader, b"foo", &mut peers).len());
 
-            let client_messages = apply_actions(leader, actions, &mut peers);
-            assert_eq!(1, client_messages.len());
-            for peer in peers.values() {
-                assert_eq!((Term(1), value), peer.log.entry(LogIndex(1)).unwrap());
-            }
+        // The followers acknowledged the leader's heartbeats; partition them.
+        let mut followers: HashMap<ServerId, TestPeer> = peer_ids[1..]
+            .iter()
+            .map(|id| (*id, peers.remove(id).unwrap()))
+            .collect();
+        let client_messages = query(leader, b"foo", ReadConsistency::Lease, &mut peers);
+        assert_eq!(1, client_messages.len());
+        assert!(is_proposal_success(&client_messages[0].1));
+        assert!(query(leader, b"foo", ReadConsistency::ReadIndex, &mut peers).is_empty());
+
+        // Once the lease has expired, the leader falls back to confirming its leadership.
+        peers.get_mut(&leader).unwrap().leader_state.write().unwrap().reset_quorum_contact();
+        assert!(query(leader, b"foo", ReadConsistency::Lease, &mut peers).is_empty());
+
+        // A partitioned follower answers stale queries on its own.
+        let client_messages = query(peer_ids[1], b"foo", ReadConsistency::Stale, &mut followers);
+        assert_eq!(1, client_messages.len());
+        assert!(is_proposal_success(&client_messages[0].1));
+    }
+
+    /// Tests that a leader steps down once it has not heard from a majority for an election
+    /// timeout, and fails the pending reads.
+    #[test]
+    fn test_check_quorum() {
+        setup_test!("test_check_quorum");
+        let mut peers = new_cluster(3);
+        let peer_ids: Vec<ServerId> = peers.keys().cloned().collect();
+        let leader = peer_ids[0];
+        elect_leader(leader, &mut peers);
+        assert_eq!(1, propose(leader, b"foo", &mut peers).len());
+
+        // The leader recently heard from the majority.
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::CheckQuorum(*lid), &mut actions);
+        assert!(peers[&leader].is_leader());
+        assert_eq!(vec![ConsensusTimeout::CheckQuorum(*lid)], actions.timeouts);
+
+        // A partitioned leader steps down.
+        for id in &peer_ids[1..] {
+            peers.remove(id);
         }
+        assert!(query(leader, b"foo", ReadConsistency::ReadIndex, &mut peers).is_empty());
+        peers.get_mut(&leader).unwrap().leader_state.write().unwrap().reset_quorum_contact();
+        let mut actions = Actions::new();
+        peers.get_mut(&leader)
+            .unwrap()
+            .apply_timeout(ConsensusTimeout::CheckQuorum(*lid), &mut actions);
+        assert!(peers[&leader].is_follower());
+        assert_eq!(1, actions.client_messages.len());
+        assert!(!is_proposal_success(&actions.client_messages[0].1));
+        assert_eq!(vec![ConsensusTimeout::Election(*lid)], actions.timeouts);
     }
 
     #[test]
@@ -1514,6 +4886,7 @@ mod tests {
                                                                     Term(0),
                                                                     &entries,
                                                                     LogIndex(0),
+                                                                    0,
                                                                     &*lid));
 
         let msg1 = reader.get_root::<message::Reader>()
@@ -1523,11 +4896,12 @@ mod tests {
                                                                     Term(0),
                                                                     &entries[0..1],
                                                                     LogIndex(0),
+                                                                    0,
                                                                     &*lid));
         let msg2 = reader.get_root::<message::Reader>()
             .unwrap();
-        follower.apply_peer_message(peer_ids[1], &msg1, &mut actions);
-        follower.apply_peer_message(peer_ids[1], &msg2, &mut actions);
+        follower.apply_peer_message(peer_ids[1], &msg1, &mut actions).unwrap();
+        follower.apply_peer_message(peer_ids[1], &msg2, &mut actions).unwrap();
 
         assert_eq!((Term(1), value), follower.log.entry(LogIndex(1)).unwrap());
         assert_eq!((Term(1), value), follower.log.entry(LogIndex(2)).unwrap());
@@ -1555,7 +4929,8 @@ mod tests {
         elect_leader(leader, &mut peers);
 
         let value: &[u8] = b"foo";
-        let reader = into_reader(&messages::proposal_request(TransactionId::new(), value, *lid));
+        let proposal = messages::proposal_request(TransactionId::new(), value, None, *lid);
+        let reader = into_reader(&proposal);
         let message_reader = reader.get_root::<client_request::Reader>()
             .unwrap();
         let client = ClientId::new();
@@ -1563,9 +4938,11 @@ mod tests {
 
         b.iter(|| {
             let mut actions = Actions::new();
-            peers.get_mut(&leader)
-                .unwrap()
-                .apply_client_message(client, &message_reader, &mut actions);
+            {
+                let leader = peers.get_mut(&leader).unwrap();
+                leader.apply_client_message(client, &message_reader, &mut actions).unwrap();
+                leader.flush(&mut actions);
+            }
 
             let client_messages = apply_actions(leader, actions, &mut peers);
             assert_eq!(1, client_messages.len());
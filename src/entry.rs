@@ -0,0 +1,64 @@
+//! The contents of log entries.
+//!
+//! Besides client commands, the log carries the cluster configuration, the boundaries of
+//! transactions, and, in the metadata log, the logs created and dropped at runtime. Each entry
+//! appended by a `Consensus` holds the bincode encoding of a `Payload`, which is decoded again
+//! when the entry is applied.
+
+use bincode::SizeLimit;
+use bincode::serde::{self, DeserializeResult};
+
+use LogId;
+use ServerId;
+use TransactionId;
+use membership::Configuration;
+use session::ProposalId;
+
+/// The contents of a log entry.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Payload {
+    /// A client command, which is applied to the `StateMachine` once committed. A command
+    /// proposed within a client session is applied only once, however often it is proposed.
+    Command(Vec<u8>, Option<ProposalId>),
+    /// A new cluster configuration, which takes effect as soon as it is appended.
+    Configuration(Configuration),
+    /// An empty entry appended by a newly elected leader. Committing it commits the entries of
+    /// earlier terms along with it.
+    Noop,
+    /// Begins a transaction.
+    TransactionBegin(TransactionId),
+    /// A client command within a transaction, which is buffered until the transaction ends.
+    TransactionCommand(TransactionId, Vec<u8>, Option<ProposalId>),
+    /// Ends the transaction, applying its commands to the `StateMachine` unless they conflict
+    /// with a command committed since the transaction began.
+    TransactionCommit(TransactionId),
+    /// Ends the transaction, dropping its commands.
+    TransactionRollback(TransactionId),
+    /// Prepares the transaction to commit as part of a transaction spanning several logs. The
+    /// transaction then ends as decided in the given coordinator log.
+    TransactionPrepare(TransactionId, LogId),
+    /// Records in a coordinator log the decision whether to commit a transaction spanning
+    /// several logs.
+    TransactionDecision(TransactionId, bool),
+    /// Sets a savepoint of the given name within the transaction.
+    TransactionSavepoint(TransactionId, String),
+    /// Drops the commands of the transaction since the savepoint of the given name.
+    TransactionRollbackTo(TransactionId, String),
+    /// Records in the metadata log that the log is created on the given replicas, or on every
+    /// server if none are given.
+    CreateLog(LogId, Option<Vec<ServerId>>),
+    /// Records in the metadata log that the log is dropped on every server.
+    DropLog(LogId),
+}
+
+impl Payload {
+    /// Encodes the payload for storage in the `Log`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde::serialize(self, SizeLimit::Infinite).expect("unable to encode log entry")
+    }
+
+    /// Decodes a payload previously encoded with `to_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> DeserializeResult<Payload> {
+        serde::deserialize(bytes)
+    }
+}
@@ -39,8 +39,11 @@
 //! Some ideas for a Persistent Log implementation:
 //!
 //!   * A PostgreSQL / SQLite instance.
-//!   * A plain old file.
-//!   * A vector in memory *(Note: Log compaction is still pending, so be aware of running out!)*
+//!   * A plain old file. (`FileLog` provided)
+//!   * A vector in memory. (`MemLog` provided)
+//!
+//! Once enough entries have been applied, the log is compacted: the `StateMachine` is snapshotted
+//! and the covered entries are discarded through `Log::compact()`.
 //!
 //! > It is our belief that in many cases the implementation of `Log` will be generic to
 //! > application purposes. You are encouraged to submit your own implementations to us!
@@ -70,6 +73,7 @@
 //! This means `.propose()` won't return until the entry is durably replicated into the log of at
 //! least the majority of the cluster and has been commited. `.query()` will perform better if
 //! you wish to only read data and not have it pass through the persisted log.
+//! `.query_with()` lets a query trade consistency for latency, see `ReadConsistency`.
 //!
 
 #![allow(non_snake_case)]
@@ -127,11 +131,21 @@ pub mod state;
 pub mod auth;
 mod transaction;
 mod log_manager;
+mod snapshot;
+mod session;
+mod membership;
+mod entry;
+mod config;
+pub mod placement;
 
 pub use server::Server;
 pub use state_machine::StateMachine;
 pub use persistent_log::Log;
 pub use client::Client;
+pub use config::{Config, LogConfig};
+pub use log_manager::LogFactory;
+pub use messages_capnp::ReadConsistency;
+pub use transaction::{TransactionState, TransactionStatus};
 
 use std::{io, net, ops, fmt};
 use uuid::Uuid;
@@ -188,6 +202,14 @@ pub enum RaftError {
     LeaderSearchExhausted,
     /// An error during transaction
     TransactionError(transaction::TransactionError),
+    /// Leadership could not be transferred to the requested peer.
+    LeaderTransferFailed(String),
+    /// A membership change could not be started.
+    MembershipChangeFailed(String),
+    /// A `Config` failed validation. Returned by `Server::new()`.
+    InvalidConfig(String),
+    /// No server reachable by the client hosts the log.
+    UnknownLog(LogId),
     Other(String),
 }
 
@@ -226,6 +248,14 @@ impl fmt::Display for RaftError {
                 fmt::Display::fmt("Cannot find leader in the cluster", f)
             }
             RaftError::TransactionError(ref error) => fmt::Display::fmt(&format!("{}", error), f),
+            RaftError::LeaderTransferFailed(ref error) => {
+                write!(f, "Leadership transfer failed: {}", error)
+            }
+            RaftError::MembershipChangeFailed(ref error) => {
+                write!(f, "Membership change failed: {}", error)
+            }
+            RaftError::InvalidConfig(ref error) => write!(f, "Invalid configuration: {}", error),
+            RaftError::UnknownLog(ref lid) => write!(f, "Unknown log: {:?}", lid),
             RaftError::Other(ref error) => fmt::Display::fmt(error, f), 
         }
     }
@@ -244,7 +274,11 @@ impl ::std::error::Error for RaftError {
             RaftError::ConnectionRegisterFailed => "Registering a connection failed",
             RaftError::LeaderSearchExhausted => "Cannot find leader in the cluster",
             RaftError::TransactionError(ref error) => "An error occured during the transaction",
+            RaftError::UnknownLog(_) => "The server does not host the log",
             RaftError::ClusterViolation(ref error) |
+            RaftError::LeaderTransferFailed(ref error) |
+            RaftError::MembershipChangeFailed(ref error) |
+            RaftError::InvalidConfig(ref error) |
             RaftError::Other(ref error) => error,
         }
     }
@@ -1,26 +1,69 @@
 use ServerId;
 use ClientId;
+use Error;
 use LogId;
+use LogIndex;
+use RaftError;
+use Result;
+use Term;
+use TransactionId;
 use StateInformation;
-use consensus::{Consensus, Actions, ConsensusTimeout};
+use config::Config;
+use consensus::{Consensus, Actions, ConsensusTimeout, Heartbeat, HeartbeatResponse,
+                HeartbeatResult};
+use entry::Payload;
+use messages;
+use placement;
 use std::net::SocketAddr;
 use std::collections::HashMap;
 use persistent_log::Log;
 use state_machine::StateMachine;
+use transaction::TransactionError;
 use uuid::Uuid;
 
 use std::sync::{Arc, RwLock};
 
 use state::{LeaderState, CandidateState, FollowerState};
 
-use capnp::message::{Reader, ReaderSegments, Builder, HeapAllocator};
-use messages_capnp::{client_request, message};
+use capnp::message::{Reader, ReaderSegments};
+use messages_capnp::{client_request, heartbeat_response, message, multi_heartbeat,
+                     multi_heartbeat_response};
+
+/// Builds the persistent log and the state machine of a log created at runtime, and disposes of
+/// them once the log is dropped. Any `FnMut(LogId) -> (L, M)` is a factory which keeps the
+/// storage of dropped logs.
+pub trait LogFactory<L, M> {
+    /// Returns the persistent log and the state machine of the log `lid`. When a server
+    /// restarts, the logs created before are built again, and should find their earlier state.
+    fn create(&mut self, lid: LogId) -> (L, M);
+
+    /// Disposes of the storage of the dropped log `lid`.
+    fn destroy(&mut self, _lid: LogId) {}
+}
+
+impl<L, M, F> LogFactory<L, M> for F
+    where F: FnMut(LogId) -> (L, M)
+{
+    fn create(&mut self, lid: LogId) -> (L, M) {
+        self(lid)
+    }
+}
+
 pub struct LogManager<L, M>
     where L: Log,
           M: StateMachine
 {
+    id: ServerId,
     peers: Arc<RwLock<HashMap<ServerId, SocketAddr>>>,
     pub consensus: HashMap<LogId, Consensus<L, M>>,
+    config: Config,
+    /// Builds the logs created at runtime.
+    factory: Option<Box<LogFactory<L, M>>>,
+    /// The logs created at runtime which this server has built, or failed to build, with the
+    /// replicas they are placed on.
+    created: HashMap<LogId, Option<Vec<ServerId>>>,
+    /// The number of peer messages rejected because this server does not host their log.
+    unknown_log_messages: u64,
 }
 
 impl<L, M> LogManager<L, M>
@@ -29,22 +72,34 @@ impl<L, M> LogManager<L, M>
 {
     pub fn new(id: ServerId,
                store_logs: Vec<(LogId, L, M)>,
-               peers: HashMap<ServerId, SocketAddr>)
+               peers: HashMap<ServerId, SocketAddr>,
+               config: &Config)
                -> Self {
         let mut logs: HashMap<LogId, Consensus<L, M>> = HashMap::new();
 
         for (lid, log, state_machine) in store_logs {
-            let consensus: Consensus<L, M> =
-                Consensus::new(id, lid, peers.clone(), log, state_machine);
-            logs.insert(lid, consensus);
+            let consensus = build_consensus(id, lid, &peers, log, state_machine, None, config);
+            if let Some(consensus) = consensus {
+                logs.insert(lid, consensus);
+            }
         }
 
         LogManager {
+            id: id,
             consensus: logs,
             peers: Arc::new(RwLock::new(peers)),
+            config: config.clone(),
+            factory: None,
+            created: HashMap::new(),
+            unknown_log_messages: 0,
         }
     }
 
+    /// Sets the factory building the logs created at runtime.
+    pub fn set_factory(&mut self, factory: Box<LogFactory<L, M>>) {
+        self.factory = Some(factory);
+    }
+
     pub fn get(&self, index: LogId) -> Option<&Consensus<L, M>> {
         self.consensus.get(&index)
     }
@@ -53,10 +108,6 @@ impl<L, M> LogManager<L, M>
         self.consensus.get_mut(&index)
     }
 
-    pub fn active_transaction(&self, logid: &LogId) -> bool {
-        self.consensus[logid].transaction.is_active
-    }
-
     pub fn init(&self) -> Actions {
         let mut actions = Actions::new();
         for id in self.consensus.keys() {
@@ -68,50 +119,284 @@ impl<L, M> LogManager<L, M>
         actions
     }
 
+    /// Applies a client message to the consensus of its log. A client naming a log this server
+    /// does not host is told so. Fails if the message is malformed.
     pub fn apply_client_message<S>(&mut self,
                                    from: ClientId,
                                    message: &Reader<S>,
                                    actions: &mut Actions)
+                                   -> Result<()>
         where S: ReaderSegments
     {
-        // TODO: implement error handling
-        let reader = message.get_root::<client_request::Reader>().unwrap();
-        let log_id = LogId(Uuid::from_bytes(reader.get_log_id().unwrap()).unwrap());
+        let reader = try!(message.get_root::<client_request::Reader>());
+        let log_id = try!(parse_log_id(try!(reader.get_log_id())));
 
         scoped_trace!("Received client message on log {:?}", log_id);
 
-        // TODO implement error handling
-        let mut cons = self.consensus.get_mut(&log_id).unwrap();
+        let change = match reader.which() {
+            Ok(client_request::Which::CreateLog(Ok(request))) => Some((request, true)),
+            Ok(client_request::Which::DropLog(Ok(request))) => Some((request, false)),
+            _ => None,
+        };
+        if let Some((request, create)) = change {
+            let lid = try!(parse_log_id(try!(request.get_log())));
+            self.log_change_request(from, lid, create, actions);
+            return Ok(());
+        }
+        if let Ok(client_request::Which::TransactionPrepare(Ok(request))) = reader.which() {
+            let coordinator = try!(parse_log_id(try!(request.get_coordinator())));
+            if let Some(error) = self.coordinator_error(coordinator) {
+                scoped_debug!("rejecting prepare on log {:?}: {}", log_id, error);
+                actions.client_messages
+                    .push((from, messages::command_transaction_failure(error, log_id)));
+                return Ok(());
+            }
+        }
+
+        match self.consensus.get_mut(&log_id) {
+            Some(cons) => try!(cons.apply_client_message(from, &reader, actions)),
+            None => {
+                scoped_debug!("Received a client message for unknown log {:?}", log_id);
+                actions.client_messages
+                    .push((from, messages::command_response_unknown_log(log_id)));
+            }
+        }
+        Ok(())
+    }
 
-        cons.apply_client_message(from, &reader, actions);
+    /// Returns why a transaction prepared on a log of this server may not be coordinated by the
+    /// log `coordinator`, if it may not. The leader of the log resolves the transaction from its
+    /// own replica of the coordinator log, so this server has to host the coordinator log, and
+    /// so does any replica which may lead the log later: the coordinator log may not be placed.
+    fn coordinator_error(&self, coordinator: LogId) -> Option<TransactionError> {
+        let reason = if !self.consensus.contains_key(&coordinator) {
+            "is not hosted by this server"
+        } else if self.created.get(&coordinator).map_or(false, |replicas| replicas.is_some()) {
+            "is not replicated on every server"
+        } else {
+            return None;
+        };
+        let reason = format!("The coordinator log {:?} {}", coordinator, reason);
+        Some(TransactionError::Other(reason))
     }
 
+    /// Passes a request to create or drop the log `lid` on to the metadata log.
+    fn log_change_request(&mut self,
+                          from: ClientId,
+                          lid: LogId,
+                          create: bool,
+                          actions: &mut Actions) {
+        let error = match self.config.metadata_log {
+            None => "No metadata log is configured",
+            Some(_) if create && self.consensus.contains_key(&lid) &&
+                       !self.created.contains_key(&lid) => "The log exists already",
+            Some(metadata) => {
+                let payload = if create {
+                    Payload::CreateLog(lid, self.place(lid))
+                } else {
+                    Payload::DropLog(lid)
+                };
+                self.consensus
+                    .get_mut(&metadata)
+                    .unwrap()
+                    .log_change_request(from, payload, actions);
+                return;
+            }
+        };
+        actions.client_messages
+            .push((from, messages::command_response_failure(error.as_bytes(), lid)));
+    }
+
+    /// Chooses the replicas of the log `lid` among the servers known to this server, if the log
+    /// is limited to a number of replicas. The metadata log records them, so that every server
+    /// builds the log on the same replicas.
+    fn place(&self, lid: LogId) -> Option<Vec<ServerId>> {
+        self.config.log_config(&lid).replicas.map(|replicas| {
+            let peers = self.peers.read().unwrap();
+            placement::place(lid, peers.keys().cloned().chain(Some(self.id)), replicas)
+        })
+    }
+
+    /// Builds and drops logs to match the logs created at runtime as recorded in the metadata
+    /// log. Returns the dropped consensus instances, whose timeouts have yet to be cleared.
+    pub fn sync_logs(&mut self, actions: &mut Actions) -> Vec<Consensus<L, M>> {
+        let recorded = match self.config.metadata_log.and_then(|lid| self.consensus.get(&lid)) {
+            Some(cons) if cons.logs != self.created => cons.logs.clone(),
+            _ => return Vec::new(),
+        };
+
+        let mut dropped = Vec::new();
+        let removed: Vec<LogId> = self.created
+            .keys()
+            .filter(|&lid| !recorded.contains_key(lid))
+            .cloned()
+            .collect();
+        for lid in removed {
+            scoped_info!("dropping log {:?}", lid);
+            self.created.remove(&lid);
+            if let Some(mut cons) = self.consensus.remove(&lid) {
+                cons.close(actions);
+                dropped.push(cons);
+                if let Some(ref mut factory) = self.factory {
+                    factory.destroy(lid);
+                }
+            }
+        }
+
+        let added: Vec<(LogId, Option<Vec<ServerId>>)> = recorded.into_iter()
+            .filter(|&(ref lid, _)| !self.created.contains_key(lid))
+            .collect();
+        for (lid, replicas) in added {
+            self.created.insert(lid, replicas.clone());
+            if self.consensus.contains_key(&lid) {
+                scoped_warn!("log {:?} was created at runtime, but exists already", lid);
+                continue;
+            }
+            let (log, state_machine) = match self.factory {
+                Some(ref mut factory) => factory.create(lid),
+                None => {
+                    scoped_warn!("unable to build log {:?}: no log factory is set", lid);
+                    continue;
+                }
+            };
+            let consensus = build_consensus(self.id,
+                                            lid,
+                                            &self.peers.read().unwrap(),
+                                            log,
+                                            state_machine,
+                                            replicas,
+                                            &self.config);
+            if let Some(consensus) = consensus {
+                scoped_info!("building log {:?}", lid);
+                self.consensus.insert(lid, consensus);
+                actions.timeouts.push(ConsensusTimeout::Election(lid));
+            }
+        }
+        dropped
+    }
+
+    /// Applies a peer message to the consensus of its log, or the heartbeats of a
+    /// `MultiHeartbeat` message to the consensus of their logs. A message for a log this server
+    /// does not host is rejected. Fails if the message is malformed.
     pub fn apply_peer_message<S>(&mut self,
                                  from: ServerId,
                                  message: &Reader<S>,
                                  actions: &mut Actions)
+                                 -> Result<()>
         where S: ReaderSegments
     {
-        let reader = message.get_root::<message::Reader>().unwrap();
-
-        let logid_in_bytes = reader.get_log_id()
-            .expect("LogId property was not set in the message");
-        let id = match Uuid::from_bytes(logid_in_bytes) {
-            Ok(id) => id,
-            Err(_) => {
-                scoped_warn!("Received a message with an invalid LogId; Discarding it");
-                return;
+        let reader = try!(message.get_root::<message::Reader>());
+        match reader.which() {
+            Ok(message::Which::MultiHeartbeat(Ok(request))) => {
+                return self.multi_heartbeat(from, request, actions);
             }
-        };
+            Ok(message::Which::MultiHeartbeatResponse(Ok(response))) => {
+                return self.multi_heartbeat_response(from, response, actions);
+            }
+            _ => (),
+        }
+        let log_id = try!(parse_log_id(try!(reader.get_log_id())));
+
+        // Messages for a dropped log may still arrive from peers which have not dropped it yet.
+        if let Some(cons) = self.consensus.get_mut(&log_id) {
+            return cons.apply_peer_message(from, &reader, actions);
+        }
+        self.reject_unknown_log(from, log_id);
+        Ok(())
+    }
+
+    /// Sends each peer the heartbeats of the logs this server leads in one `MultiHeartbeat`
+    /// message.
+    pub fn heartbeats(&mut self, actions: &mut Actions) {
+        let mut heartbeats: HashMap<ServerId, Vec<(LogId, Heartbeat)>> = HashMap::new();
+        for (&lid, cons) in &mut self.consensus {
+            for (peer, heartbeat) in cons.coalesced_heartbeats() {
+                heartbeats.entry(peer).or_insert_with(Vec::new).push((lid, heartbeat));
+            }
+        }
+        for (peer, heartbeats) in heartbeats {
+            scoped_debug!("sending {} heartbeats to peer {}", heartbeats.len(), peer);
+            actions.peer_messages.push((peer, messages::multi_heartbeat(&heartbeats)));
+        }
+    }
 
-        let log_id = LogId(id);
+    /// Applies each heartbeat of a `MultiHeartbeat` message to the consensus of its log, and
+    /// answers them in one `MultiHeartbeatResponse` message. Heartbeats for logs this server
+    /// does not host are rejected.
+    fn multi_heartbeat(&mut self,
+                       from: ServerId,
+                       request: multi_heartbeat::Reader,
+                       actions: &mut Actions)
+                       -> Result<()> {
+        let mut responses = Vec::new();
+        for entry in try!(request.get_heartbeats()).iter() {
+            let lid = try!(parse_log_id(try!(entry.get_log_id())));
+            let heartbeat = Heartbeat {
+                term: Term::from(entry.get_term()),
+                prev_log_index: LogIndex::from(entry.get_prev_log_index()),
+                prev_log_term: Term::from(entry.get_prev_log_term()),
+                commit_index: LogIndex::from(entry.get_commit_index()),
+                round: entry.get_round(),
+            };
+            if let Some(cons) = self.consensus.get_mut(&lid) {
+                responses.push((lid, cons.heartbeat_request(from, heartbeat, actions)));
+                continue;
+            }
+            self.reject_unknown_log(from, lid);
+        }
+        if !responses.is_empty() {
+            actions.peer_messages.push((from, messages::multi_heartbeat_response(&responses)));
+        }
+        Ok(())
+    }
 
-        // TODO implement error handling
-        let mut cons = self.consensus
-            .get_mut(&log_id)
-            .expect(&format!("There is not consensus instance with this {:?}", log_id));
+    /// Applies each answer of a `MultiHeartbeatResponse` message to the consensus of its log.
+    fn multi_heartbeat_response(&mut self,
+                                from: ServerId,
+                                response: multi_heartbeat_response::Reader,
+                                actions: &mut Actions)
+                                -> Result<()> {
+        for entry in try!(response.get_responses()).iter() {
+            let lid = try!(parse_log_id(try!(entry.get_log_id())));
+            let result = match try!(entry.which()) {
+                heartbeat_response::Which::Success(latest_log_index) => {
+                    HeartbeatResult::Success(LogIndex::from(latest_log_index))
+                }
+                heartbeat_response::Which::InconsistentPrevEntry(inconsistent) => {
+                    HeartbeatResult::InconsistentPrevEntry(
+                        Term::from(inconsistent.get_conflict_term()),
+                        LogIndex::from(inconsistent.get_first_index()),
+                        LogIndex::from(inconsistent.get_latest_log_index()))
+                }
+                heartbeat_response::Which::StaleTerm(()) => HeartbeatResult::StaleTerm,
+            };
+            let response = HeartbeatResponse {
+                term: Term::from(entry.get_term()),
+                round: entry.get_round(),
+                result: result,
+            };
+            if let Some(cons) = self.consensus.get_mut(&lid) {
+                cons.heartbeat_response(from, response, actions);
+                continue;
+            }
+            self.reject_unknown_log(from, lid);
+        }
+        Ok(())
+    }
+
+    /// Records a message from a peer for a log this server does not host.
+    fn reject_unknown_log(&mut self, from: ServerId, lid: LogId) {
+        self.unknown_log_messages += 1;
+        scoped_warn!("Rejected a message from peer {} for unknown log {:?} ({} so far)",
+                     from,
+                     lid,
+                     self.unknown_log_messages);
+    }
 
-        cons.apply_peer_message(from, &reader, actions);
+    /// Returns the number of peer messages rejected because this server does not host their
+    /// log.
+    pub fn unknown_log_messages(&self) -> u64 {
+        self.unknown_log_messages
     }
 
     pub fn peer_connection_reset(&mut self,
@@ -123,25 +408,61 @@ impl<L, M> LogManager<L, M>
         }
     }
 
+    /// Rolls back the transactions the client began on the logs led by this server.
+    pub fn client_connection_reset(&mut self, client: ClientId, actions: &mut Actions) {
+        for cons in self.consensus.values_mut() {
+            cons.client_connection_reset(client, actions);
+        }
+    }
+
     pub fn apply_timeout(&mut self,
                          lid: &LogId,
                          consensus: ConsensusTimeout,
                          actions: &mut Actions) {
-        self.consensus.get_mut(lid).unwrap().apply_timeout(consensus, actions);
+        match self.consensus.get_mut(lid) {
+            Some(cons) => cons.apply_timeout(consensus, actions),
+            None => return,
+        }
+        if let ConsensusTimeout::Transaction(session, _) = consensus {
+            self.resolve_transaction(lid, session, actions);
+        }
     }
 
-    pub fn handle_queue(&mut self,
-                        requests_in_queue: &mut HashMap<LogId,
-                                                        Vec<(ClientId, Builder<HeapAllocator>)>>,
-                        actions: &mut Actions) {
+    /// Ends the transaction if it is prepared on the log `lid` and this server leads the log,
+    /// as decided in the transaction's coordinator log. If the coordinator log has not decided
+    /// yet, it is asked to abort the transaction; the transaction ends with a later timeout,
+    /// once this server's replica of the coordinator log holds the decision.
+    fn resolve_transaction(&mut self, lid: &LogId, session: TransactionId, actions: &mut Actions) {
+        let coordinator = match self.consensus[lid].in_doubt(&session) {
+            Some(coordinator) => coordinator,
+            None => return,
+        };
+        let decision = match self.consensus.get_mut(&coordinator) {
+            Some(cons) => {
+                match cons.decision(&session) {
+                    Some(decision) => decision,
+                    None => {
+                        cons.request_abort(session, actions);
+                        return;
+                    }
+                }
+            }
+            None => {
+                // Prepares naming a log this server does not host are rejected, but the
+                // coordinator log may have been dropped since.
+                scoped_warn!("transaction {} is coordinated by unknown log {:?}",
+                             session,
+                             coordinator);
+                return;
+            }
+        };
+        self.consensus.get_mut(lid).unwrap().resolve_transaction(session, decision, actions);
+    }
 
-        for (&lid, ref mut messages) in requests_in_queue.iter_mut() {
-            // TODO implement deref for LogId
-            self.consensus
-                .get_mut(&LogId::from(&format!("{}", lid)).unwrap())
-                .expect(&format!("Cannot find {:?} in the logs", lid))
-                .handle_queue(messages, actions)
-                .unwrap();
+    /// Sends the entries appended to any log since the last flush to the peers.
+    pub fn flush(&mut self, actions: &mut Actions) {
+        for cons in self.consensus.values_mut() {
+            cons.flush(actions);
         }
     }
 
@@ -158,6 +479,49 @@ impl<L, M> LogManager<L, M>
         result
     }
 
+    /// Hands off leaderships to even out the number of logs each server leads, in proportion to
+    /// the servers' weights. The leaders of the logs are read from `get_states()`, as last heard
+    /// by this server. Only the logs this server leads are transferred, each to the replica
+    /// leading the fewest logs for its weight, and only while the transfer does not leave this
+    /// server leading fewer logs than the replica for their weights.
+    pub fn balance_leaders(&mut self, actions: &mut Actions) {
+        let mut leaderships: HashMap<ServerId, u64> = self.peers
+            .read()
+            .unwrap()
+            .keys()
+            .chain(Some(&self.id))
+            .map(|&server| (server, 0))
+            .collect();
+        let mut led = Vec::new();
+        for (lid, (_, _, follower_state)) in self.get_states() {
+            let leader = if self.consensus[&lid].is_leader() {
+                led.push(lid);
+                Some(self.id)
+            } else {
+                follower_state.read().unwrap().leader
+            };
+            if let Some(leader) = leader {
+                *leaderships.entry(leader).or_insert(0) += 1;
+            }
+        }
+
+        for lid in led {
+            let cons = self.consensus.get_mut(&lid).unwrap();
+            let target = match balance_target(self.id, cons.voters(), &leaderships, &self.config) {
+                Some(target) => target,
+                None => continue,
+            };
+            match cons.transfer_leadership(target, None, actions) {
+                Ok(()) => {
+                    scoped_info!("balancing leadership of log {:?} to peer {}", lid, target);
+                    *leaderships.get_mut(&self.id).unwrap() -= 1;
+                    *leaderships.entry(target).or_insert(0) += 1;
+                }
+                Err(error) => scoped_debug!("unable to balance log {:?}: {}", lid, error),
+            }
+        }
+    }
+
     pub fn get_state_machines(&self) -> HashMap<LogId, Arc<RwLock<M>>> {
         let mut result = HashMap::new();
 
@@ -170,22 +534,583 @@ impl<L, M> LogManager<L, M>
         result
     }
 
-    pub fn add_peer(&mut self, peer_id: ServerId, peer_addr: SocketAddr) {
+    /// Registers a new peer with every log. The logs led by this server start a membership
+    /// change adding the peer.
+    pub fn add_peer(&mut self, peer_id: ServerId, peer_addr: SocketAddr, actions: &mut Actions) {
         let mut lock = self.peers.write().unwrap();
         assert!(lock.insert(peer_id, peer_addr).is_none());
 
-        for mut cons in self.consensus.values_mut() {
-            cons.add_peer(peer_id, peer_addr);
+        // The replicas of a placed log do not change when servers join.
+        let created = &self.created;
+        let unplaced = self.consensus
+            .iter_mut()
+            .filter(|&(lid, _)| created.get(lid).map_or(true, |replicas| replicas.is_none()));
+        for (lid, cons) in unplaced {
+            if let Err(error) = cons.add_peer(peer_id, peer_addr, actions) {
+                scoped_warn!("unable to add peer {} to log {:?}: {}", peer_id, lid, error);
+            }
         }
     }
 
     pub fn check_peer_exists(&self, peer_id: ServerId) -> bool {
-        let (_, cons) = self.consensus.iter().next().unwrap();
-
-        cons.peers().contains_key(&peer_id)
+        self.peers.read().unwrap().contains_key(&peer_id)
     }
 
     pub fn get_peers(&self) -> Arc<RwLock<HashMap<ServerId, SocketAddr>>> {
         self.peers.clone()
     }
 }
+
+/// Builds the consensus of the log `lid` if this server `id` replicates it. A log placed on
+/// the given `replicas` only spans them, whether or not this server knows their addresses yet,
+/// and prefers the first of them as leader. Otherwise the log spans every server.
+fn build_consensus<L, M>(id: ServerId,
+                         lid: LogId,
+                         peers: &HashMap<ServerId, SocketAddr>,
+                         log: L,
+                         state_machine: M,
+                         replicas: Option<Vec<ServerId>>,
+                         config: &Config)
+                         -> Option<Consensus<L, M>>
+    where L: Log,
+          M: StateMachine
+{
+    let coalesced_heartbeats = config.heartbeat_interval.is_some();
+    let config = config.log_config(&lid);
+    let placed = match replicas {
+        None => {
+            let mut consensus =
+                Consensus::new(id, lid, peers.clone(), log, state_machine, config.clone());
+            consensus.set_coalesced_heartbeats(coalesced_heartbeats);
+            return Some(consensus);
+        }
+        Some(placed) => placed,
+    };
+    if !placed.contains(&id) {
+        scoped_debug!("log {:?} is not placed on this server", lid);
+        return None;
+    }
+    let replica_peers = peers.iter()
+        .filter(|&(peer, _)| placed.contains(peer))
+        .map(|(&peer, &addr)| (peer, addr))
+        .collect();
+    let mut consensus =
+        Consensus::new(id, lid, replica_peers, log, state_machine, config.clone());
+    consensus.set_initial_members(placed.iter().cloned().collect());
+    consensus.set_preferred_leader(placed.first().cloned());
+    consensus.set_coalesced_heartbeats(coalesced_heartbeats);
+    Some(consensus)
+}
+
+/// Returns the replica among `voters` to hand the leadership of a log led by this server `id`
+/// off to, if any. The replica which would lead the fewest logs for its weight is chosen,
+/// provided this server still leads at least as many logs for its weight after the transfer.
+fn balance_target<I>(id: ServerId,
+                     voters: I,
+                     leaderships: &HashMap<ServerId, u64>,
+                     config: &Config)
+                     -> Option<ServerId>
+    where I: IntoIterator<Item = ServerId>
+{
+    let count = |server: &ServerId| leaderships.get(server).cloned().unwrap_or(0);
+    let own_count = count(&id);
+    if own_count == 0 {
+        return None;
+    }
+    let mut best: Option<(ServerId, u64, u64)> = None;
+    for server in voters {
+        let weight = config.leader_weight(&server);
+        if server == id || weight == 0 {
+            continue;
+        }
+        let load = count(&server) + 1;
+        // Loads after the transfer, count / weight, are compared by cross-multiplying. Ties go
+        // to the lowest server ID, so that the choice does not depend on the order of `voters`.
+        let better = match best {
+            None => true,
+            Some((best_server, best_load, best_weight)) => {
+                (load * best_weight, server.as_u64()) < (best_load * weight, best_server.as_u64())
+            }
+        };
+        if better {
+            best = Some((server, load, weight));
+        }
+    }
+    best.and_then(|(server, load, weight)| {
+        if (own_count - 1) * weight >= load * config.leader_weight(&id) {
+            Some(server)
+        } else {
+            None
+        }
+    })
+}
+
+/// Parses the ID of the log a message is meant for.
+fn parse_log_id(bytes: &[u8]) -> Result<LogId> {
+    Uuid::from_bytes(bytes)
+        .map(LogId)
+        .map_err(|_| Error::Raft(RaftError::Other("Received a message with an invalid LogId"
+            .to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::io::Cursor;
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    use capnp::message::{Builder, HeapAllocator, Reader, ReaderOptions};
+    use capnp::serialize::{self, OwnedSegments};
+    use uuid::Uuid;
+
+    use {ClientId, LogId, LogIndex, ServerId, TransactionId};
+    use config::{Config, LogConfig};
+    use consensus::{Actions, ConsensusTimeout};
+    use log_manager::{balance_target, LogFactory, LogManager};
+    use messages;
+    use messages_capnp::{client_response, command_response};
+    use persistent_log::MemLog;
+    use placement;
+    use state::{ReadRequest, ReadSource};
+    use state_machine::NullStateMachine;
+    use transaction::TransactionState;
+
+    type TestManager = LogManager<MemLog, NullStateMachine>;
+
+    fn into_reader(message: &Builder<HeapAllocator>) -> Reader<OwnedSegments> {
+        let mut buf = Cursor::new(Vec::new());
+        serialize::write_message(&mut buf, message).unwrap();
+        buf.set_position(0);
+        serialize::read_message(&mut buf, ReaderOptions::new()).unwrap()
+    }
+
+    /// Returns whether the client message is the failure of a transaction request.
+    fn is_transaction_failure(message: &Builder<HeapAllocator>) -> bool {
+        let reader = into_reader(message);
+        let response = reader.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::Transaction(Ok(status)) => {
+                match status.which().unwrap() {
+                    command_response::Which::Failure(_) => true,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Delivers the peer messages of the actions, and those sent in response, between the
+    /// managers. Messages to servers missing from `managers` are lost. Returns the number of
+    /// messages delivered.
+    fn deliver(from: ServerId,
+               actions: Actions,
+               managers: &mut HashMap<ServerId, TestManager>)
+               -> usize {
+        let mut queue: VecDeque<_> = actions.peer_messages
+            .into_iter()
+            .map(|(to, message)| (from, to, message))
+            .collect();
+        let mut delivered = 0;
+        while let Some((from, to, message)) = queue.pop_front() {
+            let mut actions = Actions::new();
+            match managers.get_mut(&to) {
+                Some(manager) => {
+                    manager.apply_peer_message(from, &into_reader(&*message), &mut actions)
+                        .unwrap()
+                }
+                None => continue,
+            }
+            delivered += 1;
+            let sent = actions.peer_messages.into_iter().map(|(next, message)| (to, next, message));
+            queue.extend(sent);
+        }
+        delivered
+    }
+
+    /// Builds in-memory logs, and records the logs whose storage it disposes of.
+    struct TestFactory(Rc<RefCell<Vec<LogId>>>);
+
+    impl LogFactory<MemLog, NullStateMachine> for TestFactory {
+        fn create(&mut self, _lid: LogId) -> (MemLog, NullStateMachine) {
+            (MemLog::new(), NullStateMachine)
+        }
+
+        fn destroy(&mut self, lid: LogId) {
+            self.0.borrow_mut().push(lid);
+        }
+    }
+
+    /// Tests that a log created in the metadata log is built with an election timeout, and that
+    /// once it is dropped its pending reads are answered, its consensus is handed back for its
+    /// timeouts to be cleared, and its storage disposed of.
+    #[test]
+    fn test_sync_logs() {
+        let metadata = LogId(Uuid::new_v4());
+        let lid = LogId(Uuid::new_v4());
+        let config = Config { metadata_log: Some(metadata), ..Config::default() };
+        let logs = vec![(metadata, MemLog::new(), NullStateMachine)];
+        let mut manager: TestManager = LogManager::new(ServerId(0), logs, HashMap::new(), &config);
+        let destroyed = Rc::new(RefCell::new(Vec::new()));
+        manager.set_factory(Box::new(TestFactory(destroyed.clone())));
+        let mut actions = Actions::new();
+        manager.apply_timeout(&metadata, ConsensusTimeout::Election(metadata), &mut actions);
+
+        let mut actions = Actions::new();
+        let request = into_reader(&messages::create_log_request(metadata, lid));
+        manager.apply_client_message(ClientId::new(), &request, &mut actions).unwrap();
+        manager.flush(&mut actions);
+        let mut actions = Actions::new();
+        assert!(manager.sync_logs(&mut actions).is_empty());
+        assert!(manager.consensus.contains_key(&lid));
+        assert_eq!(vec![ConsensusTimeout::Election(lid)], actions.timeouts);
+        manager.apply_timeout(&lid, ConsensusTimeout::Election(lid), &mut actions);
+        assert!(manager.consensus[&lid].is_leader());
+
+        // Reads still waiting on the log are answered when the log is dropped.
+        let (client, forwarded) = (ClientId::new(), ClientId::new());
+        {
+            let consensus = &manager.consensus[&lid];
+            consensus.leader_state.write().unwrap().reads.push_back(ReadRequest {
+                source: ReadSource::Client(client, b"foo".to_vec()),
+                index: LogIndex(0),
+                round: 0,
+            });
+            consensus.follower_state.write().unwrap().add_read(forwarded, b"foo".to_vec());
+        }
+
+        let mut actions = Actions::new();
+        let request = into_reader(&messages::drop_log_request(metadata, lid));
+        manager.apply_client_message(ClientId::new(), &request, &mut actions).unwrap();
+        manager.flush(&mut actions);
+        let mut actions = Actions::new();
+        let dropped = manager.sync_logs(&mut actions);
+        assert_eq!(1, dropped.len());
+        assert!(dropped[0].is_leader());
+        let answered: HashSet<ClientId> =
+            actions.client_messages.iter().map(|&(client, _)| client).collect();
+        assert!(answered.contains(&client) && answered.contains(&forwarded));
+        assert!(!manager.consensus.contains_key(&lid));
+        assert_eq!(vec![lid], *destroyed.borrow());
+
+        // Timeouts set for the log before it was dropped are skipped.
+        let mut actions = Actions::new();
+        manager.apply_timeout(&lid, ConsensusTimeout::CheckQuorum(lid), &mut actions);
+        assert!(actions.timeouts.is_empty());
+    }
+
+    /// Tests that a log created on a number of replicas is built on the replicas recorded in the
+    /// metadata log, even by a server which does not know all of them, and that its peer
+    /// messages only reach them.
+    #[test]
+    fn test_placed_log() {
+        let metadata = LogId(Uuid::new_v4());
+        let servers: Vec<ServerId> = (0..4).map(ServerId).collect();
+        // Server 3 does not know server 2, so only a log placed on both tells whether server 3
+        // builds it on the recorded replicas or on replicas of its own choosing.
+        let lid = (0..)
+            .map(|_| LogId(Uuid::new_v4()))
+            .find(|&lid| {
+                let placed = placement::place(lid, servers.iter().cloned(), 2);
+                placed.contains(&ServerId(2)) && placed.contains(&ServerId(3))
+            })
+            .unwrap();
+        let placed = placement::place(lid, servers.iter().cloned(), 2);
+
+        let mut config = Config { metadata_log: Some(metadata), ..Config::default() };
+        config.log_overrides.insert(lid, LogConfig { replicas: Some(2), ..LogConfig::default() });
+        let addrs: HashMap<ServerId, SocketAddr> = servers.iter()
+            .map(|&id| (id, SocketAddr::from_str(&format!("127.0.0.1:{}", id.0)).unwrap()))
+            .collect();
+        let mut managers: HashMap<ServerId, TestManager> = servers.iter()
+            .map(|&id| {
+                let mut peers = addrs.clone();
+                peers.remove(&id);
+                if id == ServerId(3) {
+                    peers.remove(&ServerId(2));
+                }
+                let logs = vec![(metadata, MemLog::new(), NullStateMachine)];
+                let mut manager = LogManager::new(id, logs, peers, &config);
+                manager.set_factory(Box::new(|_| (MemLog::new(), NullStateMachine)));
+                (id, manager)
+            })
+            .collect();
+
+        let leader = ServerId(0);
+        let mut actions = Actions::new();
+        managers.get_mut(&leader)
+            .unwrap()
+            .apply_timeout(&metadata, ConsensusTimeout::Election(metadata), &mut actions);
+        deliver(leader, actions, &mut managers);
+        assert!(managers[&leader].consensus[&metadata].is_leader());
+
+        let mut actions = Actions::new();
+        {
+            let manager = managers.get_mut(&leader).unwrap();
+            let request = into_reader(&messages::create_log_request(metadata, lid));
+            manager.apply_client_message(ClientId::new(), &request, &mut actions).unwrap();
+            manager.flush(&mut actions);
+        }
+        deliver(leader, actions, &mut managers);
+        // The followers learn that the entry is committed with the next heartbeats.
+        for &peer in servers.iter().filter(|&&id| id != leader) {
+            let mut actions = Actions::new();
+            let heartbeat = ConsensusTimeout::Heartbeat(peer, metadata);
+            managers.get_mut(&leader).unwrap().apply_timeout(&metadata, heartbeat, &mut actions);
+            deliver(leader, actions, &mut managers);
+        }
+
+        for (id, manager) in &mut managers {
+            let mut actions = Actions::new();
+            assert!(manager.sync_logs(&mut actions).is_empty());
+            assert_eq!(placed.contains(id), manager.consensus.contains_key(&lid));
+            if let Some(cons) = manager.consensus.get(&lid) {
+                assert_eq!(placed.iter().cloned().collect::<HashSet<_>>(), cons.voters());
+            }
+        }
+
+        let preferred = placed[0];
+        let mut actions = Actions::new();
+        managers.get_mut(&preferred)
+            .unwrap()
+            .apply_timeout(&lid, ConsensusTimeout::Election(lid), &mut actions);
+        assert!(!actions.peer_messages.is_empty());
+        assert!(actions.peer_messages.iter().all(|&(to, _)| to == placed[1]));
+        deliver(preferred, actions, &mut managers);
+        assert!(managers[&preferred].consensus[&lid].is_leader());
+        for manager in managers.values() {
+            assert_eq!(0, manager.unknown_log_messages());
+        }
+
+        // The placed log cannot coordinate transactions spanning several logs.
+        let request = messages::client_transaction_prepare(metadata, TransactionId::new(), lid);
+        let mut actions = Actions::new();
+        managers.get_mut(&preferred)
+            .unwrap()
+            .apply_client_message(ClientId::new(), &into_reader(&request), &mut actions)
+            .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert!(is_transaction_failure(&actions.client_messages[0].1));
+    }
+
+    /// Tests that a transaction cannot be prepared with a coordinator log which the server does
+    /// not host, since the leader could not resolve it.
+    #[test]
+    fn test_prepare_unhosted_coordinator() {
+        let lid = LogId(Uuid::new_v4());
+        let logs = vec![(lid, MemLog::new(), NullStateMachine)];
+        let mut manager: TestManager =
+            LogManager::new(ServerId(0), logs, HashMap::new(), &Config::default());
+
+        let request = messages::client_transaction_prepare(lid,
+                                                           TransactionId::new(),
+                                                           LogId(Uuid::new_v4()));
+        let mut actions = Actions::new();
+        manager.apply_client_message(ClientId::new(), &into_reader(&request), &mut actions)
+            .unwrap();
+        assert_eq!(1, actions.client_messages.len());
+        assert!(is_transaction_failure(&actions.client_messages[0].1));
+    }
+
+    /// Applies the client request to the log manager of the server `id`, and replicates the
+    /// entries it appends.
+    fn client_request(id: ServerId,
+                      request: &Builder<HeapAllocator>,
+                      managers: &mut HashMap<ServerId, TestManager>) {
+        let mut actions = Actions::new();
+        {
+            let manager = managers.get_mut(&id).unwrap();
+            manager.apply_client_message(ClientId::new(), &into_reader(request), &mut actions)
+                .unwrap();
+            manager.flush(&mut actions);
+        }
+        deliver(id, actions, managers);
+    }
+
+    /// Tests that a transaction prepared on one log and left in doubt by the failure of the
+    /// log's leader is resolved by the next leader, as decided in the coordinator log.
+    #[test]
+    fn test_resolve_after_failover() {
+        let participant = LogId(Uuid::new_v4());
+        let coordinator = LogId(Uuid::new_v4());
+        let mut config = Config::default();
+        // Followers do not wait for an election timeout to vote for a new candidate.
+        config.log.election_timeout_min = 0;
+        config.log.election_timeout_max = 1;
+        let addrs: HashMap<ServerId, SocketAddr> = (0..3)
+            .map(|i| (ServerId(i), SocketAddr::from_str(&format!("127.0.0.1:{}", i)).unwrap()))
+            .collect();
+        let mut managers: HashMap<ServerId, TestManager> = addrs.keys()
+            .map(|&id| {
+                let mut peers = addrs.clone();
+                peers.remove(&id);
+                let logs = vec![(participant, MemLog::new(), NullStateMachine),
+                                (coordinator, MemLog::new(), NullStateMachine)];
+                (id, LogManager::new(id, logs, peers, &config))
+            })
+            .collect();
+
+        let elect = |id: ServerId, managers: &mut HashMap<ServerId, TestManager>| {
+            for &lid in &[participant, coordinator] {
+                let mut actions = Actions::new();
+                managers.get_mut(&id)
+                    .unwrap()
+                    .apply_timeout(&lid, ConsensusTimeout::Election(lid), &mut actions);
+                deliver(id, actions, managers);
+                assert!(managers[&id].consensus[&lid].is_leader());
+            }
+        };
+        let old_leader = ServerId(0);
+        elect(old_leader, &mut managers);
+
+        let session = TransactionId::new();
+        let requests = [messages::client_transaction_begin(participant, session),
+                        messages::proposal_request(session, b"foo", None, participant),
+                        messages::client_transaction_prepare(participant, session, coordinator)];
+        for request in &requests {
+            client_request(old_leader, request, &mut managers);
+        }
+        assert_eq!(Some(coordinator),
+                   managers[&old_leader].consensus[&participant].in_doubt(&session));
+
+        // The leader fails before the transaction is decided.
+        managers.remove(&old_leader);
+        let new_leader = ServerId(1);
+        elect(new_leader, &mut managers);
+        assert_eq!(Some(coordinator),
+                   managers[&new_leader].consensus[&participant].in_doubt(&session));
+
+        let request = messages::client_transaction_decide(coordinator, session, true);
+        client_request(new_leader, &request, &mut managers);
+        let mut actions = Actions::new();
+        {
+            let manager = managers.get_mut(&new_leader).unwrap();
+            let timeout = ConsensusTimeout::Transaction(session, participant);
+            manager.apply_timeout(&participant, timeout, &mut actions);
+            manager.flush(&mut actions);
+        }
+        deliver(new_leader, actions, &mut managers);
+        let status = managers[&new_leader].consensus[&participant].transactions.status(&session);
+        assert_eq!(Some(TransactionState::Committed), status.map(|status| status.state));
+    }
+
+    /// Tests that leaderships are handed off to the least loaded replica for its weight, and only
+    /// while that evens out the load.
+    #[test]
+    fn test_balance_target() {
+        let servers: Vec<ServerId> = (0..3).map(ServerId).collect();
+        let voters = || servers.iter().cloned();
+        let mut config = Config::default();
+        let mut leaderships: HashMap<ServerId, u64> =
+            vec![(ServerId(0), 6), (ServerId(1), 2), (ServerId(2), 1)].into_iter().collect();
+
+        assert_eq!(Some(ServerId(2)), balance_target(ServerId(0), voters(), &leaderships, &config));
+        assert_eq!(None, balance_target(ServerId(1), voters(), &leaderships, &config));
+        assert_eq!(None, balance_target(ServerId(2), voters(), &leaderships, &config));
+
+        // Servers 0 and 1 lead as many logs as server 2 for their weights.
+        config.leader_weights.insert(ServerId(0), 6);
+        config.leader_weights.insert(ServerId(1), 2);
+        assert_eq!(None, balance_target(ServerId(0), voters(), &leaderships, &config));
+
+        // A server with a weight of 0 hands off every leadership, but is never handed any.
+        config.leader_weights.insert(ServerId(2), 0);
+        assert_eq!(Some(ServerId(0)), balance_target(ServerId(2), voters(), &leaderships, &config));
+        config.leader_weights.remove(&ServerId(0));
+        config.leader_weights.remove(&ServerId(1));
+        leaderships.insert(ServerId(1), 6);
+        leaderships.insert(ServerId(2), 0);
+        assert_eq!(None, balance_target(ServerId(0), voters(), &leaderships, &config));
+    }
+
+    /// Tests that a server leading every log hands leaderships off to the other replicas until
+    /// every server leads as many logs.
+    #[test]
+    fn test_balance_leaders() {
+        let config = Config::default();
+        let lids: Vec<LogId> = (0..6).map(|_| LogId(Uuid::new_v4())).collect();
+        let addrs: HashMap<ServerId, SocketAddr> = (0..3)
+            .map(|i| (ServerId(i), SocketAddr::from_str(&format!("127.0.0.1:{}", i)).unwrap()))
+            .collect();
+        let mut managers: HashMap<ServerId, TestManager> = addrs.keys()
+            .map(|&id| {
+                let mut peers = addrs.clone();
+                peers.remove(&id);
+                let logs = lids.iter().map(|&lid| (lid, MemLog::new(), NullStateMachine)).collect();
+                (id, LogManager::new(id, logs, peers, &config))
+            })
+            .collect();
+
+        let leader = ServerId(0);
+        for lid in &lids {
+            let mut actions = Actions::new();
+            managers.get_mut(&leader)
+                .unwrap()
+                .apply_timeout(lid, ConsensusTimeout::Election(*lid), &mut actions);
+            deliver(leader, actions, &mut managers);
+            assert!(managers[&leader].consensus[lid].is_leader());
+        }
+
+        let mut actions = Actions::new();
+        managers.get_mut(&leader).unwrap().balance_leaders(&mut actions);
+        deliver(leader, actions, &mut managers);
+        for manager in managers.values() {
+            let led = lids.iter().filter(|&lid| manager.consensus[lid].is_leader()).count();
+            assert_eq!(2, led);
+        }
+        for lid in &lids {
+            let leaders = managers.values().filter(|manager| manager.consensus[lid].is_leader());
+            assert_eq!(1, leaders.count());
+        }
+
+        // The leaderships are balanced already.
+        let mut actions = Actions::new();
+        managers.get_mut(&leader).unwrap().balance_leaders(&mut actions);
+        assert!(actions.peer_messages.is_empty());
+    }
+
+    /// Tests that the heartbeats of all logs led for a peer travel in one message, which is
+    /// answered with one message.
+    #[test]
+    fn test_multi_heartbeat() {
+        let config = Config { heartbeat_interval: Some(1000), ..Config::default() };
+        let lids: Vec<LogId> = (0..3).map(|_| LogId(Uuid::new_v4())).collect();
+        let addrs: HashMap<ServerId, SocketAddr> = (0..2)
+            .map(|i| (ServerId(i), SocketAddr::from_str(&format!("127.0.0.1:{}", i)).unwrap()))
+            .collect();
+        let mut managers: HashMap<ServerId, TestManager> = addrs.keys()
+            .map(|&id| {
+                let mut peers = addrs.clone();
+                peers.remove(&id);
+                let logs = lids.iter().map(|&lid| (lid, MemLog::new(), NullStateMachine)).collect();
+                (id, LogManager::new(id, logs, peers, &config))
+            })
+            .collect();
+
+        let leader = ServerId(0);
+        let follower = ServerId(1);
+        for lid in &lids {
+            let mut actions = Actions::new();
+            managers.get_mut(&leader)
+                .unwrap()
+                .apply_timeout(lid, ConsensusTimeout::Election(*lid), &mut actions);
+            deliver(leader, actions, &mut managers);
+            assert!(managers[&leader].consensus[lid].is_leader());
+        }
+
+        let mut actions = Actions::new();
+        managers.get_mut(&leader).unwrap().heartbeats(&mut actions);
+        assert_eq!(1, actions.peer_messages.len());
+        assert_eq!(follower, actions.peer_messages[0].0);
+        // The MultiHeartbeat and the MultiHeartbeatResponse.
+        assert_eq!(2, deliver(leader, actions, &mut managers));
+        assert_eq!(0, managers[&follower].unknown_log_messages());
+
+        // A server leading no log sends no heartbeats.
+        let mut actions = Actions::new();
+        managers.get_mut(&follower).unwrap().heartbeats(&mut actions);
+        assert!(actions.peer_messages.is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! Cluster membership.
+//!
+//! The voting members of a log are recorded in the log itself, as configuration entries. A
+//! server always uses the latest configuration in its log, whether or not it is committed.
+//!
+//! Membership changes use joint consensus (section 6 of the Raft paper): the leader first appends
+//! a joint configuration `C_old,new`, during which elections and commitment require separate
+//! majorities of both the old and the new members. Once `C_old,new` is committed, the leader
+//! appends `C_new` on its own. At no point can the old and the new members make decisions
+//! independently of each other.
+//!
+//! A configuration may also name learners. Learners receive the log like the members do, but
+//! they neither vote nor count toward the commit quorum, so a new server can catch up without
+//! stalling commits. Adding or removing a learner takes a single configuration entry.
+
+use std::collections::HashSet;
+
+use ServerId;
+
+/// The voting members of a log.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    /// The members of the configuration, including the local server if it is a member.
+    pub members: HashSet<ServerId>,
+    /// The members of the previous configuration while the cluster is in the joint
+    /// configuration, `None` otherwise.
+    pub old_members: Option<HashSet<ServerId>>,
+    /// The non-voting servers which receive the log.
+    pub learners: HashSet<ServerId>,
+}
+
+impl Configuration {
+    /// Creates a configuration which is not in transition.
+    pub fn new(members: HashSet<ServerId>) -> Configuration {
+        Configuration {
+            members: members,
+            old_members: None,
+            learners: HashSet::new(),
+        }
+    }
+
+    /// Returns the joint configuration transitioning from this configuration to `members`.
+    /// Learners which become members are no longer learners.
+    pub fn joint(&self, members: HashSet<ServerId>) -> Configuration {
+        assert!(!self.is_joint(), "configuration is already in transition");
+        Configuration {
+            learners: self.learners.difference(&members).cloned().collect(),
+            members: members,
+            old_members: Some(self.members.clone()),
+        }
+    }
+
+    /// Returns the configuration a joint configuration transitions to.
+    pub fn finish(&self) -> Configuration {
+        Configuration {
+            members: self.members.clone(),
+            old_members: None,
+            learners: self.learners.clone(),
+        }
+    }
+
+    /// Returns whether this is a joint configuration.
+    pub fn is_joint(&self) -> bool {
+        self.old_members.is_some()
+    }
+
+    /// Returns whether `id` is a member of the old or the new configuration.
+    pub fn contains(&self, id: &ServerId) -> bool {
+        self.members.contains(id) || self.old_members.as_ref().map_or(false, |old| old.contains(id))
+    }
+
+    /// Returns the members of both the old and the new configuration.
+    pub fn voters(&self) -> HashSet<ServerId> {
+        let mut voters = self.members.clone();
+        if let Some(ref old_members) = self.old_members {
+            voters.extend(old_members.iter().cloned());
+        }
+        voters
+    }
+
+    /// Returns whether a majority of every active set of members agrees. `count` returns the
+    /// number of agreeing members within a set.
+    pub fn is_quorum<F>(&self, count: F) -> bool
+        where F: Fn(&HashSet<ServerId>) -> usize
+    {
+        count(&self.members) >= majority(self.members.len()) &&
+        self.old_members
+            .as_ref()
+            .map_or(true, |old_members| count(old_members) >= majority(old_members.len()))
+    }
+}
+
+/// Returns the number of members forming a majority of `members` members.
+pub fn majority(members: usize) -> usize {
+    (members >> 1) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ServerId;
+    use membership::{Configuration, majority};
+
+    fn members(ids: &[u64]) -> HashSet<ServerId> {
+        ids.iter().map(|&id| ServerId(id)).collect()
+    }
+
+    /// Tests the majority function.
+    #[test]
+    fn test_majority() {
+        assert_eq!(1, majority(1));
+        assert_eq!(2, majority(2));
+        assert_eq!(2, majority(3));
+        assert_eq!(3, majority(4));
+    }
+
+    /// Tests that a joint configuration requires a majority of both the old and the new members.
+    #[test]
+    fn test_joint_quorum() {
+        let stable = Configuration::new(members(&[0, 1, 2]));
+        let joint = stable.joint(members(&[2, 3, 4]));
+        assert!(joint.is_joint());
+        assert!(joint.contains(&ServerId(0)) && joint.contains(&ServerId(4)));
+        assert_eq!(members(&[0, 1, 2, 3, 4]), joint.voters());
+
+        let agree = |ids: &[u64]| {
+            let agreeing = members(ids);
+            move |members: &HashSet<ServerId>| members.intersection(&agreeing).count()
+        };
+        assert!(stable.is_quorum(agree(&[0, 1])));
+        assert!(!joint.is_quorum(agree(&[0, 1])));
+        assert!(!joint.is_quorum(agree(&[3, 4])));
+        assert!(joint.is_quorum(agree(&[1, 2, 3])));
+
+        let finished = joint.finish();
+        assert!(!finished.is_joint());
+        assert!(!finished.contains(&ServerId(0)));
+        assert!(finished.is_quorum(agree(&[3, 4])));
+    }
+
+    /// Tests that learners do not count toward a quorum, and stop being learners once they are
+    /// made members.
+    #[test]
+    fn test_learners() {
+        let mut stable = Configuration::new(members(&[0, 1]));
+        stable.learners.insert(ServerId(2));
+        assert!(!stable.contains(&ServerId(2)));
+        assert_eq!(members(&[0, 1]), stable.voters());
+
+        let agree = |ids: &[u64]| {
+            let agreeing = members(ids);
+            move |members: &HashSet<ServerId>| members.intersection(&agreeing).count()
+        };
+        assert!(!stable.is_quorum(agree(&[0, 2])));
+
+        let joint = stable.joint(members(&[0, 1, 2]));
+        assert!(joint.learners.is_empty());
+        assert!(joint.finish().contains(&ServerId(2)));
+    }
+}
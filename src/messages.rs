@@ -8,7 +8,10 @@ use std::collections::HashMap;
 use capnp::message::{Builder, HeapAllocator};
 
 use {ClientId, Term, LogIndex, ServerId, LogId, TransactionId};
-use messages_capnp::{client_request, client_response, connection_preamble, message};
+use messages_capnp::{client_request, client_response, connection_preamble, message,
+                     ReadConsistency, TransactionState};
+use consensus::{Heartbeat, HeartbeatResponse, HeartbeatResult};
+use session::ProposalId;
 use transaction;
 
 // ConnectionPreamble
@@ -76,6 +79,7 @@ pub fn append_entries_request(term: Term,
                               prev_log_term: Term,
                               entries: &[(Term, &[u8])],
                               leader_commit: LogIndex,
+                              round: u64,
                               lid: &LogId)
                               -> Rc<Builder<HeapAllocator>> {
     let bytes = &lid.as_bytes();
@@ -91,6 +95,7 @@ pub fn append_entries_request(term: Term,
         request.set_prev_log_index(prev_log_index.as_u64());
         request.set_prev_log_term(prev_log_term.as_u64());
         request.set_leader_commit(leader_commit.as_u64());
+        request.set_round(round);
 
         let mut entry_list = request.init_entries(entries.len() as u32);
         for (n, entry) in entries.iter().enumerate() {
@@ -104,6 +109,7 @@ pub fn append_entries_request(term: Term,
 
 pub fn append_entries_response_success(term: Term,
                                        log_index: LogIndex,
+                                       round: u64,
                                        lid: &LogId)
                                        -> Rc<Builder<HeapAllocator>> {
     let bytes = &lid.as_bytes();
@@ -117,6 +123,7 @@ pub fn append_entries_response_success(term: Term,
         let mut response = response.init_append_entries_response();
         response.set_term(term.as_u64());
         response.set_success(log_index.as_u64());
+        response.set_round(round);
     }
     Rc::new(message)
 }
@@ -134,7 +141,9 @@ pub fn append_entries_response_stale_term(term: Term, lid: &LogId) -> Rc<Builder
 }
 
 pub fn append_entries_response_inconsistent_prev_entry(term: Term,
-                                                       index: LogIndex,
+                                                       conflict_term: Term,
+                                                       first_index: LogIndex,
+                                                       round: u64,
                                                        lid: &LogId)
                                                        -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
@@ -143,7 +152,10 @@ pub fn append_entries_response_inconsistent_prev_entry(term: Term,
         response.set_log_id(&lid.as_bytes());
         let mut response = response.init_append_entries_response();
         response.set_term(term.as_u64());
-        response.set_inconsistent_prev_entry(index.into());
+        response.set_round(round);
+        let mut conflict = response.init_inconsistent_prev_entry();
+        conflict.set_conflict_term(conflict_term.as_u64());
+        conflict.set_first_index(first_index.as_u64());
     }
     Rc::new(message)
 }
@@ -163,6 +175,230 @@ pub fn append_entries_response_internal_error(term: Term,
     Rc::new(message)
 }
 
+// MultiHeartbeat
+
+pub fn multi_heartbeat(heartbeats: &[(LogId, Heartbeat)]) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut list = message.init_root::<message::Builder>()
+            .init_multi_heartbeat()
+            .init_heartbeats(heartbeats.len() as u32);
+        for (n, &(lid, heartbeat)) in heartbeats.iter().enumerate() {
+            let mut slot = list.borrow().get(n as u32);
+            slot.set_log_id(&lid.as_bytes());
+            slot.set_term(heartbeat.term.as_u64());
+            slot.set_prev_log_index(heartbeat.prev_log_index.as_u64());
+            slot.set_prev_log_term(heartbeat.prev_log_term.as_u64());
+            slot.set_commit_index(heartbeat.commit_index.as_u64());
+            slot.set_round(heartbeat.round);
+        }
+    }
+    Rc::new(message)
+}
+
+pub fn multi_heartbeat_response(responses: &[(LogId, HeartbeatResponse)])
+                                -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut list = message.init_root::<message::Builder>()
+            .init_multi_heartbeat_response()
+            .init_responses(responses.len() as u32);
+        for (n, &(lid, response)) in responses.iter().enumerate() {
+            let mut slot = list.borrow().get(n as u32);
+            slot.set_log_id(&lid.as_bytes());
+            slot.set_term(response.term.as_u64());
+            slot.set_round(response.round);
+            match response.result {
+                HeartbeatResult::Success(latest_log_index) => {
+                    slot.set_success(latest_log_index.as_u64())
+                }
+                HeartbeatResult::InconsistentPrevEntry(conflict_term, first_index, latest) => {
+                    let mut inconsistent = slot.init_inconsistent_prev_entry();
+                    inconsistent.set_conflict_term(conflict_term.as_u64());
+                    inconsistent.set_first_index(first_index.as_u64());
+                    inconsistent.set_latest_log_index(latest.as_u64());
+                }
+                HeartbeatResult::StaleTerm => slot.set_stale_term(()),
+            }
+        }
+    }
+    Rc::new(message)
+}
+
+// PreVote
+
+pub fn pre_vote_request(term: Term,
+                        last_log_index: LogIndex,
+                        last_log_term: Term,
+                        lid: &LogId)
+                        -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut request = request.init_pre_vote_request();
+        request.set_term(term.as_u64());
+        request.set_last_log_index(last_log_index.as_u64());
+        request.set_last_log_term(last_log_term.as_u64());
+    }
+    Rc::new(message)
+}
+
+pub fn pre_vote_response_granted(term: Term, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_pre_vote_response();
+        response.set_term(term.as_u64());
+        response.set_granted(());
+    }
+    Rc::new(message)
+}
+
+pub fn pre_vote_response_stale_term(term: Term, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_pre_vote_response();
+        response.set_term(term.as_u64());
+        response.set_stale_term(());
+    }
+    Rc::new(message)
+}
+
+pub fn pre_vote_response_inconsistent_log(term: Term, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_pre_vote_response();
+        response.set_term(term.as_u64());
+        response.set_inconsistent_log(());
+    }
+    Rc::new(message)
+}
+
+pub fn pre_vote_response_leader_active(term: Term, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_pre_vote_response();
+        response.set_term(term.as_u64());
+        response.set_leader_active(());
+    }
+    Rc::new(message)
+}
+
+// TimeoutNow
+
+pub fn timeout_now(term: Term, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        request.init_timeout_now().set_term(term.as_u64());
+    }
+    Rc::new(message)
+}
+
+// ReadIndex
+
+pub fn read_index_request(term: Term, id: u64, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut request = request.init_read_index_request();
+        request.set_term(term.as_u64());
+        request.set_id(id);
+    }
+    Rc::new(message)
+}
+
+pub fn read_index_response_success(term: Term,
+                                   id: u64,
+                                   index: LogIndex,
+                                   lid: &LogId)
+                                   -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_read_index_response();
+        response.set_term(term.as_u64());
+        response.set_id(id);
+        response.set_success(index.as_u64());
+    }
+    Rc::new(message)
+}
+
+pub fn read_index_response_not_leader(term: Term,
+                                      id: u64,
+                                      lid: &LogId)
+                                      -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_read_index_response();
+        response.set_term(term.as_u64());
+        response.set_id(id);
+        response.set_not_leader(());
+    }
+    Rc::new(message)
+}
+
+// TransactionAbort
+
+pub fn transaction_abort(session: TransactionId, lid: &LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        request.init_transaction_abort().set_session(&session.as_bytes());
+    }
+    Rc::new(message)
+}
+
+// InstallSnapshot
+
+pub fn install_snapshot_request(term: Term,
+                                last_included_index: LogIndex,
+                                last_included_term: Term,
+                                data: &[u8],
+                                lid: &LogId)
+                                -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<message::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut request = request.init_install_snapshot_request();
+        request.set_term(term.as_u64());
+        request.set_last_included_index(last_included_index.as_u64());
+        request.set_last_included_term(last_included_term.as_u64());
+        request.set_data(data);
+    }
+    Rc::new(message)
+}
+
+pub fn install_snapshot_response(term: Term,
+                                 last_included_index: LogIndex,
+                                 lid: &LogId)
+                                 -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<message::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_install_snapshot_response();
+        response.set_term(term.as_u64());
+        response.set_last_included_index(last_included_index.as_u64());
+    }
+    Rc::new(message)
+}
+
 // RequestVote
 
 pub fn request_vote_request(term: Term,
@@ -262,13 +498,17 @@ pub fn ping_request(session: TransactionId, lid: &LogId) -> Builder<HeapAllocato
 
 // Query
 
-pub fn query_request(entry: &[u8], lid: &LogId) -> Builder<HeapAllocator> {
+pub fn query_request(entry: &[u8],
+                     consistency: ReadConsistency,
+                     lid: &LogId)
+                     -> Builder<HeapAllocator> {
     let mut message = Builder::new_default();
     {
         let mut request = message.init_root::<client_request::Builder>();
         request.set_log_id(&lid.as_bytes());
         let mut request = request.init_query();
         request.set_query(entry);
+        request.set_consistency(consistency);
     }
     message
 }
@@ -278,6 +518,7 @@ pub fn query_request(entry: &[u8], lid: &LogId) -> Builder<HeapAllocator> {
 
 pub fn proposal_request(session: TransactionId,
                         entry: &[u8],
+                        proposal: Option<ProposalId>,
                         lid: LogId)
                         -> Builder<HeapAllocator> {
     let mut message = Builder::new_default();
@@ -287,6 +528,22 @@ pub fn proposal_request(session: TransactionId,
         let mut request = request.init_proposal();
         request.set_entry(entry);
         request.set_session(&session.as_bytes());
+        if let Some(proposal) = proposal {
+            request.set_client(proposal.client.as_bytes());
+            request.set_sequence(proposal.sequence);
+        }
+    }
+    message
+}
+
+// LeaderTransfer
+
+pub fn leader_transfer_request(target: ServerId, lid: &LogId) -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        request.init_leader_transfer().set_target(target.as_u64());
     }
     message
 }
@@ -304,6 +561,17 @@ pub fn command_response_success(data: &[u8], lid: LogId) -> Rc<Builder<HeapAlloc
     Rc::new(message)
 }
 
+pub fn command_response_failure(data: &[u8], lid: LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        response.init_proposal()
+            .set_failure(data);
+    }
+    Rc::new(message)
+}
+
 pub fn command_response_unknown_leader(lid: LogId) -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
@@ -328,28 +596,41 @@ pub fn command_response_not_leader(leader_hint: &SocketAddr,
     Rc::new(message)
 }
 
-// Transaction
+pub fn command_response_unknown_log(lid: LogId) -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        response.init_proposal()
+            .set_unknown_log(());
+    }
+    Rc::new(message)
+}
 
-pub fn transaction_begin(lid: LogId, session: TransactionId) -> Rc<Builder<HeapAllocator>> {
+// LogChange
+
+pub fn create_log_request(lid: LogId, log: LogId) -> Builder<HeapAllocator> {
     let mut message = Builder::new_default();
     {
-        let mut request = message.init_root::<message::Builder>();
+        let mut request = message.init_root::<client_request::Builder>();
         request.set_log_id(&lid.as_bytes());
-        request.init_transaction_begin().set_session(&session.as_bytes());
+        request.init_create_log().set_log(&log.as_bytes());
     }
-    Rc::new(message)
+    message
 }
 
-pub fn transaction_commit(lid: LogId, session: TransactionId) -> Rc<Builder<HeapAllocator>> {
+pub fn drop_log_request(lid: LogId, log: LogId) -> Builder<HeapAllocator> {
     let mut message = Builder::new_default();
     {
-        let mut request = message.init_root::<message::Builder>();
+        let mut request = message.init_root::<client_request::Builder>();
         request.set_log_id(&lid.as_bytes());
-        request.init_transaction_commit().set_session(&session.as_bytes());
+        request.init_drop_log().set_log(&log.as_bytes());
     }
-    Rc::new(message)
+    message
 }
 
+// Transaction
+
 pub fn client_transaction_begin(lid: LogId, session: TransactionId) -> Builder<HeapAllocator> {
     let mut message = Builder::new_default();
     {
@@ -381,6 +662,102 @@ pub fn client_transaction_rollback(lid: LogId, session: TransactionId) -> Builde
     message
 }
 
+pub fn client_transaction_prepare(lid: LogId,
+                                  session: TransactionId,
+                                  coordinator: LogId)
+                                  -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut prepare = request.init_transaction_prepare();
+        prepare.set_session(&session.as_bytes());
+        prepare.set_coordinator(&coordinator.as_bytes());
+    }
+    message
+}
+
+pub fn client_transaction_decide(lid: LogId,
+                                 session: TransactionId,
+                                 commit: bool)
+                                 -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut decide = request.init_transaction_decide();
+        decide.set_session(&session.as_bytes());
+        decide.set_commit(commit);
+    }
+    message
+}
+
+pub fn client_transaction_savepoint(lid: LogId,
+                                    session: TransactionId,
+                                    name: &str)
+                                    -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut savepoint = request.init_transaction_savepoint();
+        savepoint.set_session(&session.as_bytes());
+        savepoint.set_name(name);
+    }
+    message
+}
+
+pub fn client_transaction_rollback_to(lid: LogId,
+                                      session: TransactionId,
+                                      name: &str)
+                                      -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        let mut rollback = request.init_transaction_rollback_to();
+        rollback.set_session(&session.as_bytes());
+        rollback.set_name(name);
+    }
+    message
+}
+
+pub fn client_transaction_status(lid: LogId, session: TransactionId) -> Builder<HeapAllocator> {
+    let mut message = Builder::new_default();
+    {
+        let mut request = message.init_root::<client_request::Builder>();
+        request.set_log_id(&lid.as_bytes());
+        request.init_transaction_status().set_session(&session.as_bytes());
+    }
+    message
+}
+
+pub fn transaction_status_response(status: Option<transaction::TransactionStatus>,
+                                   lid: LogId)
+                                   -> Rc<Builder<HeapAllocator>> {
+    let mut message = Builder::new_default();
+    {
+        let mut response = message.init_root::<client_response::Builder>();
+        response.set_log_id(&lid.as_bytes());
+        let mut response = response.init_transaction_status();
+        match status {
+            Some(status) => {
+                let mut group = response.init_status();
+                group.set_state(match status.state {
+                    transaction::TransactionState::Active => TransactionState::Active,
+                    transaction::TransactionState::Prepared => TransactionState::Prepared,
+                    transaction::TransactionState::Committed => TransactionState::Committed,
+                    transaction::TransactionState::RolledBack => TransactionState::RolledBack,
+                });
+                group.set_commands(status.commands);
+                group.set_begin_index(status.begin_index.as_u64());
+            }
+            None => response.set_unknown(()),
+        }
+    }
+    Rc::new(message)
+}
+
 pub fn command_transaction_success(data: &[u8], lid: LogId) -> Rc<Builder<HeapAllocator>> {
     let mut message = Builder::new_default();
     {
@@ -404,13 +781,3 @@ pub fn command_transaction_failure(error: transaction::TransactionError,
     }
     Rc::new(message)
 }
-
-pub fn transaction_rollback(lid: LogId, session: TransactionId) -> Rc<Builder<HeapAllocator>> {
-    let mut message = Builder::new_default();
-    {
-        let mut request = message.init_root::<message::Builder>();
-        request.set_log_id(&lid.as_bytes());
-        request.init_transaction_rollback().set_session(&session.as_bytes());
-    }
-    Rc::new(message)
-}
@@ -0,0 +1,927 @@
+//! A durable `Log` implementation which stores entries in append-only segment files.
+//!
+//! The log directory contains a `metadata` file holding the current term and vote, an optional
+//! `snapshot` file written by `compact()`, and one or more segment files named after the index of
+//! their first entry. Every record in a segment is framed as:
+//!
+//! ```text
+//! [ length: u32 | checksum: u32 | term: u64 | data: [u8; length] ]
+//! ```
+//!
+//! All integers are stored big-endian. A record whose frame is incomplete or whose checksum does
+//! not match is considered to be the tail of an interrupted write; on recovery the segment is cut
+//! off before it and any later segments are removed.
+//!
+//! The snapshot file holds the index and term covered by the snapshot, a checksum and the snapshot
+//! data. Segments whose entries are entirely covered by the snapshot are deleted; the first
+//! remaining segment may still contain a few covered entries, which are skipped on recovery.
+
+use std::{fmt, fs, io};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::result;
+
+use persistent_log::Log;
+use LogIndex;
+use ServerId;
+use Term;
+
+/// Name of the file holding the current term and vote.
+const METADATA_FILE: &'static str = "metadata";
+/// Name of the temporary file used while atomically replacing the metadata.
+const METADATA_TMP_FILE: &'static str = "metadata.tmp";
+/// Name of the file holding the latest snapshot.
+const SNAPSHOT_FILE: &'static str = "snapshot";
+/// Name of the temporary file used while atomically replacing the snapshot.
+const SNAPSHOT_TMP_FILE: &'static str = "snapshot.tmp";
+/// File extension of segment files.
+const SEGMENT_EXTENSION: &'static str = "segment";
+/// Default number of entries stored in a single segment.
+const DEFAULT_SEGMENT_ENTRIES: u64 = 4096;
+/// Size of a record header (length, checksum and term).
+const RECORD_HEADER_LEN: usize = 16;
+/// Size of the metadata file (term, vote flag, vote and checksum).
+const METADATA_LEN: usize = 21;
+/// Size of the snapshot file header (index, term and checksum).
+const SNAPSHOT_HEADER_LEN: usize = 20;
+
+/// A segment file on disk.
+#[derive(Clone, Debug)]
+struct Segment {
+    /// Index of the first entry in the segment.
+    first_index: LogIndex,
+    /// Byte offset of every entry in the segment file.
+    offsets: Vec<u64>,
+    /// Length of the segment file in bytes.
+    len: u64,
+}
+
+/// This is a `Log` implementation that persists entries to disk. Entries are appended to segment
+/// files, and the term and vote are kept in a separate metadata file. All modifications are
+/// `fsync`ed before the corresponding method returns.
+///
+/// A copy of every entry is also kept in memory, so that `entry()` may return borrowed slices.
+///
+/// Cloning a `FileLog` does not copy the log directory; only a single instance should be used to
+/// write to a directory at any point in time.
+#[derive(Clone)]
+pub struct FileLog {
+    /// Directory holding the metadata file and the segments.
+    dir: PathBuf,
+    /// Maximum number of entries in a segment before a new one is started.
+    segment_entries: u64,
+    current_term: Term,
+    voted_for: Option<ServerId>,
+    /// Entries following the snapshot.
+    entries: Vec<(Term, Vec<u8>)>,
+    /// Segments ordered by their first index.
+    segments: Vec<Segment>,
+    /// The latest snapshot's index, term and data.
+    snapshot: Option<(LogIndex, Term, Vec<u8>)>,
+}
+
+impl FileLog {
+    /// Opens the log stored in `dir`, creating the directory if it does not exist yet.
+    ///
+    /// Partially written records at the end of the log are discarded.
+    pub fn open<P>(dir: P) -> io::Result<FileLog>
+        where P: AsRef<Path>
+    {
+        FileLog::with_segment_entries(dir, DEFAULT_SEGMENT_ENTRIES)
+    }
+
+    /// Opens the log stored in `dir` with a custom maximum number of entries per segment.
+    pub fn with_segment_entries<P>(dir: P, segment_entries: u64) -> io::Result<FileLog>
+        where P: AsRef<Path>
+    {
+        assert!(segment_entries > 0, "segments must hold at least one entry");
+        let dir = dir.as_ref().to_path_buf();
+        try!(fs::create_dir_all(&dir));
+
+        let mut log = FileLog {
+            dir: dir,
+            segment_entries: segment_entries,
+            current_term: Term(0),
+            voted_for: None,
+            entries: Vec::new(),
+            segments: Vec::new(),
+            snapshot: None,
+        };
+        try!(log.recover_metadata());
+        try!(log.recover_snapshot());
+        try!(log.recover_segments());
+        Ok(log)
+    }
+
+    /// Returns the directory the log is stored in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn segment_path(&self, first_index: LogIndex) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", first_index.as_u64(), SEGMENT_EXTENSION))
+    }
+
+    /// Reads the term and vote from the metadata file, if it exists.
+    fn recover_metadata(&mut self) -> io::Result<()> {
+        // A leftover temporary file is an interrupted update; the previous metadata is intact.
+        let tmp = self.dir.join(METADATA_TMP_FILE);
+        if tmp.exists() {
+            try!(fs::remove_file(&tmp));
+        }
+
+        let path = self.dir.join(METADATA_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        try!(try!(fs::File::open(&path)).read_to_end(&mut buf));
+        if buf.len() != METADATA_LEN ||
+           read_u32(&buf[17..21]) != checksum(&[&buf[..17]]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("corrupt metadata file {:?}", path)));
+        }
+        self.current_term = Term(read_u64(&buf[0..8]));
+        self.voted_for = if buf[8] == 1 {
+            Some(ServerId(read_u64(&buf[9..17])))
+        } else {
+            None
+        };
+        Ok(())
+    }
+
+    /// Reads the latest snapshot, if one exists.
+    fn recover_snapshot(&mut self) -> io::Result<()> {
+        // As with the metadata, the previous snapshot is intact if the update was interrupted.
+        let tmp = self.dir.join(SNAPSHOT_TMP_FILE);
+        if tmp.exists() {
+            try!(fs::remove_file(&tmp));
+        }
+
+        let path = self.dir.join(SNAPSHOT_FILE);
+        if !path.exists() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        try!(try!(fs::File::open(&path)).read_to_end(&mut buf));
+        if buf.len() < SNAPSHOT_HEADER_LEN ||
+           read_u32(&buf[16..20]) != checksum(&[&buf[..16], &buf[SNAPSHOT_HEADER_LEN..]]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("corrupt snapshot file {:?}", path)));
+        }
+        self.snapshot = Some((LogIndex(read_u64(&buf[0..8])),
+                              Term(read_u64(&buf[8..16])),
+                              buf[SNAPSHOT_HEADER_LEN..].to_vec()));
+        Ok(())
+    }
+
+    /// Reads all segments into memory, discarding everything after the first damaged record.
+    fn recover_segments(&mut self) -> io::Result<()> {
+        let mut first_indexes = Vec::new();
+        for dir_entry in try!(fs::read_dir(&self.dir)) {
+            let path = try!(dir_entry).path();
+            if path.extension().map_or(true, |ext| ext != SEGMENT_EXTENSION) {
+                continue;
+            }
+            match path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| {
+                stem.parse::<u64>().ok()
+            }) {
+                Some(index) => first_indexes.push(LogIndex(index)),
+                None => scoped_warn!("ignoring unknown file in log directory: {:?}", path),
+            }
+        }
+        first_indexes.sort();
+
+        let snapshot_index = self.snapshot_index();
+        let mut damaged = false;
+        for first_index in first_indexes {
+            let path = self.segment_path(first_index);
+            let expected = self.latest_index() + 1;
+            // The first segment may begin with entries which are covered by the snapshot.
+            let reachable = if self.segments.is_empty() {
+                first_index <= expected
+            } else {
+                first_index == expected
+            };
+            if damaged || !reachable {
+                // Either an earlier segment was cut off, or there is a gap in the log. Nothing
+                // from this point on can be trusted.
+                scoped_warn!("removing unreachable segment {:?}", path);
+                try!(fs::remove_file(&path));
+                damaged = true;
+                continue;
+            }
+
+            let mut buf = Vec::new();
+            try!(try!(fs::File::open(&path)).read_to_end(&mut buf));
+
+            let mut segment = Segment {
+                first_index: first_index,
+                offsets: Vec::new(),
+                len: 0,
+            };
+            let mut offset = 0;
+            let mut index = first_index;
+            while offset < buf.len() {
+                match decode_record(&buf[offset..]) {
+                    Some((term, data, record_len)) => {
+                        segment.offsets.push(offset as u64);
+                        if index > snapshot_index {
+                            self.entries.push((term, data.to_vec()));
+                        }
+                        index = index + 1;
+                        offset += record_len;
+                    }
+                    None => {
+                        scoped_warn!("discarding damaged tail of segment {:?} at offset {}",
+                                     path,
+                                     offset);
+                        damaged = true;
+                        break;
+                    }
+                }
+            }
+            segment.len = offset as u64;
+
+            if segment.offsets.is_empty() || index <= snapshot_index + 1 {
+                // Empty, or left behind by an interrupted compaction.
+                try!(fs::remove_file(&path));
+            } else {
+                if damaged {
+                    let file = try!(fs::OpenOptions::new().write(true).open(&path));
+                    try!(file.set_len(segment.len));
+                    try!(file.sync_all());
+                }
+                self.segments.push(segment);
+            }
+        }
+        if damaged {
+            try!(sync_dir(&self.dir));
+        }
+        Ok(())
+    }
+
+    /// Atomically replaces the metadata file with the term and vote. The caller only takes them
+    /// on once they are written, so that memory never runs ahead of the disk.
+    fn write_metadata(&self, term: Term, voted_for: Option<ServerId>) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(METADATA_LEN);
+        push_u64(&mut buf, term.as_u64());
+        match voted_for {
+            Some(id) => {
+                buf.push(1);
+                push_u64(&mut buf, id.as_u64());
+            }
+            None => {
+                buf.push(0);
+                push_u64(&mut buf, 0);
+            }
+        }
+        let sum = checksum(&[&buf[..]]);
+        push_u32(&mut buf, sum);
+        self.replace_file(METADATA_FILE, METADATA_TMP_FILE, &buf)
+    }
+
+    /// Atomically replaces the snapshot file.
+    fn write_snapshot(&self, index: LogIndex, term: Term, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(SNAPSHOT_HEADER_LEN + data.len());
+        push_u64(&mut buf, index.as_u64());
+        push_u64(&mut buf, term.as_u64());
+        let sum = checksum(&[&buf[..], data]);
+        push_u32(&mut buf, sum);
+        buf.extend_from_slice(data);
+        self.replace_file(SNAPSHOT_FILE, SNAPSHOT_TMP_FILE, &buf)
+    }
+
+    /// Replaces the file `name` with `buf` by writing to `tmp_name` first and renaming it.
+    fn replace_file(&self, name: &str, tmp_name: &str, buf: &[u8]) -> io::Result<()> {
+        let tmp = self.dir.join(tmp_name);
+        {
+            let mut file = try!(fs::File::create(&tmp));
+            try!(file.write_all(buf));
+            try!(file.sync_all());
+        }
+        try!(fs::rename(&tmp, self.dir.join(name)));
+        sync_dir(&self.dir)
+    }
+
+    /// Stores the snapshot and removes the segments it makes obsolete. The snapshot is written
+    /// first, so an interrupted compaction leaves behind segments which are skipped on recovery.
+    fn compact_to(&mut self, index: LogIndex, term: Term, data: &[u8]) -> io::Result<()> {
+        let snapshot_index = self.snapshot_index();
+        if index <= snapshot_index {
+            return Ok(());
+        }
+        let keep_suffix = index <= self.latest_index() &&
+                          self.entries[(index - snapshot_index - 1) as usize].0 == term;
+        try!(self.write_snapshot(index, term, data));
+
+        if keep_suffix {
+            self.entries.drain(..(index - snapshot_index) as usize);
+            while self.segments.first().map_or(false, |segment| {
+                segment.first_index + segment.offsets.len() as u64 <= index + 1
+            }) {
+                let segment = self.segments.remove(0);
+                try!(fs::remove_file(self.segment_path(segment.first_index)));
+            }
+        } else {
+            self.entries.clear();
+            for segment in self.segments.drain(..).collect::<Vec<_>>() {
+                try!(fs::remove_file(self.segment_path(segment.first_index)));
+            }
+        }
+        self.snapshot = Some((index, term, data.to_vec()));
+        sync_dir(&self.dir)
+    }
+
+    /// Removes all entries starting at `from` from disk and from memory.
+    fn truncate_from(&mut self, from: LogIndex) -> io::Result<()> {
+        if from > self.latest_index() {
+            return Ok(());
+        }
+        let snapshot_index = self.snapshot_index();
+        assert!(from > snapshot_index, "entries covered by the snapshot can not be truncated");
+        while let Some(segment) = self.segments.pop() {
+            let path = self.segment_path(segment.first_index);
+            if segment.first_index >= from {
+                try!(fs::remove_file(&path));
+                continue;
+            }
+            let mut segment = segment;
+            let keep = (from - segment.first_index) as usize;
+            if keep < segment.offsets.len() {
+                segment.len = segment.offsets[keep];
+                segment.offsets.truncate(keep);
+                let file = try!(fs::OpenOptions::new().write(true).open(&path));
+                try!(file.set_len(segment.len));
+                try!(file.sync_all());
+            }
+            self.segments.push(segment);
+            break;
+        }
+        try!(sync_dir(&self.dir));
+        self.entries.truncate((from - snapshot_index - 1) as usize);
+        Ok(())
+    }
+
+    /// Appends entries to the end of the log, starting new segments as necessary.
+    fn append(&mut self, entries: &[(Term, &[u8])]) -> io::Result<()> {
+        let mut created_segment = false;
+        let mut remaining = entries;
+        while !remaining.is_empty() {
+            let next_index = self.latest_index() + 1;
+            let needs_segment = self.segments
+                .last()
+                .map_or(true, |segment| segment.offsets.len() as u64 >= self.segment_entries);
+            if needs_segment {
+                self.segments.push(Segment {
+                    first_index: next_index,
+                    offsets: Vec::new(),
+                    len: 0,
+                });
+                created_segment = true;
+            }
+
+            let (path, room) = {
+                let segment = self.segments.last().unwrap();
+                (self.segment_path(segment.first_index),
+                 (self.segment_entries - segment.offsets.len() as u64) as usize)
+            };
+            let count = ::std::cmp::min(room, remaining.len());
+            let (batch, rest) = remaining.split_at(count);
+
+            let mut buf = Vec::new();
+            let mut offsets = Vec::with_capacity(batch.len());
+            {
+                let segment = self.segments.last().unwrap();
+                for &(term, data) in batch {
+                    offsets.push(segment.len + buf.len() as u64);
+                    encode_record(&mut buf, term, data);
+                }
+            }
+
+            let mut file = try!(fs::OpenOptions::new().create(true).append(true).open(&path));
+            try!(file.write_all(&buf));
+            try!(file.sync_data());
+
+            {
+                let segment = self.segments.last_mut().unwrap();
+                segment.offsets.extend(offsets);
+                segment.len += buf.len() as u64;
+            }
+            self.entries.extend(batch.iter().map(|&(term, data)| (term, data.to_vec())));
+            remaining = rest;
+        }
+        if created_segment {
+            try!(sync_dir(&self.dir));
+        }
+        Ok(())
+    }
+
+    fn latest_index(&self) -> LogIndex {
+        self.snapshot_index() + self.entries.len() as u64
+    }
+
+    fn snapshot_index(&self) -> LogIndex {
+        self.snapshot.as_ref().map_or(LogIndex(0), |&(index, _, _)| index)
+    }
+}
+
+impl fmt::Debug for FileLog {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt,
+               "FileLog {{ dir: {:?}, term: {}, index: {} }}",
+               self.dir,
+               self.current_term,
+               self.latest_index())
+    }
+}
+
+impl Log for FileLog {
+    type Error = io::Error;
+
+    fn current_term(&self) -> result::Result<Term, io::Error> {
+        Ok(self.current_term)
+    }
+
+    fn set_current_term(&mut self, term: Term) -> result::Result<(), io::Error> {
+        try!(self.write_metadata(term, None));
+        self.current_term = term;
+        self.voted_for = None;
+        Ok(())
+    }
+
+    fn inc_current_term(&mut self) -> result::Result<Term, io::Error> {
+        let term = self.current_term + 1;
+        try!(self.set_current_term(term));
+        Ok(term)
+    }
+
+    fn voted_for(&self) -> result::Result<Option<ServerId>, io::Error> {
+        Ok(self.voted_for)
+    }
+
+    fn set_voted_for(&mut self, server: Option<ServerId>) -> result::Result<(), io::Error> {
+        let term = self.current_term;
+        try!(self.write_metadata(term, server));
+        self.voted_for = server;
+        Ok(())
+    }
+
+    fn latest_log_index(&self) -> result::Result<LogIndex, io::Error> {
+        Ok(self.latest_index())
+    }
+
+    fn latest_log_term(&self) -> result::Result<Term, io::Error> {
+        match self.entries.last() {
+            Some(&(term, _)) => Ok(term),
+            None => Ok(self.snapshot.as_ref().map_or(Term(0), |&(_, term, _)| term)),
+        }
+    }
+
+    fn first_log_index(&self) -> result::Result<LogIndex, io::Error> {
+        Ok(self.snapshot_index() + 1)
+    }
+
+    fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), io::Error> {
+        let snapshot_index = self.snapshot_index();
+        if index <= snapshot_index || index > self.latest_index() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("no entry at index {}", index)));
+        }
+        let (term, ref bytes) = self.entries[(index - snapshot_index - 1) as usize];
+        Ok((term, bytes))
+    }
+
+    fn append_entries(&mut self,
+                      from: LogIndex,
+                      entries: &[(Term, &[u8])])
+                      -> result::Result<(), io::Error> {
+        assert!(self.latest_index() + 1 >= from);
+        try!(self.truncate_from(from));
+        self.append(entries)
+    }
+
+    fn truncate(&mut self, lo: LogIndex) -> result::Result<(), io::Error> {
+        self.truncate_from(lo + 1)
+    }
+
+    fn rollback(&mut self, lo: LogIndex) -> result::Result<(Vec<(Term, Vec<u8>)>), io::Error> {
+        Ok(self.entries[((lo - self.snapshot_index()) as usize)..].to_vec())
+    }
+
+    fn compact(&mut self,
+               index: LogIndex,
+               term: Term,
+               snapshot: &[u8])
+               -> result::Result<(), io::Error> {
+        self.compact_to(index, term, snapshot)
+    }
+
+    fn snapshot(&self) -> result::Result<Option<(LogIndex, Term, &[u8])>, io::Error> {
+        Ok(self.snapshot.as_ref().map(|&(index, term, ref data)| (index, term, &data[..])))
+    }
+}
+
+/// Appends a framed record to `buf`.
+fn encode_record(buf: &mut Vec<u8>, term: Term, data: &[u8]) {
+    let mut term_bytes = Vec::with_capacity(8);
+    push_u64(&mut term_bytes, term.as_u64());
+    push_u32(buf, data.len() as u32);
+    push_u32(buf, checksum(&[&term_bytes[..], data]));
+    buf.extend_from_slice(&term_bytes);
+    buf.extend_from_slice(data);
+}
+
+/// Decodes the record at the start of `buf`. Returns the term, the data and the length of the
+/// whole record, or `None` if the record is incomplete or damaged.
+fn decode_record(buf: &[u8]) -> Option<(Term, &[u8], usize)> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let len = read_u32(&buf[0..4]) as usize;
+    let sum = read_u32(&buf[4..8]);
+    let record_len = match RECORD_HEADER_LEN.checked_add(len) {
+        Some(record_len) if record_len <= buf.len() => record_len,
+        _ => return None,
+    };
+    let term_bytes = &buf[8..16];
+    let data = &buf[RECORD_HEADER_LEN..record_len];
+    if checksum(&[term_bytes, data]) != sum {
+        return None;
+    }
+    Some((Term(read_u64(term_bytes)), data, record_len))
+}
+
+/// Computes the CRC-32 (IEEE) checksum over the concatenation of `chunks`.
+fn checksum(chunks: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for chunk in chunks {
+        for &byte in chunk.iter() {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                let mask = (!(crc & 1)).wrapping_add(1);
+                crc = (crc >> 1) ^ (0xEDB88320 & mask);
+            }
+        }
+    }
+    !crc
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    for shift in (0..4).rev() {
+        buf.push((value >> (shift * 8)) as u8);
+    }
+}
+
+fn push_u64(buf: &mut Vec<u8>, value: u64) {
+    for shift in (0..8).rev() {
+        buf.push((value >> (shift * 8)) as u8);
+    }
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf.iter().take(4).fold(0, |acc, &byte| (acc << 8) | byte as u32)
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    buf.iter().take(8).fold(0, |acc, &byte| (acc << 8) | byte as u64)
+}
+
+/// Flushes directory metadata (file creation, removal and renames) to disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    try!(fs::File::open(dir)).sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::*;
+    use LogIndex;
+    use ServerId;
+    use Term;
+    use persistent_log::Log;
+    use uuid::Uuid;
+
+    /// Returns a fresh directory for a test log.
+    fn test_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("raft-file-log-{}-{}", name, Uuid::new_v4()))
+    }
+
+    /// Returns the paths of all segment files in the directory, ordered by first index.
+    fn segment_paths(dir: &PathBuf) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "segment"))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn test_current_term() {
+        let dir = test_dir("current_term");
+        let mut store = FileLog::open(&dir).unwrap();
+        assert_eq!(Term(0), store.current_term().unwrap());
+        store.set_voted_for(Some(ServerId::from(0))).unwrap();
+        store.set_current_term(Term(42)).unwrap();
+        assert_eq!(None, store.voted_for().unwrap());
+        assert_eq!(Term(42), store.current_term().unwrap());
+        store.inc_current_term().unwrap();
+        assert_eq!(Term(43), store.current_term().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_voted_for() {
+        let dir = test_dir("voted_for");
+        let mut store = FileLog::open(&dir).unwrap();
+        assert_eq!(None, store.voted_for().unwrap());
+        let id = ServerId::from(0);
+        store.set_voted_for(Some(id)).unwrap();
+        assert_eq!(Some(id), store.voted_for().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that the term and vote are left unchanged when they cannot be written.
+    #[test]
+    fn test_failed_metadata_write() {
+        let dir = test_dir("failed_metadata_write");
+        let mut store = FileLog::open(&dir).unwrap();
+        store.set_current_term(Term(1)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(store.set_voted_for(Some(ServerId::from(0))).is_err());
+        assert!(store.set_current_term(Term(2)).is_err());
+        assert!(store.inc_current_term().is_err());
+        assert_eq!(None, store.voted_for().unwrap());
+        assert_eq!(Term(1), store.current_term().unwrap());
+    }
+
+    #[test]
+    fn test_append_entries() {
+        let dir = test_dir("append_entries");
+        let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(LogIndex::from(0), store.latest_log_index().unwrap());
+        assert_eq!(Term::from(0), store.latest_log_term().unwrap());
+
+        // [0.1, 0.2, 0.3, 1.4]
+        store.append_entries(LogIndex(1),
+                            &[(Term::from(0), &[1]),
+                              (Term::from(0), &[2]),
+                              (Term::from(0), &[3]),
+                              (Term::from(1), &[4])])
+            .unwrap();
+        assert_eq!(LogIndex::from(4), store.latest_log_index().unwrap());
+        assert_eq!(Term::from(1), store.latest_log_term().unwrap());
+        assert_eq!((Term::from(0), &*vec![1u8]),
+                   store.entry(LogIndex::from(1)).unwrap());
+        assert_eq!((Term::from(0), &*vec![2u8]),
+                   store.entry(LogIndex::from(2)).unwrap());
+        assert_eq!((Term::from(0), &*vec![3u8]),
+                   store.entry(LogIndex::from(3)).unwrap());
+        assert_eq!((Term::from(1), &*vec![4u8]),
+                   store.entry(LogIndex::from(4)).unwrap());
+
+        // [0.1, 0.2, 0.3]
+        store.append_entries(LogIndex::from(4), &[]).unwrap();
+        assert_eq!(LogIndex(3), store.latest_log_index().unwrap());
+        assert_eq!(Term::from(0), store.latest_log_term().unwrap());
+        assert_eq!((Term::from(0), &*vec![1u8]),
+                   store.entry(LogIndex::from(1)).unwrap());
+        assert_eq!((Term::from(0), &*vec![2u8]),
+                   store.entry(LogIndex::from(2)).unwrap());
+        assert_eq!((Term::from(0), &*vec![3u8]),
+                   store.entry(LogIndex::from(3)).unwrap());
+
+        // [0.1, 0.2, 2.3, 3.4]
+        store.append_entries(LogIndex::from(3), &[(Term(2), &[3]), (Term(3), &[4])]).unwrap();
+        assert_eq!(LogIndex(4), store.latest_log_index().unwrap());
+        assert_eq!(Term::from(3), store.latest_log_term().unwrap());
+        assert_eq!((Term::from(0), &*vec![1u8]),
+                   store.entry(LogIndex::from(1)).unwrap());
+        assert_eq!((Term::from(0), &*vec![2u8]),
+                   store.entry(LogIndex::from(2)).unwrap());
+        assert_eq!((Term::from(2), &*vec![3u8]),
+                   store.entry(LogIndex::from(3)).unwrap());
+        assert_eq!((Term::from(3), &*vec![4u8]),
+                   store.entry(LogIndex::from(4)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that the term, vote and entries survive reopening the log.
+    #[test]
+    fn test_recover() {
+        let dir = test_dir("recover");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+            store.set_current_term(Term(7)).unwrap();
+            store.set_voted_for(Some(ServerId::from(3))).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term(5), &b"a"[..]),
+                                  (Term(6), &b"b"[..]),
+                                  (Term(7), &b"c"[..])])
+                .unwrap();
+        }
+        assert_eq!(2, segment_paths(&dir).len());
+
+        let store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(Term(7), store.current_term().unwrap());
+        assert_eq!(Some(ServerId::from(3)), store.voted_for().unwrap());
+        assert_eq!(LogIndex(3), store.latest_log_index().unwrap());
+        assert_eq!((Term(5), &b"a"[..]), store.entry(LogIndex(1)).unwrap());
+        assert_eq!((Term(6), &b"b"[..]), store.entry(LogIndex(2)).unwrap());
+        assert_eq!((Term(7), &b"c"[..]), store.entry(LogIndex(3)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that truncating the log removes the entries from disk, including whole segments.
+    #[test]
+    fn test_recover_after_truncation() {
+        let dir = test_dir("recover_after_truncation");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term(1), &b"a"[..]), (Term(1), &b"b"[..]), (Term(1), &b"c"[..]),
+                                  (Term(1), &b"d"[..]), (Term(1), &b"e"[..])])
+                .unwrap();
+            assert_eq!(3, segment_paths(&dir).len());
+            store.append_entries(LogIndex(2), &[(Term(2), &b"x"[..])]).unwrap();
+            assert_eq!(1, segment_paths(&dir).len());
+        }
+
+        let store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(LogIndex(2), store.latest_log_index().unwrap());
+        assert_eq!((Term(1), &b"a"[..]), store.entry(LogIndex(1)).unwrap());
+        assert_eq!((Term(2), &b"x"[..]), store.entry(LogIndex(2)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Cuts the last segment off part-way through a record, and checks that the partial record
+    /// is discarded while all complete records are kept.
+    #[test]
+    fn test_recover_partial_record() {
+        let dir = test_dir("recover_partial_record");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 10).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term(1), &b"first"[..]),
+                                  (Term(1), &b"second"[..]),
+                                  (Term(1), &b"third"[..])])
+                .unwrap();
+        }
+        let path = segment_paths(&dir).pop().unwrap();
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        let mut store = FileLog::with_segment_entries(&dir, 10).unwrap();
+        assert_eq!(LogIndex(2), store.latest_log_index().unwrap());
+        assert_eq!((Term(1), &b"second"[..]), store.entry(LogIndex(2)).unwrap());
+
+        // The log must be writable after recovery, and the new entry must survive a reopen.
+        store.append_entries(LogIndex(3), &[(Term(2), &b"fourth"[..])]).unwrap();
+        let store = FileLog::with_segment_entries(&dir, 10).unwrap();
+        assert_eq!(LogIndex(3), store.latest_log_index().unwrap());
+        assert_eq!((Term(2), &b"fourth"[..]), store.entry(LogIndex(3)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Cuts a segment off part-way through a record header while later segments exist, and
+    /// checks that the later segments are removed instead of leaving a gap in the log.
+    #[test]
+    fn test_recover_partial_header_with_later_segments() {
+        let dir = test_dir("recover_partial_header");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term(1), &b"a"[..]), (Term(1), &b"b"[..]), (Term(1), &b"c"[..]),
+                                  (Term(1), &b"d"[..])])
+                .unwrap();
+        }
+        let paths = segment_paths(&dir);
+        assert_eq!(2, paths.len());
+        // Keep the first record and a few bytes of the second record's header.
+        let first_record_len = (super::RECORD_HEADER_LEN + 1) as u64;
+        fs::OpenOptions::new()
+            .write(true)
+            .open(&paths[0])
+            .unwrap()
+            .set_len(first_record_len + 5)
+            .unwrap();
+
+        let store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(LogIndex(1), store.latest_log_index().unwrap());
+        assert_eq!((Term(1), &b"a"[..]), store.entry(LogIndex(1)).unwrap());
+        assert_eq!(1, segment_paths(&dir).len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Checks that a record with a damaged checksum is treated as the end of the log.
+    #[test]
+    fn test_recover_corrupt_record() {
+        let dir = test_dir("recover_corrupt_record");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 10).unwrap();
+            store.append_entries(LogIndex(1), &[(Term(1), &b"good"[..]), (Term(1), &b"bad"[..])])
+                .unwrap();
+        }
+        let path = segment_paths(&dir).pop().unwrap();
+        {
+            let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+            // Garbage that looks like the start of another record.
+            file.write_all(&[0, 0, 0, 1, 0xde, 0xad, 0xbe, 0xef]).unwrap();
+        }
+
+        let store = FileLog::with_segment_entries(&dir, 10).unwrap();
+        assert_eq!(LogIndex(2), store.latest_log_index().unwrap());
+        assert_eq!((Term(1), &b"bad"[..]), store.entry(LogIndex(2)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Checks that an interrupted metadata update leaves the previous metadata in place.
+    #[test]
+    fn test_recover_interrupted_metadata_update() {
+        let dir = test_dir("recover_metadata");
+        {
+            let mut store = FileLog::open(&dir).unwrap();
+            store.set_current_term(Term(3)).unwrap();
+        }
+        fs::File::create(dir.join("metadata.tmp")).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+        let store = FileLog::open(&dir).unwrap();
+        assert_eq!(Term(3), store.current_term().unwrap());
+        assert!(!dir.join("metadata.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that compaction removes covered segments and that the snapshot and the remaining
+    /// entries survive reopening the log.
+    #[test]
+    fn test_recover_after_compaction() {
+        let dir = test_dir("recover_after_compaction");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term(1), &b"a"[..]), (Term(1), &b"b"[..]), (Term(1), &b"c"[..]),
+                                  (Term(2), &b"d"[..]), (Term(2), &b"e"[..])])
+                .unwrap();
+            store.compact(LogIndex(3), Term(1), b"snap").unwrap();
+            assert_eq!(LogIndex(4), store.first_log_index().unwrap());
+            assert_eq!(LogIndex(5), store.latest_log_index().unwrap());
+            assert!(store.entry(LogIndex(3)).is_err());
+            // The segment holding entries 3 and 4 is only partially covered.
+            assert_eq!(2, segment_paths(&dir).len());
+
+            // Stale snapshots are ignored.
+            store.compact(LogIndex(2), Term(1), b"old").unwrap();
+        }
+
+        let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(Some((LogIndex(3), Term(1), &b"snap"[..])), store.snapshot().unwrap());
+        assert_eq!(LogIndex(4), store.first_log_index().unwrap());
+        assert_eq!(LogIndex(5), store.latest_log_index().unwrap());
+        assert_eq!((Term(2), &b"d"[..]), store.entry(LogIndex(4)).unwrap());
+        assert_eq!((Term(2), &b"e"[..]), store.entry(LogIndex(5)).unwrap());
+
+        // Truncating into the partially covered segment must keep the covered records intact.
+        store.append_entries(LogIndex(5), &[(Term(3), &b"x"[..])]).unwrap();
+        let store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(LogIndex(5), store.latest_log_index().unwrap());
+        assert_eq!((Term(3), &b"x"[..]), store.entry(LogIndex(5)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Tests that a snapshot which does not match the log replaces the log entirely.
+    #[test]
+    fn test_compact_conflicting_snapshot() {
+        let dir = test_dir("compact_conflicting_snapshot");
+        {
+            let mut store = FileLog::with_segment_entries(&dir, 2).unwrap();
+            store.append_entries(LogIndex(1),
+                                &[(Term(1), &b"a"[..]), (Term(1), &b"b"[..]), (Term(1), &b"c"[..])])
+                .unwrap();
+            store.compact(LogIndex(5), Term(2), b"snap").unwrap();
+            assert!(segment_paths(&dir).is_empty());
+            assert_eq!(LogIndex(5), store.latest_log_index().unwrap());
+            assert_eq!(Term(2), store.latest_log_term().unwrap());
+            store.append_entries(LogIndex(6), &[(Term(2), &b"f"[..])]).unwrap();
+        }
+
+        let store = FileLog::with_segment_entries(&dir, 2).unwrap();
+        assert_eq!(LogIndex(6), store.first_log_index().unwrap());
+        assert_eq!(LogIndex(6), store.latest_log_index().unwrap());
+        assert_eq!((Term(2), &b"f"[..]), store.entry(LogIndex(6)).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -17,6 +17,7 @@ pub struct MemLog {
     current_term: Term,
     voted_for: Option<ServerId>,
     entries: Vec<(Term, Vec<u8>)>,
+    snapshot: Option<(LogIndex, Term, Vec<u8>)>,
 }
 
 /// Non-instantiable error type for MemLog
@@ -46,8 +47,15 @@ impl MemLog {
             current_term: Term(0),
             voted_for: None,
             entries: Vec::new(),
+            snapshot: None,
         }
     }
+
+    /// Returns the index covered by the latest snapshot, i.e. the number of entries discarded
+    /// from the front of `entries`.
+    fn offset(&self) -> u64 {
+        self.snapshot.as_ref().map_or(0, |&(index, _, _)| index.as_u64())
+    }
 }
 
 impl Log for MemLog {
@@ -80,20 +88,22 @@ impl Log for MemLog {
     }
 
     fn latest_log_index(&self) -> result::Result<LogIndex, Error> {
-        Ok(LogIndex(self.entries.len() as u64))
+        Ok(LogIndex(self.offset() + self.entries.len() as u64))
     }
 
     fn latest_log_term(&self) -> result::Result<Term, Error> {
-        let len = self.entries.len();
-        if len == 0 {
-            Ok(Term::from(0))
-        } else {
-            Ok(self.entries[len - 1].0)
+        match self.entries.last() {
+            Some(&(term, _)) => Ok(term),
+            None => Ok(self.snapshot.as_ref().map_or(Term::from(0), |&(_, term, _)| term)),
         }
     }
 
+    fn first_log_index(&self) -> result::Result<LogIndex, Error> {
+        Ok(LogIndex(self.offset() + 1))
+    }
+
     fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), Error> {
-        let (term, ref bytes) = self.entries[(index - 1).as_u64() as usize];
+        let (term, ref bytes) = self.entries[(index.as_u64() - self.offset() - 1) as usize];
         Ok((term, bytes))
     }
 
@@ -102,16 +112,42 @@ impl Log for MemLog {
                       entries: &[(Term, &[u8])])
                       -> result::Result<(), Error> {
         assert!(self.latest_log_index().unwrap() + 1 >= from);
-        self.entries.truncate((from - 1).as_u64() as usize);
+        assert!(from.as_u64() > self.offset());
+        let offset = self.offset();
+        self.entries.truncate((from.as_u64() - offset - 1) as usize);
         Ok(self.entries.extend(entries.iter().map(|&(term, command)| (term, command.to_vec()))))
     }
 
     fn truncate(&mut self, lo: LogIndex) -> result::Result<(), Error> {
-        Ok(self.entries.truncate(lo.as_u64() as usize))
+        let offset = self.offset();
+        Ok(self.entries.truncate((lo.as_u64() - offset) as usize))
     }
 
     fn rollback(&mut self, lo: LogIndex) -> result::Result<(Vec<(Term, Vec<u8>)>), Error> {
-        Ok(self.entries[(lo.as_u64() as usize)..].to_vec())
+        Ok(self.entries[((lo.as_u64() - self.offset()) as usize)..].to_vec())
+    }
+
+    fn compact(&mut self,
+               index: LogIndex,
+               term: Term,
+               snapshot: &[u8])
+               -> result::Result<(), Error> {
+        let offset = self.offset();
+        if index.as_u64() <= offset {
+            return Ok(());
+        }
+        let covered = (index.as_u64() - offset) as usize;
+        if covered <= self.entries.len() && self.entries[covered - 1].0 == term {
+            self.entries.drain(..covered);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = Some((index, term, snapshot.to_vec()));
+        Ok(())
+    }
+
+    fn snapshot(&self) -> result::Result<Option<(LogIndex, Term, &[u8])>, Error> {
+        Ok(self.snapshot.as_ref().map(|&(index, term, ref data)| (index, term, &data[..])))
     }
 }
 
@@ -193,4 +229,42 @@ mod test {
         assert_eq!((Term::from(3), &*vec![4u8]),
                    store.entry(LogIndex::from(4)).unwrap());
     }
+
+    #[test]
+    fn test_compact() {
+        let mut store = MemLog::new();
+        assert_eq!(None, store.snapshot().unwrap());
+        assert_eq!(LogIndex(1), store.first_log_index().unwrap());
+
+        // [0.1, 0.2, 1.3, 1.4]
+        store.append_entries(LogIndex(1),
+                            &[(Term(0), &[1]), (Term(0), &[2]), (Term(1), &[3]), (Term(1), &[4])])
+            .unwrap();
+
+        // snapshot(0.2) [1.3, 1.4]
+        store.compact(LogIndex(2), Term(0), b"snap").unwrap();
+        assert_eq!(Some((LogIndex(2), Term(0), &b"snap"[..])), store.snapshot().unwrap());
+        assert_eq!(LogIndex(3), store.first_log_index().unwrap());
+        assert_eq!(LogIndex(4), store.latest_log_index().unwrap());
+        assert_eq!((Term(1), &*vec![3u8]), store.entry(LogIndex(3)).unwrap());
+
+        // Stale snapshots are ignored.
+        store.compact(LogIndex(1), Term(0), b"old").unwrap();
+        assert_eq!(Some((LogIndex(2), Term(0), &b"snap"[..])), store.snapshot().unwrap());
+
+        // snapshot(0.2) [1.3, 2.4]
+        store.append_entries(LogIndex(4), &[(Term(2), &[4])]).unwrap();
+        assert_eq!(vec![(Term(2), vec![4u8])], store.rollback(LogIndex(3)).unwrap());
+
+        // A snapshot with a conflicting term discards the whole log.
+        // snapshot(3.5) []
+        store.compact(LogIndex(5), Term(3), b"new").unwrap();
+        assert_eq!(LogIndex(5), store.latest_log_index().unwrap());
+        assert_eq!(Term(3), store.latest_log_term().unwrap());
+        assert_eq!(LogIndex(6), store.first_log_index().unwrap());
+
+        // snapshot(3.5) [3.6]
+        store.append_entries(LogIndex(6), &[(Term(3), &[6])]).unwrap();
+        assert_eq!((Term(3), &*vec![6u8]), store.entry(LogIndex(6)).unwrap());
+    }
 }
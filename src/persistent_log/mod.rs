@@ -7,8 +7,10 @@
 //! for internal use by the library, we simply chose not to be opinionated about how data is stored.
 
 pub mod mem;
+pub mod file;
 
 pub use persistent_log::mem::{MemLog, Error};
+pub use persistent_log::file::FileLog;
 
 use std::error;
 use std::fmt::Debug;
@@ -38,13 +40,20 @@ pub trait Log: Clone + Debug + Send + 'static {
     /// Sets the candidate id voted for in the current term.
     fn set_voted_for(&mut self, server: Option<ServerId>) -> result::Result<(), Self::Error>;
 
-    /// Returns the index of the latest persisted log entry (0 if the log is empty).
+    /// Returns the index of the latest persisted log entry (0 if the log is empty). If every
+    /// entry has been discarded by `compact()`, this is the index covered by the snapshot.
     fn latest_log_index(&self) -> result::Result<LogIndex, Self::Error>;
 
-    /// Returns the term of the latest persisted log entry (0 if the log is empty).
+    /// Returns the term of the latest persisted log entry (0 if the log is empty). If every
+    /// entry has been discarded by `compact()`, this is the term covered by the snapshot.
     fn latest_log_term(&self) -> result::Result<Term, Self::Error>;
 
-    /// Returns the entry at the provided log index.
+    /// Returns the index of the first entry retained in the log (1 if the log has never been
+    /// compacted).
+    fn first_log_index(&self) -> result::Result<LogIndex, Self::Error>;
+
+    /// Returns the entry at the provided log index. The index must not be lower than
+    /// `first_log_index()`.
     fn entry(&self, index: LogIndex) -> result::Result<(Term, &[u8]), Self::Error>;
 
     /// Returns the given range of entries (excluding the right endpoint).
@@ -67,4 +76,20 @@ pub trait Log: Clone + Debug + Send + 'static {
 
     fn truncate(&mut self, lo: LogIndex) -> result::Result<(), Self::Error>;
     fn rollback(&mut self, lo: LogIndex) -> result::Result<(Vec<(Term, Vec<u8>)>), Self::Error>;
+
+    /// Stores a snapshot of the state machine covering the log up to and including `index`,
+    /// whose entry is of `term`, and discards the covered prefix of the log.
+    ///
+    /// If the log does not contain an entry at `index` with the given `term`, the remainder of
+    /// the log is discarded as well and the next entry will be appended at `index + 1`. Requests
+    /// for an `index` not newer than the latest snapshot are ignored.
+    fn compact(&mut self,
+               index: LogIndex,
+               term: Term,
+               snapshot: &[u8])
+               -> result::Result<(), Self::Error>;
+
+    /// Returns the index and term covered by the latest snapshot along with its data, or `None`
+    /// if the log has never been compacted.
+    fn snapshot(&self) -> result::Result<Option<(LogIndex, Term, &[u8])>, Self::Error>;
 }
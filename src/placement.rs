@@ -0,0 +1,101 @@
+//! Placement of the replicas of a log on the servers of the cluster.
+//!
+//! A log limited to a number of replicas (see `LogConfig::replicas`) is replicated on the servers
+//! ranked highest for it by rendezvous hashing. Every server scores each pair of log and server
+//! by hashing their IDs, so all servers derive the same replicas without coordinating. The
+//! replicas of many logs spread evenly over the servers, and a server joining or leaving the
+//! cluster only moves the replicas it gains or loses. The highest ranked replica is the preferred
+//! leader of the log, which spreads the leaders evenly as well.
+
+use LogId;
+use ServerId;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Returns the `replicas` servers among `servers` which replicate the log `lid`, preferred leader
+/// first. Returns every server if there are no more than `replicas`.
+pub fn place<I>(lid: LogId, servers: I, replicas: usize) -> Vec<ServerId>
+    where I: IntoIterator<Item = ServerId>
+{
+    let mut ranked: Vec<(u64, ServerId)> = servers.into_iter()
+        .map(|server| (score(lid, server), server))
+        .collect();
+    // Ties are broken by server ID, so that the order does not depend on the order of `servers`.
+    ranked.sort_by(|&(a, a_server), &(b, b_server)| {
+        (b, b_server.as_u64()).cmp(&(a, a_server.as_u64()))
+    });
+    ranked.truncate(replicas);
+    ranked.into_iter().map(|(_, server)| server).collect()
+}
+
+/// Scores the server for the log. The score has to be the same on every server, so it is
+/// computed with FNV-1a rather than the randomly keyed hasher of the standard library.
+fn score(lid: LogId, server: ServerId) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    let id = server.as_u64();
+    let server_bytes = (0..8).map(|i| (id >> (8 * i)) as u8);
+    for byte in lid.as_bytes().iter().cloned().chain(server_bytes) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    // FNV mixes the last bytes poorly; finish with the SplitMix64 finalizer.
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use uuid::Uuid;
+
+    use {LogId, ServerId};
+    use placement::place;
+
+    fn log(i: u64) -> LogId {
+        let mut bytes = [0; 16];
+        for (j, byte) in bytes.iter_mut().enumerate().take(8) {
+            *byte = (i >> (8 * j)) as u8;
+        }
+        LogId(Uuid::from_bytes(&bytes).unwrap())
+    }
+
+    /// Tests that the placement of a log does not depend on the order of the servers.
+    #[test]
+    fn test_place_deterministic() {
+        let servers: Vec<ServerId> = (0..5).map(ServerId).collect();
+        let mut reversed = servers.clone();
+        reversed.reverse();
+        for i in 0..100 {
+            let placed = place(log(i), servers.iter().cloned(), 3);
+            assert_eq!(3, placed.len());
+            assert!(placed == place(log(i), reversed.iter().cloned(), 3));
+        }
+        assert_eq!(5, place(log(0), servers.iter().cloned(), 7).len());
+    }
+
+    /// Tests that the replicas and the preferred leaders of many logs spread evenly over the
+    /// servers.
+    #[test]
+    fn test_place_spread() {
+        let servers: Vec<ServerId> = (0..10).map(ServerId).collect();
+        let mut replicas = HashMap::new();
+        let mut leaders = HashMap::new();
+        for i in 0..1000 {
+            let placed = place(log(i), servers.iter().cloned(), 3);
+            *leaders.entry(placed[0].as_u64()).or_insert(0) += 1;
+            for server in placed {
+                *replicas.entry(server.as_u64()).or_insert(0) += 1;
+            }
+        }
+        // Each server is expected to hold 300 replicas and to lead 100 logs.
+        for server in &servers {
+            let count = replicas[&server.as_u64()];
+            assert!(count > 200 && count < 400, "{} replicas on {}", count, server);
+            let count = leaders[&server.as_u64()];
+            assert!(count > 50 && count < 150, "{} leaders on {}", count, server);
+        }
+    }
+}
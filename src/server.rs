@@ -24,6 +24,7 @@ use ServerId;
 use LogId;
 use messages;
 use messages_capnp::connection_preamble;
+use config::Config;
 use consensus::{Actions, ConsensusTimeout};
 use state_machine::StateMachine;
 use persistent_log::Log;
@@ -31,7 +32,7 @@ use connection::{Connection, ConnectionKind};
 use std::io::Cursor;
 
 use auth::Auth;
-use log_manager::LogManager;
+use log_manager::{LogFactory, LogManager};
 
 const LISTENER: Token = Token(0);
 
@@ -39,6 +40,10 @@ const LISTENER: Token = Token(0);
 pub enum ServerTimeout {
     Consensus(LogId, ConsensusTimeout),
     Reconnect(Token),
+    /// A round of leader balancing, see `Config::balance_interval`.
+    Balance,
+    /// A round of coalesced heartbeats, see `Config::heartbeat_interval`.
+    Heartbeat,
 }
 
 /// The `Server` is responsible for receiving events from peer `Server` instance or clients,
@@ -88,8 +93,8 @@ pub struct Server<L, M, A>
     /// Instance of the authentification module
     auth: A,
 
-    /// Queue for message when a transaction is active
-    requests_in_queue: HashMap<LogId, Vec<(ClientId, Builder<HeapAllocator>)>>,
+    /// Tunable parameters of the server and its logs.
+    config: Config,
 }
 
 /// The implementation of the Server.
@@ -105,19 +110,30 @@ impl<L, M, A> Server<L, M, A>
                peers: &HashMap<ServerId, SocketAddr>,
                community_string: String,
                auth: A,
-               logs: Vec<(LogId, L, M)>)
+               logs: Vec<(LogId, L, M)>,
+               config: Config)
                -> Result<(Server<L, M, A>, EventLoop<Server<L, M, A>>)> {
         if peers.contains_key(&id) {
             return Err(Error::Raft(RaftError::InvalidPeerSet));
         }
-
-        let mut requests_in_queue = HashMap::new();
-
-        for &(lid, _, _) in &logs {
-            requests_in_queue.insert(lid, Vec::new());
+        try!(config.validate());
+        if let Some(metadata) = config.metadata_log {
+            if !logs.iter().any(|&(lid, _, _)| lid == metadata) {
+                let reason = format!("the metadata log {:?} is not among the logs", metadata);
+                return Err(Error::Raft(RaftError::InvalidConfig(reason)));
+            }
+        }
+        let placed = logs.iter().find(|&&(lid, _, _)| {
+            config.log_overrides.get(&lid).map_or(false, |log| log.replicas.is_some())
+        });
+        if let Some(&(lid, _, _)) = placed {
+            let reason = format!("the log {:?} is started on every server, and cannot be limited \
+                                  to a number of replicas",
+                                 lid);
+            return Err(Error::Raft(RaftError::InvalidConfig(reason)));
         }
 
-        let log_manager = LogManager::new(id, logs, peers.clone());
+        let log_manager = LogManager::new(id, logs, peers.clone(), &config);
 
         let mut event_loop = try!(EventLoop::<Server<L, M, A>>::new());
         let listener = try!(TcpListener::bind(&addr));
@@ -128,22 +144,29 @@ impl<L, M, A> Server<L, M, A>
             addr: addr,
             log_manager: log_manager,
             listener: listener,
-            connections: Slab::new_starting_at(Token(1), 129),
+            connections: Slab::new_starting_at(Token(1), config.max_connections),
             peer_tokens: HashMap::new(),
             client_tokens: HashMap::new(),
             reconnection_timeouts: HashMap::new(),
             community_string: community_string.clone(),
             auth: auth,
-            requests_in_queue: requests_in_queue,
+            config: config,
         };
 
         for (peer_id, peer_addr) in peers {
-            server.add_peer_static(&mut event_loop, *peer_id, *peer_addr).unwrap();
+            try!(server.add_peer_static(&mut event_loop, *peer_id, *peer_addr));
         }
 
         Ok((server, event_loop))
     }
 
+    /// Sets the factory building the logs created at runtime through the metadata log. Has to
+    /// be set before the server is initialized, so that the logs created before a restart are
+    /// built again.
+    pub fn set_log_factory(&mut self, factory: Box<LogFactory<L, M>>) {
+        self.log_manager.set_factory(factory);
+    }
+
     /// Adds new peer to `peers`
     pub fn add_peer_static(&mut self,
                            event_loop: &mut EventLoop<Server<L, M, A>>,
@@ -151,7 +174,7 @@ impl<L, M, A> Server<L, M, A>
                            peer_addr: SocketAddr)
                            -> Result<()> {
         let token: Token = try!(self.connections
-            .insert(try!(Connection::peer(peer_id, peer_addr)))
+            .insert(try!(Connection::peer(peer_id, peer_addr, &self.config)))
             .map_err(|_| Error::Raft(RaftError::ConnectionLimitReached)));
         scoped_assert!(self.peer_tokens.insert(peer_id, token).is_none());
 
@@ -182,19 +205,77 @@ impl<L, M, A> Server<L, M, A>
         scoped_debug!("Start peering with {:?}", peer_addr);
 
         let token: Token = try!(self.connections
-            .insert(try!(Connection::peer(peer_id, peer_addr)))
+            .insert(try!(Connection::peer(peer_id, peer_addr, &self.config)))
             .map_err(|_| Error::Raft(RaftError::ConnectionLimitReached)));
 
         scoped_assert!(self.peer_tokens.insert(peer_id, token).is_none());
 
-        self.log_manager.add_peer(peer_id, peer_addr);
+        let mut actions = Actions::new();
+        self.log_manager.add_peer(peer_id, peer_addr, &mut actions);
 
         try!(self.connections[token].register(event_loop, token));
 
         let message = messages::server_add(self.id, &self.community_string, &self.addr);
 
         self.send_message(event_loop, token, message);
+        self.execute_actions(event_loop, actions);
+
+        Ok(())
+    }
 
+    /// Starts a membership change removing `peer_id` from the log `lid`. This server must
+    /// currently be the leader of the log.
+    pub fn remove_peer(&mut self,
+                       event_loop: &mut EventLoop<Server<L, M, A>>,
+                       lid: LogId,
+                       peer_id: ServerId)
+                       -> Result<()> {
+        let mut actions = Actions::new();
+        {
+            let consensus = try!(self.log_manager.get_mut(lid).ok_or_else(|| {
+                RaftError::MembershipChangeFailed(format!("unknown log {:?}", lid))
+            }));
+            try!(consensus.remove_peer(peer_id, &mut actions));
+        }
+        self.execute_actions(event_loop, actions);
+        Ok(())
+    }
+
+    /// Transfers leadership of the log `lid` to the peer `target`. This server must currently be
+    /// the leader of the log. Proposals are rejected until the target has been elected, or the
+    /// transfer is abandoned after an election timeout.
+    pub fn transfer_leadership(&mut self,
+                               event_loop: &mut EventLoop<Server<L, M, A>>,
+                               lid: LogId,
+                               target: ServerId)
+                               -> Result<()> {
+        let mut actions = Actions::new();
+        {
+            let consensus = try!(self.log_manager.get_mut(lid).ok_or_else(|| {
+                RaftError::LeaderTransferFailed(format!("unknown log {:?}", lid))
+            }));
+            try!(consensus.transfer_leadership(target, None, &mut actions));
+        }
+        self.execute_actions(event_loop, actions);
+        Ok(())
+    }
+
+    /// Promotes the learner `peer_id` of the log `lid` to a voting member. Peers joining through
+    /// `peering_request` start out as learners. This server must currently be the leader of the
+    /// log, and the learner must have caught up to within the configured lag.
+    pub fn promote_learner(&mut self,
+                           event_loop: &mut EventLoop<Server<L, M, A>>,
+                           lid: LogId,
+                           peer_id: ServerId)
+                           -> Result<()> {
+        let mut actions = Actions::new();
+        {
+            let consensus = try!(self.log_manager.get_mut(lid).ok_or_else(|| {
+                RaftError::MembershipChangeFailed(format!("unknown log {:?}", lid))
+            }));
+            try!(consensus.promote_learner(peer_id, &mut actions));
+        }
+        self.execute_actions(event_loop, actions);
         Ok(())
     }
 
@@ -207,15 +288,17 @@ impl<L, M, A> Server<L, M, A>
     /// * `peers` - The ID and address of all peers in the Raft cluster.
     /// * `store` - The persistent log store.
     /// * `state_machine` - The client state machine to which client commands will be applied.
+    /// * `config` - The timing and resource limits of the server and its logs.
     pub fn run(id: ServerId,
                addr: SocketAddr,
                peers: &HashMap<ServerId, SocketAddr>,
                community_string: String,
                auth: A,
-               logs: Vec<(LogId, L, M)>)
+               logs: Vec<(LogId, L, M)>,
+               config: Config)
                -> Self {
         let (mut server, mut event_loop) =
-            Server::new(id, addr, peers, community_string, auth, logs).unwrap();
+            Server::new(id, addr, peers, community_string, auth, logs, config).unwrap();
 
         server.init(&mut event_loop);
 
@@ -245,14 +328,23 @@ impl<L, M, A> Server<L, M, A>
     }
 
     fn execute_actions(&mut self, event_loop: &mut EventLoop<Server<L, M, A>>, actions: Actions) {
+        // Build and drop the logs created and dropped since. The timeouts of a dropped log are
+        // cleared, and timeouts set for it meanwhile are skipped below.
+        let mut actions = actions;
+        for consensus in self.log_manager.sync_logs(&mut actions) {
+            for (timeout, &handle) in &consensus.consensus_timeouts {
+                scoped_assert!(event_loop.clear_timeout(handle),
+                               "unable to clear timeout; {:?}",
+                               timeout);
+            }
+        }
+
         scoped_trace!("executing actions: {:?}", actions);
         let Actions { peer_messages,
                       client_messages,
                       timeouts,
                       clear_timeouts,
-                      clear_peer_messages,
-                      peer_messages_broadcast,
-                      transaction_queue } = actions;
+                      clear_peer_messages } = actions;
 
         if clear_peer_messages {
             for &token in self.peer_tokens.values() {
@@ -260,8 +352,11 @@ impl<L, M, A> Server<L, M, A>
             }
         }
         for (peer, message) in peer_messages {
-            let token = self.peer_tokens[&peer];
-            self.send_message(event_loop, token, message);
+            // Configuration entries may name members this server has not connected to yet.
+            match self.peer_tokens.get(&peer) {
+                Some(&token) => self.send_message(event_loop, token, message),
+                None => scoped_warn!("{:?}: no connection to peer {}", self, peer),
+            }
         }
         for (client, message) in client_messages {
             if let Some(&token) = self.client_tokens.get(&client) {
@@ -269,54 +364,56 @@ impl<L, M, A> Server<L, M, A>
             }
         }
 
-        if !transaction_queue.is_empty() {
-            scoped_debug!("Messages appended to queue {}", transaction_queue.len());
-            for (lid, cid, message) in transaction_queue {
-                let mut messages = self.requests_in_queue.get_mut(&lid).unwrap();
-                messages.push((cid, message));
-            }
-        }
-
-        for message in peer_messages_broadcast {
-            let tokens = self.peer_tokens.clone().into_iter().collect::<Vec<_>>();
-
-            for (_, t) in tokens {
-                self.send_message(event_loop, t, message.clone());
-            }
-        }
-
         for lid in clear_timeouts {
-            let mut consensus = self.log_manager
-                .get_mut(lid)
-                .expect(&format!("Log {:?} is not registered in the log_manager", lid));
+            let mut consensus = match self.log_manager.get_mut(lid) {
+                Some(consensus) => consensus,
+                None => continue,
+            };
 
-            for (timeout, &handle) in &consensus.consensus_timeouts {
+            // A forwarded read fails if the leader does not answer it, even while the leader's
+            // other messages keep resetting the timeouts.
+            let cleared: Vec<ConsensusTimeout> = consensus.consensus_timeouts
+                .keys()
+                .filter(|timeout| match **timeout {
+                    ConsensusTimeout::Read(..) => false,
+                    _ => true,
+                })
+                .cloned()
+                .collect();
+            for timeout in cleared {
+                let handle = consensus.consensus_timeouts.remove(&timeout).unwrap();
                 scoped_assert!(event_loop.clear_timeout(handle),
                                "unable to clear timeout; {:?}",
                                timeout);
             }
-
-            consensus.consensus_timeouts.clear();
         }
 
         for timeout in timeouts {
-            let duration = timeout.duration_ms();
-
             let lid = match timeout {
                 ConsensusTimeout::Election(lid) => lid,
                 ConsensusTimeout::Heartbeat(_, lid) => lid,
+                ConsensusTimeout::Transfer(lid) => lid,
+                ConsensusTimeout::CheckQuorum(lid) => lid,
+                ConsensusTimeout::Transaction(_, lid) => lid,
+                ConsensusTimeout::Read(_, lid) => lid,
             };
 
+            let mut consensus = match self.log_manager.get_mut(lid) {
+                Some(consensus) => consensus,
+                None => {
+                    scoped_debug!("skipping timeout {:?} of dropped log {:?}", timeout, lid);
+                    continue;
+                }
+            };
+
+            let duration = consensus.timeout_duration_ms(&timeout);
+
             // Registering a timeout may only fail if the maximum number of timeouts
             // is already registered, which is by default 65,536. We use a
             // maximum of one timeout per peer, so this unwrap should be safe.
             let handle = event_loop.timeout_ms(ServerTimeout::Consensus(lid, timeout), duration)
                 .unwrap();
 
-            let mut consensus = self.log_manager
-                .get_mut(lid)
-                .expect(&format!("Log {:?} is not registered in the log_manager", lid));
-
             consensus.consensus_timeouts
                 .insert(timeout, handle)
                 .map(|handle| {
@@ -346,11 +443,14 @@ impl<L, M, A> Server<L, M, A>
                                "timeout already registered: {:?}",
                                timeout);
             }
-            ConnectionKind::Client(ref id) => {
+            ConnectionKind::Client(id) => {
                 self.connections.remove(token).expect("unable to find client connection");
-                scoped_assert!(self.client_tokens.remove(id).is_some(),
+                scoped_assert!(self.client_tokens.remove(&id).is_some(),
                                "client {:?} not connected",
                                id);
+                let mut actions = Actions::new();
+                self.log_manager.client_connection_reset(id, &mut actions);
+                self.execute_actions(event_loop, actions);
             }
             ConnectionKind::Unknown => {
                 self.connections.remove(token).expect("unable to find unknown connection");
@@ -358,10 +458,6 @@ impl<L, M, A> Server<L, M, A>
         }
     }
 
-    fn handle_queue(&mut self, actions: &mut Actions) {
-        self.log_manager.handle_queue(&mut self.requests_in_queue, actions);
-    }
-
     /// Reads messages from the connection until no more are available.
     ///
     /// If the connection returns an error on any operation, or any message fails to be
@@ -371,22 +467,18 @@ impl<L, M, A> Server<L, M, A>
                 token: Token)
                 -> Result<()> {
 
-        let mut actions = Actions::new();
-        self.handle_queue(&mut actions);
-        self.execute_actions(event_loop, actions);
-
         scoped_trace!("{:?}: readable event", self.connections[token]);
         // Read messages from the connection until there are no more.
         while let Some(message) = try!(self.connections[token].readable()) {
             match *self.connections[token].kind() {
                 ConnectionKind::Peer(id) => {
                     let mut actions = Actions::new();
-                    self.log_manager.apply_peer_message(id, &message, &mut actions);
+                    try!(self.log_manager.apply_peer_message(id, &message, &mut actions));
                     self.execute_actions(event_loop, actions);
                 }
                 ConnectionKind::Client(id) => {
                     let mut actions = Actions::new();
-                    self.log_manager.apply_client_message(id, &message, &mut actions);
+                    try!(self.log_manager.apply_client_message(id, &message, &mut actions));
                     self.execute_actions(event_loop, actions);
                 }
                 ConnectionKind::Unknown => {
@@ -407,8 +499,10 @@ impl<L, M, A> Server<L, M, A>
                             if self.community_string == community_string {
 
                                 if !self.log_manager.check_peer_exists(peer_id) {
-                                    self.log_manager.add_peer(peer_id, peer_addr);
+                                    let mut actions = Actions::new();
+                                    self.log_manager.add_peer(peer_id, peer_addr, &mut actions);
                                     self.add_peer_static(event_loop, peer_id, peer_addr).unwrap();
+                                    self.execute_actions(event_loop, actions);
                                 } else {
                                     // Was already connected
                                     scoped_debug!("Dynamic peer wants to reconnect {:?}",
@@ -464,11 +558,13 @@ impl<L, M, A> Server<L, M, A>
                                     }
                                 }
 
+                                let mut actions = Actions::new();
                                 let prev_token = Some(match self.peer_tokens
                                     .insert(peer_id, token) {
                                     Some(x) => x,
                                     None => {
-                                        self.log_manager.add_peer(peer_id, peer_addr);
+                                        self.log_manager
+                                            .add_peer(peer_id, peer_addr, &mut actions);
                                         try!(self.connections[token].register(event_loop, token));
 
                                         token
@@ -494,7 +590,6 @@ impl<L, M, A> Server<L, M, A>
                                     _ => unreachable!(),
                                 }
                                 // Notify consensus that the connection reset.
-                                let mut actions = Actions::new();
                                 self.log_manager
                                     .peer_connection_reset(peer_id, peer_addr, &mut actions);
                                 self.execute_actions(event_loop, actions);
@@ -545,7 +640,7 @@ impl<L, M, A> Server<L, M, A>
                                              "listener.accept() returned None"))
                 })
             })
-            .and_then(|(stream, _)| Connection::unknown(stream))
+            .and_then(|(stream, _)| Connection::unknown(stream, &self.config))
             .and_then(|conn| {
                 self.connections
                     .insert(conn)
@@ -576,6 +671,22 @@ impl<L, M, A> Server<L, M, A>
         let action = self.log_manager.init();
 
         self.execute_actions(event_loop, action);
+        self.schedule_balance(event_loop);
+        self.schedule_heartbeat(event_loop);
+    }
+
+    /// Schedules the next round of coalesced heartbeats, if enabled.
+    fn schedule_heartbeat(&self, event_loop: &mut EventLoop<Server<L, M, A>>) {
+        if let Some(interval) = self.config.heartbeat_interval {
+            event_loop.timeout_ms(ServerTimeout::Heartbeat, interval).unwrap();
+        }
+    }
+
+    /// Schedules the next round of leader balancing, if enabled.
+    fn schedule_balance(&self, event_loop: &mut EventLoop<Server<L, M, A>>) {
+        if let Some(interval) = self.config.balance_interval {
+            event_loop.timeout_ms(ServerTimeout::Balance, interval).unwrap();
+        }
     }
 
     /// Converts a builder message to a reader message
@@ -672,6 +783,20 @@ impl<L, M, A> Handler for Server<L, M, A>
                 self.execute_actions(event_loop, actions);
             }
 
+            ServerTimeout::Balance => {
+                let mut actions = Actions::new();
+                self.log_manager.balance_leaders(&mut actions);
+                self.execute_actions(event_loop, actions);
+                self.schedule_balance(event_loop);
+            }
+
+            ServerTimeout::Heartbeat => {
+                let mut actions = Actions::new();
+                self.log_manager.heartbeats(&mut actions);
+                self.execute_actions(event_loop, actions);
+                self.schedule_heartbeat(event_loop);
+            }
+
             ServerTimeout::Reconnect(token) => {
                 scoped_assert!(self.reconnection_timeouts.remove(&token).is_some(),
                                "{:?} missing timeout: {:?}",
@@ -705,6 +830,13 @@ impl<L, M, A> Handler for Server<L, M, A>
             }
         }
     }
+
+    fn tick(&mut self, event_loop: &mut EventLoop<Server<L, M, A>>) {
+        // Send the entries appended during this turn of the event loop.
+        let mut actions = Actions::new();
+        self.log_manager.flush(&mut actions);
+        self.execute_actions(event_loop, actions);
+    }
 }
 
 impl<L, M, A> fmt::Debug for Server<L, M, A>
@@ -727,7 +859,7 @@ mod tests {
     use std::net::{SocketAddr, TcpListener, TcpStream};
     use std::str::FromStr;
 
-    use capnp::message::ReaderOptions;
+    use capnp::message::{Builder, ReaderOptions};
     use capnp::serialize;
     use mio::EventLoop;
 
@@ -735,9 +867,12 @@ mod tests {
     use Result;
     use ServerId;
     use LogId;
+    use Term;
+    use TransactionId;
     use messages;
-    use messages_capnp::connection_preamble;
-    use consensus::Actions;
+    use messages_capnp::{client_request, client_response, command_response, connection_preamble,
+                         message};
+    use consensus::{Actions, ConsensusTimeout};
     use state_machine::NullStateMachine;
     use persistent_log::MemLog;
     use super::*;
@@ -760,7 +895,8 @@ mod tests {
                     &peers,
                     "test".to_string(),
                     NullAuth::new(SingleCredentials::new("test".to_string(), "test".to_string())),
-                    logs)
+                    logs,
+                    Config::default())
     }
 
     /// Attempts to grab a local, unbound socket address for testing.
@@ -1031,6 +1167,174 @@ mod tests {
         assert!(!client_connected(&server, client_id));
     }
 
+    /// Tests that the server rejects a peer message for a log it does not host, but keeps the
+    /// connection to the peer.
+    #[test]
+    fn test_unknown_log_peer_message() {
+        setup_test!("test_unknown_log_peer_message");
+
+        let peer_id = ServerId::from(1);
+        let peer_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peers = HashMap::new();
+        peers.insert(peer_id, peer_listener.local_addr().unwrap());
+        let (mut server, mut event_loop) = new_test_server(peers).unwrap();
+
+        let (mut stream, _) = peer_listener.accept().unwrap();
+        assert_eq!(ServerId::from(0), read_server_preamble(&mut stream));
+
+        // Send a message for an unknown log.
+        let unknown = LogId(Uuid::new_v4());
+        serialize::write_message(&mut stream, &*messages::timeout_now(Term::from(1), &unknown))
+            .unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+
+        assert!(peer_connected(&server, peer_id));
+        assert_eq!(1, server.log_manager.unknown_log_messages());
+    }
+
+    /// Tests that the server answers a client request for a log it does not host, and keeps
+    /// the connection to the client.
+    #[test]
+    fn test_unknown_log_client_message() {
+        setup_test!("test_unknown_log_client_message");
+
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+        let mut stream = TcpStream::connect(server_addr).unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+
+        let client_id = ClientId::new();
+        serialize::write_message(&mut stream,
+                                 &*messages::client_connection_preamble(client_id,
+                                                                        "username",
+                                                                        "password"))
+            .unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+
+        // Send a proposal for an unknown log.
+        let unknown = LogId(Uuid::new_v4());
+        let proposal = messages::proposal_request(TransactionId::new(), b"foo", None, unknown);
+        serialize::write_message(&mut stream, &proposal).unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+        // Write the response.
+        event_loop.run_once(&mut server, Some(100)).unwrap();
+
+        let response = serialize::read_message(&mut stream, ReaderOptions::new()).unwrap();
+        let response = response.get_root::<client_response::Reader>().unwrap();
+        match response.which().unwrap() {
+            client_response::Which::Proposal(Ok(response)) => {
+                match response.which().unwrap() {
+                    command_response::Which::UnknownLog(()) => (),
+                    _ => panic!("unexpected command response"),
+                }
+            }
+            _ => panic!("unexpected response"),
+        }
+        assert!(client_connected(&server, client_id));
+    }
+
+    /// Tests that the server resets a client connection which sends a message naming a malformed
+    /// log ID.
+    #[test]
+    fn test_malformed_client_message() {
+        setup_test!("test_malformed_client_message");
+
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+        let mut stream = TcpStream::connect(server_addr).unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+
+        let client_id = ClientId::new();
+        serialize::write_message(&mut stream,
+                                 &*messages::client_connection_preamble(client_id,
+                                                                        "username",
+                                                                        "password"))
+            .unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+        assert!(client_connected(&server, client_id));
+
+        let mut message = Builder::new_default();
+        {
+            let mut request = message.init_root::<client_request::Builder>();
+            request.set_log_id(b"foo");
+            request.init_ping().set_session(&TransactionId::new().as_bytes());
+        }
+        serialize::write_message(&mut stream, &message).unwrap();
+        stream.flush().unwrap();
+        event_loop.run_once(&mut server, None).unwrap();
+
+        assert!(!client_connected(&server, client_id));
+    }
+
+    /// Tests that the server resets a client connection which sends a malformed or unsupported
+    /// request for a log the server hosts, and keeps running.
+    #[test]
+    fn test_malformed_request_hosted_log() {
+        setup_test!("test_malformed_request_hosted_log");
+
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+
+        // A peer message lays out its log ID like a client request, but its union variant is
+        // unknown to client requests.
+        let mut malformed = Builder::new_default();
+        {
+            let mut request = malformed.init_root::<message::Builder>();
+            request.set_log_id(&lid.as_bytes());
+            request.init_multi_heartbeat();
+        }
+        let ping = messages::ping_request(TransactionId::new(), &*lid);
+        for message in &[malformed, ping] {
+            let mut stream = TcpStream::connect(server_addr).unwrap();
+            event_loop.run_once(&mut server, None).unwrap();
+
+            let client_id = ClientId::new();
+            serialize::write_message(&mut stream,
+                                     &*messages::client_connection_preamble(client_id,
+                                                                            "username",
+                                                                            "password"))
+                .unwrap();
+            stream.flush().unwrap();
+            event_loop.run_once(&mut server, None).unwrap();
+            assert!(client_connected(&server, client_id));
+
+            serialize::write_message(&mut stream, message).unwrap();
+            stream.flush().unwrap();
+            event_loop.run_once(&mut server, None).unwrap();
+
+            assert!(!client_connected(&server, client_id));
+        }
+        assert!(server.log_manager.get(*lid).is_some());
+    }
+
+    /// Tests that the timeout of a forwarded read survives the leader's messages, which clear the
+    /// other timeouts of the log.
+    #[test]
+    fn test_read_timeout_survives_clear() {
+        setup_test!("test_read_timeout_survives_clear");
+        let (mut server, mut event_loop) = new_test_server(HashMap::new()).unwrap();
+
+        let read = ConsensusTimeout::Read(1, *lid);
+        let mut actions = Actions::new();
+        actions.timeouts.push(read);
+        actions.timeouts.push(ConsensusTimeout::Election(*lid));
+        server.execute_actions(&mut event_loop, actions);
+
+        // A heartbeat from the leader arrives before the read is answered.
+        let mut actions = Actions::new();
+        actions.clear_timeouts.push(*lid);
+        actions.timeouts.push(ConsensusTimeout::Election(*lid));
+        server.execute_actions(&mut event_loop, actions);
+
+        let timeouts = &server.log_manager.get_mut(*lid).unwrap().consensus_timeouts;
+        assert_eq!(2, timeouts.len());
+        assert!(timeouts.contains_key(&read));
+    }
+
     /// Tests that a Server will attempt to connect to peers on startup, and
     /// immediately reset the connection if unreachable.
     #[test]
@@ -0,0 +1,130 @@
+//! Client sessions, which make proposals idempotent.
+//!
+//! A `Client` numbers its proposals and resends a proposal under the same number when the
+//! response is lost. The `Consensus` records the latest applied number of every client along with
+//! the result, and answers a proposal it has already applied with that result instead of applying
+//! the command again. The table is updated as entries are applied, so that every replica holds
+//! the same table, and it is carried in every snapshot. The table holds a bounded number of
+//! sessions: the session of the client which proposed least recently expires first.
+
+use std::collections::HashMap;
+
+use ClientId;
+
+/// The number of clients whose latest proposal a log remembers. Once a session has expired, a
+/// resent proposal of the client is applied again.
+const MAX_SESSIONS: usize = 4096;
+
+/// Identifies a proposal within its client's session.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProposalId {
+    /// The proposing client.
+    pub client: ClientId,
+    /// The number of the proposal among the client's proposals. Starts at 1, and increases with
+    /// every new proposal.
+    pub sequence: u64,
+}
+
+/// The latest applied proposal of every client.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sessions {
+    /// The sequence number and the result of the latest applied proposal of every client, along
+    /// with the number of proposals recorded before it.
+    latest: HashMap<ClientId, (u64, Vec<u8>, u64)>,
+    /// The number of proposals recorded.
+    recorded: u64,
+}
+
+impl Sessions {
+    /// Creates a table without any session.
+    pub fn new() -> Sessions {
+        Sessions::default()
+    }
+
+    /// Returns whether the proposal, or a later proposal of the same client, has been applied.
+    pub fn is_applied(&self, proposal: &ProposalId) -> bool {
+        self.latest
+            .get(&proposal.client)
+            .map_or(false, |&(sequence, _, _)| proposal.sequence <= sequence)
+    }
+
+    /// Returns the result of the proposal if it is the latest applied proposal of its client.
+    /// The results of earlier proposals are not kept, since the client has moved on.
+    pub fn result(&self, proposal: &ProposalId) -> Option<&[u8]> {
+        match self.latest.get(&proposal.client) {
+            Some(&(sequence, ref result, _)) if sequence == proposal.sequence => Some(&result[..]),
+            _ => None,
+        }
+    }
+
+    /// Records the result of applying the proposal. Expires the session of the client which
+    /// proposed least recently if the table is full.
+    pub fn record(&mut self, proposal: ProposalId, result: Vec<u8>) {
+        self.latest.insert(proposal.client, (proposal.sequence, result, self.recorded));
+        self.recorded += 1;
+        if self.latest.len() > MAX_SESSIONS {
+            let expired = self.latest
+                .iter()
+                .min_by_key(|&(_, &(_, _, recorded))| recorded)
+                .map(|(&client, _)| client)
+                .unwrap();
+            self.latest.remove(&expired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ClientId;
+    use session::{MAX_SESSIONS, ProposalId, Sessions};
+
+    #[test]
+    fn test_sessions() {
+        let mut sessions = Sessions::new();
+        let client = ClientId::new();
+        let first = ProposalId {
+            client: client,
+            sequence: 1,
+        };
+        let second = ProposalId { sequence: 2, ..first };
+        assert!(!sessions.is_applied(&first));
+
+        sessions.record(first, b"foo".to_vec());
+        assert!(sessions.is_applied(&first));
+        assert_eq!(Some(&b"foo"[..]), sessions.result(&first));
+        assert!(!sessions.is_applied(&second));
+
+        // Only the result of the latest proposal is kept.
+        sessions.record(second, b"bar".to_vec());
+        assert!(sessions.is_applied(&first));
+        assert_eq!(None, sessions.result(&first));
+        assert_eq!(Some(&b"bar"[..]), sessions.result(&second));
+
+        // Other clients have sessions of their own.
+        assert!(!sessions.is_applied(&ProposalId { client: ClientId::new(), ..first }));
+    }
+
+    /// Tests that the session of the client which proposed least recently expires once the
+    /// table is full.
+    #[test]
+    fn test_session_expiry() {
+        let mut sessions = Sessions::new();
+        let proposals: Vec<ProposalId> = (0..MAX_SESSIONS + 1)
+            .map(|_| {
+                ProposalId {
+                    client: ClientId::new(),
+                    sequence: 1,
+                }
+            })
+            .collect();
+        for proposal in &proposals[..MAX_SESSIONS] {
+            sessions.record(*proposal, Vec::new());
+        }
+        // The first client proposes again, so the second client proposed least recently.
+        sessions.record(ProposalId { sequence: 2, ..proposals[0] }, Vec::new());
+        sessions.record(proposals[MAX_SESSIONS], Vec::new());
+        assert!(sessions.is_applied(&proposals[0]));
+        assert!(!sessions.is_applied(&proposals[1]));
+        assert!(proposals[2..].iter().all(|proposal| sessions.is_applied(proposal)));
+    }
+}
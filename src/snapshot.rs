@@ -0,0 +1,44 @@
+//! Snapshots taken by a `Consensus` when compacting its log.
+//!
+//! The `Log` stores a snapshot as an opaque blob, which is also what the leader ships to
+//! followers that have fallen behind the first retained log entry. The blob is the bincode
+//! encoding of a `Snapshot`.
+
+use std::collections::HashMap;
+
+use bincode::SizeLimit;
+use bincode::serde::{self, DeserializeResult};
+
+use LogId;
+use ServerId;
+use membership::Configuration;
+use session::Sessions;
+use transaction::TransactionManager;
+
+/// The replicated state covered by a snapshot.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The state machine snapshot, as returned by `StateMachine::snapshot()`.
+    pub state_machine: (Vec<u8>, Vec<u8>),
+    /// The cluster configuration in effect at the last entry covered by the snapshot.
+    pub configuration: Configuration,
+    /// The client sessions as of the last entry covered by the snapshot.
+    pub sessions: Sessions,
+    /// The transactions running as of the last entry covered by the snapshot.
+    pub transactions: TransactionManager,
+    /// The logs created at runtime and their replicas, as of the last entry covered by the
+    /// snapshot. Only the metadata log creates logs.
+    pub logs: HashMap<LogId, Option<Vec<ServerId>>>,
+}
+
+impl Snapshot {
+    /// Encodes the snapshot for storage in the `Log`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde::serialize(self, SizeLimit::Infinite).expect("unable to encode snapshot")
+    }
+
+    /// Decodes a snapshot previously encoded with `to_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> DeserializeResult<Snapshot> {
+        serde::deserialize(bytes)
+    }
+}
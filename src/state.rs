@@ -1,31 +1,118 @@
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Instant;
 
 use ClientId;
+use LogId;
 use LogIndex;
 use ServerId;
+use TransactionId;
 
-/// Consensus modules can be in one of three state:
+/// Consensus modules can be in one of four state:
 ///
 /// * `Follower` - which replicates AppendEntries requests and votes for it's leader.
 /// * `Leader` - which leads the cluster by serving incoming requests, ensuring
 ///              data is replicated, and issuing heartbeats.
+/// * `PreCandidate` - which asks its peers whether they would vote for it, without
+///                    incrementing its term. It becomes a `Candidate` if a majority
+///                    agrees, or a `Follower` if it hears from a `Leader`.
 /// * `Candidate` -  which campaigns in an election and may become a `Leader`
 ///                  (if it gets enough votes) or a `Follower`, if it hears from
 ///                  a `Leader`.
 #[derive(Copy,Clone, Debug, PartialEq, Eq)]
 pub enum ConsensusState {
     Follower,
+    PreCandidate,
     Candidate,
     Leader,
 }
 
+/// The origin of a read served by the leader.
+#[derive(Clone, Debug, Serialize)]
+pub enum ReadSource {
+    /// A query from a client of the leader.
+    Client(ClientId, Vec<u8>),
+    /// A `ReadIndexRequest` from a follower, with the ID the follower assigned to it.
+    Peer(ServerId, u64),
+    /// A request from a client of the leader for the status of a transaction.
+    TransactionStatus(ClientId, TransactionId),
+}
+
+/// A read waiting for the leader to confirm its leadership.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReadRequest {
+    pub source: ReadSource,
+    /// The commit index when the read was received.
+    pub index: LogIndex,
+    /// The heartbeat round which confirms the read.
+    pub round: u64,
+}
+
+/// How the leader replicates its log to a follower.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum ProgressMode {
+    /// The leader is looking for the last entry its log shares with the follower's, and sends
+    /// one AppendEntries request at a time.
+    Probe,
+    /// The follower's log matches the leader's up to the match index. New entries are streamed
+    /// with several requests in flight.
+    Replicate,
+    /// The follower is being sent a snapshot. Entries are sent once it has been installed.
+    Snapshot,
+}
+
+/// The leader's view of the replication to a follower.
+#[derive(Clone, Debug, Serialize)]
+struct Progress {
+    mode: ProgressMode,
+    /// The index of the last entry of each unacknowledged AppendEntries request, oldest first.
+    inflight: VecDeque<LogIndex>,
+}
+
+impl Progress {
+    fn new() -> Progress {
+        Progress {
+            mode: ProgressMode::Probe,
+            inflight: VecDeque::new(),
+        }
+    }
+}
+
 /// The state associated with a Raft consensus module in the `Leader` state.
 #[derive(Clone, Debug, Serialize)]
 pub struct LeaderState {
     next_index: HashMap<ServerId, LogIndex>,
     match_index: HashMap<ServerId, LogIndex>,
+    progress: HashMap<ServerId, Progress>,
+    /// The latest heartbeat round answered by each follower.
+    acked_round: HashMap<ServerId, u64>,
+    /// The latest heartbeat round.
+    round: u64,
+    /// When the rounds which have not been superseded by a round acknowledged by a majority were
+    /// started.
+    #[serde(skip_serializing)]
+    round_starts: BTreeMap<u64, Instant>,
+    /// When the latest round acknowledged by a majority was started.
+    #[serde(skip_serializing)]
+    quorum_contact: Option<Instant>,
     /// Stores in-flight client proposals.
     pub proposals: VecDeque<(ClientId, LogIndex)>,
+    /// Stores the reads waiting for confirmation, in the order of their rounds.
+    pub reads: VecDeque<ReadRequest>,
+    /// The peer leadership is being transferred to, and the client which requested the transfer
+    /// (if any). Proposals are rejected while a transfer is in progress.
+    pub transfer: Option<(ServerId, Option<ClientId>)>,
+    /// The transactions begun by entries in the log and not ended by later entries, along with
+    /// the client which began them through this leader, if known. Proposals within one of them
+    /// are appended as transaction commands.
+    pub transactions: HashMap<TransactionId, Option<ClientId>>,
+    /// The transactions rolled back because they timed out, whose clients have not been told
+    /// yet, along with the clients which began them through this leader. A transaction is
+    /// dropped once its client learns about the timeout or disconnects.
+    pub timed_out: HashMap<TransactionId, Option<ClientId>>,
+    /// The prepared transactions among `transactions`, along with their coordinator logs. They
+    /// only end as decided in the coordinator log.
+    pub prepared: HashMap<TransactionId, LogId>,
 }
 
 impl LeaderState {
@@ -39,11 +126,25 @@ impl LeaderState {
     pub fn new(latest_log_index: LogIndex, peers: &HashSet<ServerId>) -> LeaderState {
         let next_index = peers.iter().cloned().map(|peer| (peer, latest_log_index + 1)).collect();
         let match_index = peers.iter().cloned().map(|peer| (peer, LogIndex::from(0))).collect();
+        let progress = peers.iter().cloned().map(|peer| (peer, Progress::new())).collect();
+        let acked_round = peers.iter().cloned().map(|peer| (peer, 0)).collect();
+        let mut round_starts = BTreeMap::new();
+        round_starts.insert(0, Instant::now());
 
         LeaderState {
             next_index: next_index,
             match_index: match_index,
+            progress: progress,
+            acked_round: acked_round,
+            round: 0,
+            round_starts: round_starts,
+            quorum_contact: None,
             proposals: VecDeque::new(),
+            reads: VecDeque::new(),
+            transfer: None,
+            transactions: HashMap::new(),
+            timed_out: HashMap::new(),
+            prepared: HashMap::new(),
         }
     }
 
@@ -63,10 +164,110 @@ impl LeaderState {
         self.match_index.insert(follower, index);
     }
 
-    /// Counts the number of followers containing the given log index.
-    pub fn count_match_indexes(&self, index: LogIndex) -> usize {
-        // +1 for self.
-        self.match_index.values().filter(|&&i| i >= index).count() + 1
+    /// Returns the index of the highest log entry known to be replicated on the follower.
+    pub fn match_index(&self, follower: &ServerId) -> LogIndex {
+        self.match_index[follower]
+    }
+
+    /// Returns how the log is replicated to the follower.
+    pub fn mode(&self, follower: &ServerId) -> ProgressMode {
+        self.progress[follower].mode
+    }
+
+    /// Switches the follower to `mode`. The requests in flight are forgotten; their answers no
+    /// longer hold up further requests.
+    pub fn set_mode(&mut self, follower: ServerId, mode: ProgressMode) {
+        let progress = self.progress.get_mut(&follower).unwrap();
+        progress.mode = mode;
+        progress.inflight.clear();
+    }
+
+    /// Returns whether another AppendEntries request may be sent to the follower: one at a time
+    /// while probing, up to `max_inflight` while replicating, and none while a snapshot is being
+    /// installed.
+    pub fn can_send(&self, follower: &ServerId, max_inflight: u64) -> bool {
+        let progress = &self.progress[follower];
+        match progress.mode {
+            ProgressMode::Probe => progress.inflight.is_empty(),
+            ProgressMode::Replicate => (progress.inflight.len() as u64) < max_inflight,
+            ProgressMode::Snapshot => false,
+        }
+    }
+
+    /// Records an AppendEntries request to the follower whose last entry is at `last_index`.
+    pub fn record_inflight(&mut self, follower: ServerId, last_index: LogIndex) {
+        self.progress.get_mut(&follower).unwrap().inflight.push_back(last_index);
+    }
+
+    /// Releases the requests in flight to the follower which carried no entry after `index`.
+    pub fn release_inflight(&mut self, follower: ServerId, index: LogIndex) {
+        let inflight = &mut self.progress.get_mut(&follower).unwrap().inflight;
+        while inflight.front().map_or(false, |&last_index| last_index <= index) {
+            inflight.pop_front();
+        }
+    }
+
+    /// Counts the number of followers among `members` containing the given log index. The
+    /// leader itself is not counted.
+    pub fn count_match_indexes(&self, index: LogIndex, members: &HashSet<ServerId>) -> usize {
+        self.match_index
+            .iter()
+            .filter(|&(peer, &i)| i >= index && members.contains(peer))
+            .count()
+    }
+
+    /// Returns the latest heartbeat round.
+    pub fn round(&self) -> u64 {
+        self.round
+    }
+
+    /// Starts a new heartbeat round and returns it.
+    pub fn start_round(&mut self) -> u64 {
+        self.round += 1;
+        self.round_starts.insert(self.round, Instant::now());
+        self.round
+    }
+
+    /// Returns the rounds which may still be confirmed, latest first.
+    pub fn unconfirmed_rounds(&self) -> Vec<u64> {
+        self.round_starts.keys().rev().cloned().collect()
+    }
+
+    /// Records that a majority acknowledged `round`, which shows that the leader was in contact
+    /// with the majority when the round started.
+    pub fn confirm_round(&mut self, round: u64) {
+        if let Some(&start) = self.round_starts.get(&round) {
+            self.quorum_contact = Some(self.quorum_contact.map_or(start, |c| cmp::max(c, start)));
+            self.round_starts = self.round_starts.split_off(&round);
+        }
+    }
+
+    /// Returns when the latest round acknowledged by a majority was started.
+    pub fn quorum_contact(&self) -> Option<Instant> {
+        self.quorum_contact
+    }
+
+    /// Forgets the contact with the majority, so that only rounds started from now on can
+    /// restore it.
+    pub fn reset_quorum_contact(&mut self) {
+        self.round_starts.clear();
+        self.quorum_contact = None;
+    }
+
+    /// Records that the follower answered a request sent in `round`.
+    pub fn ack_round(&mut self, follower: ServerId, round: u64) {
+        if let Some(acked) = self.acked_round.get_mut(&follower) {
+            *acked = cmp::max(*acked, round);
+        }
+    }
+
+    /// Counts the number of followers among `members` which answered `round` or a later round.
+    /// The leader itself is not counted.
+    pub fn count_round_acks(&self, round: u64, members: &HashSet<ServerId>) -> usize {
+        self.acked_round
+            .iter()
+            .filter(|&(peer, &acked)| acked >= round && members.contains(peer))
+            .count()
     }
 
     /// Reinitializes the state following an election.
@@ -77,16 +278,48 @@ impl LeaderState {
         for mut match_index in self.match_index.values_mut() {
             *match_index = LogIndex::from(0);
         }
+        for mut progress in self.progress.values_mut() {
+            *progress = Progress::new();
+        }
+        for mut acked_round in self.acked_round.values_mut() {
+            *acked_round = 0;
+        }
+        self.round = 0;
+        self.round_starts.clear();
+        self.round_starts.insert(0, Instant::now());
+        self.quorum_contact = None;
         self.proposals.clear();
+        self.reads.clear();
+        self.transfer = None;
+        self.transactions.clear();
+        self.timed_out.clear();
+        self.prepared.clear();
     }
 
     pub fn add_peer(&mut self, peer_id: ServerId) {
         assert_eq!(self.next_index.insert(peer_id, LogIndex::from(1)), None);
         assert_eq!(self.match_index.insert(peer_id, LogIndex::from(0)), None);
+        self.progress.insert(peer_id, Progress::new());
+        self.acked_round.insert(peer_id, 0);
+    }
+
+    /// Starts tracking the followers in `peers` which are not tracked yet, and stops tracking
+    /// the followers which are not in `peers`.
+    pub fn set_peers(&mut self, peers: &HashSet<ServerId>) {
+        self.next_index.retain(|peer, _| peers.contains(peer));
+        self.match_index.retain(|peer, _| peers.contains(peer));
+        self.progress.retain(|peer, _| peers.contains(peer));
+        self.acked_round.retain(|peer, _| peers.contains(peer));
+        for &peer in peers {
+            if !self.next_index.contains_key(&peer) {
+                self.add_peer(peer);
+            }
+        }
     }
 }
 
-/// The state associated with a Raft consensus module in the `Candidate` state.
+/// The state associated with a Raft consensus module in the `PreCandidate` or `Candidate` state.
+/// During the pre-vote phase the granted votes are the pre-votes.
 #[derive(Clone, Debug,Serialize)]
 pub struct CandidateState {
     granted_votes: HashSet<ServerId>,
@@ -103,9 +336,9 @@ impl CandidateState {
         self.granted_votes.insert(voter);
     }
 
-    /// Returns the number of votes.
-    pub fn count_votes(&self) -> usize {
-        self.granted_votes.len()
+    /// Returns the number of votes from `members`.
+    pub fn count_votes(&self, members: &HashSet<ServerId>) -> usize {
+        self.granted_votes.intersection(members).count()
     }
 
     /// Clears the vote count.
@@ -131,6 +364,11 @@ pub struct FollowerState {
     /// otherwise left untouched.
     /// See see ktoso/akka-raft#66.
     pub min_index: LogIndex,
+    /// Client queries forwarded to the leader, by read ID. The read index is set once the leader
+    /// has confirmed it.
+    pub reads: HashMap<u64, (ClientId, Vec<u8>, Option<LogIndex>)>,
+    /// The ID of the next forwarded query.
+    next_read: u64,
 }
 
 impl FollowerState {
@@ -139,9 +377,18 @@ impl FollowerState {
         FollowerState {
             leader: None,
             min_index: LogIndex(0),
+            reads: HashMap::new(),
+            next_read: 0,
         }
     }
 
+    /// Records a query to be forwarded to the leader, and returns its read ID.
+    pub fn add_read(&mut self, client: ClientId, query: Vec<u8>) -> u64 {
+        self.next_read += 1;
+        self.reads.insert(self.next_read, (client, query, None));
+        self.next_read
+    }
+
     /// Sets a new leader.
     pub fn set_leader(&mut self, leader: ServerId) {
         self.leader = Some(leader);
@@ -155,7 +402,7 @@ mod tests {
 
     use {LogIndex, ServerId, ClientId};
     use std::sync::{RwLock, Arc};
-    use state::{LeaderState, CandidateState, FollowerState};
+    use state::{LeaderState, CandidateState, FollowerState, ProgressMode};
 
     use serde_json::to_string as to_json;
 
@@ -168,24 +415,87 @@ mod tests {
 
         // All peers start at 0 index.
         let leader_state = LeaderState::new(index, &peers);
-        // Should be one, since the leader node would be matched always.
-        assert_eq!(1, leader_state.count_match_indexes(LogIndex(0)));
+        // Should be zero, since the leader node is not counted.
+        assert_eq!(0, leader_state.count_match_indexes(LogIndex(0), &peers));
 
         peers.insert(ServerId(1));
         let leader_state = LeaderState::new(index, &peers);
-        assert_eq!(2, leader_state.count_match_indexes(LogIndex(0)));
+        assert_eq!(1, leader_state.count_match_indexes(LogIndex(0), &peers));
 
         peers.insert(ServerId(2));
         let leader_state = LeaderState::new(index, &peers);
-        assert_eq!(3, leader_state.count_match_indexes(LogIndex(0)));
+        assert_eq!(2, leader_state.count_match_indexes(LogIndex(0), &peers));
 
         peers.insert(ServerId(3));
         let mut leader_state = LeaderState::new(index, &peers);
-        assert_eq!(4, leader_state.count_match_indexes(LogIndex(0)));
+        assert_eq!(3, leader_state.count_match_indexes(LogIndex(0), &peers));
 
         leader_state.set_match_index(ServerId(1), LogIndex(1));
         leader_state.set_match_index(ServerId(2), LogIndex(1));
-        assert_eq!(3, leader_state.count_match_indexes(LogIndex(1)));
+        assert_eq!(2, leader_state.count_match_indexes(LogIndex(1), &peers));
+
+        // Only the given members are counted.
+        let members = [ServerId(2), ServerId(3)].iter().cloned().collect();
+        assert_eq!(1, leader_state.count_match_indexes(LogIndex(1), &members));
+
+        // Peers which are no longer tracked are not counted.
+        let all_peers = peers.clone();
+        peers.remove(&ServerId(1));
+        leader_state.set_peers(&peers);
+        assert_eq!(1, leader_state.count_match_indexes(LogIndex(1), &all_peers));
+    }
+
+    /// Tests that the number of AppendEntries requests in flight is limited according to the
+    /// follower's progress mode.
+    #[test]
+    fn test_progress_flow_control() {
+        let follower = ServerId(1);
+        let peers = [follower].iter().cloned().collect();
+        let mut leader_state = LeaderState::new(LogIndex(0), &peers);
+
+        // A probe waits for its answer.
+        assert_eq!(ProgressMode::Probe, leader_state.mode(&follower));
+        assert!(leader_state.can_send(&follower, 2));
+        leader_state.record_inflight(follower, LogIndex(1));
+        assert!(!leader_state.can_send(&follower, 2));
+
+        // Replicating, up to two requests are in flight until the follower acknowledges them.
+        leader_state.set_mode(follower, ProgressMode::Replicate);
+        leader_state.record_inflight(follower, LogIndex(2));
+        leader_state.record_inflight(follower, LogIndex(4));
+        assert!(!leader_state.can_send(&follower, 2));
+        leader_state.release_inflight(follower, LogIndex(3));
+        assert!(leader_state.can_send(&follower, 2));
+        leader_state.release_inflight(follower, LogIndex(4));
+        leader_state.record_inflight(follower, LogIndex(5));
+        leader_state.record_inflight(follower, LogIndex(6));
+        assert!(!leader_state.can_send(&follower, 2));
+
+        // Nothing is sent while a snapshot is installed.
+        leader_state.set_mode(follower, ProgressMode::Snapshot);
+        assert!(!leader_state.can_send(&follower, 2));
+    }
+
+    /// Tests that heartbeat rounds are only confirmed by acknowledgements of the same or a
+    /// later round.
+    #[test]
+    fn test_count_round_acks() {
+        let peers = [ServerId(1), ServerId(2)].iter().cloned().collect();
+        let mut leader_state = LeaderState::new(LogIndex(0), &peers);
+
+        let round = leader_state.start_round();
+        assert_eq!(0, leader_state.count_round_acks(round, &peers));
+        leader_state.ack_round(ServerId(1), round);
+        assert_eq!(1, leader_state.count_round_acks(round, &peers));
+
+        // A stale acknowledgement does not confirm a later round.
+        let next_round = leader_state.start_round();
+        leader_state.ack_round(ServerId(2), round);
+        assert_eq!(0, leader_state.count_round_acks(next_round, &peers));
+        leader_state.ack_round(ServerId(2), next_round);
+        leader_state.ack_round(ServerId(2), round);
+        assert_eq!(1, leader_state.count_round_acks(next_round, &peers));
+        assert_eq!(2, leader_state.count_round_acks(round, &peers));
     }
 
     #[test]
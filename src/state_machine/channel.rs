@@ -7,6 +7,7 @@ use state_machine::StateMachine;
 /// A state machine that simply redirects all commands to a channel.
 ///
 /// This state machine is chiefly meant for testing.
+#[derive(Clone)]
 pub struct ChannelStateMachine {
     tx: mpsc::Sender<Vec<u8>>,
 }
@@ -27,19 +28,15 @@ impl StateMachine for ChannelStateMachine {
     }
 
     fn query(&self, _query: &[u8]) -> Vec<u8> {
-        unimplemented!()
-    }
-
-    fn snapshot(&self) -> Vec<u8> {
         Vec::new()
     }
 
-    fn restore_snapshot(&mut self, _snapshot: Vec<u8>) -> () {
-        ()
+    fn snapshot(&self) -> (Vec<u8>, Vec<u8>) {
+        (Vec::new(), Vec::new())
     }
 
-    fn revert(&mut self, _command: &[u8]) -> () {
-        unimplemented!()
+    fn restore_snapshot(&mut self, _snap_map: Vec<u8>, _snap_log: Vec<u8>) {
+        ()
     }
 }
 
@@ -9,10 +9,10 @@
 //! commands would be seen by all consensus modules.
 use std::fmt::Debug;
 
-// mod channel;
+mod channel;
 mod null;
 
-// pub use state_machine::channel::ChannelStateMachine;
+pub use state_machine::channel::ChannelStateMachine;
 pub use state_machine::null::NullStateMachine;
 
 /// This trait is meant to be implemented such that the commands issued to it via `apply()` will
@@ -38,8 +38,24 @@ pub trait StateMachine: Debug + Send + Clone + 'static {
     /// Restore a snapshot of the state machine.
     fn restore_snapshot(&mut self, map: Vec<u8>, log: Vec<u8>) -> ();
 
-    /// Reverts all messages which has been applied during a transaction
-    fn revert(&mut self, command: &[u8]) -> ();
-
-    fn rollback(&mut self);
+    /// Returns the keys of the state modified by a command. A transaction fails to commit if a
+    /// command committed after the transaction began modified one of the keys modified by the
+    /// commands of the transaction. By default every command modifies the whole state, so
+    /// concurrent transactions only commit if no other command was committed in between.
+    fn keys(&self, _command: &[u8]) -> Vec<Vec<u8>> {
+        vec![Vec::new()]
+    }
+
+    /// Reverts a command applied during a transaction.
+    ///
+    /// The consensus no longer calls this method: the commands of a transaction are buffered
+    /// outside of the state machine until the transaction commits, so rolling a transaction back
+    /// leaves the state machine untouched. It is kept so that existing implementations still
+    /// compile, and will be removed.
+    #[deprecated(note = "transactions no longer reach the state machine before they commit")]
+    fn revert(&mut self, _command: &[u8]) -> () {}
+
+    /// Finishes reverting the commands of a transaction. Like `revert()`, it is no longer called.
+    #[deprecated(note = "transactions no longer reach the state machine before they commit")]
+    fn rollback(&mut self) {}
 }
@@ -20,10 +20,4 @@ impl StateMachine for NullStateMachine {
     fn restore_snapshot(&mut self, _snap_map: Vec<u8>, _snap_log: Vec<u8>) {
         ()
     }
-
-    fn revert(&mut self, _command: &[u8]) -> () {
-        unimplemented!()
-    }
-
-    fn rollback(&mut self) {}
 }
@@ -1,18 +1,49 @@
+//! Transactions on a log.
+//!
+//! Any number of transactions may run at once. The commands of a transaction are replicated like
+//! any other entry, but each replica buffers them in the transaction's write set instead of
+//! applying them. Committing the transaction applies the whole write set at once, unless a
+//! command committed since the transaction began modified one of the keys the write set modifies
+//! (see `StateMachine::keys()`), in which case the transaction fails. Rolling back drops the
+//! write set. Commands outside of transactions are applied right away. A savepoint marks a
+//! point within the write set; rolling back to it drops only the commands buffered since.
+//!
+//! A transaction spanning several logs runs as one transaction on each of them, committed with
+//! two-phase commit. Each log first prepares its transaction, which checks it for conflicts;
+//! from then on the transaction cannot fail, and only ends as decided. The decision to commit or
+//! abort is recorded in one of the logs, the coordinator log, where the first decision recorded
+//! for a transaction stands.
+
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
 
-use capnp::message::{Builder, HeapAllocator};
-use consensus::Actions;
-use messages;
-use std::rc::Rc;
-use LogIndex;
 use LogId;
-use ClientId;
+use LogIndex;
 use TransactionId;
 
+/// The number of decisions a coordinator log remembers. The participants of a transaction learn
+/// the decision long before as many later transactions have been decided.
+const MAX_DECISIONS: usize = 4096;
+
+/// The number of finished transactions whose status a log remembers, so that a client which
+/// lost the answer to the request ending a transaction can still learn how it ended.
+const MAX_FINISHED: usize = 4096;
+
 #[derive(Debug,Clone)]
 pub enum TransactionError {
     NotActive,
     AlreadyActive,
+    /// A command committed since the transaction began modified a key the transaction modifies.
+    Conflict,
+    /// The transaction went without a request for longer than the transaction timeout, and was
+    /// rolled back.
+    TimedOut,
+    /// The coordinator log decided to abort the transaction.
+    Aborted,
+    /// The transaction has no savepoint of the given name.
+    UnknownSavepoint(String),
+    /// The transaction is prepared, and so can no longer change.
+    Prepared,
     Other(String),
 }
 
@@ -23,154 +54,487 @@ impl fmt::Display for TransactionError {
             TransactionError::AlreadyActive => {
                 fmt::Display::fmt("A transaction is already active", f)
             }
+            TransactionError::Conflict => {
+                fmt::Display::fmt("The transaction conflicts with a committed command", f)
+            }
+            TransactionError::TimedOut => {
+                fmt::Display::fmt("The transaction timed out and was rolled back", f)
+            }
+            TransactionError::Aborted => fmt::Display::fmt("The transaction was aborted", f),
+            TransactionError::UnknownSavepoint(ref name) => {
+                write!(f, "The transaction has no savepoint named {}", name)
+            }
+            TransactionError::Prepared => fmt::Display::fmt("The transaction is prepared", f),
             TransactionError::Other(ref error) => fmt::Display::fmt(error, f),
         }
     }
 }
 
-#[derive(Clone)]
+/// The state of a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransactionState {
+    /// The transaction is running.
+    Active,
+    /// The transaction is running and prepared, and ends as its coordinator log decides.
+    Prepared,
+    /// The transaction committed, and its commands were applied.
+    Committed,
+    /// The transaction was rolled back, or failed to commit, and its commands were dropped.
+    RolledBack,
+}
+
+/// The status of a running or recently finished transaction.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionStatus {
+    pub state: TransactionState,
+    /// The number of commands proposed within the transaction.
+    pub commands: u64,
+    /// The index of the entry which began the transaction.
+    pub begin_index: LogIndex,
+}
+
+/// A running transaction.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Transaction {
+    /// The index of the entry which began the transaction.
+    begin_index: LogIndex,
+    /// The commands of the transaction, in log order, along with the keys each modifies.
+    commands: Vec<(Vec<u8>, Vec<Vec<u8>>)>,
+    /// The keys modified by the commands.
+    keys: HashSet<Vec<u8>>,
+    /// The savepoints of the transaction, oldest first, along with the number of commands
+    /// buffered before each.
+    savepoints: Vec<(String, usize)>,
+    /// The coordinator log of the transaction, once the transaction is prepared.
+    coordinator: Option<LogId>,
+}
+
+/// The transactions running as of the latest applied entry. Every replica holds the same
+/// transactions, and they are carried in every snapshot.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TransactionManager {
-    /// Whether a transaction is running
-    pub is_active: bool,
-    /// The ID of the current transaction. If `None`, no transaction is running
-    pub session: Option<TransactionId>,
-    /// The amount of the messages which has been applied during transaction
-    counter: usize,
-    /// The commit_index before the transaction
-    commit_index: LogIndex,
-    /// The last_applied index before the transaction
-    last_applied: LogIndex,
-    /// The follower_state_min index before the transaction
-    follower_state_min: Option<LogIndex>,
+    transactions: HashMap<TransactionId, Transaction>,
+    /// The keys modified by the commands committed since the oldest running transaction began,
+    /// along with the index of the committing entry.
+    writes: VecDeque<(LogIndex, Vec<Vec<u8>>)>,
+    /// The decisions recorded in this log as coordinator log, whether to commit, by transaction.
+    decisions: HashMap<TransactionId, bool>,
+    /// The transactions in `decisions`, oldest first.
+    decision_order: VecDeque<TransactionId>,
+    /// The status of the most recently finished transactions.
+    finished: HashMap<TransactionId, TransactionStatus>,
+    /// The transactions in `finished`, oldest first.
+    finished_order: VecDeque<TransactionId>,
 }
 
 impl TransactionManager {
     /// Creates new TransactionManager
     pub fn new() -> Self {
-        TransactionManager {
-            is_active: false,
-            session: None,
-            counter: 0,
-            commit_index: LogIndex::from(0),
-            last_applied: LogIndex::from(0),
-            follower_state_min: None,
+        TransactionManager::default()
+    }
+
+    /// Returns whether the transaction is running.
+    pub fn is_active(&self, session: &TransactionId) -> bool {
+        self.transactions.contains_key(session)
+    }
+
+    /// Returns the coordinator log of the transaction if the transaction is prepared.
+    pub fn prepared(&self, session: &TransactionId) -> Option<LogId> {
+        self.transactions.get(session).and_then(|transaction| transaction.coordinator)
+    }
+
+    /// Returns the status of the transaction if it is running or among the most recently
+    /// finished transactions.
+    pub fn status(&self, session: &TransactionId) -> Option<TransactionStatus> {
+        match self.transactions.get(session) {
+            Some(transaction) => {
+                let state = if transaction.coordinator.is_some() {
+                    TransactionState::Prepared
+                } else {
+                    TransactionState::Active
+                };
+                Some(TransactionStatus {
+                    state: state,
+                    commands: transaction.commands.len() as u64,
+                    begin_index: transaction.begin_index,
+                })
+            }
+            None => self.finished.get(session).cloned(),
         }
     }
 
+    /// Returns the running transactions.
+    pub fn sessions(&self) -> HashSet<TransactionId> {
+        self.transactions.keys().cloned().collect()
+    }
+
     /// Begins new transaction
     ///
     /// # Arguments
     /// * `session` - The ID of the transaction
-    /// * `commit_index` - The commit_index before the transaction
-    /// * `last_applied` - The last_applied index before the transaction
-    /// * `follower_state_min` - The follower_state_min index before the transaction
+    /// * `begin_index` - The index of the entry which begins the transaction
     pub fn begin(&mut self,
                  session: TransactionId,
-                 commit_index: LogIndex,
-                 last_applied: LogIndex,
-                 follower_state_min: Option<LogIndex>)
+                 begin_index: LogIndex)
                  -> Result<(), TransactionError> {
-        assert!(!self.is_active);
+        if self.is_active(&session) {
+            return Err(TransactionError::AlreadyActive);
+        }
+        scoped_debug!("transaction {} begins at {}", session, begin_index);
+        let transaction = Transaction {
+            begin_index: begin_index,
+            commands: Vec::new(),
+            keys: HashSet::new(),
+            savepoints: Vec::new(),
+            coordinator: None,
+        };
+        self.transactions.insert(session, transaction);
+        Ok(())
+    }
+
+    /// Adds a command modifying `keys` to the write set of the transaction.
+    pub fn buffer(&mut self,
+                  session: TransactionId,
+                  command: Vec<u8>,
+                  keys: Vec<Vec<u8>>)
+                  -> Result<(), TransactionError> {
+        let transaction = try!(self.transactions
+            .get_mut(&session)
+            .ok_or(TransactionError::NotActive));
+        transaction.keys.extend(keys.iter().cloned());
+        transaction.commands.push((command, keys));
+        Ok(())
+    }
 
-        if !self.is_active {
-            scoped_debug!("TRANSACTION BEGINS");
+    /// Marks the commands buffered so far in the transaction with a savepoint named `name`. A
+    /// savepoint reusing the name of an earlier one hides it.
+    pub fn savepoint(&mut self,
+                     session: TransactionId,
+                     name: String)
+                     -> Result<(), TransactionError> {
+        let transaction = try!(self.transactions
+            .get_mut(&session)
+            .ok_or(TransactionError::NotActive));
+        if transaction.coordinator.is_some() {
+            return Err(TransactionError::Prepared);
+        }
+        scoped_debug!("transaction {} sets savepoint {} after {} commands",
+                      session,
+                      name,
+                      transaction.commands.len());
+        transaction.savepoints.push((name, transaction.commands.len()));
+        Ok(())
+    }
 
-            self.session = Some(session);
-            self.is_active = true;
-            self.commit_index = commit_index;
-            self.last_applied = last_applied;
-            self.follower_state_min = follower_state_min;
-            Ok(())
-        } else {
-            Err(TransactionError::AlreadyActive)
+    /// Drops the commands buffered in the transaction since the latest savepoint named `name`,
+    /// along with the savepoints set since. The savepoint itself remains.
+    pub fn rollback_to(&mut self,
+                       session: TransactionId,
+                       name: &str)
+                       -> Result<(), TransactionError> {
+        let transaction = try!(self.transactions
+            .get_mut(&session)
+            .ok_or(TransactionError::NotActive));
+        if transaction.coordinator.is_some() {
+            return Err(TransactionError::Prepared);
         }
+        let position = try!(transaction.savepoints
+            .iter()
+            .rposition(|&(ref savepoint, _)| savepoint == name)
+            .ok_or_else(|| TransactionError::UnknownSavepoint(name.to_string())));
+        let length = transaction.savepoints[position].1;
+        scoped_debug!("transaction {} rolls back to savepoint {}, dropping {} commands",
+                      session,
+                      name,
+                      transaction.commands.len() - length);
+        transaction.savepoints.truncate(position + 1);
+        transaction.commands.truncate(length);
+        transaction.keys = transaction.commands
+            .iter()
+            .flat_map(|&(_, ref keys)| keys.iter().cloned())
+            .collect();
+        Ok(())
     }
 
-    /// Reverts all messages which has been applied during transaction
-    pub fn rollback(&mut self) -> Result<(LogIndex, LogIndex, Option<LogIndex>), TransactionError> {
-        if self.is_active {
-            let commit_index = self.commit_index;
-            let last_applied = self.last_applied;
-            let follower_state_min = self.follower_state_min;
+    /// Records a command outside of any transaction modifying `keys`, committed by the entry at
+    /// `index`.
+    pub fn record_write(&mut self, index: LogIndex, keys: Vec<Vec<u8>>) {
+        // Only running transactions can conflict with the command.
+        if !self.transactions.is_empty() {
+            self.writes.push_back((index, keys));
+        }
+    }
 
-            try!(self.end());
+    /// Returns whether a command committed since the transaction began modified one of the keys
+    /// the transaction modifies.
+    fn conflicts(&self, transaction: &Transaction) -> bool {
+        self.writes
+            .iter()
+            .filter(|&&(write_index, _)| write_index > transaction.begin_index)
+            .any(|&(_, ref keys)| keys.iter().any(|key| transaction.keys.contains(key)))
+    }
 
-            Ok((commit_index, last_applied, follower_state_min))
-        } else {
-            Err(TransactionError::NotActive)
+    /// Prepares the transaction to commit as part of a transaction spanning several logs,
+    /// prepared by the entry at `index`. Fails and ends the transaction if it conflicts with a
+    /// command committed since it began. Otherwise the transaction commits without further
+    /// checks, and the other running transactions conflict with it as if it had committed.
+    pub fn prepare(&mut self,
+                   session: TransactionId,
+                   coordinator: LogId,
+                   index: LogIndex)
+                   -> Result<(), TransactionError> {
+        let conflict = match self.transactions.get(&session) {
+            Some(transaction) if transaction.coordinator.is_some() => return Ok(()),
+            Some(transaction) => self.conflicts(transaction),
+            None => return Err(TransactionError::NotActive),
+        };
+        if conflict {
+            scoped_debug!("transaction {} conflicts with a committed command", session);
+            let transaction = self.transactions.remove(&session).unwrap();
+            self.finish(session, &transaction, TransactionState::RolledBack);
+            self.prune_writes();
+            return Err(TransactionError::Conflict);
         }
+
+        scoped_debug!("transaction {} prepared; coordinated by log {:?}", session, coordinator);
+        let keys = {
+            let transaction = self.transactions.get_mut(&session).unwrap();
+            transaction.coordinator = Some(coordinator);
+            transaction.keys.iter().cloned().collect()
+        };
+        self.writes.push_back((index, keys));
+        Ok(())
     }
 
-    /// Resets all values of the TransactionManager for the next transaction
-    pub fn end(&mut self) -> Result<(), TransactionError> {
-        if self.is_active {
-            scoped_debug!("TRANSACTION FINISHED! {} messages received", self.counter);
+    /// Ends the transaction, committed by the entry at `index`. Returns the commands of the
+    /// transaction, which have to be applied in order, unless the transaction conflicts with a
+    /// command committed since it began. A prepared transaction has been checked already.
+    pub fn commit(&mut self,
+                  session: TransactionId,
+                  index: LogIndex)
+                  -> Result<Vec<Vec<u8>>, TransactionError> {
+        let transaction = try!(self.transactions
+            .remove(&session)
+            .ok_or(TransactionError::NotActive));
+        if transaction.coordinator.is_none() && self.conflicts(&transaction) {
+            scoped_debug!("transaction {} conflicts with a committed command", session);
+            self.finish(session, &transaction, TransactionState::RolledBack);
+            self.prune_writes();
+            return Err(TransactionError::Conflict);
+        }
+
+        scoped_debug!("transaction {} commits {} commands",
+                      session,
+                      transaction.commands.len());
+        self.finish(session, &transaction, TransactionState::Committed);
+        if !self.transactions.is_empty() {
+            self.writes.push_back((index, transaction.keys.into_iter().collect()));
+        }
+        self.prune_writes();
+        Ok(transaction.commands.into_iter().map(|(command, _)| command).collect())
+    }
 
-            self.session = None;
-            self.counter = 0;
-            self.is_active = false;
-            self.commit_index = LogIndex::from(0);
-            self.last_applied = LogIndex::from(0);
-            self.follower_state_min = None;
+    /// Ends the transaction, dropping its commands.
+    pub fn rollback(&mut self, session: TransactionId) -> Result<(), TransactionError> {
+        let transaction = try!(self.transactions
+            .remove(&session)
+            .ok_or(TransactionError::NotActive));
+        scoped_debug!("transaction {} rolled back", session);
+        self.finish(session, &transaction, TransactionState::RolledBack);
+        self.prune_writes();
+        Ok(())
+    }
 
-            Ok(())
-        } else {
-            Err(TransactionError::NotActive)
+    /// Records the decision whether to commit the transaction, unless a decision has been
+    /// recorded already. Returns the decision which stands.
+    pub fn decide(&mut self, session: TransactionId, commit: bool) -> bool {
+        if let Some(&decision) = self.decisions.get(&session) {
+            return decision;
         }
+        scoped_debug!("decided to {} transaction {}",
+                      if commit { "commit" } else { "abort" },
+                      session);
+        self.decisions.insert(session, commit);
+        self.decision_order.push_back(session);
+        if self.decision_order.len() > MAX_DECISIONS {
+            let oldest = self.decision_order.pop_front().unwrap();
+            self.decisions.remove(&oldest);
+        }
+        commit
     }
 
-    /// Sends to all peers a message that a transaction has been started
-    pub fn broadcast_begin(&mut self, lid: LogId, actions: &mut Actions) {
-        scoped_debug!("BROADCAST TRANSACTION BEGINS");
-        let message = messages::transaction_begin(lid,
-                                                  self.session
-                                                      .expect("Cannot start transaction when no \
-                                                               TransactionId has been set"));
-        actions.peer_messages_broadcast.push(message);
+    /// Returns the decision whether to commit the transaction, if one has been recorded.
+    pub fn decision(&self, session: &TransactionId) -> Option<bool> {
+        self.decisions.get(session).cloned()
     }
 
-    /// Sends to all peers a message that the currently running transaction should be commited
-    pub fn broadcast_end(&self, lid: LogId, actions: &mut Actions) {
-        scoped_debug!("BROADCAST TRANSACTION ENDS");
-        let message = messages::transaction_commit(lid,
-                                                   self.session
-                                                       .expect("Cannot end transaction when \
-                                                                no TransactionId has been set"));
-        actions.peer_messages_broadcast.push(message);
+    /// Records how the transaction ended, forgetting the oldest finished transaction if too many
+    /// are remembered.
+    fn finish(&mut self,
+              session: TransactionId,
+              transaction: &Transaction,
+              state: TransactionState) {
+        let status = TransactionStatus {
+            state: state,
+            commands: transaction.commands.len() as u64,
+            begin_index: transaction.begin_index,
+        };
+        if self.finished.insert(session, status).is_none() {
+            self.finished_order.push_back(session);
+        }
+        if self.finished_order.len() > MAX_FINISHED {
+            let oldest = self.finished_order.pop_front().unwrap();
+            self.finished.remove(&oldest);
+        }
     }
 
-    /// Sends to all peers a messages that the all messages which has been applied during the
-    /// transaction should be reverted
-    pub fn broadcast_rollback(&self, lid: LogId, actions: &mut Actions) {
-        scoped_debug!("BROADCAST TRANSACTION ROLLBACK");
-        let message = messages::transaction_rollback(lid,
-                                                     self.session
-                                                         .expect("Cannot rollback transaction \
-                                                                  when no TransactionId has \
-                                                                  been set"));
-        actions.peer_messages_broadcast.push(message);
+    /// Forgets the writes which no running transaction can conflict with.
+    fn prune_writes(&mut self) {
+        let oldest = self.transactions.values().map(|transaction| transaction.begin_index).min();
+        match oldest {
+            Some(oldest) => {
+                while self.writes.front().map_or(false, |&(index, _)| index <= oldest) {
+                    self.writes.pop_front();
+                }
+            }
+            None => self.writes.clear(),
+        }
     }
+}
 
-    /// Compares the current TransactionId with the given one. If `true`, it is the same
-    ///
-    /// # Arguments
-    /// * `session` - The TransactionId which will be compared with
-    pub fn compare(&self, session: TransactionId) -> bool {
-        self.session.expect("No TransactionId has been set") == session
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use {LogId, LogIndex, TransactionId};
+    use transaction::{TransactionError, TransactionManager, TransactionState};
+
+    /// Tests that a transaction only conflicts with the commands committed after it began which
+    /// modify the same keys.
+    #[test]
+    fn test_conflicts() {
+        let mut transactions = TransactionManager::new();
+        let (first, second) = (TransactionId::new(), TransactionId::new());
+        transactions.record_write(LogIndex(1), vec![b"a".to_vec()]);
+        transactions.begin(first, LogIndex(2)).unwrap();
+        transactions.begin(second, LogIndex(3)).unwrap();
+        assert!(transactions.begin(first, LogIndex(4)).is_err());
+
+        transactions.buffer(first, b"a = 1".to_vec(), vec![b"a".to_vec()]).unwrap();
+        transactions.buffer(second, b"b = 1".to_vec(), vec![b"b".to_vec()]).unwrap();
+        transactions.record_write(LogIndex(5), vec![b"a".to_vec()]);
+
+        match transactions.commit(first, LogIndex(6)) {
+            Err(TransactionError::Conflict) => (),
+            result => panic!("unexpected commit result: {:?}", result),
+        }
+        assert_eq!(vec![b"b = 1".to_vec()],
+                   transactions.commit(second, LogIndex(7)).unwrap());
+        assert!(transactions.sessions().is_empty());
+        assert!(transactions.rollback(second).is_err());
     }
 
-    /// Returns whether the a transaction is running
-    pub fn get(&self) -> bool {
-        self.is_active
+    /// Tests that a committed transaction conflicts with the transactions running alongside it.
+    #[test]
+    fn test_commit_conflicts_with_running() {
+        let mut transactions = TransactionManager::new();
+        let (first, second) = (TransactionId::new(), TransactionId::new());
+        transactions.begin(first, LogIndex(1)).unwrap();
+        transactions.begin(second, LogIndex(2)).unwrap();
+        transactions.buffer(first, b"a = 1".to_vec(), vec![b"a".to_vec()]).unwrap();
+        transactions.buffer(second, b"a = 2".to_vec(), vec![b"a".to_vec()]).unwrap();
+
+        assert!(transactions.commit(first, LogIndex(3)).is_ok());
+        assert!(transactions.commit(second, LogIndex(4)).is_err());
     }
 
-    /// Returns how many messages has been applied
-    pub fn get_counter(&self) -> usize {
-        self.counter
+    /// Tests that a prepared transaction is checked for conflicts when it is prepared rather
+    /// than when it commits, and that the first decision on a transaction stands.
+    #[test]
+    fn test_prepare() {
+        let mut transactions = TransactionManager::new();
+        let coordinator = LogId(Uuid::new_v4());
+        let (first, second) = (TransactionId::new(), TransactionId::new());
+        transactions.begin(first, LogIndex(1)).unwrap();
+        transactions.begin(second, LogIndex(2)).unwrap();
+        transactions.buffer(first, b"a = 1".to_vec(), vec![b"a".to_vec()]).unwrap();
+        transactions.buffer(second, b"a = 2".to_vec(), vec![b"a".to_vec()]).unwrap();
+
+        transactions.prepare(first, coordinator, LogIndex(3)).unwrap();
+        assert_eq!(Some(coordinator), transactions.prepared(&first));
+        assert_eq!(None, transactions.prepared(&second));
+        transactions.record_write(LogIndex(4), vec![b"a".to_vec()]);
+
+        // The prepared transaction conflicts with the transactions running alongside it.
+        match transactions.prepare(second, coordinator, LogIndex(5)) {
+            Err(TransactionError::Conflict) => (),
+            result => panic!("unexpected prepare result: {:?}", result),
+        }
+        assert!(!transactions.is_active(&second));
+        assert_eq!(vec![b"a = 1".to_vec()],
+                   transactions.commit(first, LogIndex(6)).unwrap());
+
+        assert_eq!(None, transactions.decision(&first));
+        assert!(!transactions.decide(first, false));
+        assert!(!transactions.decide(first, true));
+        assert_eq!(Some(false), transactions.decision(&first));
     }
 
-    /// Counts up the message counter
-    pub fn count_up(&mut self) {
-        self.counter += 1;
+    /// Tests that the status of a transaction follows it from beginning to end.
+    #[test]
+    fn test_status() {
+        let mut transactions = TransactionManager::new();
+        let (first, second) = (TransactionId::new(), TransactionId::new());
+        assert_eq!(None, transactions.status(&first));
+        transactions.begin(first, LogIndex(1)).unwrap();
+        transactions.begin(second, LogIndex(2)).unwrap();
+        transactions.buffer(first, b"a = 1".to_vec(), vec![b"a".to_vec()]).unwrap();
+        transactions.buffer(first, b"b = 1".to_vec(), vec![b"b".to_vec()]).unwrap();
+
+        let status = transactions.status(&first).unwrap();
+        assert_eq!(TransactionState::Active, status.state);
+        assert_eq!(2, status.commands);
+        assert_eq!(LogIndex(1), status.begin_index);
+        transactions.prepare(first, LogId(Uuid::new_v4()), LogIndex(3)).unwrap();
+        assert_eq!(TransactionState::Prepared,
+                   transactions.status(&first).unwrap().state);
+
+        transactions.commit(first, LogIndex(4)).unwrap();
+        transactions.rollback(second).unwrap();
+        let status = transactions.status(&first).unwrap();
+        assert_eq!(TransactionState::Committed, status.state);
+        assert_eq!(2, status.commands);
+        let status = transactions.status(&second).unwrap();
+        assert_eq!(TransactionState::RolledBack, status.state);
+        assert_eq!(LogIndex(2), status.begin_index);
+    }
+
+    /// Tests that rolling back to a savepoint drops only the commands buffered since, and that
+    /// the dropped commands no longer cause conflicts.
+    #[test]
+    fn test_savepoints() {
+        let mut transactions = TransactionManager::new();
+        let session = TransactionId::new();
+        transactions.begin(session, LogIndex(1)).unwrap();
+        transactions.buffer(session, b"a = 1".to_vec(), vec![b"a".to_vec()]).unwrap();
+        transactions.savepoint(session, "first".to_string()).unwrap();
+        transactions.buffer(session, b"b = 1".to_vec(), vec![b"b".to_vec()]).unwrap();
+        transactions.savepoint(session, "second".to_string()).unwrap();
+        transactions.buffer(session, b"c = 1".to_vec(), vec![b"c".to_vec()]).unwrap();
+
+        transactions.rollback_to(session, "second").unwrap();
+        assert_eq!(2, transactions.status(&session).unwrap().commands);
+        transactions.rollback_to(session, "first").unwrap();
+        assert_eq!(1, transactions.status(&session).unwrap().commands);
+        // Rolling back to a savepoint drops the savepoints set after it.
+        match transactions.rollback_to(session, "second") {
+            Err(TransactionError::UnknownSavepoint(_)) => (),
+            result => panic!("unexpected rollback result: {:?}", result),
+        }
+        transactions.buffer(session, b"d = 1".to_vec(), vec![b"d".to_vec()]).unwrap();
+
+        transactions.record_write(LogIndex(2), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(vec![b"a = 1".to_vec(), b"d = 1".to_vec()],
+                   transactions.commit(session, LogIndex(3)).unwrap());
     }
 }